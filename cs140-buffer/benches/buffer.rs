@@ -0,0 +1,77 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::sync::Arc;
+
+use test::Bencher;
+use tokio::runtime::{Builder, Runtime};
+
+use cs140_buffer::ring_buffer::RingBuffer;
+use cs140_buffer::spsc_ring_buffer::SpscRingBuffer;
+use cs140_common::buffer::Buffer;
+
+// an iteration moves a second of samples in the chunks of the callbacks of the sound card
+const SAMPLE_COUNT: usize = 48000;
+const CHUNK_SAMPLE_COUNT: usize = 480;
+const CAPACITY: usize = 5000000;
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().build().unwrap()
+}
+
+async fn pop_chunks(buffer: &impl Buffer<f32>) {
+    for _ in 0..SAMPLE_COUNT / CHUNK_SAMPLE_COUNT {
+        buffer.pop(CHUNK_SAMPLE_COUNT, |first, second| (first.len() + second.len(), CHUNK_SAMPLE_COUNT)).await;
+    }
+}
+
+// push a chunk and pop it in one thread, the cost of the buffer without any contention
+fn single_thread(bencher: &mut Bencher, buffer: impl Buffer<f32>) {
+    let rt = runtime();
+    let chunk = vec![0.5; CHUNK_SAMPLE_COUNT];
+    bencher.iter(|| rt.block_on(async {
+        for _ in 0..SAMPLE_COUNT / CHUNK_SAMPLE_COUNT {
+            buffer.push_by_ref(&chunk).await;
+            buffer.pop(CHUNK_SAMPLE_COUNT, |first, second| (first.len() + second.len(), CHUNK_SAMPLE_COUNT)).await;
+        }
+    }));
+}
+
+// a thread pushes the chunks like the input callback while another thread pops them like the physical layer
+fn two_threads(bencher: &mut Bencher, buffer: impl Buffer<f32> + 'static) {
+    let rt = runtime();
+    let buffer = Arc::new(buffer);
+    bencher.iter(|| {
+        let producer_buffer = buffer.clone();
+        let producer = std::thread::spawn(move || {
+            let rt = runtime();
+            let chunk = vec![0.5; CHUNK_SAMPLE_COUNT];
+            for _ in 0..SAMPLE_COUNT / CHUNK_SAMPLE_COUNT {
+                rt.block_on(producer_buffer.push_by_ref(&chunk));
+            }
+        });
+        rt.block_on(pop_chunks(buffer.as_ref()));
+        producer.join().unwrap();
+    });
+}
+
+#[bench]
+fn ring_buffer_single_thread(bencher: &mut Bencher) {
+    single_thread(bencher, RingBuffer::<f32>::new(CAPACITY));
+}
+
+#[bench]
+fn spsc_ring_buffer_single_thread(bencher: &mut Bencher) {
+    single_thread(bencher, SpscRingBuffer::<f32>::new(CAPACITY));
+}
+
+#[bench]
+fn ring_buffer_two_threads(bencher: &mut Bencher) {
+    two_threads(bencher, RingBuffer::<f32>::new(CAPACITY));
+}
+
+#[bench]
+fn spsc_ring_buffer_two_threads(bencher: &mut Bencher) {
+    two_threads(bencher, SpscRingBuffer::<f32>::new(CAPACITY));
+}
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::{Future, poll_fn};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Poll, Waker};
+
+use tokio::time::Instant;
+
+use cs140_common::buffer::Timeout;
+
+/// SlowSubscriber decides what happens to a subscriber whose items are overwritten before it pops them
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlowSubscriber {
+    /// the subscriber is dropped, its pops return SubscriberDropped
+    Drop,
+    /// the subscriber skips the overwritten items, they are counted by lost
+    Lossy,
+}
+
+/// SubscriberDropped is returned by the pops of a subscriber which is dropped for being slow
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SubscriberDropped;
+
+impl Display for SubscriberDropped {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the subscriber is dropped since its items are overwritten before it pops them")
+    }
+}
+
+impl std::error::Error for SubscriberDropped {}
+
+struct Cursor {
+    popped: usize,
+    slow: SlowSubscriber,
+    lost: usize,
+    dropped: bool,
+    waker: Option<Waker>,
+}
+
+struct Broadcast<T> {
+    buffer: Box<[T]>,
+    // the count of the items pushed since the buffer is created, an item is at its count modulo the capacity
+    pushed: usize,
+    next_id: usize,
+    cursors: HashMap<usize, Cursor>,
+}
+
+/// BroadcastRingBuffer is a ring buffer of a runtime capacity whose items are popped by every subscriber,
+/// each subscriber pops from its own read cursor, so the data receiver and a recorder can share an input stream.
+/// The producer never waits, a subscriber which falls a capacity behind it is dropped or made lossy.
+pub struct BroadcastRingBuffer<T>(Mutex<Broadcast<T>>);
+
+impl<T: Copy + Default> BroadcastRingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "the capacity of a ring buffer must be positive");
+        Self(Mutex::new(Broadcast {
+            buffer: vec![T::default(); capacity].into_boxed_slice(),
+            pushed: 0,
+            next_id: 0,
+            cursors: HashMap::new(),
+        }))
+    }
+
+    /// push at most count items without waiting, the producer gets count slots and returns the count of the items pushed,
+    /// only the latest capacity items are kept if count is larger than the capacity
+    pub fn push(&self, count: usize, producer: impl for<'a> FnOnce(&'a mut [T], &'a mut [T]) -> usize) {
+        let mut guard = self.lock();
+        let broadcast = &mut *guard;
+        let capacity = broadcast.buffer.len();
+        if count > capacity {
+            let mut items = vec![T::default(); count];
+            let pushed = producer(&mut items, &mut []);
+            assert!(pushed <= count, "push {} items into {} slots", pushed, count);
+            // the items before the latest capacity ones are overwritten at once
+            let skipped = pushed.saturating_sub(capacity);
+            broadcast.pushed += skipped;
+            for item in &items[skipped..pushed] {
+                broadcast.buffer[broadcast.pushed % capacity] = *item;
+                broadcast.pushed += 1;
+            }
+        } else {
+            let tail = broadcast.pushed % capacity;
+            let first_len = std::cmp::min(count, capacity - tail);
+            let (second, first) = broadcast.buffer.split_at_mut(tail);
+            let pushed = producer(&mut first[..first_len], &mut second[..count - first_len]);
+            assert!(pushed <= count, "push {} items into {} slots", pushed, count);
+            broadcast.pushed += pushed;
+        }
+        let pushed = broadcast.pushed;
+        for cursor in broadcast.cursors.values_mut() {
+            if !cursor.dropped && pushed - cursor.popped > capacity {
+                match cursor.slow {
+                    SlowSubscriber::Drop => cursor.dropped = true,
+                    SlowSubscriber::Lossy => {
+                        cursor.lost += pushed - capacity - cursor.popped;
+                        cursor.popped = pushed - capacity;
+                    }
+                }
+            }
+            if let Some(waker) = cursor.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> BroadcastRingBuffer<T> {
+    pub fn capacity(&self) -> usize {
+        self.lock().buffer.len()
+    }
+
+    /// the subscribers which are not dropped
+    pub fn subscriber_count(&self) -> usize {
+        self.lock().cursors.values().filter(|cursor| !cursor.dropped).count()
+    }
+
+    /// subscribe the items pushed from now on
+    pub fn subscribe(self: &Arc<Self>, slow: SlowSubscriber) -> BroadcastSubscriber<T> {
+        let mut guard = self.lock();
+        let id = guard.next_id;
+        guard.next_id += 1;
+        let cursor = Cursor {
+            popped: guard.pushed,
+            slow,
+            lost: 0,
+            dropped: false,
+            waker: None,
+        };
+        guard.cursors.insert(id, cursor);
+        BroadcastSubscriber {
+            buffer: self.clone(),
+            id,
+        }
+    }
+
+    /// subscribe the items pushed from now on by a lossy subscriber, which can be popped through PopBuffer
+    pub fn subscribe_lossy(self: &Arc<Self>) -> LossySubscriber<T> {
+        LossySubscriber(self.subscribe(SlowSubscriber::Lossy))
+    }
+
+    // the consumers never run with the lock held, and a panicking producer leaves the cursors consistent,
+    // so the lock is taken over after it is poisoned
+    fn lock(&self) -> MutexGuard<'_, Broadcast<T>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// BroadcastSubscriber pops the items of a BroadcastRingBuffer from its own read cursor,
+/// it unsubscribes when it is dropped.
+/// The items are copied out before the consumer gets them, so a slow consumer never blocks the producer,
+/// and a subscriber is popped by one consumer at a time.
+pub struct BroadcastSubscriber<T> {
+    buffer: Arc<BroadcastRingBuffer<T>>,
+    id: usize,
+}
+
+impl<T> BroadcastSubscriber<T> {
+    /// the items which are not popped by the subscriber
+    pub fn len(&self) -> usize {
+        let guard = self.buffer.lock();
+        guard.pushed - guard.cursors[&self.id].popped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the items overwritten before the subscriber pops them, always 0 if it is not lossy
+    pub fn lost(&self) -> usize {
+        self.buffer.lock().cursors[&self.id].lost
+    }
+
+    /// the buffer subscribed, more subscribers can subscribe it
+    pub fn buffer(&self) -> &Arc<BroadcastRingBuffer<T>> {
+        &self.buffer
+    }
+
+    // wait until the subscriber has count items or the deadline passes, returns whether it has count items
+    async fn wait_items(&self, count: usize, deadline: Option<Instant>) -> Result<bool, SubscriberDropped> {
+        let mut sleep = deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline)));
+        poll_fn(|cx| {
+            let mut guard = self.buffer.lock();
+            let broadcast = &mut *guard;
+            let capacity = broadcast.buffer.len();
+            assert!(count <= capacity, "pop {} items from a ring buffer of {}", count, capacity);
+            let cursor = broadcast.cursors.get_mut(&self.id).unwrap();
+            if cursor.dropped {
+                return Poll::Ready(Err(SubscriberDropped));
+            }
+            if broadcast.pushed - cursor.popped >= count {
+                return Poll::Ready(Ok(true));
+            }
+            cursor.waker = Some(cx.waker().clone());
+            if sleep.as_mut().is_some_and(|sleep| sleep.as_mut().poll(cx).is_ready()) {
+                Poll::Ready(Ok(false))
+            } else {
+                Poll::Pending
+            }
+        }).await
+    }
+
+    // remove count items of the ones copied from start,
+    // the items overwritten while the consumer ran are only lost if the consumer did not take them
+    fn remove_items(&self, start: usize, count: usize) {
+        let mut guard = self.buffer.lock();
+        let cursor = guard.cursors.get_mut(&self.id).unwrap();
+        let end = start + count;
+        if cursor.popped > start {
+            cursor.lost -= std::cmp::min(cursor.popped, end) - start;
+        }
+        cursor.popped = std::cmp::max(cursor.popped, end);
+    }
+}
+
+impl<T: Copy> BroadcastSubscriber<T> {
+    /// pop the items once there are count items, the consumer gets all the items and returns the count to remove
+    pub async fn pop<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> Result<U, SubscriberDropped> {
+        self.wait_items(count, None).await?;
+        self.pop_items(usize::MAX, consumer)
+    }
+
+    /// pop count items once there are count items, Timeout is returned if there are not before the deadline
+    pub async fn pop_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> Result<Result<U, Timeout>, SubscriberDropped> {
+        if !self.wait_items(count, Some(deadline)).await? {
+            return Ok(Err(Timeout));
+        }
+        self.pop_items(count, consumer).map(Ok)
+    }
+
+    /// pop at most max items once there is any, the consumer returns the count of the items to remove
+    pub async fn pop_at_most<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> Result<U, SubscriberDropped> {
+        self.wait_items(std::cmp::min(max, 1), None).await?;
+        self.pop_items(max, consumer)
+    }
+
+    /// pop at most max items once there are max items or the deadline passes
+    pub async fn pop_at_most_until<U>(
+        &self,
+        max: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> Result<U, SubscriberDropped> {
+        self.wait_items(max, Some(deadline)).await?;
+        self.pop_items(max, consumer)
+    }
+
+    /// inspect all the items without removing them once there are count items
+    pub async fn peek<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U,
+    ) -> Result<U, SubscriberDropped> {
+        self.wait_items(count, None).await?;
+        self.pop_items(usize::MAX, |first, second| (consumer(first, second), 0))
+    }
+
+    /// peek like peek, but Timeout is returned if there are not count items before the deadline
+    pub async fn peek_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U,
+    ) -> Result<Result<U, Timeout>, SubscriberDropped> {
+        if !self.wait_items(count, Some(deadline)).await? {
+            return Ok(Err(Timeout));
+        }
+        self.pop_items(usize::MAX, |first, second| (consumer(first, second), 0)).map(Ok)
+    }
+
+    /// pop count items without waiting, the items missing in the subscriber are taken from the producer
+    pub fn must_pop<U>(
+        &self,
+        count: usize,
+        consumer: impl FnOnce(&[T], &[T]) -> (U, usize),
+        producer: impl Iterator<Item=T>,
+    ) -> Result<U, SubscriberDropped> {
+        self.pop_items(usize::MAX, |first, _| {
+            if count <= first.len() {
+                consumer(first, &[])
+            } else {
+                let padding: Vec<_> = producer.take(count - first.len()).collect();
+                let (value, _) = consumer(first, &padding);
+                (value, first.len())
+            }
+        })
+    }
+
+    // copy at most max items out of the buffer, then the consumer gets them without the lock held
+    fn pop_items<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> Result<U, SubscriberDropped> {
+        let (start, items) = {
+            let guard = self.buffer.lock();
+            let cursor = &guard.cursors[&self.id];
+            if cursor.dropped {
+                return Err(SubscriberDropped);
+            }
+            let capacity = guard.buffer.len();
+            let len = std::cmp::min(guard.pushed - cursor.popped, max);
+            let head = cursor.popped % capacity;
+            let first_len = std::cmp::min(len, capacity - head);
+            let mut items = Vec::with_capacity(len);
+            items.extend_from_slice(&guard.buffer[head..head + first_len]);
+            items.extend_from_slice(&guard.buffer[..len - first_len]);
+            (cursor.popped, items)
+        };
+        let (result, count) = consumer(&items, &[]);
+        assert!(count <= items.len(), "pop {} items from {} items", count, items.len());
+        self.remove_items(start, count);
+        Ok(result)
+    }
+}
+
+impl<T> Drop for BroadcastSubscriber<T> {
+    fn drop(&mut self) {
+        self.buffer.lock().cursors.remove(&self.id);
+    }
+}
+
+/// LossySubscriber is a BroadcastSubscriber which skips the overwritten items, it is never dropped for being slow,
+/// so its pops through PopBuffer never fail
+pub struct LossySubscriber<T>(pub(crate) BroadcastSubscriber<T>);
+
+impl<T> Deref for LossySubscriber<T> {
+    type Target = BroadcastSubscriber<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cs140_common::buffer::{PopBuffer, PushBuffer};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_subscribers() {
+        let buffer = Arc::new(BroadcastRingBuffer::<i32>::new(4));
+        let lossy = buffer.subscribe(SlowSubscriber::Lossy);
+        let dropped = buffer.subscribe(SlowSubscriber::Drop);
+        let receiver = buffer.subscribe(SlowSubscriber::Drop);
+        buffer.push_by_iterator(3, &mut [1, 2, 3].into_iter()).await;
+        assert_eq!(receiver.pop(3, |first, second| ([first, second].concat(), 3)).await, Ok(vec![1, 2, 3]));
+        buffer.push_by_iterator(3, &mut [4, 5, 6].into_iter()).await;
+        assert_eq!(receiver.pop(3, |first, second| ([first, second].concat(), 3)).await, Ok(vec![4, 5, 6]));
+        // the slow subscribers miss the first two items
+        assert_eq!(lossy.pop_at_most(8, |first, second| ([first, second].concat(), 4)).await, Ok(vec![3, 4, 5, 6]));
+        assert_eq!(lossy.lost(), 2);
+        assert_eq!(dropped.peek(1, |_, _| ()).await, Err(SubscriberDropped));
+        assert_eq!(buffer.subscriber_count(), 2);
+        // a new subscriber only gets the items pushed after it
+        let late = buffer.subscribe(SlowSubscriber::Lossy);
+        assert!(late.is_empty());
+        drop(dropped);
+        drop(late);
+        assert_eq!(buffer.subscriber_count(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_subscriber_wakes_up() {
+        let buffer = Arc::new(BroadcastRingBuffer::<u32>::new(1000));
+        let subscribers: Vec<_> = (0..2).map(|_| buffer.subscribe(SlowSubscriber::Drop)).collect();
+        let consumers: Vec<_> = subscribers.into_iter().map(|subscriber| tokio::spawn(async move {
+            let mut next = 0;
+            while next < 10000 {
+                let items = subscriber.pop(1, |first, second| ([first, second].concat(), first.len() + second.len())).await.unwrap();
+                for item in items {
+                    assert_eq!(item, next);
+                    next += 1;
+                }
+            }
+        })).collect();
+        for chunk in (0..10000).collect::<Vec<u32>>().chunks(100) {
+            // the producer never waits, so it leaves the subscribers time to keep up
+            while {
+                let guard = buffer.lock();
+                guard.cursors.values().any(|cursor| cursor.popped + 500 < guard.pushed)
+            } {
+                tokio::task::yield_now().await;
+            }
+            buffer.push_by_iterator(chunk.len(), &mut chunk.iter().cloned()).await;
+        }
+        for consumer in consumers {
+            consumer.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_producer_never_blocked_by_consumer() {
+        let buffer = Arc::new(BroadcastRingBuffer::<i32>::new(4));
+        let subscriber = buffer.subscribe(SlowSubscriber::Lossy);
+        buffer.push_by_iterator(2, &mut [1, 2].into_iter()).await;
+        // the producer overwrites the items while the consumer holds them, only the items it does not take are lost
+        let items = subscriber.pop(1, |first, second| {
+            buffer.push(4, |first, second| {
+                first.iter_mut().chain(second.iter_mut()).zip(3..).for_each(|(store, item)| *store = item);
+                4
+            });
+            ([first, second].concat(), 1)
+        }).await;
+        assert_eq!(items, Ok(vec![1, 2]));
+        assert_eq!(subscriber.lost(), 1);
+        assert_eq!(subscriber.pop(4, |first, second| ([first, second].concat(), 4)).await, Ok(vec![3, 4, 5, 6]));
+        // only the latest items of a push larger than the capacity are kept
+        buffer.push_by_iterator(6, &mut (7..13)).await;
+        assert_eq!(subscriber.pop(4, |first, second| ([first, second].concat(), 4)).await, Ok(vec![9, 10, 11, 12]));
+        assert_eq!(subscriber.lost(), 3);
+    }
+
+    async fn receive(buffer: impl PopBuffer<u32>, count: usize) -> Vec<u32> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            buffer.pop_at_most_by_ref(count - received.len(), |data| {
+                received.extend_from_slice(data);
+                ((), data.len())
+            }).await;
+        }
+        received
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_two_consumers() {
+        let buffer = Arc::new(BroadcastRingBuffer::<u32>::new(100000));
+        let receiver = tokio::spawn(receive(buffer.subscribe_lossy(), 10000));
+        let recorder = tokio::spawn(receive(buffer.subscribe_lossy(), 10000));
+        // the producer pushes in chunks like the callback of the sound card
+        let producer = buffer.clone();
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            for chunk in (0..10000).collect::<Vec<u32>>().chunks(480) {
+                rt.block_on(producer.push_by_iterator(chunk.len(), &mut chunk.iter().cloned()));
+            }
+        }).join().unwrap();
+        let expected: Vec<u32> = (0..10000).collect();
+        assert_eq!(receiver.await.unwrap(), expected);
+        assert_eq!(recorder.await.unwrap(), expected);
+    }
+}
@@ -1,11 +1,144 @@
 use async_trait::async_trait;
+use tokio::time::Instant;
 
-use cs140_common::buffer::Buffer;
+use cs140_common::buffer::{PopBuffer, PushBuffer, Timeout};
 
+use crate::broadcast_ring_buffer::{BroadcastRingBuffer, LossySubscriber, SubscriberDropped};
 use crate::ring_buffer::RingBuffer;
+use crate::spsc_ring_buffer::SpscRingBuffer;
 
 #[async_trait]
-impl<T, const N: usize> Buffer<T> for RingBuffer<T, N>
+impl<T> PopBuffer<T> for RingBuffer<T>
+    where
+        T: Sync + Send + Copy,
+{
+    async fn pop<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        self.pop(count, consumer).await
+    }
+
+    async fn pop_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        self.pop_until(count, deadline, consumer).await
+    }
+
+    async fn pop_at_most<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        self.pop_at_most(max, consumer).await
+    }
+
+    async fn pop_at_most_until<U>(
+        &self,
+        max: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        self.pop_at_most_until(max, deadline, consumer).await
+    }
+
+    async fn peek<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U + Send + 'async_trait,
+    ) -> U {
+        self.peek(count, consumer).await
+    }
+
+    async fn peek_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        self.peek_until(count, deadline, consumer).await
+    }
+    fn must_pop<U>(
+        &self,
+        count: usize,
+        consumer: impl FnOnce(&[T], &[T]) -> (U, usize),
+        producer: impl Iterator<Item=T>,
+    ) -> U {
+        self.must_pop(count, consumer, producer)
+    }
+}
+
+#[async_trait]
+impl<T> PopBuffer<T> for SpscRingBuffer<T>
+    where
+        T: Sync + Send + Copy,
+{
+    async fn pop<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        self.pop(count, consumer).await
+    }
+
+    async fn pop_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        self.pop_until(count, deadline, consumer).await
+    }
+
+    async fn pop_at_most<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        self.pop_at_most(max, consumer).await
+    }
+
+    async fn pop_at_most_until<U>(
+        &self,
+        max: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        self.pop_at_most_until(max, deadline, consumer).await
+    }
+
+    async fn peek<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U + Send + 'async_trait,
+    ) -> U {
+        self.peek(count, consumer).await
+    }
+
+    async fn peek_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        self.peek_until(count, deadline, consumer).await
+    }
+    fn must_pop<U>(
+        &self,
+        count: usize,
+        consumer: impl FnOnce(&[T], &[T]) -> (U, usize),
+        producer: impl Iterator<Item=T>,
+    ) -> U {
+        self.must_pop(count, consumer, producer)
+    }
+}
+
+#[async_trait]
+impl<T> PushBuffer<T> for RingBuffer<T>
     where
         T: Sync + Send + Copy,
 {
@@ -16,13 +149,95 @@ impl<T, const N: usize> Buffer<T> for RingBuffer<T, N>
     ) {
         self.push(count, producer).await
     }
+}
 
+#[async_trait]
+impl<T> PushBuffer<T> for SpscRingBuffer<T>
+    where
+        T: Sync + Send + Copy,
+{
+    async fn push(
+        &self,
+        count: usize,
+        producer: impl for<'a> FnOnce(&'a mut [T], &'a mut [T]) -> usize + Send + 'async_trait,
+    ) {
+        self.push(count, producer).await
+    }
+}
+
+#[async_trait]
+impl<T> PushBuffer<T> for BroadcastRingBuffer<T>
+    where
+        T: Sync + Send + Copy + Default,
+{
+    async fn push(
+        &self,
+        count: usize,
+        producer: impl for<'a> FnOnce(&'a mut [T], &'a mut [T]) -> usize + Send + 'async_trait,
+    ) {
+        self.push(count, producer)
+    }
+}
+
+// the pops of PopBuffer can not return SubscriberDropped, which is never returned to a lossy subscriber
+fn lossy<U>(result: Result<U, SubscriberDropped>) -> U {
+    result.expect("a lossy subscriber is never dropped")
+}
+
+#[async_trait]
+impl<T> PopBuffer<T> for LossySubscriber<T>
+    where
+        T: Sync + Send + Copy,
+{
     async fn pop<U>(
         &self,
         count: usize,
         consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
     ) -> U {
-        self.pop(count, consumer).await
+        lossy(self.0.pop(count, consumer).await)
+    }
+
+    async fn pop_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        lossy(self.0.pop_until(count, deadline, consumer).await)
+    }
+
+    async fn pop_at_most<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        lossy(self.0.pop_at_most(max, consumer).await)
+    }
+
+    async fn pop_at_most_until<U>(
+        &self,
+        max: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        lossy(self.0.pop_at_most_until(max, deadline, consumer).await)
+    }
+
+    async fn peek<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U + Send + 'async_trait,
+    ) -> U {
+        lossy(self.0.peek(count, consumer).await)
+    }
+
+    async fn peek_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        lossy(self.0.peek_until(count, deadline, consumer).await)
     }
     fn must_pop<U>(
         &self,
@@ -30,6 +245,6 @@ impl<T, const N: usize> Buffer<T> for RingBuffer<T, N>
         consumer: impl FnOnce(&[T], &[T]) -> (U, usize),
         producer: impl Iterator<Item=T>,
     ) -> U {
-        self.must_pop(count, consumer, producer)
+        lossy(self.0.must_pop(count, consumer, producer))
     }
 }
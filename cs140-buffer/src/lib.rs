@@ -1,4 +1,4 @@
-#![feature(new_uninit)]
-
+pub mod broadcast_ring_buffer;
 pub mod buffer;
 pub mod ring_buffer;
+pub mod spsc_ring_buffer;
@@ -1,13 +1,17 @@
 use std::collections::VecDeque;
-use std::future::Future;
+use std::future::{Future, poll_fn};
 use std::pin::Pin;
 use std::sync::Mutex;
 use std::task::{Context, Poll, Waker};
 
-const DEFAULT_PUSH_BLOCKING_SIZE: usize = 48000;
+use tokio::time::Instant;
 
-pub struct BlockingRingBuffer<T, const N: usize> {
-    buffer: Box<[T; N]>,
+use cs140_common::buffer::Timeout;
+
+pub(crate) const DEFAULT_PUSH_BLOCKING_SIZE: usize = 48000;
+
+pub struct BlockingRingBuffer<T> {
+    buffer: Box<[T]>,
     head: usize,
     len: usize,
     push_waker: VecDeque<Waker>,
@@ -15,15 +19,18 @@ pub struct BlockingRingBuffer<T, const N: usize> {
     push_blocking_size: usize,
 }
 
-pub struct RingBuffer<T, const N: usize>(Mutex<BlockingRingBuffer<T, N>>);
+/// RingBuffer is a ring buffer of a runtime capacity guarded by a mutex
+pub struct RingBuffer<T>(Mutex<BlockingRingBuffer<T>>);
 
-impl<T, const N: usize> RingBuffer<T, N> {
-    pub fn new() -> Self {
+impl<T: Copy + Default> RingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
         Self {
-            0: Mutex::new(BlockingRingBuffer::new()),
+            0: Mutex::new(BlockingRingBuffer::new(capacity)),
         }
     }
+}
 
+impl<T> RingBuffer<T> {
     pub fn must_pop<U>(
         &self,
         count: usize,
@@ -47,10 +54,7 @@ impl<T, const N: usize> RingBuffer<T, N> {
         } else {
             std::cmp::max(guard.push_blocking_size, count * 2)
         };
-        for push_waker in &guard.push_waker {
-            push_waker.wake_by_ref();
-        }
-        guard.push_waker.clear();
+        guard.wake_producers();
         result
     }
 
@@ -81,30 +85,119 @@ impl<T, const N: usize> RingBuffer<T, N> {
             .await
     }
 
+    pub async fn pop_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> Result<U, Timeout> {
+        self.wait_items(count, Some(deadline), |buffer, ready| {
+            if ready {
+                Ok(buffer.pop_at_most(count, consumer))
+            } else {
+                Err(Timeout)
+            }
+        }).await
+    }
+
+    pub async fn pop_at_most<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> U {
+        self.wait_items(std::cmp::min(max, 1), None, |buffer, _| buffer.pop_at_most(max, consumer)).await
+    }
+
+    pub async fn pop_at_most_until<U>(
+        &self,
+        max: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> U {
+        self.wait_items(max, Some(deadline), |buffer, _| buffer.pop_at_most(max, consumer)).await
+    }
+
+    pub async fn peek<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U,
+    ) -> U {
+        self.wait_items(count, None, |buffer, _| {
+            let (first, second) = buffer.items(usize::MAX);
+            consumer(first, second)
+        }).await
+    }
+
+    pub async fn peek_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U,
+    ) -> Result<U, Timeout> {
+        self.wait_items(count, Some(deadline), |buffer, ready| {
+            if ready {
+                let (first, second) = buffer.items(usize::MAX);
+                Ok(consumer(first, second))
+            } else {
+                Err(Timeout)
+            }
+        }).await
+    }
+
+    // wait until the buffer holds count items or the deadline passes,
+    // then call ready with the buffer and whether it holds count items
+    async fn wait_items<U>(
+        &self,
+        count: usize,
+        deadline: Option<Instant>,
+        ready: impl FnOnce(&mut BlockingRingBuffer<T>, bool) -> U,
+    ) -> U {
+        let mut ready = Some(ready);
+        let mut sleep = deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline)));
+        poll_fn(|cx| {
+            let mut guard = self.0.lock().unwrap();
+            let enough = guard.len() >= count;
+            if !enough && !sleep.as_mut().is_some_and(|sleep| sleep.as_mut().poll(cx).is_ready()) {
+                register(&mut guard.pop_waker, cx.waker());
+                return Poll::Pending;
+            }
+            let len = guard.len();
+            let result = ready.take().unwrap()(&mut guard, enough);
+            if guard.len() < len {
+                guard.wake_producers();
+            }
+            Poll::Ready(result)
+        }).await
+    }
+
     pub fn len(&self) -> usize {
         self.0.lock().unwrap().len
     }
+
+    pub fn capacity(&self) -> usize {
+        self.0.lock().unwrap().capacity()
+    }
 }
 
-pub struct RingBufferPushFuture<'a, PushCallback, T, const N: usize>
+pub struct RingBufferPushFuture<'a, PushCallback, T>
     where
         PushCallback: for<'b> FnOnce(&'b mut [T], &'b mut [T]) -> usize,
 {
-    buffer: &'a Mutex<BlockingRingBuffer<T, N>>,
+    buffer: &'a Mutex<BlockingRingBuffer<T>>,
     push_len_required: usize,
     push_fn: PushCallback,
 }
 
-pub struct RingBufferPopFuture<'a, U, PopCallback, T, const N: usize>
+pub struct RingBufferPopFuture<'a, U, PopCallback, T>
     where
         PopCallback: for<'b> FnOnce(&'b [T], &'b [T]) -> (U, usize),
 {
-    buffer: &'a Mutex<BlockingRingBuffer<T, N>>,
+    buffer: &'a Mutex<BlockingRingBuffer<T>>,
     pop_len_required: usize,
     pop_fn: PopCallback,
 }
 
-impl<'a, PushCallback, T, const N: usize> Future for RingBufferPushFuture<'a, PushCallback, T, N>
+impl<'a, PushCallback, T> Future for RingBufferPushFuture<'a, PushCallback, T>
     where
         PushCallback: for<'b> FnOnce(&'b mut [T], &'b mut [T]) -> usize,
 {
@@ -122,13 +215,13 @@ impl<'a, PushCallback, T, const N: usize> Future for RingBufferPushFuture<'a, Pu
             // log::warn!("push head: {}",guard.head);
             Poll::Ready(())
         } else {
-            guard.push_waker.push_back(cx.waker().clone());
+            register(&mut guard.push_waker, cx.waker());
             Poll::Pending
         }
     }
 }
 
-impl<'a, U, PopCallback, T, const N: usize> Future for RingBufferPopFuture<'a, U, PopCallback, T, N>
+impl<'a, U, PopCallback, T> Future for RingBufferPopFuture<'a, U, PopCallback, T>
     where
         PopCallback: for<'b> FnOnce(&'b [T], &'b [T]) -> (U, usize),
 {
@@ -139,24 +232,28 @@ impl<'a, U, PopCallback, T, const N: usize> Future for RingBufferPopFuture<'a, U
         if guard.len() >= self.pop_len_required {
             let pop_fn: PopCallback = unsafe { std::mem::transmute_copy(&self.pop_fn) };
             let result = guard.pop_blocking(self.pop_len_required, pop_fn);
-            for push_waker in &guard.push_waker {
-                push_waker.wake_by_ref();
-            }
-            guard.push_waker.clear();
+            guard.wake_producers();
             // log::warn!("pop head: {}",guard.head);
             Poll::Ready(result)
         } else {
-            guard.pop_waker.push_back(cx.waker().clone());
+            register(&mut guard.pop_waker, cx.waker());
             Poll::Pending
         }
     }
 }
 
-impl<T, const N: usize> BlockingRingBuffer<T, N> {
-    #[allow(clippy::uninit_assumed_init)]
-    pub fn new() -> Self {
+// a future polled again is registered once, so it is not woken up more than once by a push or a pop
+fn register(wakers: &mut VecDeque<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+        wakers.push_back(waker.clone());
+    }
+}
+
+impl<T: Copy + Default> BlockingRingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "the capacity of a ring buffer must be positive");
         BlockingRingBuffer {
-            buffer: unsafe { Box::new_uninit().assume_init() },
+            buffer: vec![T::default(); capacity].into_boxed_slice(),
             head: 0,
             len: 0,
             push_waker: Default::default(),
@@ -164,33 +261,55 @@ impl<T, const N: usize> BlockingRingBuffer<T, N> {
             push_blocking_size: DEFAULT_PUSH_BLOCKING_SIZE,
         }
     }
+}
 
+impl<T> BlockingRingBuffer<T> {
     fn len(&self) -> usize {
         self.len
     }
 
     fn capacity(&self) -> usize {
-        N
+        self.buffer.len()
+    }
+
+    fn wake_producers(&mut self) {
+        for push_waker in self.push_waker.drain(..) {
+            push_waker.wake();
+        }
+    }
+
+    // the first max items in the order they are pushed
+    fn items(&self, max: usize) -> (&[T], &[T]) {
+        let len = std::cmp::min(self.len(), max);
+        let first_len = std::cmp::min(len, self.capacity() - self.head);
+        (&self.buffer[self.head..self.head + first_len], &self.buffer[..len - first_len])
+    }
+
+    // the consumer gets at most max items and returns the count of the items to remove
+    fn pop_at_most<U>(
+        &mut self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> U {
+        let (first, second) = self.items(max);
+        let len = first.len() + second.len();
+        let (result, count) = consumer(first, second);
+        assert!(count <= len, "pop {} items from {} items", count, len);
+        self.head = (self.head + count) % self.capacity();
+        self.len -= count;
+        result
     }
 
     fn push_blocking(&mut self, producer: impl for<'a> FnOnce(&'a mut [T], &'a mut [T]) -> usize) {
         let head = self.head;
-        let tail = (head + self.len()) % N;
-
-        let count = unsafe {
-            if head <= tail {
-                let first_ptr = self.buffer[tail..].as_ptr();
-                let first_ptr = first_ptr as *mut T;
-                let second_ptr = self.buffer[..head].as_ptr();
-                let second_ptr = second_ptr as *mut T;
-                producer(
-                    std::slice::from_raw_parts_mut(first_ptr, N - tail),
-                    std::slice::from_raw_parts_mut(second_ptr, head),
-                )
-            } else {
-                let first = &mut self.buffer[tail..head];
-                producer(first, &mut [])
-            }
+        let tail = (head + self.len()) % self.capacity();
+
+        let count = if head <= tail && self.len() < self.capacity() {
+            let (second, first) = self.buffer.split_at_mut(tail);
+            producer(first, &mut second[..head])
+        } else {
+            let first = &mut self.buffer[tail..head];
+            producer(first, &mut [])
         };
 
         self.len += count;
@@ -202,7 +321,7 @@ impl<T, const N: usize> BlockingRingBuffer<T, N> {
         consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
     ) -> U {
         let head = self.head;
-        let tail = (head + self.len()) % N;
+        let tail = (head + self.len()) % self.capacity();
         let (result, count) = {
             if head < tail {
                 let slice = &self.buffer[head..tail];
@@ -221,7 +340,7 @@ impl<T, const N: usize> BlockingRingBuffer<T, N> {
                 }
             }
         };
-        self.head = (head + count) % N;
+        self.head = (head + count) % self.capacity();
         self.len -= count;
         result
     }
@@ -235,7 +354,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_timeout() {
-        let buffer = Arc::new(RingBuffer::<i32, 1000000>::new());
+        let buffer = Arc::new(RingBuffer::<i32>::new(1000000));
         buffer.push(4, |x, y| {
             x[0] = 1;
             x[1] = 2;
@@ -248,4 +367,44 @@ mod tests {
         assert_eq!(buffer.pop(1, |x, y| (x[0], 1)).await, 3);
         assert_eq!(buffer.pop(1, |x, y| (x[0], 1)).await, 4);
     }
+
+    #[tokio::test]
+    async fn test_peek_and_pop_at_most() {
+        let buffer = RingBuffer::<i32>::new(4);
+        buffer.push(3, |first, _| {
+            first[..3].copy_from_slice(&[1, 2, 3]);
+            3
+        }).await;
+        assert_eq!(buffer.peek(2, |first, second| [first, second].concat()).await, vec![1, 2, 3]);
+        assert_eq!(buffer.pop_at_most(2, |first, second| ([first, second].concat(), 1)).await, vec![1, 2]);
+        buffer.push(2, |first, second| {
+            assert_eq!((first.len(), second.len()), (1, 1));
+            first[0] = 4;
+            second[0] = 5;
+            2
+        }).await;
+        assert_eq!(buffer.pop_at_most(8, |first, second| ([first, second].concat(), 4)).await, vec![2, 3, 4, 5]);
+        // the consumer gets the partial data or nothing after the deadline
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        assert_eq!(buffer.peek_until(1, deadline, |_, _| ()).await, Err(Timeout));
+        assert_eq!(buffer.pop_until(1, deadline, |_, _| ((), 0)).await, Err(Timeout));
+        buffer.push(1, |first, _| {
+            first[0] = 6;
+            1
+        }).await;
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        assert_eq!(buffer.pop_at_most_until(2, deadline, |first, second| ([first, second].concat(), 1)).await, vec![6]);
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_waker_registered_once() {
+        let buffer = RingBuffer::<i32>::new(4);
+        let pending = buffer.peek(1, |first, _| first[0]);
+        futures::pin_mut!(pending);
+        for _ in 0..3 {
+            assert!(futures::poll!(pending.as_mut()).is_pending());
+        }
+        assert_eq!(buffer.0.lock().unwrap().pop_waker.len(), 1);
+    }
 }
@@ -0,0 +1,434 @@
+use std::future::{Future, poll_fn};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::task::Poll;
+
+use futures::task::AtomicWaker;
+use tokio::time::Instant;
+
+use cs140_common::buffer::Timeout;
+
+use crate::ring_buffer::DEFAULT_PUSH_BLOCKING_SIZE;
+
+/// SpscRingBuffer is a lock-free ring buffer of a runtime capacity for one producer and one consumer,
+/// neither of them waits for a lock of the other one, so it can be used in the callback of the sound card.
+/// Like RingBuffer, push waits while the buffer holds twice the count of the largest must_pop,
+/// which keeps the latency of the samples to play low.
+/// It panics if two producers push or two consumers pop or peek at the same time.
+pub struct SpscRingBuffer<T> {
+    data: *mut T,
+    capacity: usize,
+    // the counts of the items pushed and popped since the buffer is created, an item is at its count modulo the capacity
+    pushed: AtomicUsize,
+    popped: AtomicUsize,
+    push_blocking_size: AtomicUsize,
+    // the count of the largest must_pop, the items a callback of the sound card takes at once
+    largest_must_pop: AtomicUsize,
+    pushing: AtomicBool,
+    popping: AtomicBool,
+    push_waker: AtomicWaker,
+    pop_waker: AtomicWaker,
+}
+
+// the producer only writes the free slots and the consumer only reads the items
+unsafe impl<T: Send> Send for SpscRingBuffer<T> {}
+
+unsafe impl<T: Send> Sync for SpscRingBuffer<T> {}
+
+// a side of the buffer is taken until the guard is dropped
+struct SideGuard<'a>(&'a AtomicBool);
+
+impl<'a> SideGuard<'a> {
+    fn take(side: &'a AtomicBool, name: &str) -> Self {
+        assert!(!side.swap(true, Acquire), "two {}s use the spsc ring buffer at the same time", name);
+        Self(side)
+    }
+}
+
+impl Drop for SideGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Release);
+    }
+}
+
+impl<T: Copy + Default> SpscRingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "the capacity of a ring buffer must be positive");
+        let data = Box::into_raw(vec![T::default(); capacity].into_boxed_slice()) as *mut T;
+        Self {
+            data,
+            capacity,
+            pushed: AtomicUsize::new(0),
+            popped: AtomicUsize::new(0),
+            push_blocking_size: AtomicUsize::new(DEFAULT_PUSH_BLOCKING_SIZE),
+            largest_must_pop: AtomicUsize::new(0),
+            pushing: AtomicBool::new(false),
+            popping: AtomicBool::new(false),
+            push_waker: AtomicWaker::new(),
+            pop_waker: AtomicWaker::new(),
+        }
+    }
+}
+
+impl<T> SpscRingBuffer<T> {
+    pub fn len(&self) -> usize {
+        // popped is loaded first, it never passes the pushed loaded after it
+        let popped = self.popped.load(Acquire);
+        self.pushed.load(Acquire).wrapping_sub(popped)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// the count of the largest must_pop so far, 0 before any must_pop
+    pub fn largest_must_pop(&self) -> usize {
+        self.largest_must_pop.load(Relaxed)
+    }
+
+    fn can_push(&self, count: usize) -> bool {
+        let len = self.len();
+        self.capacity - len >= count && len < self.push_blocking_size.load(Relaxed)
+    }
+
+    pub async fn push(
+        &self,
+        count: usize,
+        producer: impl for<'a> FnOnce(&'a mut [T], &'a mut [T]) -> usize,
+    ) {
+        assert!(count <= self.capacity, "push {} items into a ring buffer of {}", count, self.capacity);
+        let _guard = SideGuard::take(&self.pushing, "producer");
+        let mut producer = Some(producer);
+        poll_fn(|cx| {
+            if !self.can_push(count) {
+                // the consumer may pop between the check and the registration
+                self.push_waker.register(cx.waker());
+                if !self.can_push(count) {
+                    return Poll::Pending;
+                }
+            }
+            let pushed = self.pushed.load(Relaxed);
+            let popped = self.popped.load(Acquire);
+            let len = pushed.wrapping_sub(popped);
+            let tail = pushed % self.capacity;
+            let first_len = std::cmp::min(self.capacity - len, self.capacity - tail);
+            // the free slots are not read by the consumer until pushed is stored
+            let (first, second) = unsafe {
+                (
+                    std::slice::from_raw_parts_mut(self.data.add(tail), first_len),
+                    std::slice::from_raw_parts_mut(self.data, self.capacity - len - first_len),
+                )
+            };
+            let count = producer.take().unwrap()(first, second);
+            assert!(count <= self.capacity - len, "push {} items into {} free slots", count, self.capacity - len);
+            self.pushed.store(pushed.wrapping_add(count), Release);
+            self.pop_waker.wake();
+            Poll::Ready(())
+        }).await
+    }
+
+    /// wait until the consumer pops all the items, Timeout is returned if the buffer is not empty before the deadline.
+    /// It is called by the producer, so it panics if another producer pushes at the same time
+    pub async fn drain_until(&self, deadline: Instant) -> Result<(), Timeout> {
+        let _guard = SideGuard::take(&self.pushing, "producer");
+        let mut sleep = Box::pin(tokio::time::sleep_until(deadline));
+        poll_fn(|cx| {
+            if self.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            // the consumer may pop between the check and the registration
+            self.push_waker.register(cx.waker());
+            if self.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            match sleep.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(Timeout)),
+                Poll::Pending => Poll::Pending,
+            }
+        }).await
+    }
+
+    pub async fn pop<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> U {
+        let _guard = SideGuard::take(&self.popping, "consumer");
+        self.wait_items(count, None).await;
+        self.pop_items(usize::MAX, consumer)
+    }
+
+    pub async fn pop_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> Result<U, Timeout> {
+        let _guard = SideGuard::take(&self.popping, "consumer");
+        if !self.wait_items(count, Some(deadline)).await {
+            return Err(Timeout);
+        }
+        Ok(self.pop_items(count, consumer))
+    }
+
+    pub async fn pop_at_most<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> U {
+        let _guard = SideGuard::take(&self.popping, "consumer");
+        self.wait_items(std::cmp::min(max, 1), None).await;
+        self.pop_items(max, consumer)
+    }
+
+    pub async fn pop_at_most_until<U>(
+        &self,
+        max: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize),
+    ) -> U {
+        let _guard = SideGuard::take(&self.popping, "consumer");
+        self.wait_items(max, Some(deadline)).await;
+        self.pop_items(max, consumer)
+    }
+
+    pub async fn peek<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U,
+    ) -> U {
+        let _guard = SideGuard::take(&self.popping, "consumer");
+        self.wait_items(count, None).await;
+        self.pop_items(usize::MAX, |first, second| (consumer(first, second), 0))
+    }
+
+    pub async fn peek_until<U>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> U,
+    ) -> Result<U, Timeout> {
+        let _guard = SideGuard::take(&self.popping, "consumer");
+        if !self.wait_items(count, Some(deadline)).await {
+            return Err(Timeout);
+        }
+        Ok(self.pop_items(usize::MAX, |first, second| (consumer(first, second), 0)))
+    }
+
+    // wait until the buffer holds count items or the deadline passes, returns whether it holds count items,
+    // the items are only popped by the caller, so they are still in the buffer after it returns
+    async fn wait_items(&self, count: usize, deadline: Option<Instant>) -> bool {
+        let mut sleep = deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline)));
+        poll_fn(|cx| {
+            if self.len() >= count {
+                return Poll::Ready(true);
+            }
+            // the producer may push between the check and the registration
+            self.pop_waker.register(cx.waker());
+            if self.len() >= count {
+                return Poll::Ready(true);
+            }
+            if sleep.as_mut().is_some_and(|sleep| sleep.as_mut().poll(cx).is_ready()) {
+                Poll::Ready(false)
+            } else {
+                Poll::Pending
+            }
+        }).await
+    }
+
+    /// pop count items without waiting, the items missing in the buffer are taken from the producer
+    pub fn must_pop<U>(
+        &self,
+        count: usize,
+        consumer: impl FnOnce(&[T], &[T]) -> (U, usize),
+        producer: impl Iterator<Item=T>,
+    ) -> U where T: Copy {
+        let _guard = SideGuard::take(&self.popping, "consumer");
+        let len = self.len();
+        let result = if count <= len {
+            self.pop_items(usize::MAX, consumer)
+        } else {
+            self.pop_items(usize::MAX, |first: &[T], second: &[T]| {
+                let data: Vec<_> = first.iter().chain(second.iter()).take(len).cloned().collect();
+                let padding: Vec<_> = producer.take(count - len).collect();
+                let (value, _) = consumer(&data, &padding);
+                (value, len)
+            })
+        };
+        let push_blocking_size = self.push_blocking_size.load(Relaxed);
+        let push_blocking_size = if push_blocking_size == DEFAULT_PUSH_BLOCKING_SIZE && count > 0 {
+            count * 2
+        } else {
+            std::cmp::max(push_blocking_size, count * 2)
+        };
+        self.push_blocking_size.store(push_blocking_size, Relaxed);
+        self.largest_must_pop.fetch_max(count, Relaxed);
+        // the producer may be blocked by the old push blocking size
+        self.push_waker.wake();
+        result
+    }
+
+    // the consumer gets at most max items in the buffer and returns the count of the items to remove
+    fn pop_items<U>(&self, max: usize, consumer: impl for<'a> FnOnce(&'a [T], &'a [T]) -> (U, usize)) -> U {
+        let popped = self.popped.load(Relaxed);
+        let pushed = self.pushed.load(Acquire);
+        let len = std::cmp::min(pushed.wrapping_sub(popped), max);
+        let head = popped % self.capacity;
+        let first_len = std::cmp::min(len, self.capacity - head);
+        // the items are not written by the producer until popped is stored
+        let (first, second) = unsafe {
+            (
+                std::slice::from_raw_parts(self.data.add(head), first_len),
+                std::slice::from_raw_parts(self.data, len - first_len),
+            )
+        };
+        let (result, count) = consumer(first, second);
+        assert!(count <= len, "pop {} items from {} items", count, len);
+        self.popped.store(popped.wrapping_add(count), Release);
+        self.push_waker.wake();
+        result
+    }
+}
+
+impl<T> Drop for SpscRingBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(self.data, self.capacity)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wrap_around() {
+        let buffer = SpscRingBuffer::<i32>::new(5);
+        buffer.push(3, |first, _| {
+            first[..3].copy_from_slice(&[1, 2, 3]);
+            3
+        }).await;
+        assert_eq!(buffer.pop(2, |first, _| (first[..2].to_vec(), 2)).await, vec![1, 2]);
+        buffer.push(4, |first, second| {
+            assert_eq!((first.len(), second.len()), (2, 2));
+            first.copy_from_slice(&[4, 5]);
+            second.copy_from_slice(&[6, 7]);
+            4
+        }).await;
+        assert_eq!(buffer.len(), 5);
+        let items = buffer.pop(5, |first, second| ([first, second].concat(), 5)).await;
+        assert_eq!(items, vec![3, 4, 5, 6, 7]);
+        // the missing items are padded
+        let items = buffer.must_pop(3, |first, second| ([first, second].concat(), 0), std::iter::repeat(0));
+        assert!(buffer.is_empty());
+        assert_eq!(items, vec![0, 0, 0]);
+        assert_eq!(buffer.largest_must_pop(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_peek_and_pop_at_most() {
+        let buffer = SpscRingBuffer::<i32>::new(4);
+        buffer.push(3, |first, _| {
+            first[..3].copy_from_slice(&[1, 2, 3]);
+            3
+        }).await;
+        assert_eq!(buffer.peek(2, |first, second| [first, second].concat()).await, vec![1, 2, 3]);
+        assert_eq!(buffer.pop_at_most(2, |first, second| ([first, second].concat(), 1)).await, vec![1, 2]);
+        buffer.push(2, |first, second| {
+            assert_eq!((first.len(), second.len()), (1, 1));
+            first[0] = 4;
+            second[0] = 5;
+            2
+        }).await;
+        assert_eq!(buffer.pop_at_most(8, |first, second| ([first, second].concat(), 4)).await, vec![2, 3, 4, 5]);
+        // the consumer gets the partial data or nothing after the deadline
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        assert_eq!(buffer.peek_until(1, deadline, |_, _| ()).await, Err(Timeout));
+        assert_eq!(buffer.pop_until(1, deadline, |_, _| ((), 0)).await, Err(Timeout));
+        buffer.push(1, |first, _| {
+            first[0] = 6;
+            1
+        }).await;
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        assert_eq!(buffer.pop_at_most_until(2, deadline, |first, second| ([first, second].concat(), 1)).await, vec![6]);
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_peek_wakes_up() {
+        let buffer = Arc::new(SpscRingBuffer::<i32>::new(4));
+        let producer_buffer = buffer.clone();
+        let producer = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            producer_buffer.push(2, |first, _| {
+                first[..2].copy_from_slice(&[1, 2]);
+                2
+            }).await;
+        });
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        assert_eq!(buffer.peek_until(2, deadline, |first, second| [first, second].concat()).await, Ok(vec![1, 2]));
+        assert_eq!(buffer.len(), 2);
+        producer.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain_until() {
+        let buffer = Arc::new(SpscRingBuffer::<i32>::new(4));
+        buffer.push(2, |first, _| {
+            first[..2].copy_from_slice(&[1, 2]);
+            2
+        }).await;
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        assert_eq!(buffer.drain_until(deadline).await, Err(Timeout));
+        let consumer_buffer = buffer.clone();
+        let consumer = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            consumer_buffer.pop(1, |_, _| ((), 1)).await;
+            consumer_buffer.pop(1, |_, _| ((), 1)).await;
+        });
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        assert_eq!(buffer.drain_until(deadline).await, Ok(()));
+        assert!(buffer.is_empty());
+        consumer.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_producer_and_consumer_threads() {
+        let buffer = Arc::new(SpscRingBuffer::<u32>::new(1000));
+        let producer_buffer = buffer.clone();
+        let producer = tokio::spawn(async move {
+            for chunk in (0..100000).collect::<Vec<u32>>().chunks(300) {
+                producer_buffer.push(chunk.len(), |first, second| {
+                    first.iter_mut().chain(second.iter_mut()).zip(chunk).for_each(|(slot, item)| *slot = *item);
+                    chunk.len()
+                }).await;
+            }
+        });
+        let mut next = 0;
+        while next < 100000 {
+            let items = buffer.pop(1, |first, second| ([first, second].concat(), first.len() + second.len())).await;
+            for item in items {
+                assert_eq!(item, next);
+                next += 1;
+            }
+        }
+        producer.await.unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "two consumers")]
+    async fn test_two_consumers() {
+        let buffer = SpscRingBuffer::<i32>::new(5);
+        let pending = buffer.pop(1, |first, _| (first[0], 1));
+        futures::pin_mut!(pending);
+        assert!(futures::poll!(pending.as_mut()).is_pending());
+        buffer.pop(1, |first, _| (first[0], 1)).await;
+    }
+}
@@ -1,7 +1,25 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
 use async_trait::async_trait;
+use tokio::time::Instant;
+
+/// Timeout is returned when the items are not in the buffer before the deadline
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timeout;
+
+impl Display for Timeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the items are not in the buffer before the deadline")
+    }
+}
+
+impl std::error::Error for Timeout {}
 
+/// PushBuffer is the side of a buffer pushed by a producer like the InputDevice
 #[async_trait]
-pub trait Buffer<Data: Send + Sync>: Send + Sync {
+pub trait PushBuffer<Data: Send + Sync>: Send + Sync {
     /// push the data into buffer, the process will be blocking when there is no space in the storage.
     async fn push(
         &self,
@@ -38,13 +56,51 @@ pub trait Buffer<Data: Send + Sync>: Send + Sync {
         })
             .await;
     }
+}
 
+/// PopBuffer is the side of a buffer popped by a consumer like the OutputDevice or the PhysicalLayer
+#[async_trait]
+pub trait PopBuffer<Data: Send + Sync>: Send + Sync {
     /// pop the data from the buffer, the data will be removed after the consumer call
     async fn pop<T>(
         &self,
         count: usize,
         consumer: impl for<'a> FnOnce(&'a [Data], &'a [Data]) -> (T, usize) + Send + 'async_trait,
     ) -> T;
+    /// pop like pop, but nothing is removed and Timeout is returned if there are not count items before the deadline
+    async fn pop_until<T>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [Data], &'a [Data]) -> (T, usize) + Send + 'async_trait,
+    ) -> Result<T, Timeout>;
+    /// pop at most max items once the buffer is not empty, the consumer gets the items and returns the count to remove
+    async fn pop_at_most<T>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [Data], &'a [Data]) -> (T, usize) + Send + 'async_trait,
+    ) -> T;
+    /// pop at most max items once there are max items or the deadline passes,
+    /// the consumer gets the items received before the deadline, which may be none of them
+    async fn pop_at_most_until<T>(
+        &self,
+        max: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [Data], &'a [Data]) -> (T, usize) + Send + 'async_trait,
+    ) -> T;
+    /// inspect the data without removing it, the consumer gets all the data once there are at least count items
+    async fn peek<T>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [Data], &'a [Data]) -> T + Send + 'async_trait,
+    ) -> T;
+    /// peek like peek, but Timeout is returned if there are not count items before the deadline
+    async fn peek_until<T>(
+        &self,
+        count: usize,
+        deadline: Instant,
+        consumer: impl for<'a> FnOnce(&'a [Data], &'a [Data]) -> T + Send + 'async_trait,
+    ) -> Result<T, Timeout>;
     fn must_pop<U>(
         &self,
         count: usize,
@@ -66,6 +122,21 @@ pub trait Buffer<Data: Send + Sync>: Send + Sync {
             .await
     }
 
+    async fn pop_at_most_by_ref<T>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [Data]) -> (T, usize) + Send + 'async_trait,
+    ) -> T
+        where
+            Data: Copy + Clone,
+    {
+        self.pop_at_most(max, |first, second| {
+            let slice = [first, second].concat();
+            consumer(&slice)
+        })
+            .await
+    }
+
     async fn pop_by_iterator<T>(
         &self,
         count: usize,
@@ -83,3 +154,33 @@ pub trait Buffer<Data: Send + Sync>: Send + Sync {
             .await
     }
 }
+
+/// Buffer is pushed and popped by the two sides of a stream
+pub trait Buffer<Data: Send + Sync>: PushBuffer<Data> + PopBuffer<Data> {}
+
+impl<Data: Send + Sync, T: PushBuffer<Data> + PopBuffer<Data>> Buffer<Data> for T {}
+
+/// the items in the range of the two slices given to a consumer, they are only copied if the range wraps around
+pub fn ring_range<'a, Data: Clone>(first: &'a [Data], second: &'a [Data], range: Range<usize>) -> Cow<'a, [Data]> {
+    let split = first.len();
+    if range.end <= split {
+        Cow::Borrowed(&first[range])
+    } else if range.start >= split {
+        Cow::Borrowed(&second[range.start - split..range.end - split])
+    } else {
+        Cow::Owned(first[range.start..].iter().chain(&second[..range.end - split]).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_range() {
+        let (first, second) = ([1, 2, 3], [4, 5]);
+        assert!(matches!(ring_range(&first, &second, 1..3), Cow::Borrowed([2, 3])));
+        assert!(matches!(ring_range(&first, &second, 3..5), Cow::Borrowed([4, 5])));
+        assert_eq!(ring_range(&first, &second, 2..4), Cow::<[i32]>::Owned(vec![3, 4]));
+    }
+}
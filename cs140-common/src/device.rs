@@ -1,61 +1,275 @@
+use std::convert::Infallible;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::thread;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, SampleFormat, SampleRate, StreamConfig, StreamError, SupportedBufferSize};
+use cpal::{Device, SampleFormat, SampleRate, Stream, StreamConfig, StreamError, SupportedBufferSize};
+use log::{info, warn};
 use tokio::runtime::{Builder, Runtime};
+use tokio::sync::watch;
 
-use crate::buffer::Buffer as Buf;
+use crate::buffer::{PopBuffer, PushBuffer};
 use crate::descriptor::SoundDescriptor;
 use crate::padding::padding_range;
 
-pub struct InputDevice<Buffer: Buf<f32>> {
+// the interval to build the stream again after it is lost
+const STREAM_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// DeviceStatus is the state of the stream of a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceStatus {
+    Up,
+    /// the stream is stopped by the error, it is built again when the device comes back
+    Down(String),
+}
+
+/// LinkStatus is the state of the input and the output device of a link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkStatus {
+    pub input: DeviceStatus,
+    pub output: DeviceStatus,
+}
+
+impl Default for LinkStatus {
+    fn default() -> Self {
+        Self {
+            input: DeviceStatus::Up,
+            output: DeviceStatus::Up,
+        }
+    }
+}
+
+impl LinkStatus {
+    /// the link is up if both devices are up
+    pub fn is_up(&self) -> bool {
+        self.input == DeviceStatus::Up && self.output == DeviceStatus::Up
+    }
+}
+
+// the messages to the thread of a stream, an error carries the generation of the stream which reports it
+enum StreamEvent {
+    Error(usize, StreamError),
+    Stop,
+}
+
+// run the stream built by build until Stop is received. The stream is dropped after the device is lost
+// and built again every retry_interval, the generation of the stream increases at each build.
+// The other errors of the backend are only logged since the stream may still run.
+// The status is reported when the stream is lost or built again
+fn run_stream<S>(events: Receiver<StreamEvent>, retry_interval: Duration, mut build: impl FnMut(usize) -> Result<S, String>, mut report: impl FnMut(DeviceStatus)) {
+    let mut generation = 0;
+    let mut stream = match build(generation) {
+        Ok(stream) => Some(stream),
+        Err(err) => {
+            warn!("failed to build the stream: {}, it is built again when the device comes back", err);
+            report(DeviceStatus::Down(err));
+            None
+        }
+    };
+    loop {
+        let event = match stream {
+            Some(_) => match events.recv() {
+                Ok(event) => event,
+                Err(_) => return,
+            },
+            None => match events.recv_timeout(retry_interval) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    generation += 1;
+                    if let Ok(new_stream) = build(generation) {
+                        info!("the stream is built again");
+                        stream = Some(new_stream);
+                        report(DeviceStatus::Up);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+        };
+        match event {
+            StreamEvent::Stop => return,
+            // the errors of the dropped streams are ignored
+            StreamEvent::Error(error_generation, err) if error_generation == generation && stream.is_some() => match err {
+                StreamError::DeviceNotAvailable => {
+                    warn!("the stream is lost: {}, it is built again when the device comes back", err);
+                    stream = None;
+                    report(DeviceStatus::Down(err.to_string()));
+                }
+                StreamError::BackendSpecific { .. } => warn!("the stream reports an error: {}", err),
+            },
+            StreamEvent::Error(..) => {}
+        }
+    }
+}
+
+// a stream built again must produce the samples described by the descriptor of the device
+fn check_rebuilt_config(old: (&StreamConfig, SampleFormat), new: (&StreamConfig, SampleFormat), device_config: &DeviceConfig) -> Result<(), String> {
+    let ((old, old_format), (new, new_format)) = (old, new);
+    if new.sample_rate != old.sample_rate {
+        return Err(format!("the sample rate changes from {} to {}", old.sample_rate.0, new.sample_rate.0));
+    }
+    if new.channels != old.channels {
+        return Err(format!("the channels change from {} to {}", old.channels, new.channels));
+    }
+    if new_format != old_format {
+        return Err(format!("the sample format changes from {:?} to {:?}", old_format, new_format));
+    }
+    check_device_config(new, device_config)
+}
+
+// the stream config of the device must have the channels chosen by the device config
+fn check_device_config(config: &StreamConfig, device_config: &DeviceConfig) -> Result<(), String> {
+    if device_config.stereo && config.channels < 2 {
+        return Err(format!("the device only has {} channel, the stereo lanes need 2 channels", config.channels));
+    }
+    match device_config.channel {
+        Some(channel) if channel >= config.channels => Err(format!("the device only has {} channels", config.channels)),
+        _ => Ok(()),
+    }
+}
+
+fn update_status(link_status: &Option<Arc<watch::Sender<LinkStatus>>>, update: impl FnOnce(&mut LinkStatus) -> &mut DeviceStatus, status: DeviceStatus) {
+    if let Some(link_status) = link_status {
+        link_status.send_if_modified(|link| {
+            let device_status = update(link);
+            let modified = *device_status != status;
+            *device_status = status;
+            modified
+        });
+    }
+}
+
+pub struct InputDevice<Buffer: PushBuffer<f32>> {
     stream_config: (Device, StreamConfig, SampleFormat),
+    /// select the device again when the stream is built again
+    device_config: DeviceConfig,
+    /// where the status of the stream is reported
+    link_status: Option<Arc<watch::Sender<LinkStatus>>>,
     /// store the audio data from the microphone, the data is packed per sampling
     audio_buffer: Arc<Buffer>,
+    /// the channel to record, None records the first channel
+    channel: Option<u16>,
+    /// record the first two channels interleaved
+    stereo: bool,
+}
+
+/// the default sample rate, DeviceConfig can choose another one such as 44100 or 96000
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// DeviceSelector chooses a device among the devices of the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    /// the default device of the host
+    Default,
+    /// the index in the device list of the host, the default device is used if the index is out of range
+    Index(usize),
+    /// the first device whose name contains the string
+    Name(String),
 }
 
-const SAMPLE_RATE: u32 = 48000;
+impl FromStr for DeviceSelector {
+    type Err = Infallible;
+
+    /// "default" selects the default device, a number selects the device by index, anything else selects the device by name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("default") {
+            Ok(DeviceSelector::Default)
+        } else if let Ok(index) = s.parse() {
+            Ok(DeviceSelector::Index(index))
+        } else {
+            Ok(DeviceSelector::Name(s.to_string()))
+        }
+    }
+}
+
+impl DeviceSelector {
+    fn select(&self, mut devices: impl Iterator<Item=Device>, default: impl FnOnce() -> Option<Device>) -> Result<Device, String> {
+        match self {
+            DeviceSelector::Default => default().ok_or_else(|| "no default device available".to_string()),
+            DeviceSelector::Index(device_index) => {
+                for (index, device) in devices.enumerate() {
+                    if index == *device_index {
+                        return Ok(device);
+                    }
+                }
+                default().ok_or_else(|| "no default device available".to_string())
+            }
+            DeviceSelector::Name(name) => devices
+                .find(|device| device.name().is_ok_and(|device_name| device_name.contains(name.as_str())))
+                .ok_or_else(|| format!("no device name contains {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub device: DeviceSelector,
+    pub sample_rate: u32,
+    /// the channel to record or play, None records the first channel and plays on all channels
+    pub channel: Option<u16>,
+    /// the buffer holds the samples of the first two channels interleaved as (left, right) instead of one channel,
+    /// channel is ignored if it is true
+    pub stereo: bool,
+}
+
+impl DeviceConfig {
+    fn with_index(device_index: usize) -> Self {
+        Self {
+            device: DeviceSelector::Index(device_index),
+            sample_rate: SAMPLE_RATE,
+            channel: None,
+            stereo: false,
+        }
+    }
+}
 
 impl<Buffer> InputDevice<Buffer>
     where
-        Buffer: Buf<f32> + 'static,
+        Buffer: PushBuffer<f32> + 'static,
 {
     /// new returns InputDevice as well as some config about the device / stream
     pub fn new(audio_buffer: Arc<Buffer>) -> (Self, SoundDescriptor) {
         // let config = Self::init_stream_config(&"USB Audio Device");
-        let config = Self::init_stream_config(0);
-        let descriptor = SoundDescriptor {
-            channels: config.1.channels,
-            sample_rate: config.1.sample_rate.0,
-            sample_format: config.2.into(),
-        };
-        (
-            InputDevice {
-                stream_config: config,
-                audio_buffer,
-            },
-            descriptor,
-        )
+        Self::new_with_config(audio_buffer, &DeviceConfig::with_index(0)).unwrap_or_else(|err| panic!("{}", err))
     }
 
     pub fn new_with_specific_device(audio_buffer: Arc<Buffer>, device_name: usize) -> (Self, SoundDescriptor) {
-        let config = Self::init_stream_config(device_name);
+        Self::new_with_config(audio_buffer, &DeviceConfig::with_index(device_name)).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// the error tells why the device can not be opened by the config
+    pub fn new_with_config(audio_buffer: Arc<Buffer>, device_config: &DeviceConfig) -> Result<(Self, SoundDescriptor), String> {
+        let config = Self::init_stream_config(device_config)?;
+        info!("using {} as input device with sample rate {}", config.0.name().map_err(|err| err.to_string())?, config.1.sample_rate.0);
+        // the descriptor describes the samples in the buffer
         let descriptor = SoundDescriptor {
-            channels: config.1.channels,
+            channels: if device_config.stereo { 2 } else { 1 },
             sample_rate: config.1.sample_rate.0,
             sample_format: config.2.into(),
         };
-        (
+        Ok((
             InputDevice {
                 stream_config: config,
+                device_config: device_config.clone(),
+                link_status: None,
                 audio_buffer,
+                channel: device_config.channel,
+                stereo: device_config.stereo,
             },
             descriptor,
-        )
+        ))
+    }
+
+    /// report the status of the stream to the input of the link status
+    pub fn with_link_status(mut self, link_status: Arc<watch::Sender<LinkStatus>>) -> Self {
+        self.link_status = Some(link_status);
+        self
     }
 
-    fn init_stream_config(device_name: usize) -> (Device, StreamConfig, SampleFormat) {
+    fn init_stream_config(device_config: &DeviceConfig) -> Result<(Device, StreamConfig, SampleFormat), String> {
         // Get the input device from user
         let host = cpal::default_host();
         // let host = if cfg!(target_os = "windows")
@@ -64,40 +278,28 @@ impl<Buffer> InputDevice<Buffer>
         // } else {
         //     cpal::default_host()
         // };
-        let choose_device = || {
-            // for (index, input_) in host.input_devices().unwrap().enumerate() {
-            //     println!("input_device {}: {}", index, input_.name().unwrap());
-            // }
-            for (index, input_) in host.input_devices().unwrap().enumerate() {
-                if index == device_name {
-                    return input_;
-                }
-            }
-            return host.default_input_device().expect("no input device available");
-        };
-        // let input_device = host
-        //     .default_input_device()
-        //     .expect("no input device available");
-        let input_device = choose_device();
+        // for (index, input_) in host.input_devices().unwrap().enumerate() {
+        //     println!("input_device {}: {}", index, input_.name().unwrap());
+        // }
+        let input_device = device_config.device.select(host.input_devices().map_err(|err| err.to_string())?, || host.default_input_device())?;
         // Choose the device that has the maximum of sample rates
-        println!("using {} as input device", input_device.name().unwrap());
         let mut config = input_device
             .default_input_config()
-            .expect("error while querying configs");
+            .map_err(|err| format!("error while querying configs: {}", err))?;
+        let sample_rate = device_config.sample_rate;
         // Choose the device that has the maximum of sample rates
-        for _config in input_device.supported_input_configs().unwrap() {
+        for _config in input_device.supported_input_configs().map_err(|err| err.to_string())? {
             // println!("{:?}", _config.max_sample_rate());
             // println!("{:?}", _config.buffer_size());
             // println!("{:?}", _config.channels());
-            if _config.max_sample_rate().0 >= SAMPLE_RATE && _config.channels() == 2 {
-                config = _config.with_sample_rate(SampleRate{0:SAMPLE_RATE});
+            if _config.max_sample_rate().0 >= sample_rate && _config.min_sample_rate().0 <= sample_rate && _config.channels() == 2 {
+                config = _config.with_sample_rate(SampleRate{0:sample_rate});
                 break;
             }
         }
         let config_ = config.clone();
         let sample_format = config.sample_format();
         let mut config: StreamConfig = config.into();
-        println!("with sample rate:{:?}", config.sample_rate);
         let buffer_size = match config_.buffer_size() {
             SupportedBufferSize::Range { min, max } => {
                 // cpal::BufferSize::Fixed(*min)
@@ -108,123 +310,140 @@ impl<Buffer> InputDevice<Buffer>
             }
         };
         config.buffer_size = buffer_size;
-        (input_device, config, sample_format)
+        check_device_config(&config, device_config)?;
+        Ok((input_device, config, sample_format))
     }
 
-    pub fn listen(self) -> impl FnOnce() -> Self {
+    /// record in a thread until the returned function is called. The errors of the stream are reported to the link status,
+    /// and the stream is built again by the same device config when the device comes back.
+    pub fn listen(mut self) -> impl FnOnce() -> Self {
+        let (event_sender, events) = mpsc::channel();
+        let stop_sender = event_sender.clone();
         let thread_handle = std::thread::spawn(move || {
-            let stream_config = &self.stream_config.1;
-            let channels = stream_config.channels;
-            let device = &self.stream_config.0;
-            let audio_buffer = self.audio_buffer.clone();
-            let rt = Builder::new_multi_thread().enable_all().build().unwrap();
-            // Build the stream
-            let stream = match self.stream_config.2 {
-                SampleFormat::I16 => device
-                    .build_input_stream(
-                        stream_config,
-                        move |data: &[i16], _: &_| {
-                            Self::listen_handler(data, channels as usize, audio_buffer.clone(), &rt);
-                        },
-                        Self::listen_error_handler,
-                    )
-                    .unwrap(),
-                SampleFormat::U16 => device
-                    .build_input_stream(
-                        stream_config,
-                        move |data: &[u16], _: &_| {
-                            Self::listen_handler(data, channels as usize, audio_buffer.clone(), &rt);
-                        },
-                        Self::listen_error_handler,
-                    )
-                    .unwrap(),
-                SampleFormat::F32 => device
-                    .build_input_stream(
-                        stream_config,
-                        move |data: &[f32], _: &_| {
-                            Self::listen_handler(data, channels as usize, audio_buffer.clone(), &rt);
-                        },
-                        Self::listen_error_handler,
-                    )
-                    .unwrap(),
-            };
-            stream.play().unwrap();
-            thread::park();
+            let rt = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+            let mut rebuilt_config = None;
+            run_stream(events, STREAM_RETRY_INTERVAL, |generation| {
+                if generation > 0 {
+                    let config = Self::init_stream_config(&self.device_config)?;
+                    check_rebuilt_config((&self.stream_config.1, self.stream_config.2), (&config.1, config.2), &self.device_config)?;
+                    rebuilt_config = Some(config);
+                }
+                self.build_stream(rebuilt_config.as_ref().unwrap_or(&self.stream_config), generation, event_sender.clone(), rt.clone())
+            }, |status| update_status(&self.link_status, |link| &mut link.input, status));
+            if let Some(config) = rebuilt_config {
+                self.stream_config = config;
+            }
             self
         });
 
         move || {
-            thread_handle.thread().unpark();
+            let _ = stop_sender.send(StreamEvent::Stop);
             thread_handle.join().unwrap()
         }
     }
 
-    fn listen_handler<T>(input: &[T], channels: usize, audio_buffer: Arc<Buffer>, rt: &Runtime)
+    fn build_stream(&self, stream_config: &(Device, StreamConfig, SampleFormat), generation: usize, events: Sender<StreamEvent>, rt: Arc<Runtime>) -> Result<Stream, String> {
+        let (device, config, sample_format) = stream_config;
+        let channels = config.channels as usize;
+        let channel = if self.stereo { None } else { Some(self.channel.unwrap_or(0) as usize) };
+        let audio_buffer = self.audio_buffer.clone();
+        let error_handler = move |err| {
+            let _ = events.send(StreamEvent::Error(generation, err));
+        };
+        let stream = match sample_format {
+            SampleFormat::I16 => device.build_input_stream(config, move |data: &[i16], _: &_| {
+                Self::listen_handler(data, channels, channel, audio_buffer.clone(), &rt);
+            }, error_handler),
+            SampleFormat::U16 => device.build_input_stream(config, move |data: &[u16], _: &_| {
+                Self::listen_handler(data, channels, channel, audio_buffer.clone(), &rt);
+            }, error_handler),
+            SampleFormat::F32 => device.build_input_stream(config, move |data: &[f32], _: &_| {
+                Self::listen_handler(data, channels, channel, audio_buffer.clone(), &rt);
+            }, error_handler),
+        }.map_err(|err| err.to_string())?;
+        stream.play().map_err(|err| err.to_string())?;
+        Ok(stream)
+    }
+
+    // channel is None to record the first two channels interleaved
+    fn listen_handler<T>(input: &[T], channels: usize, channel: Option<usize>, audio_buffer: Arc<Buffer>, rt: &Runtime)
         where
             T: cpal::Sample + Sync,
     {
-        let mut iterator = input.iter().step_by(channels).map(|value| value.to_f32());
-        rt.block_on(audio_buffer.push_by_iterator(input.len() / channels, &mut iterator));
-    }
-
-    fn listen_error_handler(err: StreamError) {
-        panic!("{}", err)
+        match channel {
+            Some(channel) => {
+                let mut iterator = input.iter().skip(channel).step_by(channels).map(|value| value.to_f32());
+                rt.block_on(audio_buffer.push_by_iterator(input.len() / channels, &mut iterator));
+            }
+            None => {
+                let mut iterator = input.chunks(channels).flat_map(|frame| frame[..2].iter()).map(|value| value.to_f32());
+                rt.block_on(audio_buffer.push_by_iterator(input.len() / channels * 2, &mut iterator));
+            }
+        }
     }
 }
 
-pub struct OutputDevice<Buffer: Buf<f32>> {
+pub struct OutputDevice<Buffer: PopBuffer<f32>> {
     stream_config: (Device, StreamConfig, SampleFormat),
+    /// select the device again when the stream is built again
+    device_config: DeviceConfig,
+    /// where the status of the stream is reported
+    link_status: Option<Arc<watch::Sender<LinkStatus>>>,
     /// play the audio from audio buffer, consumes n packed data per play, where n is the number of channels to play
     audio_buffer: Arc<Buffer>,
+    /// the channel to play, None plays on all channels
+    channel: Option<u16>,
+    /// play the interleaved samples on the first two channels
+    stereo: bool,
 }
 
 impl<Buffer> OutputDevice<Buffer>
     where
-        Buffer: Buf<f32> + 'static,
+        Buffer: PopBuffer<f32> + 'static,
 {
     /// new returns InputDevice as well as some config about the device / stream, for example: channels
     pub fn new(audio_buffer: Arc<Buffer>) -> (Self, SoundDescriptor) {
         // let config = Self::init_stream_config(&"USB Audio Device");
-        let config = Self::init_stream_config(0);
-        let descriptor = SoundDescriptor {
-            channels: config.1.channels,
-            sample_rate: config.1.sample_rate.0,
-            sample_format: config.2.into(),
-        };
-        (
-            OutputDevice {
-                stream_config: config,
-                audio_buffer,
-            },
-            descriptor,
-        )
+        Self::new_with_config(audio_buffer, &DeviceConfig::with_index(0)).unwrap_or_else(|err| panic!("{}", err))
     }
 
     pub fn new_with_specific_device(audio_buffer: Arc<Buffer>, device_name: usize) -> (Self, SoundDescriptor) {
-        let config = Self::init_stream_config(device_name);
-        let descriptor = SoundDescriptor {
-            channels: config.1.channels,
-            sample_rate: config.1.sample_rate.0,
-            sample_format: config.2.into(),
+        Self::new_with_config(audio_buffer, &DeviceConfig::with_index(device_name)).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// the error tells why the device can not be opened by the config
+    pub fn new_with_config(audio_buffer: Arc<Buffer>, device_config: &DeviceConfig) -> Result<(Self, SoundDescriptor), String> {
+        let config = Self::init_stream_config(device_config)?;
+        info!("using {} as output device with sample rate {}", config.0.name().map_err(|err| err.to_string())?, config.1.sample_rate.0);
+        let device = OutputDevice {
+            stream_config: config,
+            device_config: device_config.clone(),
+            link_status: None,
+            audio_buffer,
+            channel: device_config.channel,
+            stereo: device_config.stereo,
         };
-        (
-            OutputDevice {
-                stream_config: config,
-                audio_buffer,
-            },
-            descriptor,
-        )
+        let descriptor = device.sound_descriptor();
+        Ok((device, descriptor))
     }
 
+    /// the descriptor describes the samples in the buffer, which has 2 channels if it is stereo, otherwise 1,
+    /// whatever the channels of the device are
     pub fn sound_descriptor(&self) -> SoundDescriptor {
         SoundDescriptor {
-            channels: self.stream_config.1.channels,
+            channels: if self.stereo { 2 } else { 1 },
             sample_rate: self.stream_config.1.sample_rate.0,
             sample_format: self.stream_config.2.into(),
         }
     }
 
-    fn init_stream_config(device_name: usize) -> (Device, StreamConfig, SampleFormat) {
+    /// report the status of the stream to the output of the link status
+    pub fn with_link_status(mut self, link_status: Arc<watch::Sender<LinkStatus>>) -> Self {
+        self.link_status = Some(link_status);
+        self
+    }
+
+    fn init_stream_config(device_config: &DeviceConfig) -> Result<(Device, StreamConfig, SampleFormat), String> {
         // Get the input device from user
         let host = cpal::default_host();
         // let host = if cfg!(target_os = "windows")
@@ -233,36 +452,24 @@ impl<Buffer> OutputDevice<Buffer>
         // } else {
         //     cpal::default_host()
         // };
-        let choose_device = || {
-            // for (index, output_) in host.output_devices().unwrap().enumerate() {
-            //     println!("output_device {}: {}", index, output_.name().unwrap());
-            // }
-            for (index, output_) in host.output_devices().unwrap().enumerate() {
-                if index == device_name {
-                    return output_;
-                }
-            }
-            return host.default_output_device().expect("no output device available");
-        };
+        // for (index, output_) in host.output_devices().unwrap().enumerate() {
+        //     println!("output_device {}: {}", index, output_.name().unwrap());
+        // }
 
-        // let output_device = host
-        //     .default_output_device()
-        //     .expect("no output device available");
-        let output_device = choose_device();
-        println!("using {} as output device", output_device.name().unwrap());
+        let output_device = device_config.device.select(host.output_devices().map_err(|err| err.to_string())?, || host.default_output_device())?;
 
         let mut config = output_device
             .default_output_config()
-            .expect("error while querying configs");
-
+            .map_err(|err| format!("error while querying configs: {}", err))?;
 
+        let sample_rate = device_config.sample_rate;
         // Choose the device that has the maximum of sample rates
-        for _config in output_device.supported_output_configs().unwrap() {
+        for _config in output_device.supported_output_configs().map_err(|err| err.to_string())? {
             // println!("{:?}", _config.max_sample_rate());
             // println!("{:?}", _config.buffer_size());
             // println!("{:?}", _config.channels());
-            if _config.max_sample_rate().0 >= SAMPLE_RATE && _config.channels() == 2 {
-                config = _config.with_sample_rate(SampleRate{0:SAMPLE_RATE});
+            if _config.max_sample_rate().0 >= sample_rate && _config.min_sample_rate().0 <= sample_rate && _config.channels() == 2 {
+                config = _config.with_sample_rate(SampleRate{0:sample_rate});
                 break;
             }
         }
@@ -278,79 +485,158 @@ impl<Buffer> OutputDevice<Buffer>
                 config.buffer_size
             }
         };
-        println!("with sample rate:{:?}", config.sample_rate);
         config.buffer_size = buffer_size;
-        (output_device, config, sample_format)
+        check_device_config(&config, device_config)?;
+        Ok((output_device, config, sample_format))
     }
 
-    pub fn play(self) -> impl FnOnce() -> Self {
+    /// play in a thread until the returned function is called. The errors of the stream are reported to the link status,
+    /// and the stream is built again by the same device config when the device comes back.
+    pub fn play(mut self) -> impl FnOnce() -> Self {
+        let (event_sender, events) = mpsc::channel();
+        let stop_sender = event_sender.clone();
         let thread_handle = std::thread::spawn(move || {
-            let stream_config = &self.stream_config.1;
-            let device = &self.stream_config.0;
-            let audio_buffer = self.audio_buffer.clone();
-            let channels = stream_config.channels as usize;
-
-            // Build the stream
-            let stream = match self.stream_config.2 {
-                SampleFormat::I16 => device
-                    .build_output_stream(
-                        stream_config,
-                        move |data: &mut [i16], _: &_| {
-                            Self::play_handler(data, channels, audio_buffer.clone());
-                        },
-                        Self::play_error_handler,
-                    )
-                    .unwrap(),
-                SampleFormat::U16 => device
-                    .build_output_stream(
-                        stream_config,
-                        move |data: &mut [u16], _: &_| {
-                            Self::play_handler(data, channels, audio_buffer.clone());
-                        },
-                        Self::play_error_handler,
-                    )
-                    .unwrap(),
-                SampleFormat::F32 => device
-                    .build_output_stream(
-                        stream_config,
-                        move |data: &mut [f32], _: &_| {
-                            Self::play_handler(data, channels, audio_buffer.clone());
-                        },
-                        Self::play_error_handler,
-                    )
-                    .unwrap(),
-            };
-
-            stream.play().unwrap();
-            thread::park();
+            let mut rebuilt_config = None;
+            run_stream(events, STREAM_RETRY_INTERVAL, |generation| {
+                if generation > 0 {
+                    let config = Self::init_stream_config(&self.device_config)?;
+                    check_rebuilt_config((&self.stream_config.1, self.stream_config.2), (&config.1, config.2), &self.device_config)?;
+                    rebuilt_config = Some(config);
+                }
+                self.build_stream(rebuilt_config.as_ref().unwrap_or(&self.stream_config), generation, event_sender.clone())
+            }, |status| update_status(&self.link_status, |link| &mut link.output, status));
+            if let Some(config) = rebuilt_config {
+                self.stream_config = config;
+            }
             self
         });
 
         move || {
-            thread_handle.thread().unpark();
+            let _ = stop_sender.send(StreamEvent::Stop);
             thread_handle.join().unwrap()
         }
     }
 
-    fn play_handler<T>(output: &mut [T], channels: usize, audio_buffer: Arc<Buffer>)
+    fn build_stream(&self, stream_config: &(Device, StreamConfig, SampleFormat), generation: usize, events: Sender<StreamEvent>) -> Result<Stream, String> {
+        let (device, config, sample_format) = stream_config;
+        let channels = config.channels as usize;
+        let channel = self.channel.map(|channel| channel as usize);
+        let stereo = self.stereo;
+        let audio_buffer = self.audio_buffer.clone();
+        let error_handler = move |err| {
+            let _ = events.send(StreamEvent::Error(generation, err));
+        };
+        let stream = match sample_format {
+            SampleFormat::I16 => device.build_output_stream(config, move |data: &mut [i16], _: &_| {
+                Self::play_handler(data, channels, channel, stereo, audio_buffer.clone());
+            }, error_handler),
+            SampleFormat::U16 => device.build_output_stream(config, move |data: &mut [u16], _: &_| {
+                Self::play_handler(data, channels, channel, stereo, audio_buffer.clone());
+            }, error_handler),
+            SampleFormat::F32 => device.build_output_stream(config, move |data: &mut [f32], _: &_| {
+                Self::play_handler(data, channels, channel, stereo, audio_buffer.clone());
+            }, error_handler),
+        }.map_err(|err| err.to_string())?;
+        stream.play().map_err(|err| err.to_string())?;
+        Ok(stream)
+    }
+
+    fn play_handler<T>(output: &mut [T], channels: usize, channel: Option<usize>, stereo: bool, audio_buffer: Arc<Buffer>)
         where
             T: cpal::Sample,
     {
+        if stereo {
+            let len = output.len() / channels * 2;
+            audio_buffer.must_pop(len, move |first, second| {
+                let mut values = first.iter().chain(second.iter());
+                for frame in output.chunks_mut(channels) {
+                    for (index, sample) in frame.iter_mut().enumerate() {
+                        *sample = if index < 2 {
+                            cpal::Sample::from(values.next().unwrap())
+                        } else {
+                            cpal::Sample::from(&0.0f32)
+                        };
+                    }
+                }
+                ((), len)
+            }, padding_range(-0.0001, 0.0001));
+            return;
+        }
         let len = output.len() / channels;
         audio_buffer.must_pop(len, move |first, second| {
             for (frame, value) in output
                 .chunks_mut(channels)
                 .zip(first.iter().chain(second.iter()))
             {
-                for sample in frame.iter_mut() {
-                    *sample = cpal::Sample::from(value);
+                for (index, sample) in frame.iter_mut().enumerate() {
+                    *sample = if channel.is_none_or(|channel| channel == index) {
+                        cpal::Sample::from(value)
+                    } else {
+                        cpal::Sample::from(&0.0f32)
+                    };
                 }
             }
             ((), len)
         }, padding_range(-0.0001, 0.0001));
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpal::{BackendSpecificError, BufferSize};
 
-    fn play_error_handler(err: StreamError) {
-        panic!("{}", err)
+    use super::*;
+
+    fn stream_config(channels: u16) -> StreamConfig {
+        StreamConfig {
+            channels,
+            sample_rate: SampleRate(SAMPLE_RATE),
+            buffer_size: BufferSize::Default,
+        }
+    }
+
+    #[test]
+    fn test_check_device_config() {
+        let stereo = DeviceConfig { stereo: true, ..DeviceConfig::with_index(0) };
+        assert!(check_device_config(&stream_config(1), &stereo).is_err());
+        assert!(check_device_config(&stream_config(2), &stereo).is_ok());
+        let channel = DeviceConfig { channel: Some(1), ..DeviceConfig::with_index(0) };
+        assert!(check_device_config(&stream_config(1), &channel).is_err());
+        assert!(check_device_config(&stream_config(1), &DeviceConfig::with_index(0)).is_ok());
+    }
+
+    #[test]
+    fn test_check_rebuilt_config() {
+        let config = DeviceConfig::with_index(0);
+        assert!(check_rebuilt_config((&stream_config(2), SampleFormat::F32), (&stream_config(2), SampleFormat::F32), &config).is_ok());
+        assert!(check_rebuilt_config((&stream_config(2), SampleFormat::F32), (&stream_config(1), SampleFormat::F32), &config).is_err());
+        assert!(check_rebuilt_config((&stream_config(2), SampleFormat::F32), (&stream_config(2), SampleFormat::I16), &config).is_err());
+        let faster = StreamConfig { sample_rate: SampleRate(96000), ..stream_config(2) };
+        assert!(check_rebuilt_config((&stream_config(2), SampleFormat::F32), (&faster, SampleFormat::F32), &config).is_err());
+    }
+
+    #[test]
+    fn test_run_stream() {
+        let (event_sender, events) = mpsc::channel();
+        let (report_sender, reports) = mpsc::channel();
+        let stream_thread = std::thread::spawn(move || {
+            // the first build fails, so does the first one after the device is lost
+            run_stream(events, Duration::from_millis(10), |generation| match generation {
+                0 | 2 => Err(format!("build {}", generation)),
+                _ => Ok(generation),
+            }, |status| report_sender.send(status).unwrap());
+        });
+        assert_eq!(reports.recv().unwrap(), DeviceStatus::Down("build 0".to_string()));
+        assert_eq!(reports.recv().unwrap(), DeviceStatus::Up);
+        // the stream is kept after an error of the backend or an error of a dropped stream
+        let backend_error = BackendSpecificError { description: "underrun".to_string() };
+        event_sender.send(StreamEvent::Error(1, StreamError::BackendSpecific { err: backend_error })).unwrap();
+        event_sender.send(StreamEvent::Error(0, StreamError::DeviceNotAvailable)).unwrap();
+        event_sender.send(StreamEvent::Error(1, StreamError::DeviceNotAvailable)).unwrap();
+        assert_eq!(reports.recv().unwrap(), DeviceStatus::Down(StreamError::DeviceNotAvailable.to_string()));
+        assert_eq!(reports.recv().unwrap(), DeviceStatus::Up);
+        event_sender.send(StreamEvent::Stop).unwrap();
+        stream_thread.join().unwrap();
+        assert!(reports.try_recv().is_err());
     }
 }
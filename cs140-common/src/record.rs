@@ -33,6 +33,11 @@ impl<Writer> Recorder<Writer>
             Some(self)
         }
     }
+
+    /// update the header of the wav file, so that the samples written so far can be read even if the recorder is not dropped
+    pub fn flush(&mut self) {
+        self.writer.as_mut().unwrap().flush().unwrap();
+    }
 }
 
 impl<Writer> Drop for Recorder<Writer>
@@ -0,0 +1,375 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use log::debug;
+use tokio::time::Instant;
+
+use crate::redundancy::{BYTE_IN_ARQ, RedundancyPackage};
+
+/// the sequence numbers of the frames are counted modulo SEQUENCE_MODULO
+pub const SEQUENCE_MODULO: u8 = 64;
+// the flags of the first arq byte in the header of a RedundancyPackage, the lower 6 bits are the sequence number.
+// The second byte is the epoch.
+const DATA_FLAG: u8 = 0x40;
+const ACK_FLAG: u8 = 0x80;
+const SYNC_FLAG: u8 = DATA_FLAG | ACK_FLAG;
+
+/// Arq is the automatic repeat request of the link, both ends must use the same mode.
+/// The receiver acknowledges the next sequence number it expects, so the acknowledgements are cumulative.
+/// Each sender counts the sequence numbers in a random epoch, so the ends find out that the other end has restarted.
+/// A sender which restarts in the same process takes the next epoch. A sender in a new process may take the epoch of
+/// the old one, then the receiver acknowledges the packages it has not sent, and the sender moves on to the next epoch.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Arq {
+    /// the packages are sent only once
+    None,
+    /// a package is sent after the previous package is acknowledged, it is sent again after the timeout
+    StopAndWait { timeout: Duration },
+    /// go-back-n, at most window_size packages are not acknowledged, all of them are sent again after the timeout of the first one
+    SlidingWindow { window_size: usize, timeout: Duration },
+}
+
+impl Arq {
+    /// the max count of the packages which are sent but not acknowledged, 0 if the arq is disabled
+    pub fn window_size(&self) -> usize {
+        match self {
+            Arq::None => 0,
+            Arq::StopAndWait { .. } => 1,
+            Arq::SlidingWindow { window_size, .. } => *window_size,
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            Arq::None => None,
+            Arq::StopAndWait { timeout } | Arq::SlidingWindow { timeout, .. } => Some(*timeout),
+        }
+    }
+}
+
+/// ArqHeader is the bytes in the header of a RedundancyPackage which tell the arq what the package is
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ArqHeader {
+    /// the package is not sequenced, such as the broadcast packages or the packages sent without arq
+    None,
+    /// a data package, the epoch of its sender and its sequence number
+    Data { epoch: u8, sequence: u8 },
+    /// a data package which is the first one not acknowledged, a receiver which does not know the epoch starts from it
+    Sync { epoch: u8, sequence: u8 },
+    /// an acknowledgement of the epoch and the next sequence number the receiver expects
+    Ack { epoch: u8, sequence: u8 },
+}
+
+impl ArqHeader {
+    pub fn to_bytes(self) -> [u8; BYTE_IN_ARQ] {
+        let (flag, epoch, sequence) = match self {
+            ArqHeader::None => return [0; BYTE_IN_ARQ],
+            ArqHeader::Data { epoch, sequence } => (DATA_FLAG, epoch, sequence),
+            ArqHeader::Sync { epoch, sequence } => (SYNC_FLAG, epoch, sequence),
+            ArqHeader::Ack { epoch, sequence } => (ACK_FLAG, epoch, sequence),
+        };
+        [flag | (sequence % SEQUENCE_MODULO), epoch]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (byte, epoch) = match bytes {
+            [byte, epoch] => (*byte, *epoch),
+            _ => return None,
+        };
+        let sequence = byte % SEQUENCE_MODULO;
+        match byte & SYNC_FLAG {
+            0 if byte == 0 && epoch == 0 => Some(ArqHeader::None),
+            DATA_FLAG => Some(ArqHeader::Data { epoch, sequence }),
+            SYNC_FLAG => Some(ArqHeader::Sync { epoch, sequence }),
+            ACK_FLAG => Some(ArqHeader::Ack { epoch, sequence }),
+            _ => None,
+        }
+    }
+}
+
+// the distance from start to end in the sequence space
+fn distance(start: u8, end: u8) -> usize {
+    ((end + SEQUENCE_MODULO - start) % SEQUENCE_MODULO) as usize
+}
+
+/// ArqSender keeps the packages which are sent but not acknowledged
+pub(crate) struct ArqSender {
+    window_size: usize,
+    timeout: Duration,
+    epoch: u8,
+    next_sequence: u8,
+    unacknowledged: VecDeque<RedundancyPackage>,
+    deadline: Option<Instant>,
+}
+
+impl ArqSender {
+    pub(crate) fn new(arq: Arq) -> Self {
+        let window_size = arq.window_size();
+        // the receiver can not tell a new package from a retransmitted one if the window is not smaller than the sequence space
+        assert!(window_size < SEQUENCE_MODULO as usize, "the window size must be less than {}", SEQUENCE_MODULO);
+        Self {
+            window_size,
+            timeout: arq.timeout().unwrap_or_default(),
+            epoch: rand::random(),
+            next_sequence: 0,
+            unacknowledged: VecDeque::with_capacity(window_size),
+            deadline: None,
+        }
+    }
+
+    /// a new sender of the arq in the next epoch, the receivers do not take its packages for the packages of this sender
+    pub(crate) fn restart(&self, arq: Arq) -> Self {
+        Self {
+            epoch: self.epoch.wrapping_add(1),
+            ..Self::new(arq)
+        }
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.unacknowledged.len() >= self.window_size
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.unacknowledged.is_empty()
+    }
+
+    /// the time to send the unacknowledged packages again
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    // the sequence number of the first package which is not acknowledged
+    fn base(&self) -> u8 {
+        (self.next_sequence + SEQUENCE_MODULO - self.unacknowledged.len() as u8) % SEQUENCE_MODULO
+    }
+
+    // the header of the package of the sequence number, the first package which is not acknowledged syncs the receiver
+    fn header(&self, sequence: u8) -> ArqHeader {
+        let epoch = self.epoch;
+        if sequence == self.base() {
+            ArqHeader::Sync { epoch, sequence }
+        } else {
+            ArqHeader::Data { epoch, sequence }
+        }
+    }
+
+    /// give the package the next sequence number and keep it until it is acknowledged
+    pub(crate) fn push(&mut self, mut package: RedundancyPackage) -> RedundancyPackage {
+        assert!(!self.is_full());
+        package.set_arq_header(self.header(self.next_sequence));
+        self.next_sequence = (self.next_sequence + 1) % SEQUENCE_MODULO;
+        self.unacknowledged.push_back(package.clone());
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.timeout);
+        }
+        package
+    }
+
+    /// the peer has received all the packages of the epoch before next_sequence
+    pub(crate) fn acknowledge(&mut self, epoch: u8, next_sequence: u8) {
+        let count = distance(self.base(), next_sequence);
+        if epoch != self.epoch || count == 0 {
+            // a duplicated acknowledgement, or one for the sender before a restart
+            return;
+        }
+        if count > self.unacknowledged.len() {
+            // the packages which are not sent are acknowledged, the receiver knows the epoch from another sender
+            self.next_epoch();
+            return;
+        }
+        self.unacknowledged.drain(..count);
+        self.deadline = if self.unacknowledged.is_empty() {
+            None
+        } else {
+            Some(Instant::now() + self.timeout)
+        };
+    }
+
+    /// the packages to send again if the deadline has passed, the first one syncs a receiver which has restarted
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<RedundancyPackage> {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {
+                debug!("timeout, send {} packages again", self.unacknowledged.len());
+                self.deadline = Some(now + self.timeout);
+                let header = self.header(self.base());
+                if let Some(first) = self.unacknowledged.front_mut() {
+                    first.set_arq_header(header);
+                }
+                self.unacknowledged.iter().cloned().collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // count the unacknowledged packages again in the next epoch, they are sent in it after the deadline
+    fn next_epoch(&mut self) {
+        self.epoch = self.epoch.wrapping_add(1);
+        debug!("the epoch is used by another sender, move on to the epoch {}", self.epoch);
+        let base = self.base();
+        for index in 0..self.unacknowledged.len() {
+            let header = self.header((base + index as u8) % SEQUENCE_MODULO);
+            self.unacknowledged[index].set_arq_header(header);
+        }
+    }
+}
+
+/// ArqReceiver accepts the packages of each source in the order of their sequence numbers
+#[derive(Default)]
+pub(crate) struct ArqReceiver {
+    // the epoch of each source and the next sequence number of it
+    next_sequences: HashMap<u8, (u8, u8)>,
+}
+
+impl ArqReceiver {
+    /// returns whether the package is new and the acknowledgement to send.
+    /// The packages of a new epoch are dropped without an acknowledgement until the first one not acknowledged is synced.
+    pub(crate) fn receive(&mut self, src: u8, header: ArqHeader) -> (bool, Option<ArqHeader>) {
+        let (epoch, sequence, sync) = match header {
+            ArqHeader::Data { epoch, sequence } => (epoch, sequence, false),
+            ArqHeader::Sync { epoch, sequence } => (epoch, sequence, true),
+            ArqHeader::None | ArqHeader::Ack { .. } => return (false, None),
+        };
+        let next_sequence = match self.next_sequences.get_mut(&src) {
+            Some((current, next_sequence)) if *current == epoch => next_sequence,
+            _ if sync => {
+                debug!("sync the epoch {} of {} from {}", epoch, src, sequence);
+                let session = self.next_sequences.entry(src).or_default();
+                *session = (epoch, sequence);
+                &mut session.1
+            }
+            _ => {
+                debug!("drop the package {} from {} before the epoch {} is synced", sequence, src, epoch);
+                return (false, None);
+            }
+        };
+        let is_new = sequence == *next_sequence;
+        if is_new {
+            *next_sequence = (*next_sequence + 1) % SEQUENCE_MODULO;
+        } else {
+            debug!("drop the package {} from {}, expect {}", sequence, src, next_sequence);
+        }
+        (is_new, Some(ArqHeader::Ack { epoch, sequence: *next_sequence }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let headers = [
+            ArqHeader::None,
+            ArqHeader::Data { epoch: 0, sequence: 0 },
+            ArqHeader::Data { epoch: 7, sequence: 63 },
+            ArqHeader::Sync { epoch: 0, sequence: 0 },
+            ArqHeader::Ack { epoch: 255, sequence: 5 },
+        ];
+        for header in headers {
+            assert_eq!(ArqHeader::from_bytes(&header.to_bytes()), Some(header));
+        }
+        assert_eq!(ArqHeader::from_bytes(&[1, 0]), None);
+        assert_eq!(ArqHeader::from_bytes(&[0, 1]), None);
+        assert_eq!(ArqHeader::from_bytes(&[DATA_FLAG]), None);
+    }
+
+    #[tokio::test]
+    async fn test_go_back_n() {
+        let timeout = Duration::from_millis(100);
+        let mut sender = ArqSender::new(Arq::SlidingWindow { window_size: 3, timeout });
+        let epoch = sender.epoch;
+        let mut receiver = ArqReceiver::default();
+        let package = |x: u8| RedundancyPackage::new(std::iter::once(x), 1, false, 1, 2);
+        let sent: Vec<RedundancyPackage> = (0..3).map(|x| sender.push(package(x))).collect();
+        assert!(sender.is_full());
+        assert_eq!(sent[0].arq_header(), Some(ArqHeader::Sync { epoch, sequence: 0 }));
+        assert_eq!(sent[2].arq_header(), Some(ArqHeader::Data { epoch, sequence: 2 }));
+        assert_eq!(sent[2].data(), &[2]);
+
+        // the second package is lost, so the third one is dropped
+        let ack = |sequence| Some(ArqHeader::Ack { epoch, sequence });
+        assert_eq!(receiver.receive(1, sent[0].arq_header().unwrap()), (true, ack(1)));
+        assert_eq!(receiver.receive(1, sent[2].arq_header().unwrap()), (false, ack(1)));
+        sender.acknowledge(epoch, 1);
+        sender.acknowledge(epoch, 1);
+        assert!(!sender.is_full());
+        assert!(sender.expired(Instant::now()).is_empty());
+
+        let resent = sender.expired(Instant::now() + timeout);
+        assert_eq!(resent.iter().map(|package| package.data()[0]).collect::<Vec<u8>>(), vec![1, 2]);
+        assert_eq!(resent[0].arq_header(), Some(ArqHeader::Sync { epoch, sequence: 1 }));
+        assert_eq!(resent[1], sent[2]);
+        assert_eq!(receiver.receive(1, resent[0].arq_header().unwrap()), (true, ack(2)));
+        assert_eq!(receiver.receive(1, resent[1].arq_header().unwrap()), (true, ack(3)));
+        // the other sources have their own sequence numbers
+        assert_eq!(receiver.receive(3, ArqHeader::Sync { epoch, sequence: 0 }), (true, ack(1)));
+        sender.acknowledge(epoch, 3);
+        assert_eq!(sender.deadline(), None);
+    }
+
+    #[tokio::test]
+    async fn test_resync() {
+        let arq = Arq::SlidingWindow { window_size: 3, timeout: Duration::from_millis(100) };
+        let package = |x: u8| RedundancyPackage::new(std::iter::once(x), 1, false, 1, 2);
+        let mut sender = ArqSender::new(arq);
+        let mut receiver = ArqReceiver::default();
+        for x in 0..5 {
+            let (is_new, ack) = receiver.receive(1, sender.push(package(x)).arq_header().unwrap());
+            assert!(is_new);
+            let Some(ArqHeader::Ack { epoch, sequence }) = ack else { unreachable!() };
+            sender.acknowledge(epoch, sequence);
+        }
+
+        // the sender restarts in another epoch, the receiver starts from its first package
+        let mut restarted = sender.restart(arq);
+        let first = restarted.push(package(0));
+        // the acknowledgement for the sender before the restart is ignored
+        restarted.acknowledge(sender.epoch, 1);
+        assert!(!restarted.is_empty());
+        let epoch = restarted.epoch;
+        assert_eq!(receiver.receive(1, first.arq_header().unwrap()), (true, Some(ArqHeader::Ack { epoch, sequence: 1 })));
+        restarted.acknowledge(epoch, 1);
+        assert!(restarted.is_empty());
+
+        // the receiver restarts, it waits for the sender to send the first package which is not acknowledged again
+        let mut receiver = ArqReceiver::default();
+        let sent: Vec<RedundancyPackage> = (1..3).map(|x| restarted.push(package(x))).collect();
+        assert_eq!(receiver.receive(1, sent[1].arq_header().unwrap()), (false, None));
+        let resent = restarted.expired(Instant::now() + Duration::from_millis(100));
+        assert_eq!(receiver.receive(1, resent[0].arq_header().unwrap()), (true, Some(ArqHeader::Ack { epoch, sequence: 2 })));
+        assert_eq!(receiver.receive(1, resent[1].arq_header().unwrap()), (true, Some(ArqHeader::Ack { epoch, sequence: 3 })));
+    }
+
+    #[tokio::test]
+    async fn test_reused_epoch() {
+        let arq = Arq::SlidingWindow { window_size: 3, timeout: Duration::from_millis(100) };
+        let package = |x: u8| RedundancyPackage::new(std::iter::once(x), 1, false, 1, 2);
+        let mut sender = ArqSender::new(arq);
+        let mut receiver = ArqReceiver::default();
+        for x in 0..5 {
+            let (_, ack) = receiver.receive(1, sender.push(package(x)).arq_header().unwrap());
+            let Some(ArqHeader::Ack { epoch, sequence }) = ack else { unreachable!() };
+            sender.acknowledge(epoch, sequence);
+        }
+
+        // the sender restarts in a new process and takes the same epoch by chance
+        let mut restarted = ArqSender::new(arq);
+        restarted.epoch = sender.epoch;
+        let epoch = restarted.epoch;
+        let sent: Vec<RedundancyPackage> = (0..2).map(|x| restarted.push(package(x))).collect();
+        let (is_new, ack) = receiver.receive(1, sent[0].arq_header().unwrap());
+        assert_eq!((is_new, ack), (false, Some(ArqHeader::Ack { epoch, sequence: 5 })));
+        // the packages which are not sent are acknowledged, so they are sent again in the next epoch
+        restarted.acknowledge(epoch, 5);
+        assert!(!restarted.is_empty());
+        let resent = restarted.expired(Instant::now() + Duration::from_millis(100));
+        let epoch = epoch.wrapping_add(1);
+        assert_eq!(resent.iter().map(|package| package.arq_header().unwrap()).collect::<Vec<ArqHeader>>(),
+                   vec![ArqHeader::Sync { epoch, sequence: 0 }, ArqHeader::Data { epoch, sequence: 1 }]);
+        assert_eq!(resent.iter().map(|package| package.data()[0]).collect::<Vec<u8>>(), vec![0, 1]);
+        assert_eq!(receiver.receive(1, resent[0].arq_header().unwrap()), (true, Some(ArqHeader::Ack { epoch, sequence: 1 })));
+        assert_eq!(receiver.receive(1, resent[1].arq_header().unwrap()), (true, Some(ArqHeader::Ack { epoch, sequence: 2 })));
+        restarted.acknowledge(epoch, 2);
+        assert!(restarted.is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use clap::{App, Arg};
+
+use cs140_network::diagnostic::Capture;
+
+// the rows of the ascii eye diagram cover [-EYE_RANGE, EYE_RANGE] of the scaled samples
+const EYE_RANGE: f32 = 1.5;
+const EYE_HEIGHT: usize = 15;
+const HISTOGRAM_BIN_COUNT: usize = 10;
+
+fn print_margins(capture: &Capture, weakest_count: usize) {
+    let margins = capture.margins();
+    if margins.is_empty() {
+        println!("no bit is traced, the frame may be demodulated by a modulation other than baseband");
+        return;
+    }
+    let min = margins.iter().cloned().fold(f32::MAX, f32::min);
+    let mean = margins.iter().sum::<f32>() / margins.len() as f32;
+    println!("{} bits, {} Hz, {:?} samples per bit", margins.len(), capture.sample_rate, capture.sample_per_bit());
+    println!("margin: min {:.3}, mean {:.3}", min, mean);
+    println!("bit slips at bits {:?}", capture.slips());
+
+    let mut weakest: Vec<(usize, f32)> = margins.iter().cloned().enumerate().collect();
+    weakest.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap());
+    for (index, margin) in weakest.into_iter().take(weakest_count) {
+        let bit_trace = &capture.trace[index];
+        println!("  bit {:5} at sample {:6}: {} average {:+.4} margin {:.3}", index, bit_trace.sample_index, bit_trace.bit as u8, bit_trace.average, margin);
+    }
+
+    // the margins above 1 are counted in the last bin
+    let mut histogram = [0usize; HISTOGRAM_BIN_COUNT];
+    for margin in &margins {
+        let bin = ((margin.max(0.0) * HISTOGRAM_BIN_COUNT as f32) as usize).min(HISTOGRAM_BIN_COUNT - 1);
+        histogram[bin] += 1;
+    }
+    let max_count = *histogram.iter().max().unwrap();
+    for (bin, count) in histogram.iter().enumerate() {
+        let bar = "#".repeat((count * 50 + max_count - 1) / max_count);
+        let low = bin as f32 / HISTOGRAM_BIN_COUNT as f32;
+        if bin + 1 == HISTOGRAM_BIN_COUNT {
+            println!("  >= {:.1}      {:5} {}", low, count, bar);
+        } else {
+            println!("  [{:.1}, {:.1}) {:5} {}", low, low + 1.0 / HISTOGRAM_BIN_COUNT as f32, count, bar);
+        }
+    }
+}
+
+fn print_eye(eye_traces: &[Vec<f32>]) {
+    let span = match eye_traces.first() {
+        None => return,
+        Some(eye_trace) => eye_trace.len(),
+    };
+    let mut counts = vec![vec![0usize; span]; EYE_HEIGHT];
+    for eye_trace in eye_traces {
+        for (column, sample) in eye_trace.iter().enumerate() {
+            let row = ((EYE_RANGE - sample.clamp(-EYE_RANGE, EYE_RANGE)) / (2.0 * EYE_RANGE) * (EYE_HEIGHT - 1) as f32).round() as usize;
+            counts[row][column] += 1;
+        }
+    }
+    let max_count = counts.iter().flatten().cloned().max().unwrap_or(1);
+    println!("eye diagram of {} bits, a column is a sample from half a bit before the bit to half a bit after it", eye_traces.len());
+    for (row, row_counts) in counts.iter().enumerate() {
+        let level = EYE_RANGE - 2.0 * EYE_RANGE * row as f32 / (EYE_HEIGHT - 1) as f32;
+        let cells: String = row_counts.iter().map(|count| match count * 4 / max_count {
+            _ if *count == 0 => "   ",
+            0 => " . ",
+            1 => " : ",
+            2 => " * ",
+            _ => " # ",
+        }).collect();
+        println!("{:+.2} |{}|", level, cells);
+    }
+}
+
+fn write_eye_csv(eye_traces: &[Vec<f32>], path: &str) -> anyhow::Result<()> {
+    let mut csv = BufWriter::new(File::create(path)?);
+    writeln!(csv, "bit_index,offset,value")?;
+    for (index, eye_trace) in eye_traces.iter().enumerate() {
+        for (offset, value) in eye_trace.iter().enumerate() {
+            writeln!(csv, "{},{},{}", index, offset, value)?;
+        }
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let matches = App::new("analyze_capture")
+        .about("print the bit margins and the eye diagram of the frames saved by the diagnostic tap")
+        .arg(Arg::with_name("capture").help("the wav files of the frames, the csv files of the same names are read too").required(true).multiple(true))
+        .arg(Arg::with_name("weakest").long("weakest").takes_value(true).default_value("5").help("the count of the bits with the smallest margins to print"))
+        .arg(Arg::with_name("eye-csv").long("eye-csv").takes_value(true).help("also write the eye diagram of all the frames to the csv file for plotting"))
+        .get_matches();
+    let weakest_count: usize = matches.value_of("weakest").unwrap().parse()?;
+    let mut eye_traces = Vec::new();
+    for path in matches.values_of("capture").unwrap() {
+        println!("{}", path);
+        let capture = Capture::load(path)?;
+        print_margins(&capture, weakest_count);
+        let capture_eye_traces = capture.eye_traces();
+        print_eye(&capture_eye_traces);
+        eye_traces.extend(capture_eye_traces);
+        println!();
+    }
+    if let Some(path) = matches.value_of("eye-csv") {
+        write_eye_csv(&eye_traces, path)?;
+    }
+    Ok(())
+}
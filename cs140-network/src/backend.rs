@@ -0,0 +1,624 @@
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use cpal::traits::{DeviceTrait, HostTrait};
+use hound::{WavReader, WavWriter};
+use tokio::runtime::Builder;
+use tokio::sync::watch;
+
+use cs140_buffer::broadcast_ring_buffer::{BroadcastRingBuffer, BroadcastSubscriber, LossySubscriber, SlowSubscriber};
+use cs140_buffer::spsc_ring_buffer::SpscRingBuffer;
+use cs140_common::buffer::{PopBuffer, PushBuffer, Timeout};
+use cs140_common::descriptor::{SampleFormat, SoundDescriptor};
+use cs140_common::device::{DeviceConfig, InputDevice, LinkStatus, OutputDevice};
+use cs140_common::padding::padding_range;
+use cs140_common::record::Recorder;
+
+use crate::channel::{ChannelConfig, ChannelSimulator};
+
+/// the buffers between the backend and PhysicalLayer, each of them has one producer and one consumer
+pub type DefaultBuffer = SpscRingBuffer<f32>;
+
+/// InputBuffer is the buffer that PhysicalLayer receives samples from
+pub enum InputBuffer {
+    /// the samples are only popped by PhysicalLayer
+    Single(Arc<DefaultBuffer>),
+    /// the samples are shared with other consumers such as a recorder, PhysicalLayer pops them from a lossy subscriber
+    Shared(LossySubscriber<f32>),
+}
+
+impl InputBuffer {
+    /// the samples which are not popped
+    pub fn len(&self) -> usize {
+        match self {
+            InputBuffer::Single(buffer) => buffer.len(),
+            InputBuffer::Shared(subscriber) => subscriber.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// subscribe the samples received from now on, None if they are not shared
+    pub fn subscribe(&self, slow: SlowSubscriber) -> Option<BroadcastSubscriber<f32>> {
+        match self {
+            InputBuffer::Single(_) => None,
+            InputBuffer::Shared(subscriber) => Some(subscriber.buffer().subscribe(slow)),
+        }
+    }
+}
+
+#[async_trait]
+impl PopBuffer<f32> for InputBuffer {
+    async fn pop<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [f32], &'a [f32]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        match self {
+            InputBuffer::Single(buffer) => buffer.pop(count, consumer).await,
+            InputBuffer::Shared(subscriber) => PopBuffer::pop(subscriber, count, consumer).await,
+        }
+    }
+
+    async fn pop_until<U>(
+        &self,
+        count: usize,
+        deadline: tokio::time::Instant,
+        consumer: impl for<'a> FnOnce(&'a [f32], &'a [f32]) -> (U, usize) + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        match self {
+            InputBuffer::Single(buffer) => buffer.pop_until(count, deadline, consumer).await,
+            InputBuffer::Shared(subscriber) => PopBuffer::pop_until(subscriber, count, deadline, consumer).await,
+        }
+    }
+
+    async fn pop_at_most<U>(
+        &self,
+        max: usize,
+        consumer: impl for<'a> FnOnce(&'a [f32], &'a [f32]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        match self {
+            InputBuffer::Single(buffer) => buffer.pop_at_most(max, consumer).await,
+            InputBuffer::Shared(subscriber) => PopBuffer::pop_at_most(subscriber, max, consumer).await,
+        }
+    }
+
+    async fn pop_at_most_until<U>(
+        &self,
+        max: usize,
+        deadline: tokio::time::Instant,
+        consumer: impl for<'a> FnOnce(&'a [f32], &'a [f32]) -> (U, usize) + Send + 'async_trait,
+    ) -> U {
+        match self {
+            InputBuffer::Single(buffer) => buffer.pop_at_most_until(max, deadline, consumer).await,
+            InputBuffer::Shared(subscriber) => PopBuffer::pop_at_most_until(subscriber, max, deadline, consumer).await,
+        }
+    }
+
+    async fn peek<U>(
+        &self,
+        count: usize,
+        consumer: impl for<'a> FnOnce(&'a [f32], &'a [f32]) -> U + Send + 'async_trait,
+    ) -> U {
+        match self {
+            InputBuffer::Single(buffer) => buffer.peek(count, consumer).await,
+            InputBuffer::Shared(subscriber) => PopBuffer::peek(subscriber, count, consumer).await,
+        }
+    }
+
+    async fn peek_until<U>(
+        &self,
+        count: usize,
+        deadline: tokio::time::Instant,
+        consumer: impl for<'a> FnOnce(&'a [f32], &'a [f32]) -> U + Send + 'async_trait,
+    ) -> Result<U, Timeout> {
+        match self {
+            InputBuffer::Single(buffer) => buffer.peek_until(count, deadline, consumer).await,
+            InputBuffer::Shared(subscriber) => PopBuffer::peek_until(subscriber, count, deadline, consumer).await,
+        }
+    }
+    fn must_pop<U>(
+        &self,
+        count: usize,
+        consumer: impl FnOnce(&[f32], &[f32]) -> (U, usize),
+        producer: impl Iterator<Item=f32>,
+    ) -> U {
+        match self {
+            InputBuffer::Single(buffer) => buffer.must_pop(count, consumer, producer),
+            InputBuffer::Shared(subscriber) => PopBuffer::must_pop(subscriber, count, consumer, producer),
+        }
+    }
+}
+
+// about 100 seconds of samples at 48000 Hz
+pub const DEFAULT_BUFFER_CAPACITY: usize = 5000000;
+
+// the buffers of the backends without a sound card hold a few seconds of samples, which is more than a frame
+// and the samples a producer pushes ahead of its consumer
+const SIMULATED_BUFFER_SECONDS: usize = 4;
+
+fn simulated_buffer(descriptor: &SoundDescriptor) -> Arc<DefaultBuffer> {
+    Arc::new(DefaultBuffer::new(SIMULATED_BUFFER_SECONDS * descriptor.sample_rate as usize * descriptor.channels as usize))
+}
+
+/// An AudioBackend moves samples between the buffers of PhysicalLayer and the outside world.
+/// The backend is started when it is constructed and stopped when it is dropped, PhysicalLayer only pushes samples
+/// into the output buffer and pops samples from the input buffer.
+pub trait AudioBackend: Send {
+    /// the descriptor and the buffer that PhysicalLayer receives samples from
+    fn input(&self) -> (SoundDescriptor, InputBuffer);
+    /// the descriptor and the buffer that PhysicalLayer sends samples to
+    fn output(&self) -> (SoundDescriptor, Arc<DefaultBuffer>);
+    /// the status of the devices, a backend without a device is always up
+    fn link_status(&self) -> watch::Receiver<LinkStatus> {
+        watch::channel(LinkStatus::default()).1
+    }
+}
+
+/// CpalBackend plays and records the samples with the real sound card
+pub struct CpalBackend {
+    input_descriptor: SoundDescriptor,
+    input_buffer: CpalInput,
+    output_descriptor: SoundDescriptor,
+    output_buffer: Arc<DefaultBuffer>,
+    link_status: Arc<watch::Sender<LinkStatus>>,
+    // stop the streams and join their threads
+    stop_streams: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+// the buffer that the input device pushes the samples into
+enum CpalInput {
+    Single(Arc<DefaultBuffer>),
+    Shared(Arc<BroadcastRingBuffer<f32>>),
+}
+
+impl CpalBackend {
+    pub fn new(input_device_index: usize, output_device_index: usize) -> Self {
+        let input_buffer = Arc::new(DefaultBuffer::new(DEFAULT_BUFFER_CAPACITY));
+        let (input_device, input_descriptor) = InputDevice::new_with_specific_device(input_buffer.clone(), input_device_index);
+        let output_buffer = Arc::new(DefaultBuffer::new(DEFAULT_BUFFER_CAPACITY));
+        let (output_device, output_descriptor) = OutputDevice::new_with_specific_device(output_buffer.clone(), output_device_index);
+        Self::start(input_device, input_descriptor, CpalInput::Single(input_buffer), output_device, output_descriptor, output_buffer)
+    }
+
+    /// the error tells which device can not be opened by its config
+    pub fn with_config(input_config: &DeviceConfig, output_config: &DeviceConfig) -> Result<Self, String> {
+        let input_buffer = Arc::new(DefaultBuffer::new(DEFAULT_BUFFER_CAPACITY));
+        let (input_device, input_descriptor) = InputDevice::new_with_config(input_buffer.clone(), input_config)
+            .map_err(|err| format!("failed to open the input device: {}", err))?;
+        let output_buffer = Arc::new(DefaultBuffer::new(DEFAULT_BUFFER_CAPACITY));
+        let (output_device, output_descriptor) = OutputDevice::new_with_config(output_buffer.clone(), output_config)
+            .map_err(|err| format!("failed to open the output device: {}", err))?;
+        Ok(Self::start(input_device, input_descriptor, CpalInput::Single(input_buffer), output_device, output_descriptor, output_buffer))
+    }
+
+    /// with_shared_input is like with_config, but the received samples are pushed into a broadcast buffer,
+    /// so a recorder or a monitor can subscribe them besides PhysicalLayer, see PhysicalLayer::subscribe_input
+    pub fn with_shared_input(input_config: &DeviceConfig, output_config: &DeviceConfig) -> Result<Self, String> {
+        let input_buffer = Arc::new(BroadcastRingBuffer::new(DEFAULT_BUFFER_CAPACITY));
+        let (input_device, input_descriptor) = InputDevice::new_with_config(input_buffer.clone(), input_config)
+            .map_err(|err| format!("failed to open the input device: {}", err))?;
+        let output_buffer = Arc::new(DefaultBuffer::new(DEFAULT_BUFFER_CAPACITY));
+        let (output_device, output_descriptor) = OutputDevice::new_with_config(output_buffer.clone(), output_config)
+            .map_err(|err| format!("failed to open the output device: {}", err))?;
+        Ok(Self::start(input_device, input_descriptor, CpalInput::Shared(input_buffer), output_device, output_descriptor, output_buffer))
+    }
+
+    fn start<I: PushBuffer<f32> + 'static>(
+        input_device: InputDevice<I>,
+        input_descriptor: SoundDescriptor,
+        input_buffer: CpalInput,
+        output_device: OutputDevice<DefaultBuffer>,
+        output_descriptor: SoundDescriptor,
+        output_buffer: Arc<DefaultBuffer>,
+    ) -> Self {
+        let link_status = Arc::new(watch::channel(LinkStatus::default()).0);
+        let stop_input = input_device.with_link_status(link_status.clone()).listen();
+        let stop_output = output_device.with_link_status(link_status.clone()).play();
+        Self {
+            input_descriptor,
+            input_buffer,
+            output_descriptor,
+            output_buffer,
+            link_status,
+            stop_streams: vec![Box::new(move || { stop_input(); }), Box::new(move || { stop_output(); })],
+        }
+    }
+
+    /// print all the devices and let the user choose the input and output device from stdin
+    pub fn choose_from_stdin() -> Self {
+        let host = cpal::default_host();
+        for (index, input_) in host.input_devices().unwrap().enumerate() {
+            println!("input_device {}: {}", index, input_.name().unwrap());
+        }
+        println!("please choose your input audio device: ");
+        let mut buf = String::new();
+        std::io::stdin().read_line(&mut buf).unwrap();
+        let input = buf.trim().parse().unwrap();
+
+        for (index, output_) in host.output_devices().unwrap().enumerate() {
+            println!("output_device {}: {}", index, output_.name().unwrap());
+        }
+        println!("please choose your output audio device: ");
+        buf.clear();
+        std::io::stdin().read_line(&mut buf).unwrap();
+        let output = buf.trim().parse().unwrap();
+        Self::new(input, output)
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn input(&self) -> (SoundDescriptor, InputBuffer) {
+        let input_buffer = match &self.input_buffer {
+            CpalInput::Single(buffer) => InputBuffer::Single(buffer.clone()),
+            CpalInput::Shared(buffer) => InputBuffer::Shared(buffer.subscribe_lossy()),
+        };
+        (self.input_descriptor, input_buffer)
+    }
+
+    fn output(&self) -> (SoundDescriptor, Arc<DefaultBuffer>) {
+        (self.output_descriptor, self.output_buffer.clone())
+    }
+
+    fn link_status(&self) -> watch::Receiver<LinkStatus> {
+        self.link_status.subscribe()
+    }
+}
+
+impl Drop for CpalBackend {
+    fn drop(&mut self) {
+        for stop in self.stop_streams.drain(..) {
+            stop();
+        }
+    }
+}
+
+/// LoopbackBackend simulates an audio cable inside the process.
+/// The samples sent by one end of the pair are received by the other end in real time,
+/// the silence between packages is filled with noise like the real sound card does.
+pub struct LoopbackBackend {
+    descriptor: SoundDescriptor,
+    input_buffer: Arc<DefaultBuffer>,
+    output_buffer: Arc<DefaultBuffer>,
+    // shared by the backends of a pair or a bus, the wires stop after all of them are dropped
+    #[allow(dead_code)]
+    wire_owner: Arc<()>,
+}
+
+// the wire moves samples every 10ms
+const LOOPBACK_TICK_PER_SECOND: u32 = 100;
+
+impl LoopbackBackend {
+    /// pair returns two connected backends, the output of each one is the input of the other one.
+    pub fn pair(sample_rate: u32) -> (Self, Self) {
+        Self::pair_with_channel(sample_rate, ChannelConfig::default())
+    }
+
+    /// pair_with_channel returns two connected backends, the samples in both directions pass through a ChannelSimulator.
+    /// The direction from the second backend to the first one uses the next seed.
+    pub fn pair_with_channel(sample_rate: u32, channel: ChannelConfig) -> (Self, Self) {
+        Self::pair_with_layout(sample_rate, 1, channel)
+    }
+
+    /// stereo_pair_with_channel returns two connected stereo backends, the buffers hold the samples of two channels interleaved.
+    /// Each channel passes through its own ChannelSimulator, the right channel uses the seed after the next seed of the left channel.
+    pub fn stereo_pair_with_channel(sample_rate: u32, channel: ChannelConfig) -> (Self, Self) {
+        Self::pair_with_layout(sample_rate, 2, channel)
+    }
+
+    fn pair_with_layout(sample_rate: u32, channels: u16, channel: ChannelConfig) -> (Self, Self) {
+        let simulators = |seed: u64| -> Vec<ChannelSimulator> {
+            (0..channels as u64).map(|index| ChannelSimulator::new(ChannelConfig {
+                seed: seed.wrapping_add(2 * index),
+                ..channel
+            })).collect()
+        };
+        let descriptor = SoundDescriptor {
+            channels,
+            sample_rate,
+            sample_format: SampleFormat::F32,
+        };
+        let first_to_second = simulated_buffer(&descriptor);
+        let second_to_first = simulated_buffer(&descriptor);
+        let first_received = simulated_buffer(&descriptor);
+        let second_received = simulated_buffer(&descriptor);
+        let wire_owner = Arc::new(());
+        Self::wire(vec![first_to_second.clone()], vec![(second_received.clone(), simulators(channel.seed))], sample_rate, Arc::downgrade(&wire_owner));
+        Self::wire(vec![second_to_first.clone()], vec![(first_received.clone(), simulators(channel.seed.wrapping_add(1)))], sample_rate, Arc::downgrade(&wire_owner));
+        (
+            Self {
+                descriptor,
+                input_buffer: first_received,
+                output_buffer: first_to_second,
+                wire_owner: wire_owner.clone(),
+            },
+            Self {
+                descriptor,
+                input_buffer: second_received,
+                output_buffer: second_to_first,
+                wire_owner,
+            },
+        )
+    }
+
+    /// bus returns node_count mono backends on one shared medium like an audio mixer, every backend receives the sum of
+    /// the samples sent by all the backends including itself. The samples received by the n-th backend pass through
+    /// a ChannelSimulator with the seed plus n.
+    pub fn bus(sample_rate: u32, node_count: usize, channel: ChannelConfig) -> Vec<Self> {
+        let descriptor = SoundDescriptor {
+            channels: 1,
+            sample_rate,
+            sample_format: SampleFormat::F32,
+        };
+        let wire_owner = Arc::new(());
+        let nodes: Vec<Self> = (0..node_count).map(|_| Self {
+            descriptor,
+            input_buffer: simulated_buffer(&descriptor),
+            output_buffer: simulated_buffer(&descriptor),
+            wire_owner: wire_owner.clone(),
+        }).collect();
+        let receivers = nodes.iter().enumerate().map(|(index, node)| {
+            let simulator = ChannelSimulator::new(ChannelConfig {
+                seed: channel.seed.wrapping_add(index as u64),
+                ..channel
+            });
+            (node.input_buffer.clone(), vec![simulator])
+        }).collect();
+        Self::wire(nodes.iter().map(|node| node.output_buffer.clone()).collect(), receivers, sample_rate, Arc::downgrade(&wire_owner));
+        nodes
+    }
+
+    // the sum of the samples of the senders is received by each receiver,
+    // the samples of each channel pass through one of the simulators of the receiver, until the owner is dropped
+    fn wire(senders: Vec<Arc<DefaultBuffer>>, mut receivers: Vec<(Arc<DefaultBuffer>, Vec<ChannelSimulator>)>, sample_rate: u32, owner: Weak<()>) {
+        std::thread::spawn(move || {
+            let rt = Builder::new_current_thread().enable_all().build().unwrap();
+            let channels = receivers[0].1.len();
+            let chunk = (sample_rate / LOOPBACK_TICK_PER_SECOND) as usize * channels;
+            let tick = Duration::from_secs(1) / LOOPBACK_TICK_PER_SECOND;
+            let start = Instant::now();
+            let mut tick_count = 0;
+            while owner.strong_count() > 0 {
+                let mut samples = vec![0.0; chunk];
+                for sender in senders.iter() {
+                    let sent: Vec<f32> = sender.must_pop(chunk, |first, second| {
+                        let samples: Vec<f32> = first.iter().chain(second.iter()).take(chunk).cloned().collect();
+                        (samples, chunk)
+                    }, padding_range(-0.0001, 0.0001));
+                    samples.iter_mut().zip(sent).for_each(|(sample, sent)| *sample += sent);
+                }
+                for (to, simulators) in receivers.iter_mut() {
+                    let samples = if channels == 1 {
+                        simulators[0].transmit(&samples)
+                    } else {
+                        // the channels may have different lengths after dropped samples, the longer ones are cut
+                        let lanes: Vec<Vec<f32>> = simulators.iter_mut().enumerate().map(|(index, simulator)| {
+                            let lane: Vec<f32> = samples.iter().skip(index).step_by(channels).cloned().collect();
+                            simulator.transmit(&lane)
+                        }).collect();
+                        let len = lanes.iter().map(|lane| lane.len()).min().unwrap();
+                        (0..len).flat_map(|index| lanes.iter().map(move |lane| lane[index])).collect()
+                    };
+                    rt.block_on(to.push_by_ref(&samples));
+                }
+                tick_count += 1;
+                let next_tick = start + tick * tick_count;
+                let now = Instant::now();
+                if next_tick > now {
+                    std::thread::sleep(next_tick - now);
+                }
+            }
+        });
+    }
+}
+
+impl AudioBackend for LoopbackBackend {
+    fn input(&self) -> (SoundDescriptor, InputBuffer) {
+        (self.descriptor, InputBuffer::Single(self.input_buffer.clone()))
+    }
+
+    fn output(&self) -> (SoundDescriptor, Arc<DefaultBuffer>) {
+        (self.descriptor, self.output_buffer.clone())
+    }
+}
+
+/// WavBackend replays the received samples from a wav file and records the sent samples into a wav file.
+/// After the replayed file ends, the input keeps producing silence.
+pub struct WavBackend {
+    input_descriptor: SoundDescriptor,
+    input_buffer: Arc<DefaultBuffer>,
+    output_descriptor: SoundDescriptor,
+    output_buffer: Arc<DefaultBuffer>,
+    stopped: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+// the count of samples moved between the buffer and the file at once
+const WAV_CHUNK_SAMPLE_COUNT: usize = 4800;
+// the replayed samples are pushed when the input buffer has less samples than it
+const WAV_REPLAY_BUFFERED_SAMPLE_COUNT: usize = 10 * WAV_CHUNK_SAMPLE_COUNT;
+
+impl WavBackend {
+    /// replay_from: the wav file to receive from, only the first channel is used. Silence is received if it is None.
+    /// record_to: the wav file to record the sent samples. The samples are dropped if it is None.
+    /// the error tells why the replayed file can not be read or the recorded file can not be created
+    pub fn new(replay_from: Option<&Path>, record_to: Option<&Path>, sample_rate: u32) -> Result<Self, hound::Error> {
+        let mut input_descriptor = SoundDescriptor {
+            channels: 1,
+            sample_rate,
+            sample_format: SampleFormat::F32,
+        };
+        let output_descriptor = input_descriptor;
+        let samples = match replay_from {
+            None => Vec::new(),
+            Some(path) => {
+                let (sample_rate, samples) = read_wav(path)?;
+                input_descriptor.sample_rate = sample_rate;
+                samples
+            }
+        };
+        // the file is created before any thread is started
+        let recorder = match record_to {
+            None => None,
+            Some(path) => Some(Recorder::new(WavWriter::create(path, output_descriptor.into())?, usize::MAX)),
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
+        let input_buffer = simulated_buffer(&input_descriptor);
+        let replay = Self::replay(samples, input_buffer.clone(), stopped.clone());
+
+        let output_buffer = simulated_buffer(&output_descriptor);
+        let record = Self::record(recorder, output_buffer.clone(), stopped.clone());
+
+        Ok(Self {
+            input_descriptor,
+            input_buffer,
+            output_descriptor,
+            output_buffer,
+            stopped,
+            threads: vec![replay, record],
+        })
+    }
+
+    fn replay(samples: Vec<f32>, to: Arc<DefaultBuffer>, stopped: Arc<AtomicBool>) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            let rt = Builder::new_current_thread().enable_all().build().unwrap();
+            let silence = vec![0.0; WAV_CHUNK_SAMPLE_COUNT];
+            let mut chunks = samples.chunks(WAV_CHUNK_SAMPLE_COUNT);
+            while !stopped.load(Relaxed) {
+                if to.len() >= WAV_REPLAY_BUFFERED_SAMPLE_COUNT {
+                    std::thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                rt.block_on(to.push_by_ref(chunks.next().unwrap_or(&silence)));
+            }
+        })
+    }
+
+    // the samples left in the buffer are recorded after the backend is stopped
+    fn record<Writer>(mut recorder: Option<Recorder<Writer>>, from: Arc<DefaultBuffer>, stopped: Arc<AtomicBool>) -> JoinHandle<()>
+        where
+            Writer: std::io::Write + std::io::Seek + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            loop {
+                let len = std::cmp::min(from.len(), WAV_CHUNK_SAMPLE_COUNT);
+                if len == 0 {
+                    if stopped.load(Relaxed) {
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                let samples = from.must_pop(len, |first, second| {
+                    let samples: Vec<f32> = first.iter().chain(second.iter()).take(len).cloned().collect();
+                    (samples, len)
+                }, std::iter::repeat(0.0));
+                recorder = recorder.and_then(|recorder| recorder.record_from_slice(&samples)).map(|mut recorder| {
+                    recorder.flush();
+                    recorder
+                });
+            }
+        })
+    }
+}
+
+impl Drop for WavBackend {
+    fn drop(&mut self) {
+        self.stopped.store(true, Relaxed);
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl AudioBackend for WavBackend {
+    fn input(&self) -> (SoundDescriptor, InputBuffer) {
+        (self.input_descriptor, InputBuffer::Single(self.input_buffer.clone()))
+    }
+
+    fn output(&self) -> (SoundDescriptor, Arc<DefaultBuffer>) {
+        (self.output_descriptor, self.output_buffer.clone())
+    }
+}
+
+/// the sample rate and the samples of the first channel of a wav file of f32 samples or integer samples of any width
+pub fn read_wav(path: &Path) -> Result<(u32, Vec<f32>), hound::Error> {
+    let mut reader = WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            // the integer samples are in [-2^(bits-1), 2^(bits-1))
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 / scale)).collect::<Result<_, _>>()?
+        }
+    };
+    Ok((spec.sample_rate, samples.into_iter().step_by(spec.channels as usize).collect()))
+}
+
+/// NullBackend is a mono backend without any device, nothing is received and the sent samples stay in the output buffer.
+/// It is for using the receiver of a PhysicalLayer on the samples from elsewhere, such as decoding a recording offline.
+pub struct NullBackend {
+    descriptor: SoundDescriptor,
+    input_buffer: Arc<DefaultBuffer>,
+    output_buffer: Arc<DefaultBuffer>,
+}
+
+impl NullBackend {
+    pub fn new(sample_rate: u32) -> Self {
+        let descriptor = SoundDescriptor {
+            channels: 1,
+            sample_rate,
+            sample_format: SampleFormat::F32,
+        };
+        Self {
+            descriptor,
+            input_buffer: simulated_buffer(&descriptor),
+            output_buffer: simulated_buffer(&descriptor),
+        }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn input(&self) -> (SoundDescriptor, InputBuffer) {
+        (self.descriptor, InputBuffer::Single(self.input_buffer.clone()))
+    }
+
+    fn output(&self) -> (SoundDescriptor, Arc<DefaultBuffer>) {
+        (self.descriptor, self.output_buffer.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_wav() {
+        for bits_per_sample in [8, 16, 24, 32] {
+            let path = std::env::temp_dir().join(format!("cs140_backend_test_read_wav_{}.wav", bits_per_sample));
+            let spec = hound::WavSpec {
+                channels: 2,
+                sample_rate: 44100,
+                bits_per_sample,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let max = 1i64 << (bits_per_sample - 1);
+            let mut writer = WavWriter::create(&path, spec).unwrap();
+            for sample in [max / 2, 0, -max, 0] {
+                writer.write_sample(sample as i32).unwrap();
+            }
+            writer.finalize().unwrap();
+            assert_eq!(read_wav(&path).unwrap(), (44100, vec![0.5, -1.0]), "{} bits", bits_per_sample);
+        }
+        let missing = std::env::temp_dir().join("cs140_backend_test_read_wav_missing.wav");
+        assert!(WavBackend::new(Some(&missing), None, 48000).is_err());
+    }
+}
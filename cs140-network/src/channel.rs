@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+
+/// ChannelConfig describes the impairments of a simulated audio cable.
+/// The default value is an ideal channel which passes the samples unchanged.
+#[derive(Debug, Copy, Clone)]
+pub struct ChannelConfig {
+    /// the seed of the random generator, the same seed always produces the same output
+    pub seed: u64,
+    /// signal to noise ratio of the additive white gaussian noise in dB, the signal power is the power of a full scale (±1.0) signal after gain
+    pub snr_db: Option<f32>,
+    /// the amplitude scaling of the channel
+    pub gain: f32,
+    /// the constant added to every sample
+    pub dc_offset: f32,
+    /// how much faster the receiver clock is than the sender clock, in ppm. A positive value produces more samples than sent.
+    pub drift_ppm: f64,
+    /// the probability that a sample is lost
+    pub drop_probability: f64,
+    /// the probability that a sample is received twice
+    pub duplicate_probability: f64,
+    /// (delay in samples, gain) of a reflected copy of the signal
+    pub echo: Option<(usize, f32)>,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            snr_db: None,
+            gain: 1.0,
+            dc_offset: 0.0,
+            drift_ppm: 0.0,
+            drop_probability: 0.0,
+            duplicate_probability: 0.0,
+            echo: None,
+        }
+    }
+}
+
+/// ChannelSimulator applies the impairments of ChannelConfig to a stream of samples.
+/// The samples are processed in the order of echo, gain, dc offset, noise, clock drift and dropped / duplicated samples.
+pub struct ChannelSimulator {
+    config: ChannelConfig,
+    rng: Pcg64,
+    echo_history: VecDeque<f32>,
+    // the position of the next output sample, 0 is the last sample of the previous chunk
+    resample_position: f64,
+    last_sample: f32,
+}
+
+impl ChannelSimulator {
+    pub fn new(config: ChannelConfig) -> Self {
+        let echo_delay = config.echo.map_or(0, |(delay, _)| delay);
+        Self {
+            config,
+            rng: Pcg64::seed_from_u64(config.seed),
+            echo_history: std::iter::repeat(0.0).take(echo_delay).collect(),
+            resample_position: 1.0,
+            last_sample: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> &ChannelConfig {
+        &self.config
+    }
+
+    /// transmit passes a chunk of samples through the channel. The state is kept between chunks,
+    /// so a stream can be transmitted in chunks of any size.
+    pub fn transmit(&mut self, samples: &[f32]) -> Vec<f32> {
+        let noise_deviation = self.config.snr_db.map(|snr_db| self.config.gain.abs() * 10f32.powf(-snr_db / 20.0));
+        let samples: Vec<f32> = samples.iter().map(|&sample| {
+            let sample = match self.config.echo {
+                None => sample,
+                Some((_, echo_gain)) => {
+                    self.echo_history.push_back(sample);
+                    sample + echo_gain * self.echo_history.pop_front().unwrap()
+                }
+            };
+            let sample = sample * self.config.gain + self.config.dc_offset;
+            match noise_deviation {
+                None => sample,
+                Some(deviation) => sample + deviation * self.gaussian(),
+            }
+        }).collect();
+        let samples = self.resample(samples);
+        if self.config.drop_probability == 0.0 && self.config.duplicate_probability == 0.0 {
+            return samples;
+        }
+        let mut result = Vec::with_capacity(samples.len());
+        for sample in samples {
+            if self.rng.gen_bool(self.config.drop_probability) {
+                continue;
+            }
+            result.push(sample);
+            if self.rng.gen_bool(self.config.duplicate_probability) {
+                result.push(sample);
+            }
+        }
+        result
+    }
+
+    // linear interpolation at the sample clock of the receiver
+    fn resample(&mut self, samples: Vec<f32>) -> Vec<f32> {
+        if self.config.drift_ppm == 0.0 || samples.is_empty() {
+            return samples;
+        }
+        let step = 1.0 / (1.0 + self.config.drift_ppm * 1e-6);
+        let last_index = samples.len() as f64;
+        let sample_at = |index: usize| if index == 0 { self.last_sample } else { samples[index - 1] };
+        let mut result = Vec::with_capacity((samples.len() as f64 / step) as usize + 1);
+        let mut position = self.resample_position;
+        while position < last_index {
+            let index = position.floor() as usize;
+            let fraction = (position - index as f64) as f32;
+            result.push(sample_at(index) * (1.0 - fraction) + sample_at(index + 1) * fraction);
+            position += step;
+        }
+        self.resample_position = position - last_index;
+        self.last_sample = *samples.last().unwrap();
+        result
+    }
+
+    // standard normal distribution by Box-Muller transform
+    fn gaussian(&mut self) -> f32 {
+        let u1: f32 = 1.0 - self.rng.gen::<f32>();
+        let u2: f32 = self.rng.gen();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{BitStore, decode_4b5b, decode_nrzi, encode_4b5b, encode_nrzi};
+    use crate::sample_reader::{LineConfig, SampleReader, ZeroReader};
+
+    use super::*;
+
+    fn modulate(bits: &BitStore) -> Vec<f32> {
+        let bits = encode_nrzi(&encode_4b5b(bits));
+        let mut samples: Vec<f32> = std::iter::repeat(0.0).take(100).collect();
+        samples.extend(bits.into_iter().flat_map(|bit| std::iter::repeat(if bit { 1.0 } else { -1.0 }).take(2)));
+        samples.extend(std::iter::repeat(0.0).take(100));
+        samples
+    }
+
+    fn demodulate(samples: &[f32]) -> BitStore {
+        let mut zero_reader = ZeroReader::new(LineConfig::default());
+        let index = zero_reader.read_all(samples);
+        let mut sample_reader = SampleReader::from(zero_reader);
+        let (bits, _) = sample_reader.read_all(&samples[index..]);
+        bits
+    }
+
+    fn transmit_in_chunks(config: ChannelConfig, samples: &[f32]) -> Vec<f32> {
+        let mut channel = ChannelSimulator::new(config);
+        samples.chunks(480).flat_map(|chunk| channel.transmit(chunk)).collect()
+    }
+
+    #[test]
+    fn test_ideal_channel() {
+        let samples: Vec<f32> = (0..1000).map(|x| (x as f32 / 100.0).sin()).collect();
+        assert_eq!(transmit_in_chunks(ChannelConfig::default(), &samples), samples);
+    }
+
+    #[test]
+    fn test_seeded() {
+        let samples: Vec<f32> = (0..1000).map(|x| (x as f32 / 100.0).sin()).collect();
+        let config = ChannelConfig {
+            seed: 42,
+            snr_db: Some(10.0),
+            drop_probability: 0.01,
+            duplicate_probability: 0.01,
+            ..Default::default()
+        };
+        assert_eq!(transmit_in_chunks(config, &samples), transmit_in_chunks(config, &samples));
+    }
+
+    #[test]
+    fn test_drift_sample_count() {
+        let samples = vec![0.5; 1000000];
+        let config = ChannelConfig {
+            drift_ppm: 500.0,
+            ..Default::default()
+        };
+        let received = transmit_in_chunks(config, &samples);
+        assert!((received.len() as i64 - 1000500).abs() <= 1);
+        assert!(received.iter().skip(1).all(|&sample| (sample - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_decode_attenuated_noisy_signal() {
+        let data: Vec<u8> = (0..=255).collect();
+        let bits = BitStore::from_vec(data);
+        let config = ChannelConfig {
+            seed: 1,
+            snr_db: Some(30.0),
+            gain: 0.3,
+            dc_offset: 0.005,
+            ..Default::default()
+        };
+        let received = transmit_in_chunks(config, &modulate(&bits));
+        let decoded = demodulate(&received);
+        assert_eq!(decode_4b5b(&decode_nrzi(&decoded)), bits);
+    }
+
+    #[test]
+    fn test_decode_with_clock_drift() {
+        let data: Vec<u8> = (0..=255).collect();
+        let bits = BitStore::from_vec(data);
+        let config = ChannelConfig {
+            seed: 2,
+            gain: 0.5,
+            drift_ppm: 1000.0,
+            ..Default::default()
+        };
+        let received = transmit_in_chunks(config, &modulate(&bits));
+        let decoded = demodulate(&received);
+        assert_eq!(decode_4b5b(&decode_nrzi(&decoded)), bits);
+    }
+}
@@ -0,0 +1,353 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use cs140_common::device::{DeviceConfig, DeviceSelector, SAMPLE_RATE};
+
+use crate::backend::CpalBackend;
+use crate::diagnostic::DiagnosticTap;
+use crate::encoding::LineCodeKind;
+use crate::modulation::ModulationKind;
+use crate::physical::{LaneMode, PhysicalLayer};
+use crate::sample_reader::LineConfig;
+
+/// PhysicalLayerConfig constructs a PhysicalLayer with the sound card without asking anything from stdin.
+///
+/// The config can be loaded from the toml file named by the environment variable `CS140_CONFIG`, the environment variables
+/// `CS140_INPUT_DEVICE`, `CS140_OUTPUT_DEVICE`, `CS140_SAMPLE_RATE`, `CS140_CHANNEL`,
+/// `CS140_PADDING_ZERO_BYTE_LEN`, `CS140_MAX_PACKAGE_BYTE_LEN`, `CS140_LINE_CODE`, `CS140_MODULATION`, `CS140_LANE_MODE`, `CS140_SAMPLE_PER_BIT`,
+/// `CS140_ACCEPTABLE_SIGNAL_RANGE`, `CS140_BIT_SLIP_HISTORY_COUNT`, `CS140_EWMA_NEW_DATA_RATIO`, `CS140_TIMING_RECOVERY` and `CS140_DIAGNOSTIC_DIRECTORY`,
+/// or from a toml file with the keys of the same names in lower case without the prefix, for example:
+///
+/// ```toml
+/// input_device = "USB Audio Device"
+/// output_device = "default"
+/// sample_rate = 48000
+/// channel = 0
+/// padding_zero_byte_len = 1
+/// max_package_byte_len = 128
+/// line_code = "4b5b"
+/// modulation = "baseband"
+/// lane_mode = "mono"
+/// sample_per_bit = 2
+/// acceptable_signal_range = 0.5
+/// bit_slip_history_count = 4
+/// ewma_new_data_ratio = 0.5
+/// timing_recovery = "bitslip"
+/// diagnostic_directory = "captures"
+/// ```
+///
+/// A device is selected by index if the value is a number, by the default device if the value is `default`,
+/// otherwise by the first device whose name contains the value.
+/// The line code is one of `4b5b`, `manchester` and `8b10b`.
+/// The modulation is one of `baseband`, `fsk`, `bpsk`, `qpsk` and `ofdm`.
+/// The timing recovery is `bitslip` or `gardner`, which follows a larger clock drift between two sound cards, see TimingRecovery.
+/// The lane mode is one of `mono`, `striped` and `differential`, the channel is ignored if it is not mono.
+/// Both ends must use the same sample rate, line code, modulation, lane mode and sample_per_bit.
+/// The received frames failing the decoding are saved into the diagnostic directory if it is set, see DiagnosticTap.
+#[derive(Debug, Clone)]
+pub struct PhysicalLayerConfig {
+    input_device: DeviceSelector,
+    output_device: DeviceSelector,
+    sample_rate: u32,
+    channel: Option<u16>,
+    padding_zero_byte_len: usize,
+    max_package_byte_len: usize,
+    line_code: LineCodeKind,
+    modulation: ModulationKind,
+    lane_mode: LaneMode,
+    line: LineConfig,
+    diagnostic_directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PhysicalLayerConfigFile {
+    input_device: Option<String>,
+    output_device: Option<String>,
+    sample_rate: Option<u32>,
+    channel: Option<u16>,
+    padding_zero_byte_len: Option<usize>,
+    max_package_byte_len: Option<usize>,
+    line_code: Option<String>,
+    modulation: Option<String>,
+    lane_mode: Option<String>,
+    sample_per_bit: Option<usize>,
+    acceptable_signal_range: Option<f32>,
+    bit_slip_history_count: Option<usize>,
+    ewma_new_data_ratio: Option<f32>,
+    timing_recovery: Option<String>,
+    diagnostic_directory: Option<String>,
+}
+
+impl Default for PhysicalLayerConfig {
+    fn default() -> Self {
+        Self {
+            input_device: DeviceSelector::Default,
+            output_device: DeviceSelector::Default,
+            sample_rate: SAMPLE_RATE,
+            channel: None,
+            padding_zero_byte_len: 1,
+            max_package_byte_len: 128,
+            line_code: LineCodeKind::FourBFiveB,
+            modulation: ModulationKind::Baseband,
+            lane_mode: LaneMode::Mono,
+            line: LineConfig::default(),
+            diagnostic_directory: None,
+        }
+    }
+}
+
+impl PhysicalLayerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the default config overridden by the environment variables
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::default().with_env()
+    }
+
+    /// the default config overridden by the toml file
+    pub fn from_toml_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::default().with_toml_file(path)
+    }
+
+    /// override the config with the toml file in `CS140_CONFIG` and then with the environment variables which are set
+    pub fn with_env(self) -> anyhow::Result<Self> {
+        fn var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
+            where
+                T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            match std::env::var(name) {
+                Ok(value) => Ok(Some(value.trim().parse().with_context(|| format!("invalid value of {}: {}", name, value))?)),
+                Err(std::env::VarError::NotPresent) => Ok(None),
+                Err(err) => Err(err).with_context(|| format!("invalid value of {}", name)),
+            }
+        }
+        let config = match var::<String>("CS140_CONFIG")? {
+            Some(path) => self.with_toml_file(path)?,
+            None => self,
+        };
+        let file = PhysicalLayerConfigFile {
+            input_device: var("CS140_INPUT_DEVICE")?,
+            output_device: var("CS140_OUTPUT_DEVICE")?,
+            sample_rate: var("CS140_SAMPLE_RATE")?,
+            channel: var("CS140_CHANNEL")?,
+            padding_zero_byte_len: var("CS140_PADDING_ZERO_BYTE_LEN")?,
+            max_package_byte_len: var("CS140_MAX_PACKAGE_BYTE_LEN")?,
+            line_code: var("CS140_LINE_CODE")?,
+            modulation: var("CS140_MODULATION")?,
+            lane_mode: var("CS140_LANE_MODE")?,
+            sample_per_bit: var("CS140_SAMPLE_PER_BIT")?,
+            acceptable_signal_range: var("CS140_ACCEPTABLE_SIGNAL_RANGE")?,
+            bit_slip_history_count: var("CS140_BIT_SLIP_HISTORY_COUNT")?,
+            ewma_new_data_ratio: var("CS140_EWMA_NEW_DATA_RATIO")?,
+            timing_recovery: var("CS140_TIMING_RECOVERY")?,
+            diagnostic_directory: var("CS140_DIAGNOSTIC_DIRECTORY")?,
+        };
+        config.merge(file)
+    }
+
+    /// override the config with the keys in the toml file
+    pub fn with_toml_file(self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        self.with_toml_str(&content).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    pub fn with_toml_str(self, content: &str) -> anyhow::Result<Self> {
+        let file: PhysicalLayerConfigFile = toml::from_str(content)?;
+        self.merge(file)
+    }
+
+    fn merge(mut self, file: PhysicalLayerConfigFile) -> anyhow::Result<Self> {
+        if let Some(input_device) = file.input_device {
+            self.input_device = input_device.parse()?;
+        }
+        if let Some(output_device) = file.output_device {
+            self.output_device = output_device.parse()?;
+        }
+        if let Some(sample_rate) = file.sample_rate {
+            self.sample_rate = sample_rate;
+        }
+        if let Some(channel) = file.channel {
+            self.channel = Some(channel);
+        }
+        if let Some(padding_zero_byte_len) = file.padding_zero_byte_len {
+            self.padding_zero_byte_len = padding_zero_byte_len;
+        }
+        if let Some(max_package_byte_len) = file.max_package_byte_len {
+            self.max_package_byte_len = max_package_byte_len;
+        }
+        if let Some(line_code) = file.line_code {
+            self.line_code = line_code.parse()?;
+        }
+        if let Some(modulation) = file.modulation {
+            self.modulation = modulation.parse()?;
+        }
+        if let Some(lane_mode) = file.lane_mode {
+            self.lane_mode = lane_mode.parse()?;
+        }
+        if let Some(sample_per_bit) = file.sample_per_bit {
+            self.line.sample_per_bit = sample_per_bit;
+        }
+        if let Some(acceptable_signal_range) = file.acceptable_signal_range {
+            self.line.acceptable_signal_range = acceptable_signal_range;
+        }
+        if let Some(bit_slip_history_count) = file.bit_slip_history_count {
+            self.line.bit_slip_history_count = bit_slip_history_count;
+        }
+        if let Some(ewma_new_data_ratio) = file.ewma_new_data_ratio {
+            self.line.ewma_new_data_ratio = ewma_new_data_ratio;
+        }
+        if let Some(timing_recovery) = file.timing_recovery {
+            self.line.timing_recovery = timing_recovery.parse()?;
+        }
+        if let Some(diagnostic_directory) = file.diagnostic_directory {
+            self.diagnostic_directory = Some(PathBuf::from(diagnostic_directory));
+        }
+        self.line.validate()?;
+        Ok(self)
+    }
+
+    pub fn input_device(mut self, device: DeviceSelector) -> Self {
+        self.input_device = device;
+        self
+    }
+
+    pub fn output_device(mut self, device: DeviceSelector) -> Self {
+        self.output_device = device;
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// the channel to record and play, None records the first channel and plays on all channels
+    pub fn channel(mut self, channel: Option<u16>) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn padding_zero_byte_len(mut self, padding_zero_byte_len: usize) -> Self {
+        self.padding_zero_byte_len = padding_zero_byte_len;
+        self
+    }
+
+    pub fn max_package_byte_len(mut self, max_package_byte_len: usize) -> Self {
+        self.max_package_byte_len = max_package_byte_len;
+        self
+    }
+
+    pub fn line_code(mut self, line_code: LineCodeKind) -> Self {
+        self.line_code = line_code;
+        self
+    }
+
+    pub fn modulation(mut self, modulation: ModulationKind) -> Self {
+        self.modulation = modulation;
+        self
+    }
+
+    pub fn lane_mode(mut self, lane_mode: LaneMode) -> Self {
+        self.lane_mode = lane_mode;
+        self
+    }
+
+    pub fn line_config(mut self, line: LineConfig) -> Self {
+        self.line = line;
+        self
+    }
+
+    /// save the received frames into the directory for the offline analysis
+    pub fn diagnostic_directory(mut self, directory: Option<PathBuf>) -> Self {
+        self.diagnostic_directory = directory;
+        self
+    }
+
+    pub fn input_device_config(&self) -> DeviceConfig {
+        DeviceConfig {
+            device: self.input_device.clone(),
+            sample_rate: self.sample_rate,
+            channel: self.channel,
+            stereo: self.lane_mode != LaneMode::Mono,
+        }
+    }
+
+    pub fn output_device_config(&self) -> DeviceConfig {
+        DeviceConfig {
+            device: self.output_device.clone(),
+            sample_rate: self.sample_rate,
+            channel: self.channel,
+            stereo: self.lane_mode != LaneMode::Mono,
+        }
+    }
+
+    /// validate the line config, open the sound card and construct the PhysicalLayer
+    pub fn build(self) -> anyhow::Result<PhysicalLayer> {
+        self.line.validate()?;
+        let backend = CpalBackend::with_config(&self.input_device_config(), &self.output_device_config()).map_err(anyhow::Error::msg)?;
+        let layer = PhysicalLayer::with_backend(backend, self.padding_zero_byte_len, self.max_package_byte_len)
+            .with_line_config(self.line)
+            .with_line_code(self.line_code.build())
+            .with_lane_mode(self.lane_mode);
+        let layer = match self.modulation {
+            ModulationKind::Baseband => layer,
+            modulation => layer.with_modulation(modulation.build(self.sample_rate, self.line)),
+        };
+        Ok(match self.diagnostic_directory {
+            None => layer,
+            Some(directory) => layer.with_diagnostic_tap(DiagnosticTap::new(directory)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sample_reader::TimingRecovery;
+
+    use super::*;
+
+    #[test]
+    fn test_toml_config() {
+        let config = PhysicalLayerConfig::new()
+            .max_package_byte_len(256)
+            .with_toml_str(r#"
+                input_device = "USB Audio"
+                output_device = "2"
+                channel = 1
+                padding_zero_byte_len = 3
+                modulation = "QPSK"
+                line_code = "8b10b"
+                sample_rate = 96000
+                sample_per_bit = 8
+                lane_mode = "differential"
+                timing_recovery = "gardner"
+                diagnostic_directory = "captures"
+            "#)
+            .unwrap();
+        assert_eq!(config.input_device, DeviceSelector::Name("USB Audio".to_string()));
+        assert_eq!(config.output_device, DeviceSelector::Index(2));
+        assert_eq!(config.sample_rate, 96000);
+        assert_eq!(config.line, LineConfig { sample_per_bit: 8, timing_recovery: TimingRecovery::gardner(), ..Default::default() });
+        assert_eq!(config.channel, Some(1));
+        assert_eq!(config.padding_zero_byte_len, 3);
+        assert_eq!(config.max_package_byte_len, 256);
+        assert_eq!(config.modulation, ModulationKind::Qpsk);
+        assert_eq!(config.line_code, LineCodeKind::EightBTenB);
+        assert_eq!(config.lane_mode, LaneMode::Differential);
+        assert_eq!(config.diagnostic_directory, Some(PathBuf::from("captures")));
+        assert!(config.output_device_config().stereo);
+        assert!(PhysicalLayerConfig::new().with_toml_str("modulation = \"am\"").is_err());
+        assert!(PhysicalLayerConfig::new().with_toml_str("line_code = \"nrz\"").is_err());
+        assert!(PhysicalLayerConfig::new().with_toml_str("unknown_key = 1").is_err());
+        assert!(PhysicalLayerConfig::new().with_toml_str("sample_per_bit = 1").is_err());
+        let line = LineConfig { sample_per_bit: 1, ..Default::default() };
+        assert!(PhysicalLayerConfig::new().line_config(line).build().is_err());
+    }
+}
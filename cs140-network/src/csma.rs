@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+
+/// CsmaConfig is the carrier sense multiple access with collision avoidance of a PhysicalLayer.
+/// Before a frame is sent, the latest received samples are checked by the energy detection of the ZeroReader,
+/// the frame is sent after a random backoff if the medium is busy.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CsmaConfig {
+    /// the count of the latest received samples of a lane in which the energy is detected
+    pub sense_sample_count: usize,
+    /// the unit of the random backoff
+    pub slot_time: Duration,
+    /// the backoff after the n-th busy medium is a random count of slots in [1, 2^min(n, max_backoff_exponent)]
+    pub max_backoff_exponent: u32,
+    /// the frame is dropped if the medium is busy or the frame collides for so many times
+    pub max_attempts: usize,
+    /// detect the collisions while sending, only for the medium on which a node receives its own signal
+    pub collision_detection: Option<CollisionDetection>,
+}
+
+impl Default for CsmaConfig {
+    fn default() -> Self {
+        Self {
+            sense_sample_count: 96,
+            slot_time: Duration::from_millis(5),
+            max_backoff_exponent: 6,
+            max_attempts: 16,
+            collision_detection: None,
+        }
+    }
+}
+
+/// CollisionDetection compares the received samples with the sent samples while sending.
+/// The sent samples are found in the received samples by the correlation, then the frame collides
+/// if the received samples differ from the scaled sent samples too much. The sending is aborted by a jam pattern.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CollisionDetection {
+    /// the max count of samples of a lane between sending a sample and receiving it back
+    pub max_latency: usize,
+    /// the count of samples of a lane that are sent and compared at a time
+    pub window_sample_count: usize,
+    /// the frame collides if the power of the difference is more than this ratio of the power of the scaled sent samples
+    pub residual_ratio: f32,
+    /// the count of samples of a lane of the jam pattern
+    pub jam_sample_count: usize,
+}
+
+impl Default for CollisionDetection {
+    fn default() -> Self {
+        Self {
+            max_latency: 4800,
+            window_sample_count: 240,
+            residual_ratio: 0.1,
+            jam_sample_count: 480,
+        }
+    }
+}
+
+impl CollisionDetection {
+    /// the offset of the sent samples in the received samples and the gain between them,
+    /// None if the sent samples are not received clearly
+    pub(crate) fn find_echo(&self, sent: &[f32], received: &[f32]) -> Option<(usize, f32)> {
+        let sent_energy: f32 = sent.iter().map(|x| x * x).sum();
+        let (offset, correlation, gain) = (0..=received.len().saturating_sub(sent.len())).map(|offset| {
+            let window = &received[offset..offset + sent.len()];
+            let product: f32 = window.iter().zip(sent.iter()).map(|(x, y)| x * y).sum();
+            let energy: f32 = window.iter().map(|x| x * x).sum();
+            (offset, product / (energy * sent_energy).sqrt().max(f32::EPSILON), product / sent_energy.max(f32::EPSILON))
+        }).fold((0, 0.0, 0.0), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+        // the correlation of a clean echo is almost 1
+        if correlation > 1.0 - self.residual_ratio {
+            Some((offset, gain))
+        } else {
+            None
+        }
+    }
+
+    /// whether the received samples are not the sent samples scaled by the gain,
+    /// the power of the difference is compared with the average power of the whole frame
+    pub(crate) fn collides(&self, sent: &[f32], received: &[f32], gain: f32, frame_power: f32) -> bool {
+        let residual_power = sent.iter().zip(received.iter()).map(|(x, y)| (y - gain * x).powi(2)).sum::<f32>() / sent.len() as f32;
+        residual_power > self.residual_ratio * gain * gain * frame_power
+    }
+
+    /// the jam pattern, the full scale square wave that breaks any frame on the medium
+    pub(crate) fn jam(&self) -> Vec<f32> {
+        (0..self.jam_sample_count).map(|index| if index % 4 < 2 { 1.0 } else { -1.0 }).collect()
+    }
+}
+
+/// SendError is why a frame is not sent
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SendError {
+    /// the medium is busy or the frame collides in all the attempts
+    Busy,
+    /// the frame collides with the frame of another node and is aborted, it should be sent again
+    Collision,
+    /// the sound card of the link is lost, see PhysicalLayer::link_status
+    LinkDown,
+}
+
+/// Backoff is the randomized exponential backoff of a frame
+pub(crate) struct Backoff {
+    config: CsmaConfig,
+    attempt: usize,
+    collided: bool,
+    rng: Pcg64,
+}
+
+impl Backoff {
+    pub(crate) fn new(config: CsmaConfig, seed: u64) -> Self {
+        Self {
+            config,
+            attempt: 0,
+            collided: false,
+            rng: Pcg64::seed_from_u64(seed),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &CsmaConfig {
+        &self.config
+    }
+
+    /// start the backoff of the next frame
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+        self.collided = false;
+    }
+
+    /// the frame collides, the next attempt waits for a backoff first
+    pub(crate) fn collide(&mut self) {
+        self.collided = true;
+    }
+
+    pub(crate) fn has_collided(&self) -> bool {
+        self.collided
+    }
+
+    /// the time to wait after the medium is busy, None if there is no attempt left
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        self.attempt += 1;
+        if self.attempt >= self.config.max_attempts {
+            return None;
+        }
+        let exponent = std::cmp::min(self.attempt as u32, self.config.max_backoff_exponent);
+        let slot_count = self.rng.gen_range(1..=1u32 << exponent);
+        Some(self.config.slot_time * slot_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let config = CsmaConfig {
+            slot_time: Duration::from_millis(1),
+            max_backoff_exponent: 3,
+            max_attempts: 6,
+            ..Default::default()
+        };
+        let mut backoff = Backoff::new(config, 0);
+        let delays: Vec<Duration> = std::iter::from_fn(|| backoff.next_delay()).collect();
+        assert_eq!(delays.len(), 5);
+        for (attempt, delay) in delays.iter().enumerate() {
+            let max_slot_count = 1 << std::cmp::min(attempt + 1, 3);
+            assert!(*delay >= Duration::from_millis(1) && *delay <= Duration::from_millis(max_slot_count), "{:?}", delays);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay().is_some());
+    }
+
+    #[test]
+    fn test_collision_detection() {
+        let detection = CollisionDetection::default();
+        let sent: Vec<f32> = (0..200).map(|index| if (index * 7) % 13 < 6 { 1.0 } else { -1.0 }).collect();
+        let mut received = vec![0.0; 100];
+        received.extend(sent.iter().map(|x| x * 0.5));
+        received.extend(vec![0.0; 100]);
+        assert_eq!(detection.find_echo(&sent[..50], &received), Some((100, 0.5)));
+        assert!(!detection.collides(&sent, &received[100..300], 0.5, 1.0));
+        // the frame of another node is added to the echo
+        let collided: Vec<f32> = received[100..300].iter().enumerate().map(|(index, x)| x + if index % 10 < 5 { 0.3 } else { -0.3 }).collect();
+        assert!(detection.collides(&sent, &collided, 0.5, 1.0));
+        assert_eq!(detection.find_echo(&sent[..50], &vec![0.0; 300]), None);
+    }
+}
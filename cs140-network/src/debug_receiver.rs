@@ -6,7 +6,7 @@ use cs140_common::padding::padding_inclusive_range;
 use cs140_common::record::Recorder;
 use cs140_network::encoding::{BitStore, HandlePackage};
 use cs140_network::ip::IPLayer;
-use cs140_network::physical::PhysicalLayer;
+use cs140_network::config::PhysicalLayerConfig;
 use cs140_network::physical::PhysicalPackage;
 use cs140_network::redundancy::RedundancyLayer;
 
@@ -14,7 +14,7 @@ use cs140_network::redundancy::RedundancyLayer;
 async fn main() {
     let mut builder = env_logger::Builder::from_default_env();
     builder.format_timestamp_millis().init();
-    let layer = PhysicalLayer::new(2, 256);
+    let layer = PhysicalLayerConfig::new().padding_zero_byte_len(2).max_package_byte_len(256).with_env().unwrap().build().unwrap();
     let layer = RedundancyLayer::new(layer);
     let mut layer = IPLayer::new(layer);
     loop {
@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::thread::JoinHandle;
+
+use anyhow::Context;
+use hound::{WavReader, WavSpec, WavWriter};
+use log::{debug, warn};
+
+use crate::sample_reader::BitTrace;
+
+// the frames waiting for the writer thread before the new ones are dropped
+const TAP_QUEUE_LEN: usize = 16;
+const TRACE_HEADER: &str = "bit_index,sample_index,bit,average,threshold,one_amplitude,neg_one_amplitude,slip,margin";
+
+/// DiagnosticTap saves the frames failing the decoding for the offline analysis.
+/// A frame is saved as `frame_<n>.wav` with the samples of its lane from the start of the preamble to the end of the frame,
+/// and `frame_<n>.csv` with a row per line bit of how the demodulator reads it.
+/// The sample indexes in the csv are counted from the start of the wav. Only the baseband modulation records the bits,
+/// the csv of the other modulations has no rows.
+///
+/// The frames with invalid line symbols are saved when they are detected. The last frame without is held,
+/// and saved if the layers above find it corrupted, see PhysicalLayer::capture_last_frame.
+/// The files are written by a thread so that the receiver is not blocked by the disk,
+/// the frames are dropped if the thread falls behind. The thread is joined when the tap is dropped.
+pub struct DiagnosticTap {
+    directory: PathBuf,
+    pub(crate) sample_rate: u32,
+    frame_count: usize,
+    held: Option<TapFrame>,
+    sender: Option<SyncSender<TapFrame>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+// a frame waiting for the writer thread
+struct TapFrame {
+    path: PathBuf,
+    sample_rate: u32,
+    samples: Vec<f32>,
+    first_bit_index: usize,
+    trace: Vec<BitTrace>,
+}
+
+impl DiagnosticTap {
+    /// save the frames into the directory, which is created if it does not exist
+    pub fn new(directory: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory).with_context(|| format!("failed to create {}", directory.display()))?;
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<TapFrame>(TAP_QUEUE_LEN);
+        let writer = std::thread::Builder::new().name("diagnostic-tap".to_string()).spawn(move || {
+            for frame in receiver {
+                match frame.write() {
+                    Ok(()) => debug!("frame saved to {}", frame.path.display()),
+                    Err(err) => warn!("failed to save the frame to {}: {:?}", frame.path.display(), err),
+                }
+            }
+        }).context("failed to spawn the writer thread")?;
+        Ok(Self {
+            directory,
+            sample_rate: 48000,
+            frame_count: 0,
+            held: None,
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// the count of the frames queued to be saved
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// save a frame, the errors are logged so that the receiver keeps going
+    pub(crate) fn capture(&mut self, samples: &[f32], first_bit_index: usize, trace: &[BitTrace]) {
+        let frame = self.frame(samples, first_bit_index, trace);
+        self.send(frame);
+    }
+
+    /// keep a frame decoded without errors until the next one, in case the layers above find it corrupted
+    pub(crate) fn hold(&mut self, samples: &[f32], first_bit_index: usize, trace: &[BitTrace]) {
+        self.held = Some(self.frame(samples, first_bit_index, trace));
+    }
+
+    /// save the held frame, if any
+    pub(crate) fn capture_held(&mut self) {
+        if let Some(frame) = self.held.take() {
+            self.send(frame);
+        }
+    }
+
+    fn frame(&self, samples: &[f32], first_bit_index: usize, trace: &[BitTrace]) -> TapFrame {
+        TapFrame {
+            path: PathBuf::new(),
+            sample_rate: self.sample_rate,
+            samples: samples.to_vec(),
+            first_bit_index,
+            trace: trace.to_vec(),
+        }
+    }
+
+    fn send(&mut self, mut frame: TapFrame) {
+        frame.path = self.directory.join(format!("frame_{:06}.wav", self.frame_count));
+        match self.sender.as_ref().unwrap().try_send(frame) {
+            Ok(()) => self.frame_count += 1,
+            Err(TrySendError::Full(frame)) => warn!("the writer falls behind, {} is not saved", frame.path.display()),
+            Err(TrySendError::Disconnected(frame)) => warn!("the writer is gone, {} is not saved", frame.path.display()),
+        }
+    }
+}
+
+impl Drop for DiagnosticTap {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                warn!("the writer thread of the diagnostic tap panicked");
+            }
+        }
+    }
+}
+
+impl TapFrame {
+    fn write(&self) -> anyhow::Result<()> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(&self.path, spec)?;
+        for sample in &self.samples {
+            writer.write_sample(*sample)?;
+        }
+        writer.finalize()?;
+
+        let mut csv = BufWriter::new(File::create(self.path.with_extension("csv"))?);
+        writeln!(csv, "{}", TRACE_HEADER)?;
+        for (index, bit_trace) in self.trace.iter().enumerate() {
+            writeln!(csv, "{},{},{},{},{},{},{},{},{}", index, self.first_bit_index + bit_trace.sample_index, bit_trace.bit as u8, bit_trace.average,
+                     bit_trace.threshold, bit_trace.one_amplitude, bit_trace.neg_one_amplitude, bit_trace.slip, bit_trace.margin())?;
+        }
+        csv.flush()?;
+        Ok(())
+    }
+}
+
+/// Capture is a frame saved by the DiagnosticTap
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+    /// the sample indexes are counted from the start of samples
+    pub trace: Vec<BitTrace>,
+}
+
+impl Capture {
+    /// load the wav file and the csv file of the same name
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut reader = WavReader::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let sample_rate = reader.spec().sample_rate;
+        let samples = reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()?;
+        let csv_path = path.with_extension("csv");
+        let csv = BufReader::new(File::open(&csv_path).with_context(|| format!("failed to open {}", csv_path.display()))?);
+        let trace = csv.lines().skip(1).enumerate().map(|(index, line)| {
+            Self::parse_row(&line?).with_context(|| format!("invalid row {} of {}", index + 1, csv_path.display()))
+        }).collect::<anyhow::Result<Vec<BitTrace>>>()?;
+        Ok(Self {
+            sample_rate,
+            samples,
+            trace,
+        })
+    }
+
+    fn parse_row(line: &str) -> anyhow::Result<BitTrace> {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        anyhow::ensure!(fields.len() == TRACE_HEADER.split(',').count(), "expect {} fields, got {}", TRACE_HEADER.split(',').count(), fields.len());
+        Ok(BitTrace {
+            sample_index: fields[1].parse()?,
+            bit: fields[2] == "1",
+            average: fields[3].parse()?,
+            threshold: fields[4].parse()?,
+            one_amplitude: fields[5].parse()?,
+            neg_one_amplitude: fields[6].parse()?,
+            slip: fields[7].parse()?,
+        })
+    }
+
+    /// the samples per bit of the frame, which is the most common distance between the bits
+    pub fn sample_per_bit(&self) -> Option<usize> {
+        let mut counts = std::collections::HashMap::new();
+        for pair in self.trace.windows(2) {
+            *counts.entry(pair[1].sample_index - pair[0].sample_index).or_insert(0) += 1;
+        }
+        counts.into_iter().max_by_key(|(distance, count)| (*count, std::cmp::Reverse(*distance))).map(|(distance, _)| distance)
+    }
+
+    /// the eye diagram, the samples from half a bit before each bit to half a bit after it,
+    /// scaled so that the threshold is 0 and the amplitude estimates are 1 and -1.
+    /// The bits too close to the ends of the samples are skipped.
+    pub fn eye_traces(&self) -> Vec<Vec<f32>> {
+        let sample_per_bit = match self.sample_per_bit() {
+            None => return Vec::new(),
+            Some(sample_per_bit) => sample_per_bit,
+        };
+        let before = sample_per_bit / 2;
+        let span = sample_per_bit + 2 * before;
+        self.trace.iter().filter(|bit_trace| bit_trace.sample_index >= before && bit_trace.sample_index - before + span <= self.samples.len()).map(|bit_trace| {
+            let scale = (bit_trace.one_amplitude - bit_trace.neg_one_amplitude) / 2.0;
+            let start = bit_trace.sample_index - before;
+            self.samples[start..start + span].iter().map(|sample| (sample - bit_trace.threshold) / scale).collect()
+        }).collect()
+    }
+
+    pub fn margins(&self) -> Vec<f32> {
+        self.trace.iter().map(BitTrace::margin).collect()
+    }
+
+    /// the indexes of the bits whose sample slips
+    pub fn slips(&self) -> Vec<usize> {
+        self.trace.iter().enumerate().filter(|(_, bit_trace)| bit_trace.slip != 0).map(|(index, _)| index).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sample_reader::{LineConfig, SampleReader};
+
+    use super::*;
+
+    #[test]
+    fn test_capture_round_trip() {
+        let directory = std::env::temp_dir().join("cs140_diagnostic_test_capture_round_trip");
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut tap = DiagnosticTap::new(&directory).unwrap();
+        // 4 samples of silence and 8 bits of 2 samples with a bit slip in the middle
+        let mut samples = vec![0.0; 4];
+        samples.extend([0.5, 0.5, -0.4, -0.4, 0.5, 0.5, 0.5, -0.4, -0.4, 0.5, 0.5, -0.4, -0.4, 0.5, 0.5, -0.4, -0.4, 0.0, 0.0]);
+        let mut reader = SampleReader::new(LineConfig::default(), 0.0, 0.5, -0.5);
+        let mut trace = Vec::new();
+        let (bits, _) = reader.read_exact_traced(&samples[4..], 8, 0, &mut trace).unwrap();
+        assert_eq!(bits.into_vec(), vec![0b10110101]);
+        assert_eq!(reader.bit_slip_count(), 1);
+        tap.hold(&samples, 4, &trace);
+        assert_eq!(tap.frame_count(), 0);
+        tap.capture_held();
+        tap.capture_held();
+        assert_eq!(tap.frame_count(), 1);
+        drop(tap);
+
+        let capture = Capture::load(directory.join("frame_000000.wav")).unwrap();
+        assert_eq!(capture.samples, samples);
+        assert_eq!(capture.trace.len(), 8);
+        assert_eq!(capture.trace[0].sample_index, 4);
+        assert_eq!(capture.slips().len(), 1);
+        assert_eq!(capture.sample_per_bit(), Some(2));
+        assert_eq!(capture.margins()[0], 1.0);
+        let eye_traces = capture.eye_traces();
+        assert_eq!(eye_traces.len(), 8);
+        assert_eq!(eye_traces[0], vec![0.0, 1.0, 1.0, -0.8]);
+    }
+}
@@ -1,9 +1,10 @@
+use std::str::FromStr;
+
 use async_trait::async_trait;
 use bitvec::order::Msb0;
+use bitvec::slice::BitSlice;
 use bitvec::vec::BitVec;
 
-use cs140_common::padding::{padding_inclusive_range};
-
 pub type BitStore = BitVec<Msb0, u8>;
 
 pub trait NetworkPackage {}
@@ -43,28 +44,28 @@ pub fn encode_4b5b(data: &BitStore) -> BitStore {
 
 
 pub fn decode_4b5b(data: &BitStore) -> BitStore {
-    let mut result: BitStore = BitVec::with_capacity((data.len() as f64 * 0.8).floor() as usize);
+    decode_4b5b_with_errors(data).0
+}
+
+/// decode 4B5B, the invalid symbols and the partial symbol at the end are decoded as zeros and returned as the errors
+pub fn decode_4b5b_with_errors(data: &BitStore) -> (BitStore, Vec<SymbolError>) {
+    let mut errors = Vec::new();
+    let mut result: BitStore = BitVec::with_capacity(data.len() / 5 * 4);
     for bits in data.chunks(5) {
-        if bits.len() < 5{
-            log::warn!("Fail to decode, bits len {}, which is too short.", bits.len());
-            break;
-        }
-        let mut value: u8 = 0;
-        for bit in bits {
-            value <<= 1;
-            value += *bit as u8;
-        }
-        let decoded = TABLE.iter().position(|&x| { x == value });
-        if let Some(decoded) = decoded {
-            for shift in (0..4).rev() {
-                result.push(((decoded >> shift) & 1) == 1);
+        let value = symbol_value(bits);
+        match TABLE.iter().position(|&x| x == value) {
+            Some(decoded) if bits.len() == 5 => {
+                for shift in (0..4).rev() {
+                    result.push(((decoded >> shift) & 1) == 1);
+                }
+            }
+            _ => {
+                errors.push(SymbolError { bit_index: result.len(), bit_count: 4 });
+                result.resize(result.len() + 4, false);
             }
-        } else {
-            log::warn!("Fail to decode, pushing random bits.");
-            result.extend(padding_inclusive_range(0..=1).take(4).map(|x| x == 1));
         }
     }
-    result
+    (result, errors)
 }
 
 pub fn decode_nrzi(data: &BitStore) -> BitStore {
@@ -93,6 +94,213 @@ pub fn encode_nrzi(data: &BitStore) -> BitStore {
     result
 }
 
+// the value of at most 8 bits, the first bit is the most significant
+fn symbol_value(bits: &BitSlice<Msb0, u8>) -> u8 {
+    bits.iter().fold(0u8, |value, bit| (value << 1) + *bit as u8)
+}
+
+/// SymbolError is a symbol of the line bits which is not a code word of the line code,
+/// the decoded bits [bit_index, bit_index + bit_count) of the symbol are zeros
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SymbolError {
+    pub bit_index: usize,
+    pub bit_count: usize,
+}
+
+/// LineCode maps the bits of a frame to the line bits, which have enough transitions for the receiver to follow the clock.
+/// Both ends of a link must use the same line code.
+pub trait LineCode: Send + Sync {
+    fn encode(&self, data: &BitStore) -> BitStore;
+    /// decode the line bits of a frame from its first symbol, returns the bits and the invalid symbols
+    fn decode(&self, line: &BitStore) -> (BitStore, Vec<SymbolError>);
+    /// the count of line bits of byte_count bytes
+    fn encoded_bit_count(&self, byte_count: usize) -> usize;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LineCodeKind {
+    FourBFiveB,
+    Manchester,
+    EightBTenB,
+}
+
+impl FromStr for LineCodeKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "4b5b" => Ok(LineCodeKind::FourBFiveB),
+            "manchester" => Ok(LineCodeKind::Manchester),
+            "8b10b" => Ok(LineCodeKind::EightBTenB),
+            _ => Err(anyhow::anyhow!("unknown line code {}", s)),
+        }
+    }
+}
+
+impl LineCodeKind {
+    pub fn build(&self) -> Box<dyn LineCode> {
+        match self {
+            LineCodeKind::FourBFiveB => Box::new(FourBFiveB),
+            LineCodeKind::Manchester => Box::new(Manchester),
+            LineCodeKind::EightBTenB => Box::new(EightBTenB),
+        }
+    }
+}
+
+/// FourBFiveB is 4B5B followed by NRZI, there are at most 3 line bits without a transition
+pub struct FourBFiveB;
+
+impl LineCode for FourBFiveB {
+    fn encode(&self, data: &BitStore) -> BitStore {
+        encode_nrzi(&encode_4b5b(data))
+    }
+
+    fn decode(&self, line: &BitStore) -> (BitStore, Vec<SymbolError>) {
+        decode_4b5b_with_errors(&decode_nrzi(line))
+    }
+
+    fn encoded_bit_count(&self, byte_count: usize) -> usize {
+        byte_count * 8 / 4 * 5
+    }
+}
+
+/// Manchester sends 0 as 10 and 1 as 01 like IEEE 802.3, every bit has a transition in the middle and the line is balanced
+pub struct Manchester;
+
+impl LineCode for Manchester {
+    fn encode(&self, data: &BitStore) -> BitStore {
+        let mut result = BitStore::with_capacity(data.len() * 2);
+        for bit in data.iter() {
+            result.push(!*bit);
+            result.push(*bit);
+        }
+        result
+    }
+
+    fn decode(&self, line: &BitStore) -> (BitStore, Vec<SymbolError>) {
+        let mut errors = Vec::new();
+        let mut result = BitStore::with_capacity(line.len() / 2);
+        for symbol in line.chunks(2) {
+            if symbol.len() != 2 || symbol[0] == symbol[1] {
+                errors.push(SymbolError { bit_index: result.len(), bit_count: 1 });
+            }
+            result.push(symbol.len() == 2 && symbol[1] && !symbol[0]);
+        }
+        (result, errors)
+    }
+
+    fn encoded_bit_count(&self, byte_count: usize) -> usize {
+        byte_count * 8 * 2
+    }
+}
+
+// the 6 bit code words abcdei of the 5 bit values EDCBA when the running disparity is negative,
+// the code word of the positive running disparity is the complement if the code word is not balanced or the value is 7
+const TABLE_5B6B: [u8; 32] = [
+    0b100111, 0b011101, 0b101101, 0b110001, 0b110101, 0b101001, 0b011001, 0b111000,
+    0b111001, 0b100101, 0b010101, 0b110100, 0b001101, 0b101100, 0b011100, 0b010111,
+    0b011011, 0b100011, 0b010011, 0b110010, 0b001011, 0b101010, 0b011010, 0b111010,
+    0b110011, 0b100110, 0b010110, 0b110110, 0b001110, 0b101110, 0b011110, 0b101011,
+];
+// the 4 bit code words fghj of the 3 bit values HGF when the running disparity is negative, the last one is the alternate 7
+const TABLE_3B4B: [u8; 9] = [0b1011, 0b1001, 0b0101, 0b1100, 0b1101, 0b1010, 0b0110, 0b1110, 0b0111];
+
+/// EightBTenB is the 8b/10b code of the data characters, the running disparity keeps the line balanced
+/// and a code word of the wrong disparity is a symbol error
+pub struct EightBTenB;
+
+impl EightBTenB {
+    // the code word of value in the table for the running disparity, and whether the code word flips the disparity
+    fn code_word(table: &[u8], value: usize, bit_count: u32, positive: bool) -> (u8, bool) {
+        let code = table[value];
+        let flip = code.count_ones() * 2 != bit_count;
+        // the balanced 111000 of D.07 and 1100 of D.x.3 also have a form for each disparity
+        let balanced_pair = (bit_count == 6 && code == 0b111000) || (bit_count == 4 && code == 0b1100);
+        if positive && (flip || balanced_pair) {
+            (!code & ((1u16 << bit_count) - 1) as u8, flip)
+        } else {
+            (code, flip)
+        }
+    }
+
+    fn code_word_5b6b(x: usize, positive: bool) -> (u8, bool) {
+        Self::code_word(&TABLE_5B6B, x, 6, positive)
+    }
+
+    // the code word of the 3 bit value y after the 5 bit value x
+    fn code_word_3b4b(x: usize, y: usize, positive: bool) -> (u8, bool) {
+        // the alternate 7 avoids a run of five equal bits
+        let alternate = y == 7 && ((!positive && [17, 18, 20].contains(&x)) || (positive && [11, 13, 14].contains(&x)));
+        Self::code_word(&TABLE_3B4B, if alternate { 8 } else { y }, 4, positive)
+    }
+
+    // the byte of the 10 bit symbol and the running disparity after it, None if it is not a code word of the disparity
+    fn decode_symbol(six: u8, four: u8, positive: bool) -> Option<(u8, bool)> {
+        let x = (0..32).find(|&x| Self::code_word_5b6b(x, positive).0 == six)?;
+        let positive = positive ^ Self::code_word_5b6b(x, positive).1;
+        let y = (0..8).find(|&y| Self::code_word_3b4b(x, y, positive).0 == four)?;
+        Some(((x + (y << 5)) as u8, positive ^ Self::code_word_3b4b(x, y, positive).1))
+    }
+
+    fn push_bits(result: &mut BitStore, code: u8, bit_count: u32) {
+        for shift in (0..bit_count).rev() {
+            result.push((code >> shift) & 1 == 1);
+        }
+    }
+}
+
+impl LineCode for EightBTenB {
+    fn encode(&self, data: &BitStore) -> BitStore {
+        let mut result = BitStore::with_capacity(data.len() / 8 * 10);
+        let mut positive = false;
+        for byte in data.chunks(8) {
+            let byte = symbol_value(byte) as usize;
+            let (x, y) = (byte & 0x1f, byte >> 5);
+            let (code, flip) = Self::code_word_5b6b(x, positive);
+            Self::push_bits(&mut result, code, 6);
+            positive ^= flip;
+            let (code, flip) = Self::code_word_3b4b(x, y, positive);
+            Self::push_bits(&mut result, code, 4);
+            positive ^= flip;
+        }
+        result
+    }
+
+    fn decode(&self, line: &BitStore) -> (BitStore, Vec<SymbolError>) {
+        let mut errors = Vec::new();
+        let mut result = BitStore::with_capacity(line.len() / 10 * 8);
+        let mut positive = false;
+        for symbol in line.chunks(10) {
+            let decoded = if symbol.len() == 10 {
+                Self::decode_symbol(symbol_value(&symbol[..6]), symbol_value(&symbol[6..]), positive)
+            } else {
+                None
+            };
+            let byte = match decoded {
+                Some((byte, next_positive)) => {
+                    positive = next_positive;
+                    byte
+                }
+                None => {
+                    errors.push(SymbolError { bit_index: result.len(), bit_count: 8 });
+                    // follow the disparity of the received bits to find the next code words
+                    let ones = symbol.count_ones();
+                    if ones * 2 != symbol.len() {
+                        positive = ones * 2 > symbol.len();
+                    }
+                    0
+                }
+            };
+            Self::push_bits(&mut result, byte, 8);
+        }
+        (result, errors)
+    }
+
+    fn encoded_bit_count(&self, byte_count: usize) -> usize {
+        byte_count * 10
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::encoding::BitStore;
@@ -113,4 +321,59 @@ mod test {
         let decoded_4b5b_nrzi = decode_4b5b(&encoded_4b5b_nrzi);
         assert_eq!(bv, decode_nrzi(&decoded_4b5b_nrzi));
     }
+
+    #[test]
+    fn test_line_codes() {
+        let data = BitStore::from_vec((0..=255).collect());
+        for kind in [LineCodeKind::FourBFiveB, LineCodeKind::Manchester, LineCodeKind::EightBTenB] {
+            let line_code = kind.build();
+            let line = line_code.encode(&data);
+            assert_eq!(line.len(), line_code.encoded_bit_count(256), "{:?}", kind);
+            assert_eq!(line_code.decode(&line), (data.clone(), Vec::new()), "{:?}", kind);
+            // the receiver follows the clock by the transitions
+            let max_run = line.iter().fold((0, 0, false), |(max_run, run, last), bit| {
+                let run = if *bit == last { run + 1 } else { 1 };
+                (std::cmp::max(max_run, run), run, *bit)
+            }).0;
+            assert!(max_run <= 5, "{:?} has a run of {}", kind, max_run);
+        }
+
+        // the running disparity of 8b/10b is -1 or +1 after every symbol
+        let line = EightBTenB.encode(&data);
+        let mut disparity = 0i32;
+        for symbol in line.chunks(10) {
+            disparity += symbol.iter().map(|bit| if *bit { 1 } else { -1 }).sum::<i32>();
+            assert!(disparity.abs() <= 2, "{}", disparity);
+        }
+    }
+
+    #[test]
+    fn test_symbol_errors() {
+        let data = BitStore::from_vec(vec![0x5a, 0x00, 0xff, 0x3c]);
+        let mut line = Manchester.encode(&data);
+        let bit = line[8];
+        line.set(9, bit);
+        let (decoded, errors) = Manchester.decode(&line);
+        assert_eq!(errors, vec![SymbolError { bit_index: 4, bit_count: 1 }]);
+        assert_eq!(decoded.len(), data.len());
+
+        // five equal line bits are five zeros after NRZI, which is not a 4B5B code word
+        let mut line = FourBFiveB.encode(&data);
+        let bit = line[9];
+        for index in 10..15 {
+            line.set(index, bit);
+        }
+        let (_, errors) = FourBFiveB.decode(&line);
+        assert_eq!(errors, vec![SymbolError { bit_index: 8, bit_count: 4 }]);
+
+        let mut line = EightBTenB.encode(&data);
+        let flipped = !line[12];
+        line.set(12, flipped);
+        let (_, errors) = EightBTenB.decode(&line);
+        assert!(!errors.is_empty());
+        assert!(errors.iter().all(|error| error.bit_index >= 8 && error.bit_count == 8), "{:?}", errors);
+        // a partial symbol at the end is an error
+        let (_, errors) = EightBTenB.decode(&EightBTenB.encode(&data)[..35].to_bitvec());
+        assert_eq!(errors, vec![SymbolError { bit_index: 24, bit_count: 8 }]);
+    }
 }
@@ -0,0 +1,419 @@
+// the primitive polynomial x^8 + x^4 + x^3 + x^2 + 1 of GF(256)
+static PRIMITIVE_POLYNOMIAL: usize = 0x11d;
+// the max length of a Reed-Solomon codeword over GF(256)
+pub const MAX_CODEWORD_LEN: usize = 255;
+
+/// ReedSolomon is a systematic Reed-Solomon code over GF(256), which corrects parity_len / 2 wrong bytes in a codeword.
+pub struct ReedSolomon {
+    parity_len: usize,
+    exp: [u8; 512],
+    log: [u8; 256],
+    generator: Vec<u8>,
+}
+
+// the polynomials are stored with the coefficient of the highest degree first
+impl ReedSolomon {
+    pub fn new(parity_len: usize) -> Self {
+        assert!(parity_len > 0 && parity_len < MAX_CODEWORD_LEN);
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x = 1usize;
+        for index in 0..255 {
+            exp[index] = x as u8;
+            log[x] = index as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= PRIMITIVE_POLYNOMIAL;
+            }
+        }
+        for index in 255..512 {
+            exp[index] = exp[index - 255];
+        }
+        let mut code = Self {
+            parity_len,
+            exp,
+            log,
+            generator: vec![1],
+        };
+        let mut generator = vec![1];
+        for index in 0..parity_len {
+            generator = code.poly_mul(&generator, &[1, code.pow(2, index as isize)]);
+        }
+        code.generator = generator;
+        code
+    }
+
+    pub fn parity_len(&self) -> usize {
+        self.parity_len
+    }
+
+    fn mul(&self, x: u8, y: u8) -> u8 {
+        if x == 0 || y == 0 {
+            return 0;
+        }
+        self.exp[self.log[x as usize] as usize + self.log[y as usize] as usize]
+    }
+
+    fn div(&self, x: u8, y: u8) -> u8 {
+        assert_ne!(y, 0);
+        if x == 0 {
+            return 0;
+        }
+        self.exp[(self.log[x as usize] as usize + 255 - self.log[y as usize] as usize) % 255]
+    }
+
+    fn pow(&self, x: u8, power: isize) -> u8 {
+        self.exp[(self.log[x as usize] as isize * power).rem_euclid(255) as usize]
+    }
+
+    fn inverse(&self, x: u8) -> u8 {
+        self.exp[255 - self.log[x as usize] as usize]
+    }
+
+    fn poly_scale(&self, p: &[u8], x: u8) -> Vec<u8> {
+        p.iter().map(|&coefficient| self.mul(coefficient, x)).collect()
+    }
+
+    fn poly_add(&self, p: &[u8], q: &[u8]) -> Vec<u8> {
+        let len = std::cmp::max(p.len(), q.len());
+        let mut result = vec![0; len];
+        for (index, &coefficient) in p.iter().enumerate() {
+            result[index + len - p.len()] = coefficient;
+        }
+        for (index, &coefficient) in q.iter().enumerate() {
+            result[index + len - q.len()] ^= coefficient;
+        }
+        result
+    }
+
+    fn poly_mul(&self, p: &[u8], q: &[u8]) -> Vec<u8> {
+        let mut result = vec![0; p.len() + q.len() - 1];
+        for (j, &q_coefficient) in q.iter().enumerate() {
+            for (i, &p_coefficient) in p.iter().enumerate() {
+                result[i + j] ^= self.mul(p_coefficient, q_coefficient);
+            }
+        }
+        result
+    }
+
+    fn poly_eval(&self, p: &[u8], x: u8) -> u8 {
+        p.iter().skip(1).fold(p[0], |y, &coefficient| self.mul(y, x) ^ coefficient)
+    }
+
+    // the remainder of dividend / divisor, the divisor is monic
+    fn poly_remainder(&self, dividend: &[u8], divisor: &[u8]) -> Vec<u8> {
+        let mut result = dividend.to_vec();
+        for i in 0..dividend.len() - (divisor.len() - 1) {
+            let coefficient = result[i];
+            if coefficient != 0 {
+                for (j, &divisor_coefficient) in divisor.iter().enumerate().skip(1) {
+                    result[i + j] ^= self.mul(divisor_coefficient, coefficient);
+                }
+            }
+        }
+        result[dividend.len() - (divisor.len() - 1)..].to_vec()
+    }
+
+    /// the codeword of the data followed by parity_len parity bytes
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        assert!(data.len() + self.parity_len <= MAX_CODEWORD_LEN);
+        let mut codeword = data.to_vec();
+        codeword.extend(std::iter::repeat(0).take(self.parity_len));
+        let parity = self.poly_remainder(&codeword, &self.generator);
+        codeword[data.len()..].copy_from_slice(&parity);
+        codeword
+    }
+
+    fn syndromes(&self, codeword: &[u8]) -> Vec<u8> {
+        (0..self.parity_len).map(|index| self.poly_eval(codeword, self.pow(2, index as isize))).collect()
+    }
+
+    /// correct the codeword in place, returns the count of corrected bytes or None if there are too many errors
+    pub fn decode(&self, codeword: &mut [u8]) -> Option<usize> {
+        self.decode_with_erasures(codeword, &[])
+    }
+
+    /// correct the codeword in place with the indexes of the bytes known to be unreliable, such as the invalid line symbols.
+    /// It corrects e wrong bytes and v erasures if 2e + v <= parity_len,
+    /// returns the count of corrected bytes or None if there are too many errors
+    pub fn decode_with_erasures(&self, codeword: &mut [u8], erasures: &[usize]) -> Option<usize> {
+        if codeword.len() <= self.parity_len || codeword.len() > MAX_CODEWORD_LEN || erasures.len() > self.parity_len {
+            return None;
+        }
+        let syndromes = self.syndromes(codeword);
+        if syndromes.iter().all(|&syndrome| syndrome == 0) {
+            return Some(0);
+        }
+        let len = codeword.len();
+        // the Forney syndromes remove the erasures from the syndromes, so the errors are located without them
+        let mut forney_syndromes = syndromes.clone();
+        for &position in erasures {
+            let location = self.pow(2, (len - 1 - position) as isize);
+            for index in 0..forney_syndromes.len() - 1 {
+                forney_syndromes[index] = self.mul(forney_syndromes[index], location) ^ forney_syndromes[index + 1];
+            }
+        }
+        // Berlekamp-Massey algorithm for the error locator polynomial
+        let mut error_locator = vec![1];
+        let mut old_locator = vec![1];
+        for index in 0..self.parity_len - erasures.len() {
+            let mut delta = forney_syndromes[index];
+            for j in 1..std::cmp::min(error_locator.len(), index + 1) {
+                delta ^= self.mul(error_locator[error_locator.len() - 1 - j], forney_syndromes[index - j]);
+            }
+            old_locator.push(0);
+            if delta != 0 {
+                if old_locator.len() > error_locator.len() {
+                    let new_locator = self.poly_scale(&old_locator, delta);
+                    old_locator = self.poly_scale(&error_locator, self.inverse(delta));
+                    error_locator = new_locator;
+                }
+                error_locator = self.poly_add(&error_locator, &self.poly_scale(&old_locator, delta));
+            }
+        }
+        let leading_zero = error_locator.iter().take_while(|&&coefficient| coefficient == 0).count();
+        let error_locator = &error_locator[leading_zero..];
+        let error_count = error_locator.len() - 1;
+        if error_count * 2 + erasures.len() > self.parity_len {
+            return None;
+        }
+        // Chien search for the positions of the errors, the locator is evaluated in the reversed order
+        let reversed_locator: Vec<u8> = error_locator.iter().rev().cloned().collect();
+        let mut error_positions: Vec<usize> = (0..len)
+            .filter(|&index| self.poly_eval(&reversed_locator, self.pow(2, index as isize)) == 0)
+            .map(|index| len - 1 - index)
+            .collect();
+        if error_positions.len() != error_count {
+            return None;
+        }
+        let erased_positions: Vec<usize> = erasures.iter().filter(|position| !error_positions.contains(position)).cloned().collect();
+        error_positions.extend(erased_positions);
+        // Forney algorithm for the magnitudes of the errors and the erasures
+        let coefficient_positions: Vec<usize> = error_positions.iter().map(|position| len - 1 - position).collect();
+        let mut errata_locator = vec![1];
+        for &position in &coefficient_positions {
+            errata_locator = self.poly_mul(&errata_locator, &[self.pow(2, position as isize), 1]);
+        }
+        // the syndrome polynomial is S(x) * x, the coefficient of x^0 is 0
+        let mut reversed_syndromes: Vec<u8> = syndromes.iter().rev().cloned().collect();
+        reversed_syndromes.push(0);
+        let mut divisor = vec![1];
+        divisor.extend(std::iter::repeat(0).take(errata_locator.len()));
+        let error_evaluator = self.poly_remainder(&self.poly_mul(&reversed_syndromes, &errata_locator), &divisor);
+        let locations: Vec<u8> = coefficient_positions.iter().map(|&position| self.pow(2, position as isize)).collect();
+        let mut corrected = 0;
+        for (index, &location) in locations.iter().enumerate() {
+            let location_inverse = self.inverse(location);
+            let locator_derivative = locations.iter().enumerate()
+                .filter(|(j, _)| *j != index)
+                .fold(1, |product, (_, &other)| self.mul(product, 1 ^ self.mul(location_inverse, other)));
+            let y = self.mul(location, self.poly_eval(&error_evaluator, location_inverse));
+            if locator_derivative == 0 {
+                return None;
+            }
+            let magnitude = self.div(y, locator_derivative);
+            if magnitude != 0 {
+                codeword[error_positions[index]] ^= magnitude;
+                corrected += 1;
+            }
+        }
+        if self.syndromes(codeword).iter().any(|&syndrome| syndrome != 0) {
+            return None;
+        }
+        Some(corrected)
+    }
+}
+
+/// Fec is the forward error correction of a frame.
+///
+/// The frame is split into Reed-Solomon codewords of at most MAX_CODEWORD_LEN bytes, and into at least depth codewords
+/// if the frame has so many bytes. The bytes of the codewords are interleaved, so a burst of wrong bytes is spread over the codewords:
+/// the codewords of parity_len parity bytes correct a burst of depth * parity_len / 2 bytes, with depth * parity_len bytes of parity.
+pub enum Fec {
+    None,
+    ReedSolomon {
+        code: ReedSolomon,
+        depth: usize,
+    },
+}
+
+impl Fec {
+    /// Reed-Solomon code with parity_len parity bytes per codeword, the frames shorter than MAX_CODEWORD_LEN are one codeword
+    pub fn reed_solomon(parity_len: usize) -> Self {
+        Fec::ReedSolomon {
+            code: ReedSolomon::new(parity_len),
+            depth: 1,
+        }
+    }
+
+    /// split every frame into at least depth interleaved codewords, both ends must use the same depth
+    pub fn with_depth(self, depth: usize) -> Self {
+        assert!(depth > 0);
+        match self {
+            Fec::None => Fec::None,
+            Fec::ReedSolomon { code, .. } => Fec::ReedSolomon { code, depth },
+        }
+    }
+
+    // the count of the codewords of data_len bytes of data
+    fn block_count(code: &ReedSolomon, depth: usize, data_len: usize) -> usize {
+        let block_count = (data_len + MAX_CODEWORD_LEN - code.parity_len() - 1) / (MAX_CODEWORD_LEN - code.parity_len());
+        std::cmp::max(block_count, std::cmp::min(depth, data_len))
+    }
+
+    /// the max count of data bytes in an encoded frame of encoded_len bytes
+    pub fn max_data_len(&self, encoded_len: usize) -> usize {
+        match self {
+            Fec::None => encoded_len,
+            Fec::ReedSolomon { code, depth } => (0..=encoded_len).rev()
+                .find(|&data_len| data_len + Self::block_count(code, *depth, data_len) * code.parity_len() <= encoded_len)
+                .unwrap_or(0),
+        }
+    }
+
+    // the lengths of the data in the codewords of data_len bytes
+    fn block_data_lens(data_len: usize, block_count: usize) -> Vec<usize> {
+        (0..block_count).map(|index| data_len / block_count + if index < data_len % block_count { 1 } else { 0 }).collect()
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let (code, depth) = match self {
+            Fec::None => return data.to_vec(),
+            Fec::ReedSolomon { code, depth } => (code, *depth),
+        };
+        let block_count = Self::block_count(code, depth, data.len());
+        let mut start = 0;
+        let codewords: Vec<Vec<u8>> = Self::block_data_lens(data.len(), block_count).into_iter().map(|len| {
+            let codeword = code.encode(&data[start..start + len]);
+            start += len;
+            codeword
+        }).collect();
+        let max_len = codewords.iter().map(|codeword| codeword.len()).max().unwrap_or(0);
+        (0..max_len).flat_map(|index| codewords.iter().filter_map(move |codeword| codeword.get(index).cloned())).collect()
+    }
+
+    /// decode the encoded frame, returns the data and the count of corrected bytes, or None if it can not be corrected
+    pub fn decode(&self, encoded: &[u8]) -> Option<(Vec<u8>, usize)> {
+        self.decode_with_erasures(encoded, &[])
+    }
+
+    /// decode the encoded frame with the indexes of the unreliable bytes in it, see ReedSolomon::decode_with_erasures
+    pub fn decode_with_erasures(&self, encoded: &[u8], erasures: &[usize]) -> Option<(Vec<u8>, usize)> {
+        let (code, depth) = match self {
+            Fec::None => return Some((encoded.to_vec(), 0)),
+            Fec::ReedSolomon { code, depth } => (code, *depth),
+        };
+        // the data of fewer bytes is in no more codewords, so there is at most one count of codewords for the length
+        let block_count = (0..=encoded.len() / (code.parity_len() + 1))
+            .find(|&block_count| Self::block_count(code, depth, encoded.len() - block_count * code.parity_len()) == block_count)?;
+        let data_lens = Self::block_data_lens(encoded.len() - block_count * code.parity_len(), block_count);
+        let mut codewords: Vec<Vec<u8>> = data_lens.iter().map(|len| Vec::with_capacity(len + code.parity_len())).collect();
+        let mut codeword_erasures: Vec<Vec<usize>> = vec![Vec::new(); block_count];
+        let mut bytes = encoded.iter().enumerate();
+        for index in 0..data_lens.iter().max().map_or(0, |len| len + code.parity_len()) {
+            for ((codeword, len), erased) in codewords.iter_mut().zip(data_lens.iter()).zip(codeword_erasures.iter_mut()) {
+                if index < len + code.parity_len() {
+                    let (position, byte) = bytes.next().unwrap();
+                    if erasures.contains(&position) {
+                        erased.push(codeword.len());
+                    }
+                    codeword.push(*byte);
+                }
+            }
+        }
+        let mut data = Vec::with_capacity(encoded.len());
+        let mut corrected = 0;
+        for ((mut codeword, len), erased) in codewords.into_iter().zip(data_lens).zip(codeword_erasures) {
+            corrected += code.decode_with_erasures(&mut codeword, &erased)?;
+            data.extend_from_slice(&codeword[..len]);
+        }
+        Some((data, corrected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    use super::*;
+
+    #[test]
+    fn test_reed_solomon() {
+        let code = ReedSolomon::new(8);
+        let mut rng = Pcg64::seed_from_u64(0);
+        for len in [1, 10, 100, 247] {
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let codeword = code.encode(&data);
+            assert_eq!(&codeword[..len], &data[..]);
+            for error_count in 0..=4 {
+                let mut corrupted = codeword.clone();
+                for _ in 0..error_count {
+                    let index = rng.gen_range(0..corrupted.len());
+                    corrupted[index] ^= rng.gen_range(1..=255u8);
+                }
+                assert!(code.decode(&mut corrupted).unwrap() <= error_count);
+                assert_eq!(corrupted, codeword);
+            }
+        }
+    }
+
+    #[test]
+    fn test_erasures() {
+        let code = ReedSolomon::new(8);
+        let mut rng = Pcg64::seed_from_u64(1);
+        let data: Vec<u8> = (0..100).map(|_| rng.gen()).collect();
+        let codeword = code.encode(&data);
+        // 8 erasures are twice the errors corrected without the positions
+        let mut corrupted = codeword.clone();
+        let erasures: Vec<usize> = (0..8).map(|index| index * 13).collect();
+        for &position in &erasures {
+            corrupted[position] ^= rng.gen_range(1..=255u8);
+        }
+        assert_eq!(code.decode(&mut corrupted.clone()), None);
+        assert_eq!(code.decode_with_erasures(&mut corrupted, &erasures), Some(8));
+        assert_eq!(corrupted, codeword);
+        // 2 errors and 4 erasures, one of which is not wrong
+        let mut corrupted = codeword.clone();
+        for position in [3, 50, 60, 70, 90] {
+            corrupted[position] ^= rng.gen_range(1..=255u8);
+        }
+        assert_eq!(code.decode_with_erasures(&mut corrupted, &[60, 70, 90, 107]), Some(5));
+        assert_eq!(corrupted, codeword);
+        assert_eq!(code.decode_with_erasures(&mut corrupted, &erasures), Some(0));
+    }
+
+    #[test]
+    fn test_interleaved_burst() {
+        let fec = Fec::reed_solomon(8);
+        let data: Vec<u8> = (0..600).map(|x| (x * 7) as u8).collect();
+        let encoded = fec.encode(&data);
+        assert_eq!(encoded.len(), 600 + 3 * 8);
+        assert_eq!(fec.max_data_len(encoded.len()), 600);
+        // a burst of 12 bytes is 4 bytes in each of the 3 codewords
+        let mut corrupted = encoded.clone();
+        for byte in corrupted[100..112].iter_mut() {
+            *byte = !*byte;
+        }
+        assert_eq!(fec.decode(&corrupted), Some((data.clone(), 12)));
+        assert_eq!(fec.decode(&encoded), Some((data, 0)));
+        assert_eq!(fec.decode(&fec.encode(&[])), Some((vec![], 0)));
+
+        // a short frame is one codeword, which does not correct the burst of 8 bytes without the depth
+        let data: Vec<u8> = (0..100).map(|x| (x * 3) as u8).collect();
+        for (depth, recovered) in [(1, false), (4, true)] {
+            let fec = Fec::reed_solomon(4).with_depth(depth);
+            let mut encoded = fec.encode(&data);
+            assert_eq!(encoded.len(), 100 + depth * 4);
+            assert_eq!(fec.max_data_len(encoded.len()), 100);
+            for byte in encoded[40..48].iter_mut() {
+                *byte = !*byte;
+            }
+            // the codeword of too many errors is either not decoded or miscorrected to another one
+            let decoded = fec.decode(&encoded).map(|(decoded, _)| decoded);
+            assert_eq!(decoded.as_ref() == Some(&data), recovered);
+        }
+        let fec = Fec::reed_solomon(4).with_depth(4);
+        assert_eq!(fec.decode(&fec.encode(&[1, 2])), Some((vec![1, 2], 0)));
+    }
+}
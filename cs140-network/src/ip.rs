@@ -1,11 +1,23 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
 use async_trait::async_trait;
+use tokio::select;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use cs140_common::device::LinkStatus;
 
-use log::trace;
+use log::{trace, warn};
 
 use crate::encoding::{HandlePackage, HandlePackageMut, NetworkPackage};
-use crate::redundancy::{BYTE_IN_ADDRESS, BYTE_IN_ENDING, BYTE_IN_LENGTH, RedundancyLayer, RedundancyPackage};
+use crate::redundancy::{BYTE_IN_HEADER, RedundancyLayer, RedundancyPackage};
+use crate::stats::{IPStats, PhysicalStats, RedundancyStats};
 use crate::tcp::TCPPackage;
 
 #[derive(Debug, Clone)]
@@ -17,6 +29,14 @@ impl IPPackage {
     pub fn new(data: Vec<u8>) -> Self {
         Self { data }
     }
+
+    /// the destination address in the header if the package is an ipv4 packet
+    pub fn destination(&self) -> Option<Ipv4Addr> {
+        if self.data.len() < 20 || self.data[0] >> 4 != 4 {
+            return None;
+        }
+        Some(Ipv4Addr::new(self.data[16], self.data[17], self.data[18], self.data[19]))
+    }
 }
 
 impl NetworkPackage for IPPackage {}
@@ -25,47 +45,91 @@ pub struct IPLayer {
     pub(crate) byte_in_frame: usize,
     send_package_sender: Sender<IPPackage>,
     recv_package_receiver: Mutex<Receiver<IPPackage>>,
+    stats: Arc<IPStats>,
+    redundancy_stats: Arc<RedundancyStats>,
+    physical_stats: Arc<PhysicalStats>,
+    link_status: watch::Receiver<LinkStatus>,
+    // the deadline to flush the packages before the task shuts down the layers below
+    shutdown_sender: oneshot::Sender<Instant>,
+    task: JoinHandle<()>,
+}
+
+// the time for a task to return after the deadline of the shutdown, the task is aborted after it
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// wait for the task to return after a shutdown until the grace period after the deadline
+pub(crate) async fn join_task(mut task: JoinHandle<()>, deadline: Instant) {
+    if tokio::time::timeout_at(deadline + SHUTDOWN_GRACE_PERIOD, &mut task).await.is_err() {
+        warn!("the task does not return after the shutdown, abort it");
+        task.abort();
+        let _ = task.await;
+    }
+}
+
+async fn send_fragments(redundancy: &mut RedundancyLayer, package: IPPackage, byte_in_frame: usize, stats: &IPStats) {
+    let (address, dest) = (redundancy.address(), redundancy.resolve(package.destination()));
+    let chunks = package.data.chunks(byte_in_frame);
+    let last_chunk_index = chunks.len() - 1;
+    for (index, ip_data) in chunks.enumerate() {
+        let package = RedundancyPackage::new(ip_data.iter().cloned(), ip_data.len(), index != last_chunk_index, address, dest);
+        stats.fragments_sent.fetch_add(1, Relaxed);
+        redundancy.send(package).await;
+    }
 }
 
 impl IPLayer {
     pub fn new(mut redundancy: RedundancyLayer) -> Self {
         let byte_in_frame = redundancy.byte_in_frame;
+        let stats = Arc::new(IPStats::default());
+        let (redundancy_stats, physical_stats) = (redundancy.stats(), redundancy.physical_stats());
+        let link_status = redundancy.link_status();
+        let task_stats = stats.clone();
         let (send_package_sender, mut send_package_receiver) = tokio::sync::mpsc::channel::<IPPackage>(1);
         let (recv_package_sender, recv_package_receiver) = tokio::sync::mpsc::channel::<IPPackage>(1024);
 
-        tokio::spawn(async move{
-            let mut data: Vec<u8> = Vec::new();
-            loop{
-                tokio::select! {
+        let (shutdown_sender, mut shutdown_receiver) = oneshot::channel();
+
+        let task = tokio::spawn(async move{
+            // the fragments are merged per source, so the packages of the nodes on a shared bus are not mixed
+            let mut data: HashMap<u8, Vec<u8>> = HashMap::new();
+            let deadline = loop{
+                select! {
+                    biased;
+                    // the layer is dropped without a shutdown if the sender is dropped
+                    deadline = &mut shutdown_receiver => break deadline.unwrap_or_else(|_| Instant::now()),
                     package = send_package_receiver.recv() => {
                         match package {
                             None => {
-                                return;
-                            }
-                            Some(package) => {
-                                let chunks = package.data.chunks(byte_in_frame);
-                                let last_chunk_index = chunks.len() - 1;
-                                for (index, ip_data) in chunks.enumerate() {
-                                    let package = RedundancyPackage::new(ip_data.iter().cloned(),ip_data.len(),index != last_chunk_index,0,0);
-                                    redundancy.send(package).await;
-                                }
+                                break Instant::now();
                             }
+                            Some(package) => send_fragments(&mut redundancy, package, byte_in_frame, &task_stats).await,
                         }
                     },
+                    // the receive is cancelled by the other branches, the received packages are kept in the redundancy layer
                     package = redundancy.receive() =>{
                         trace!("fragment:{:?},len:{}",package,package.len());
                         let len = package.len();
                         let more_fragments = package.has_more_fragments();
-                        data.extend(package.data.into_iter().skip(BYTE_IN_LENGTH + BYTE_IN_ENDING + BYTE_IN_ADDRESS).take(len));
-                        trace!("merged_data:{:?}",data);
+                        let (src, _) = package.address();
+                        task_stats.fragments_received.fetch_add(1, Relaxed);
+                        let merged = data.entry(src).or_default();
+                        merged.extend(package.data.into_iter().skip(BYTE_IN_HEADER).take(len));
+                        trace!("merged_data:{:?}",merged);
                         if !more_fragments {
-                            let empty_data = Vec::new();
-                            let data = std::mem::replace(&mut data,empty_data);
+                            let data = data.remove(&src).unwrap();
+                            task_stats.reassemblies.fetch_add(1, Relaxed);
                             recv_package_sender.send(IPPackage { data }).await;
                         }
                     }
                 }
-            }
+            };
+            // the package accepted by send before the shutdown is still sent
+            let _ = tokio::time::timeout_at(deadline, async {
+                while let Ok(package) = send_package_receiver.try_recv() {
+                    send_fragments(&mut redundancy, package, byte_in_frame, &task_stats).await;
+                }
+            }).await;
+            redundancy.shutdown(deadline.saturating_duration_since(Instant::now())).await;
         });
         //
         // tokio::spawn(async move{
@@ -106,8 +170,42 @@ impl IPLayer {
             byte_in_frame,
             send_package_sender,
             recv_package_receiver: Mutex::new(recv_package_receiver),
+            stats,
+            redundancy_stats,
+            physical_stats,
+            link_status,
+            shutdown_sender,
+            task,
         }
     }
+
+    /// stop accepting packages, send the packages accepted before until the flush timeout, then shut down the layers below.
+    /// The packages received and not taken by receive are dropped.
+    pub async fn shutdown(self, flush_timeout: Duration) {
+        let deadline = Instant::now() + flush_timeout;
+        let _ = self.shutdown_sender.send(deadline);
+        drop(self.recv_package_receiver);
+        join_task(self.task, deadline).await;
+    }
+
+    pub fn stats(&self) -> Arc<IPStats> {
+        self.stats.clone()
+    }
+
+    /// the statistics of the redundancy layer below
+    pub fn redundancy_stats(&self) -> Arc<RedundancyStats> {
+        self.redundancy_stats.clone()
+    }
+
+    /// the statistics of the physical layer below
+    pub fn physical_stats(&self) -> Arc<PhysicalStats> {
+        self.physical_stats.clone()
+    }
+
+    /// the status of the sound card of the physical layer below
+    pub fn link_status(&self) -> watch::Receiver<LinkStatus> {
+        self.link_status.clone()
+    }
 }
 
 #[async_trait]
@@ -121,4 +219,69 @@ impl HandlePackage<IPPackage> for IPLayer {
         let package = guard.recv().await.unwrap();
         package
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::ack::Arq;
+    use crate::backend::{AudioBackend, InputBuffer, LoopbackBackend};
+    use crate::channel::ChannelConfig;
+    use crate::csma::CsmaConfig;
+    use crate::physical::PhysicalLayer;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown() {
+        // the stacks can be created and destroyed repeatedly in one process
+        for round in 0..3u8 {
+            let (first, second) = LoopbackBackend::pair(48000);
+            let (_, InputBuffer::Single(input_buffer)) = first.input() else { unreachable!() };
+            let sender = IPLayer::new(RedundancyLayer::new(PhysicalLayer::with_backend(first, 1, 64)));
+            let receiver = IPLayer::new(RedundancyLayer::new(PhysicalLayer::with_backend(second, 1, 64)));
+            let data: Vec<u8> = (0..200).map(|x| x as u8 ^ round).collect();
+            sender.send(IPPackage::new(data.clone())).await;
+            // the package accepted by send is flushed before the layers below are shut down
+            sender.shutdown(Duration::from_secs(5)).await;
+            let package = tokio::time::timeout(Duration::from_secs(5), receiver.receive()).await.unwrap();
+            assert_eq!(package.data, data);
+            receiver.shutdown(Duration::from_secs(1)).await;
+            // the wires of the backends stop and release the buffers
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(Arc::strong_count(&input_buffer), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_neighbors() {
+        let mut nodes = LoopbackBackend::bus(48000, 3, ChannelConfig::default()).into_iter();
+        let arq = Arq::StopAndWait { timeout: Duration::from_millis(200) };
+        // the retransmissions wait for the acknowledgements on the shared medium
+        let mut layer = |address| RedundancyLayer::new(PhysicalLayer::with_backend(nodes.next().unwrap(), 1, 64).with_csma(CsmaConfig::default()))
+            .with_address(address)
+            .with_arq(arq);
+        let sender = IPLayer::new(layer(1)
+            .with_neighbor(Ipv4Addr::new(10, 0, 0, 2), 2)
+            .with_neighbor(Ipv4Addr::new(10, 0, 0, 3), 3));
+        let receivers = [IPLayer::new(layer(2)), IPLayer::new(layer(3))];
+        // ipv4 packets with only the version and the destination in the header
+        let packet = |host: u8, index: u8| {
+            let mut data = vec![index; 100];
+            data[0] = 0x45;
+            data[16..20].copy_from_slice(&[10, 0, 0, host]);
+            IPPackage::new(data)
+        };
+        // the packages to the two nodes are sequenced separately, so each of them is acknowledged by its node
+        tokio::time::timeout(Duration::from_secs(10), async {
+            for index in 0..4 {
+                sender.send(packet(2 + index % 2, index)).await;
+            }
+        }).await.unwrap();
+        for (offset, receiver) in receivers.iter().enumerate() {
+            for index in (offset as u8..4).step_by(2) {
+                let package = tokio::time::timeout(Duration::from_secs(10), receiver.receive()).await.unwrap();
+                assert_eq!(package.destination(), Some(Ipv4Addr::new(10, 0, 0, 2 + offset as u8)));
+                assert_eq!(package.data[99], index);
+            }
+        }
+    }
+}
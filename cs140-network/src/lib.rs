@@ -1,9 +1,20 @@
 #![feature(linked_list_cursors)]
 
+pub mod backend;
+pub mod channel;
+pub mod config;
+pub mod csma;
+pub mod diagnostic;
 pub mod encoding;
+pub mod fec;
 pub mod ip;
+pub mod modulation;
+pub mod offline;
 pub mod physical;
+pub mod preamble;
 pub mod redundancy;
+pub mod stats;
 pub mod tcp;
+pub mod training;
 pub mod ack;
-mod sample_reader;
+pub mod sample_reader;
@@ -0,0 +1,522 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bitvec::order::Msb0;
+use bitvec::slice::BitSlice;
+use rustfft::{Fft, FftPlanner};
+use rustfft::num_complex::Complex;
+
+use crate::encoding::BitStore;
+use crate::preamble::PreambleMatch;
+use crate::sample_reader::{BitTrace, LineConfig, SampleReader};
+
+/// Modulator maps the line bits of a frame to samples
+pub trait Modulator: Send {
+    fn modulate(&self, bits: &BitStore) -> Vec<f32>;
+    /// the count of samples that modulate returns for bit_count bits
+    fn sample_count(&self, bit_count: usize) -> usize;
+}
+
+/// Demodulator maps the samples after a preamble back to line bits
+pub trait Demodulator: Send {
+    /// prepare to demodulate a new frame, the samples passed to demodulate start right after the preamble
+    fn begin(&mut self, found: &PreambleMatch);
+    /// demodulate exactly count bits, returns the bits and the count of samples used,
+    /// or None if the signal ends before that.
+    /// Consecutive calls continue from the samples used by the previous call.
+    fn demodulate(&mut self, data: &[f32], count: usize) -> Option<(BitStore, usize)>;
+    /// the count of bit slips corrected since begin, 0 if the demodulator does not recover the bit clock
+    fn bit_slip_count(&self) -> usize {
+        0
+    }
+    /// the estimated offset of the sample clock of the receiver to the sender since begin in ppm,
+    /// None if the demodulator does not track it
+    fn clock_offset_ppm(&self) -> Option<f32> {
+        None
+    }
+    /// record how each bit is read from the next begin on, for the diagnostics
+    fn set_tracing(&mut self, _tracing: bool) {}
+    /// how the bits since begin are read, the sample indexes start right after the preamble.
+    /// It is empty if the tracing is disabled or the demodulator does not support it.
+    fn trace(&self) -> &[BitTrace] {
+        &[]
+    }
+}
+
+pub trait Modulation: Modulator + Demodulator {}
+
+impl<T: Modulator + Demodulator> Modulation for T {}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ModulationKind {
+    Baseband,
+    Fsk,
+    Bpsk,
+    Qpsk,
+    Ofdm,
+}
+
+impl FromStr for ModulationKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "baseband" => Ok(ModulationKind::Baseband),
+            "fsk" => Ok(ModulationKind::Fsk),
+            "bpsk" => Ok(ModulationKind::Bpsk),
+            "qpsk" => Ok(ModulationKind::Qpsk),
+            "ofdm" => Ok(ModulationKind::Ofdm),
+            _ => Err(anyhow::anyhow!("unknown modulation {}", s)),
+        }
+    }
+}
+
+impl ModulationKind {
+    /// the modulation with the default parameters for the sample rate, line is only used by the baseband modulation
+    pub fn build(&self, sample_rate: u32, line: LineConfig) -> Box<dyn Modulation> {
+        match self {
+            ModulationKind::Baseband => Box::new(BasebandModulation::with_config(line)),
+            ModulationKind::Fsk => Box::new(FskModulation::new(sample_rate, 24, 4000.0, 6000.0)),
+            ModulationKind::Bpsk => Box::new(PskModulation::bpsk(sample_rate, 8, sample_rate as f32 / 4.0)),
+            ModulationKind::Qpsk => Box::new(PskModulation::qpsk(sample_rate, 8, sample_rate as f32 / 4.0)),
+            ModulationKind::Ofdm => Box::new(OfdmModulation::new(64, 16, 8, 16)),
+        }
+    }
+}
+
+// read blocks of samples until there are count bits, the bits left in the last block are kept in pending
+fn read_blocks(pending: &mut BitStore, data: &[f32], count: usize, block_sample_count: usize, mut read_block: impl FnMut(&[f32]) -> BitStore) -> Option<(BitStore, usize)> {
+    let mut sample_used = 0;
+    while pending.len() < count {
+        if data.len() < sample_used + block_sample_count {
+            return None;
+        }
+        let bits = read_block(&data[sample_used..sample_used + block_sample_count]);
+        pending.extend_from_bitslice(bits.as_bitslice());
+        sample_used += block_sample_count;
+    }
+    let rest = pending.split_off(count);
+    Some((std::mem::replace(pending, rest), sample_used))
+}
+
+/// BasebandModulation sends 1 as +1.0 and 0 as -1.0 for sample_per_bit samples, it needs an audio cable.
+/// The bits are read by SampleReader, which follows the amplitude and the sample clock of the sender.
+pub struct BasebandModulation {
+    config: LineConfig,
+    sample_reader: SampleReader,
+    // None if the tracing is disabled
+    trace: Option<Vec<BitTrace>>,
+    // the count of samples used since begin
+    sample_used: usize,
+}
+
+impl BasebandModulation {
+    pub fn new() -> Self {
+        Self::with_config(LineConfig::default())
+    }
+
+    pub fn with_config(config: LineConfig) -> Self {
+        Self {
+            config,
+            sample_reader: SampleReader::new(config, 0.0, 0.1, -0.1),
+            trace: None,
+            sample_used: 0,
+        }
+    }
+}
+
+impl Default for BasebandModulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Modulator for BasebandModulation {
+    fn modulate(&self, bits: &BitStore) -> Vec<f32> {
+        bits.iter().flat_map(|bit| {
+            std::iter::repeat(if *bit { 1.0 } else { -1.0 }).take(self.config.sample_per_bit)
+        }).collect()
+    }
+
+    fn sample_count(&self, bit_count: usize) -> usize {
+        bit_count * self.config.sample_per_bit
+    }
+}
+
+impl Demodulator for BasebandModulation {
+    fn begin(&mut self, found: &PreambleMatch) {
+        self.sample_reader = SampleReader::new(self.config, 0.0, found.one_amplitude, found.neg_one_amplitude);
+        self.sample_used = 0;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.clear();
+        }
+    }
+
+    fn demodulate(&mut self, data: &[f32], count: usize) -> Option<(BitStore, usize)> {
+        let result = match self.trace.as_mut() {
+            None => self.sample_reader.read_exact(data, count),
+            Some(trace) => self.sample_reader.read_exact_traced(data, count, self.sample_used, trace),
+        };
+        if let Some((_, sample_used)) = result {
+            self.sample_used += sample_used;
+        }
+        result
+    }
+
+    fn bit_slip_count(&self) -> usize {
+        self.sample_reader.bit_slip_count()
+    }
+
+    fn clock_offset_ppm(&self) -> Option<f32> {
+        self.sample_reader.clock_offset_ppm()
+    }
+
+    fn set_tracing(&mut self, tracing: bool) {
+        if tracing != self.trace.is_some() {
+            self.trace = if tracing { Some(Vec::new()) } else { None };
+        }
+    }
+
+    fn trace(&self) -> &[BitTrace] {
+        self.trace.as_deref().unwrap_or_default()
+    }
+}
+
+/// FskModulation sends a bit as a tone of one of two frequencies with continuous phase.
+/// The receiver compares the energy of the two frequencies, so the phase of the channel does not matter.
+pub struct FskModulation {
+    sample_rate: f32,
+    sample_per_bit: usize,
+    zero_frequency: f32,
+    one_frequency: f32,
+    pending: BitStore,
+}
+
+impl FskModulation {
+    /// the frequencies should be spaced by a multiple of sample_rate / sample_per_bit to be orthogonal
+    pub fn new(sample_rate: u32, sample_per_bit: usize, zero_frequency: f32, one_frequency: f32) -> Self {
+        Self {
+            sample_rate: sample_rate as f32,
+            sample_per_bit,
+            zero_frequency,
+            one_frequency,
+            pending: BitStore::new(),
+        }
+    }
+
+    fn energy(&self, samples: &[f32], frequency: f32) -> f32 {
+        let (i, q) = samples.iter().enumerate().fold((0.0, 0.0), |(i, q), (index, sample)| {
+            let phase = 2.0 * PI * frequency * index as f32 / self.sample_rate;
+            (i + sample * phase.cos(), q + sample * phase.sin())
+        });
+        i * i + q * q
+    }
+}
+
+impl Modulator for FskModulation {
+    fn modulate(&self, bits: &BitStore) -> Vec<f32> {
+        let mut phase: f32 = 0.0;
+        let mut samples = Vec::with_capacity(self.sample_count(bits.len()));
+        for bit in bits.iter() {
+            let frequency = if *bit { self.one_frequency } else { self.zero_frequency };
+            for _ in 0..self.sample_per_bit {
+                samples.push(phase.sin());
+                phase = (phase + 2.0 * PI * frequency / self.sample_rate) % (2.0 * PI);
+            }
+        }
+        samples
+    }
+
+    fn sample_count(&self, bit_count: usize) -> usize {
+        bit_count * self.sample_per_bit
+    }
+}
+
+impl Demodulator for FskModulation {
+    fn begin(&mut self, _found: &PreambleMatch) {
+        self.pending.clear();
+    }
+
+    fn demodulate(&mut self, data: &[f32], count: usize) -> Option<(BitStore, usize)> {
+        let mut pending = std::mem::take(&mut self.pending);
+        let result = read_blocks(&mut pending, data, count, self.sample_per_bit, |samples| {
+            let mut bits = BitStore::new();
+            bits.push(self.energy(samples, self.one_frequency) > self.energy(samples, self.zero_frequency));
+            bits
+        });
+        self.pending = pending;
+        result
+    }
+}
+
+/// PskModulation sends symbols as the phase change of a carrier, one bit per symbol for DBPSK and two bits per symbol for DQPSK.
+/// Every frame starts with a reference symbol like OfdmModulation, so the phase response of the channel does not matter.
+/// The carrier should have whole cycles in a symbol.
+pub struct PskModulation {
+    sample_rate: f32,
+    sample_per_symbol: usize,
+    carrier_frequency: f32,
+    bit_per_symbol: usize,
+    pending: BitStore,
+    // the index of the next sample since the end of the preamble
+    sample_index: usize,
+    previous_symbol: Option<Complex<f32>>,
+}
+
+impl PskModulation {
+    pub fn bpsk(sample_rate: u32, sample_per_symbol: usize, carrier_frequency: f32) -> Self {
+        Self::new(sample_rate, sample_per_symbol, carrier_frequency, 1)
+    }
+
+    pub fn qpsk(sample_rate: u32, sample_per_symbol: usize, carrier_frequency: f32) -> Self {
+        Self::new(sample_rate, sample_per_symbol, carrier_frequency, 2)
+    }
+
+    fn new(sample_rate: u32, sample_per_symbol: usize, carrier_frequency: f32, bit_per_symbol: usize) -> Self {
+        Self {
+            sample_rate: sample_rate as f32,
+            sample_per_symbol,
+            carrier_frequency,
+            bit_per_symbol,
+            pending: BitStore::new(),
+            sample_index: 0,
+            previous_symbol: None,
+        }
+    }
+
+    fn carrier(&self, sample_index: usize) -> (f32, f32) {
+        let phase = 2.0 * PI * ((self.carrier_frequency * sample_index as f32 / self.sample_rate) % 1.0);
+        (phase.cos(), phase.sin())
+    }
+
+    // the phase change of a symbol
+    fn phase_change(&self, bits: &BitSlice<Msb0, u8>) -> Complex<f32> {
+        let level = |index: usize| if bits.get(index).map_or(false, |bit| *bit) { 1.0 } else { -1.0 };
+        if self.bit_per_symbol == 1 {
+            Complex::new(level(0), 0.0)
+        } else {
+            Complex::new(level(0), level(1)) / 2f32.sqrt()
+        }
+    }
+}
+
+impl Modulator for PskModulation {
+    fn modulate(&self, bits: &BitStore) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(self.sample_count(bits.len()));
+        let mut symbol = Complex::new(1.0, 0.0);
+        let symbols = std::iter::once(symbol).chain(bits.chunks(self.bit_per_symbol).map(|chunk| {
+            symbol *= self.phase_change(chunk);
+            symbol
+        }));
+        for symbol in symbols {
+            for _ in 0..self.sample_per_symbol {
+                let (cos, sin) = self.carrier(samples.len());
+                samples.push(symbol.re * cos - symbol.im * sin);
+            }
+        }
+        samples
+    }
+
+    fn sample_count(&self, bit_count: usize) -> usize {
+        (1 + (bit_count + self.bit_per_symbol - 1) / self.bit_per_symbol) * self.sample_per_symbol
+    }
+}
+
+impl Demodulator for PskModulation {
+    fn begin(&mut self, _found: &PreambleMatch) {
+        self.pending.clear();
+        self.sample_index = 0;
+        self.previous_symbol = None;
+    }
+
+    fn demodulate(&mut self, data: &[f32], count: usize) -> Option<(BitStore, usize)> {
+        let mut pending = std::mem::take(&mut self.pending);
+        let mut sample_index = self.sample_index;
+        let mut previous_symbol = self.previous_symbol.take();
+        let result = read_blocks(&mut pending, data, count, self.sample_per_symbol, |samples| {
+            let symbol = samples.iter().fold(Complex::new(0.0, 0.0), |symbol, sample| {
+                let (cos, sin) = self.carrier(sample_index);
+                sample_index += 1;
+                symbol + Complex::new(sample * cos, -sample * sin)
+            });
+            let mut bits = BitStore::new();
+            if let Some(previous_symbol) = previous_symbol {
+                let change = symbol * previous_symbol.conj();
+                bits.push(change.re > 0.0);
+                if self.bit_per_symbol == 2 {
+                    bits.push(change.im > 0.0);
+                }
+            }
+            previous_symbol = Some(symbol);
+            bits
+        });
+        self.pending = pending;
+        self.sample_index = sample_index;
+        self.previous_symbol = previous_symbol;
+        result
+    }
+}
+
+/// OfdmModulation sends the bits on several subcarriers at once with differential BPSK on each subcarrier.
+/// Every frame starts with a reference symbol, a bit is the phase change of a subcarrier from the previous symbol,
+/// so the receiver does not need to know the phase response of the channel.
+pub struct OfdmModulation {
+    fft_size: usize,
+    cyclic_prefix_len: usize,
+    first_subcarrier: usize,
+    subcarrier_count: usize,
+    fft: Arc<dyn Fft<f32>>,
+    inverse_fft: Arc<dyn Fft<f32>>,
+    pending: BitStore,
+    previous_symbol: Option<Vec<Complex<f32>>>,
+}
+
+impl OfdmModulation {
+    /// the subcarriers are [first_subcarrier, first_subcarrier + subcarrier_count), they must be below fft_size / 2
+    pub fn new(fft_size: usize, cyclic_prefix_len: usize, first_subcarrier: usize, subcarrier_count: usize) -> Self {
+        assert!(first_subcarrier > 0 && first_subcarrier + subcarrier_count <= fft_size / 2);
+        let mut planner = FftPlanner::new();
+        Self {
+            fft_size,
+            cyclic_prefix_len,
+            first_subcarrier,
+            subcarrier_count,
+            fft: planner.plan_fft_forward(fft_size),
+            inverse_fft: planner.plan_fft_inverse(fft_size),
+            pending: BitStore::new(),
+            previous_symbol: None,
+        }
+    }
+
+    fn symbol_sample_count(&self) -> usize {
+        self.fft_size + self.cyclic_prefix_len
+    }
+
+    fn symbol_samples(&self, subcarriers: &[Complex<f32>]) -> Vec<f32> {
+        let mut spectrum = vec![Complex::new(0.0, 0.0); self.fft_size];
+        for (index, value) in subcarriers.iter().enumerate() {
+            let k = self.first_subcarrier + index;
+            spectrum[k] = *value;
+            spectrum[self.fft_size - k] = value.conj();
+        }
+        self.inverse_fft.process(&mut spectrum);
+        let time: Vec<f32> = spectrum.iter().map(|x| x.re).collect();
+        let peak = time.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+        let mut samples: Vec<f32> = time[self.fft_size - self.cyclic_prefix_len..].to_vec();
+        samples.extend_from_slice(&time);
+        samples.iter().map(|x| x / peak).collect()
+    }
+}
+
+impl Modulator for OfdmModulation {
+    fn modulate(&self, bits: &BitStore) -> Vec<f32> {
+        let mut symbol = vec![Complex::new(1.0, 0.0); self.subcarrier_count];
+        let mut samples = self.symbol_samples(&symbol);
+        for chunk in bits.chunks(self.subcarrier_count) {
+            for (index, value) in symbol.iter_mut().enumerate() {
+                if !chunk.get(index).map_or(false, |bit| *bit) {
+                    *value = -*value;
+                }
+            }
+            samples.extend(self.symbol_samples(&symbol));
+        }
+        samples
+    }
+
+    fn sample_count(&self, bit_count: usize) -> usize {
+        (1 + (bit_count + self.subcarrier_count - 1) / self.subcarrier_count) * self.symbol_sample_count()
+    }
+}
+
+impl Demodulator for OfdmModulation {
+    fn begin(&mut self, _found: &PreambleMatch) {
+        self.pending.clear();
+        self.previous_symbol = None;
+    }
+
+    fn demodulate(&mut self, data: &[f32], count: usize) -> Option<(BitStore, usize)> {
+        let mut pending = std::mem::take(&mut self.pending);
+        let mut previous_symbol = self.previous_symbol.take();
+        let result = read_blocks(&mut pending, data, count, self.symbol_sample_count(), |samples| {
+            let mut spectrum: Vec<Complex<f32>> = samples[self.cyclic_prefix_len..].iter().map(|x| Complex::new(*x, 0.0)).collect();
+            self.fft.process(&mut spectrum);
+            let symbol = spectrum[self.first_subcarrier..self.first_subcarrier + self.subcarrier_count].to_vec();
+            let mut bits = BitStore::new();
+            if let Some(previous_symbol) = &previous_symbol {
+                for (current, previous) in symbol.iter().zip(previous_symbol.iter()) {
+                    bits.push((current * previous.conj()).re > 0.0);
+                }
+            }
+            previous_symbol = Some(symbol);
+            bits
+        });
+        self.pending = pending;
+        self.previous_symbol = previous_symbol;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel::{ChannelConfig, ChannelSimulator};
+    use crate::preamble::Preamble;
+
+    use super::*;
+
+    // the samples after the preamble are scaled by data_gain, a negative gain shifts their phase by 180 degrees
+    fn round_trip(mut modulation: Box<dyn Modulation>, channel: ChannelConfig, data_gain: f32) {
+        let bits = BitStore::from_vec((0..=255).collect());
+        let preamble = Preamble::modulated(modulation.as_ref());
+        let mut samples = vec![0.0; 100];
+        samples.extend_from_slice(preamble.samples());
+        samples.extend(modulation.modulate(&bits).into_iter().map(|x| x * data_gain));
+        samples.extend(vec![0.0; 100]);
+        let received = ChannelSimulator::new(channel).transmit(&samples);
+        let found = preamble.find(&received, received.len(), 0.05).unwrap();
+        assert_eq!(found.index, 100);
+        modulation.begin(&found);
+        let data = &received[found.index + preamble.len()..];
+        // the header and the data are demodulated by two calls
+        let (mut result, header_sample_used) = modulation.demodulate(data, 20).unwrap();
+        let (rest, _) = modulation.demodulate(&data[header_sample_used..], bits.len() - 20).unwrap();
+        result.extend_from_bitslice(rest.as_bitslice());
+        assert_eq!(result, bits);
+    }
+
+    #[test]
+    fn test_modulations() {
+        let channel = ChannelConfig {
+            seed: 5,
+            snr_db: Some(20.0),
+            gain: 0.5,
+            ..Default::default()
+        };
+        for kind in [ModulationKind::Baseband, ModulationKind::Fsk, ModulationKind::Bpsk, ModulationKind::Qpsk, ModulationKind::Ofdm] {
+            round_trip(kind.build(48000, LineConfig::default()), channel, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_ofdm_phase_shift() {
+        // the echo changes the phase of every subcarrier, the differential phase does not change
+        let channel = ChannelConfig {
+            gain: 0.5,
+            echo: Some((3, 0.3)),
+            ..Default::default()
+        };
+        round_trip(ModulationKind::Ofdm.build(48000, LineConfig::default()), channel, 1.0);
+    }
+
+    #[test]
+    fn test_psk_phase_shift() {
+        // the phase of the carrier after the preamble is shifted by 180 degrees, the differential phase does not change
+        let channel = ChannelConfig {
+            gain: 0.5,
+            ..Default::default()
+        };
+        for kind in [ModulationKind::Bpsk, ModulationKind::Qpsk] {
+            round_trip(kind.build(48000, LineConfig::default()), channel, -1.0);
+        }
+    }
+}
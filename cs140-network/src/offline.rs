@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use crate::backend::NullBackend;
+use crate::encoding::LineCode;
+use crate::fec::Fec;
+use crate::modulation::Modulation;
+use crate::physical::PhysicalLayer;
+use crate::redundancy::{BYTE_IN_HEADER, RedundancyPackage};
+use crate::sample_reader::LineConfig;
+use crate::stats::PhysicalStats;
+
+/// DecodedFrame is a frame found in a recording
+#[derive(Debug, Clone)]
+pub struct DecodedFrame {
+    /// the package after the fec is removed, None if the fec can not correct the frame or the frame is an erasure
+    pub package: Option<RedundancyPackage>,
+    /// the bytes corrected by the fec
+    pub corrected_bytes: usize,
+    /// the invalid line symbols of the frame
+    pub symbol_errors: usize,
+}
+
+impl DecodedFrame {
+    /// whether the frame is received by the RedundancyLayer
+    pub fn is_valid(&self) -> bool {
+        matches!(&self.package, Some(package) if package.validate_checksum())
+    }
+
+    /// the source and the destination address, None if the frame is too short to have a header
+    pub fn address(&self) -> Option<(u8, u8)> {
+        self.package.as_ref().filter(|package| package.data.len() >= BYTE_IN_HEADER).map(|package| package.address())
+    }
+}
+
+/// OfflineDecoder finds the frames in the samples of a recording by the receivers of the PhysicalLayer and the RedundancyLayer,
+/// without any sound card. The parameters must be the ones of the sender.
+pub struct OfflineDecoder {
+    physical: PhysicalLayer,
+    fec: Fec,
+}
+
+impl OfflineDecoder {
+    pub fn new(sample_rate: u32, max_package_byte_len: usize) -> Self {
+        Self {
+            physical: PhysicalLayer::with_backend(NullBackend::new(sample_rate), 0, max_package_byte_len),
+            fec: Fec::None,
+        }
+    }
+
+    pub fn with_line_config(mut self, line: LineConfig) -> Self {
+        self.physical = self.physical.with_line_config(line);
+        self
+    }
+
+    pub fn with_line_code(mut self, line_code: Box<dyn LineCode>) -> Self {
+        self.physical = self.physical.with_line_code(line_code);
+        self
+    }
+
+    /// the modulation of the sender, call it after with_line_config
+    pub fn with_modulation(mut self, modulation: Box<dyn Modulation>) -> Self {
+        self.physical = self.physical.with_modulation(modulation);
+        self
+    }
+
+    pub fn with_fec(mut self, fec: Fec) -> Self {
+        self.fec = fec;
+        self
+    }
+
+    /// the statistics of the frames found by all the calls of decode
+    pub fn physical_stats(&self) -> Arc<PhysicalStats> {
+        self.physical.stats()
+    }
+
+    /// all the frames in the samples of a mono recording, including the frames with a wrong checksum
+    pub fn decode(&mut self, samples: &[f32]) -> Vec<DecodedFrame> {
+        self.physical.decode(samples).into_iter().map(|package| {
+            let symbol_errors = package.symbol_errors().len();
+            match RedundancyPackage::from_fec(&self.fec, package) {
+                Some((package, corrected_bytes)) => DecodedFrame {
+                    package: Some(package),
+                    corrected_bytes,
+                    symbol_errors,
+                },
+                None => DecodedFrame {
+                    package: None,
+                    corrected_bytes: 0,
+                    symbol_errors,
+                },
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use serde::Deserialize;
+
+    use crate::backend::read_wav;
+    use crate::channel::{ChannelConfig, ChannelSimulator};
+    use crate::encoding::{BitStore, FourBFiveB};
+    use crate::modulation::{BasebandModulation, Modulator};
+    use crate::physical::PhysicalPackage;
+    use crate::preamble::Preamble;
+
+    use super::*;
+
+    // the recordings in the corpus and the count of valid frames in them, see corpus/manifest.toml
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Recording {
+        file: String,
+        sample_per_bit: usize,
+        max_package_byte_len: usize,
+        #[serde(default)]
+        fec_parity_len: usize,
+        valid_frames: usize,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Manifest {
+        recording: Vec<Recording>,
+    }
+
+    fn corpus_directory() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus")
+    }
+
+    fn fec(parity_len: usize) -> Fec {
+        if parity_len == 0 { Fec::None } else { Fec::reed_solomon(parity_len) }
+    }
+
+    #[test]
+    fn test_corpus() {
+        let directory = corpus_directory();
+        let manifest: Manifest = toml::from_str(&std::fs::read_to_string(directory.join("manifest.toml")).unwrap()).unwrap();
+        for recording in manifest.recording {
+            let (sample_rate, samples) = read_wav(&directory.join(&recording.file)).unwrap();
+            let mut decoder = OfflineDecoder::new(sample_rate, recording.max_package_byte_len)
+                .with_line_config(LineConfig::sample_per_bit(recording.sample_per_bit))
+                .with_fec(fec(recording.fec_parity_len));
+            let frames = decoder.decode(&samples);
+            let valid_frames = frames.iter().filter(|frame| frame.is_valid()).count();
+            assert_eq!(valid_frames, recording.valid_frames, "{}", recording.file);
+        }
+    }
+
+    // write the synthetic recordings of the corpus, run it with --ignored after the frame format changes
+    #[test]
+    #[ignore]
+    fn generate_corpus() {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let recordings = [
+            ("clean.wav", ChannelConfig::default(), 0),
+            ("noisy.wav", ChannelConfig { seed: 1, snr_db: Some(14.0), gain: 0.3, ..Default::default() }, 0),
+            ("drift.wav", ChannelConfig { seed: 2, snr_db: Some(25.0), gain: 0.5, drift_ppm: 200.0, ..Default::default() }, 0),
+            ("fec.wav", ChannelConfig { seed: 3, snr_db: Some(14.0), gain: 0.3, ..Default::default() }, 8),
+        ];
+        for (file, channel, parity_len) in recordings {
+            let modulation = BasebandModulation::new();
+            let mut samples = vec![0.0; 1000];
+            for index in 0..8u8 {
+                let data: Vec<u8> = (0..32).map(|x| x * 7 + index).collect();
+                let package = RedundancyPackage::new(data.into_iter(), 32, index % 2 == 0, index % 3, 1);
+                let package = PhysicalPackage::from(BitStore::from_vec(fec(parity_len).encode(&package.data)));
+                samples.extend_from_slice(Preamble::modulated(&modulation).samples());
+                samples.extend(modulation.modulate(&package.to_samples(&FourBFiveB)));
+                samples.extend(vec![0.0; 200]);
+            }
+            samples.extend(vec![0.0; 1000]);
+            let samples = ChannelSimulator::new(channel).transmit(&samples);
+            let mut writer = hound::WavWriter::create(corpus_directory().join(file), spec).unwrap();
+            for sample in samples {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use clap::{App, Arg};
+
+use cs140_network::backend::read_wav;
+use cs140_network::encoding::LineCodeKind;
+use cs140_network::fec::Fec;
+use cs140_network::modulation::ModulationKind;
+use cs140_network::offline::{DecodedFrame, OfflineDecoder};
+use cs140_network::sample_reader::{LineConfig, TimingRecovery};
+
+fn hex_dump(data: &[u8]) {
+    for (index, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = line.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        println!("    {:08x}  {:<47}  |{}|", index * 16, hex.join(" "), text);
+    }
+}
+
+fn print_frame(index: usize, frame: &DecodedFrame) {
+    let package = match &frame.package {
+        None if frame.symbol_errors > 0 && frame.corrected_bytes == 0 => {
+            println!("frame {}: dropped, {} invalid line symbols", index, frame.symbol_errors);
+            return;
+        }
+        None => {
+            println!("frame {}: fec failed", index);
+            return;
+        }
+        Some(package) => package,
+    };
+    let status = if frame.is_valid() { "ok" } else { "failed" };
+    match frame.address() {
+        None => println!("frame {}: crc {}, {} bytes, too short for a header", index, status, package.data.len()),
+        Some((src, dest)) => println!("frame {}: crc {} ({:?}), {} -> {}, {} bytes{}, arq {:?}, {} bytes corrected",
+                                      index, status, package.checksum_type(), src, dest, package.data.len(),
+                                      if package.has_more_fragments() { ", more fragments" } else { "" },
+                                      package.arq_header(), frame.corrected_bytes),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let matches = App::new("offline_decoder")
+        .about("decode the frames in mono wav recordings by the receiver of the physical and the redundancy layer")
+        .arg(Arg::with_name("recording").help("the wav files, only the first channel is decoded").required(true).multiple(true))
+        .arg(Arg::with_name("sample-per-bit").long("sample-per-bit").takes_value(true).default_value("2"))
+        .arg(Arg::with_name("timing-recovery").long("timing-recovery").takes_value(true).default_value("bitslip").help("bitslip or gardner, gardner also estimates the clock offset of the recording"))
+        .arg(Arg::with_name("line-code").long("line-code").takes_value(true).default_value("4b5b").help("4b5b, manchester or 8b10b"))
+        .arg(Arg::with_name("modulation").long("modulation").takes_value(true).default_value("baseband").help("baseband, fsk, bpsk, qpsk or ofdm"))
+        .arg(Arg::with_name("max-package-byte-len").long("max-package-byte-len").takes_value(true).default_value("256"))
+        .arg(Arg::with_name("fec-parity-len").long("fec-parity-len").takes_value(true).default_value("0").help("the parity bytes of a Reed-Solomon codeword, 0 if the fec is disabled"))
+        .arg(Arg::with_name("fec-depth").long("fec-depth").takes_value(true).default_value("1").help("the min count of the interleaved codewords in a frame"))
+        .get_matches();
+    let timing_recovery: TimingRecovery = matches.value_of("timing-recovery").unwrap().parse()?;
+    let line = LineConfig {
+        timing_recovery,
+        ..LineConfig::sample_per_bit(matches.value_of("sample-per-bit").unwrap().parse()?)
+    };
+    line.validate()?;
+    let line_code: LineCodeKind = matches.value_of("line-code").unwrap().parse()?;
+    let modulation: ModulationKind = matches.value_of("modulation").unwrap().parse()?;
+    let max_package_byte_len: usize = matches.value_of("max-package-byte-len").unwrap().parse()?;
+    let fec_parity_len: usize = matches.value_of("fec-parity-len").unwrap().parse()?;
+    let fec_depth: usize = matches.value_of("fec-depth").unwrap().parse()?;
+    anyhow::ensure!(fec_depth > 0, "the fec depth must be positive");
+
+    for path in matches.values_of("recording").unwrap() {
+        let (sample_rate, samples) = read_wav(Path::new(path))?;
+        println!("{}: {} samples at {} Hz", path, samples.len(), sample_rate);
+        let mut decoder = OfflineDecoder::new(sample_rate, max_package_byte_len)
+            .with_line_config(line)
+            .with_line_code(line_code.build())
+            .with_fec(if fec_parity_len == 0 { Fec::None } else { Fec::reed_solomon(fec_parity_len).with_depth(fec_depth) });
+        if modulation != ModulationKind::Baseband {
+            decoder = decoder.with_modulation(modulation.build(sample_rate, line));
+        }
+        let frames = decoder.decode(&samples);
+        // the fragments of the ip packages are merged per source like the IPLayer does
+        let mut fragments: HashMap<u8, Vec<u8>> = HashMap::new();
+        for (index, frame) in frames.iter().enumerate() {
+            print_frame(index, frame);
+            let package = match &frame.package {
+                Some(package) if frame.is_valid() => package,
+                _ => continue,
+            };
+            let (src, _) = package.address();
+            let merged = fragments.entry(src).or_default();
+            merged.extend_from_slice(package.data());
+            if !package.has_more_fragments() {
+                let data = fragments.remove(&src).unwrap();
+                println!("  ip package from {}, {} bytes", src, data.len());
+                hex_dump(&data);
+            }
+        }
+        for (src, data) in fragments {
+            println!("  incomplete ip package from {}, {} bytes", src, data.len());
+            hex_dump(&data);
+        }
+        let stats = decoder.physical_stats().snapshot();
+        println!("{} frames, {} valid, {} bit slips, {} invalid line symbols", frames.len(), frames.iter().filter(|frame| frame.is_valid()).count(), stats.bit_slips, stats.decode_failures);
+        if timing_recovery != TimingRecovery::BitSlip && !frames.is_empty() {
+            println!("clock offset of the last frame: {} ppm", stats.clock_offset_ppm);
+        }
+        println!();
+    }
+    Ok(())
+}
@@ -1,136 +1,1356 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use cpal::traits::{DeviceTrait, HostTrait};
+use log::{debug, trace, warn};
+use tokio::sync::watch;
 
-use cs140_buffer::ring_buffer::RingBuffer;
-use cs140_common::buffer::Buffer;
+use cs140_buffer::broadcast_ring_buffer::{BroadcastSubscriber, SlowSubscriber};
+use cs140_common::buffer::{PopBuffer, PushBuffer, ring_range};
 use cs140_common::descriptor::SoundDescriptor;
-use cs140_common::device::{InputDevice, OutputDevice};
+use cs140_common::device::LinkStatus;
 
-use crate::encoding::{BitStore, decode_4b5b, decode_nrzi, encode_4b5b, encode_nrzi, HandlePackageMut, NetworkPackage};
-use crate::sample_reader::{SampleReader, ZeroReader};
+use crate::backend::{AudioBackend, CpalBackend, DefaultBuffer, InputBuffer};
+use crate::csma::{Backoff, CollisionDetection, CsmaConfig, SendError};
+use crate::diagnostic::DiagnosticTap;
+use crate::encoding::{BitStore, FourBFiveB, HandlePackageMut, LineCode, NetworkPackage, SymbolError};
+use crate::modulation::{BasebandModulation, Demodulator, Modulation};
+use crate::preamble::Preamble;
+use crate::sample_reader::{LineConfig, ZeroReader};
+use crate::stats::PhysicalStats;
+use crate::training::{ChannelMeasurement, LinkQuality, NOISE_FLOOR_SAMPLE_COUNT, training_pattern};
 
-type DefaultBuffer = RingBuffer<f32, 5000000>;
+// PhysicalFrame
+// preamble: Barker-13, one chip per bit, modulated like the rest of the frame
+// length: BYTE_IN_PHYSICAL_LENGTH, the count of bytes in data, little endian
+// data: len(data)
+// length and data are encoded by the LineCode of the layer, 4B5B and NRZI by default, then modulated by the Modulation of the layer
+pub const BYTE_IN_PHYSICAL_LENGTH: usize = 2;
+// a bit slip or a clock drift consumes more samples, this is the margin of samples in a frame
+static SAMPLE_MARGIN_IN_FRAME: usize = 64;
+// the line parameters that the receiver looks for when it can not find a frame of its own parameters
+static CANDIDATE_SAMPLE_PER_BIT: [usize; 4] = [2, 3, 4, 8];
+static CANDIDATE_SAMPLE_RATE: [u32; 3] = [44100, 48000, 96000];
+
+// the count of samples between the preambles of the two halves of a striped frame
+static STRIPE_SKEW_SAMPLE_COUNT: usize = 8;
+
+/// LaneMode is how a frame uses the channels of a stereo device
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LaneMode {
+    /// one channel, the backend must have one channel
+    Mono,
+    /// the first half of a frame on the left channel and the second half on the right channel at the same time,
+    /// which doubles the throughput
+    Striped,
+    /// the frame on the left channel and the inverted frame on the right channel,
+    /// the receiver reads the half of the difference which rejects the noise common to both channels
+    Differential,
+}
+
+impl LaneMode {
+    /// the count of channels in the buffers of the backend
+    pub fn channels(&self) -> u16 {
+        match self {
+            LaneMode::Mono => 1,
+            LaneMode::Striped | LaneMode::Differential => 2,
+        }
+    }
+}
+
+impl FromStr for LaneMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "mono" => Ok(LaneMode::Mono),
+            "striped" => Ok(LaneMode::Striped),
+            "differential" => Ok(LaneMode::Differential),
+            _ => Err(anyhow::anyhow!("unknown lane mode {}", s)),
+        }
+    }
+}
+
+/// LinkMismatch is the guessed line parameters of a preamble that is sent with other parameters than the receiver
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LinkMismatch {
+    pub sample_per_bit: usize,
+    pub sample_rate: u32,
+}
 
 pub struct PhysicalLayer {
+    // the backend keeps streaming as long as the layer is alive
+    #[allow(dead_code)]
+    backend: Box<dyn AudioBackend>,
     input_descriptor: SoundDescriptor,
-    input_buffer: Arc<DefaultBuffer>,
+    input_buffer: Arc<InputBuffer>,
     output_descriptor: SoundDescriptor,
     output_buffer: Arc<DefaultBuffer>,
+    link_status: watch::Receiver<LinkStatus>,
     padding_zero_byte_len: usize,
     max_package_byte_len: usize,
+    line: LineConfig,
+    lane_mode: LaneMode,
     zero_reader: ZeroReader,
+    preamble: Preamble,
+    modulation: Box<dyn Modulation>,
+    line_code: Box<dyn LineCode>,
+    // the stretched baseband preambles of other line parameters, empty for other modulations
+    mismatch_preambles: Vec<(LinkMismatch, Preamble)>,
+    link_mismatch: Option<LinkMismatch>,
+    link_quality: Option<LinkQuality>,
+    calibration: Calibration,
+    // None if the link is not trained when it comes up
+    training_timeout: Option<Duration>,
+    trained: bool,
+    // the frames received while waiting for the training report of the peer
+    received_during_training: VecDeque<PhysicalPackage>,
+    // None if the carrier is not sensed before sending
+    backoff: Option<Backoff>,
+    stats: Arc<PhysicalStats>,
+    // None if the detected frames are not saved
+    diagnostic_tap: Option<DiagnosticTap>,
+}
+
+// what the receiver records about the detected frames
+struct FrameMonitor<'a> {
+    stats: &'a PhysicalStats,
+    diagnostic_tap: Option<&'a mut DiagnosticTap>,
+}
+
+// what the receiver keeps from the training of the link
+#[derive(Debug, Default, Copy, Clone)]
+struct Calibration {
+    // removed from the received samples
+    dc_offset: f32,
+    // the samples below it are the noise of the idle line
+    noise_threshold: f32,
+}
+
+impl Calibration {
+    fn signal_threshold(&self, zero_reader: &ZeroReader) -> f32 {
+        f32::max(zero_reader.signal_threshold(), self.noise_threshold)
+    }
 }
 
-pub struct PhysicalPackage(BitStore);
+// the format of the frames on the line and how they are received
+struct FrameFormat<'a> {
+    preamble: &'a Preamble,
+    line_code: &'a dyn LineCode,
+    max_package_byte_len: usize,
+    calibration: Calibration,
+}
+
+pub struct PhysicalPackage {
+    bits: BitStore,
+    symbol_errors: Vec<SymbolError>,
+}
 
 impl PhysicalPackage {
-    fn to_samples(&self) -> BitStore {
-        let bits = &self.0;
-        let bits = encode_4b5b(bits);
-        let bits = encode_nrzi(&bits);
-        bits
+    /// the invalid line symbols of a received package, the bits of the package are zeros where they are
+    pub fn symbol_errors(&self) -> &[SymbolError] {
+        &self.symbol_errors
+    }
+
+    // the line bits of the frame
+    pub(crate) fn to_samples(&self, line_code: &dyn LineCode) -> BitStore {
+        let mut bits = self.bits.clone();
+        // the length field counts whole bytes
+        bits.resize((bits.len() + 7) / 8 * 8, false);
+        let mut length = bits.len() / 8;
+        let mut frame = BitStore::with_capacity(bits.len() + BYTE_IN_PHYSICAL_LENGTH * 8);
+        for _ in 0..BYTE_IN_PHYSICAL_LENGTH {
+            frame.extend_from_bitslice(BitStore::from_element((length & 0xff) as u8).as_bitslice());
+            length >>= 8;
+        }
+        frame.extend_from_bitslice(bits.as_bitslice());
+        line_code.encode(&frame)
+    }
+
+    // the package of the line bits of a frame whose length is valid
+    pub(crate) fn from_bits(bits: &BitStore, line_code: &dyn LineCode) -> Self {
+        let (bits, symbol_errors) = line_code.decode(bits);
+        let header_bit_count = BYTE_IN_PHYSICAL_LENGTH * 8;
+        PhysicalPackage {
+            bits: bits[header_bit_count..].to_bitvec(),
+            symbol_errors: symbol_errors.into_iter().filter(|error| error.bit_index >= header_bit_count).map(|error| SymbolError {
+                bit_index: error.bit_index - header_bit_count,
+                ..error
+            }).collect(),
+        }
+    }
+
+    // split into the first half of the bytes and the rest for the striped lanes
+    fn split(&self) -> (Self, Self) {
+        let half = std::cmp::min((self.bits.len() + 15) / 16 * 8, self.bits.len());
+        (PhysicalPackage::from(self.bits[..half].to_bitvec()), PhysicalPackage::from(self.bits[half..].to_bitvec()))
     }
 
-    fn from_bits(bits: &BitStore) -> Self {
-        let bits = decode_nrzi(bits);
-        let bits = decode_4b5b(&bits);
-        PhysicalPackage(bits)
+    // the package of the two halves of the striped lanes
+    fn merge(left: Self, right: Self) -> Self {
+        let offset = left.bits.len();
+        let mut package = left;
+        package.bits.extend_from_bitslice(right.bits.as_bitslice());
+        package.symbol_errors.extend(right.symbol_errors.into_iter().map(|error| SymbolError {
+            bit_index: error.bit_index + offset,
+            ..error
+        }));
+        package
     }
 }
 
 impl From<PhysicalPackage> for BitStore {
     fn from(package: PhysicalPackage) -> Self {
-        package.0
+        package.bits
     }
 }
 
 impl From<BitStore> for PhysicalPackage {
     fn from(bits: BitStore) -> Self {
-        PhysicalPackage(bits)
+        PhysicalPackage {
+            bits,
+            symbol_errors: Vec::new(),
+        }
     }
 }
 
 impl NetworkPackage for PhysicalPackage {}
 
+enum FrameSearch {
+    // no frame is found, the samples before the index can be dropped
+    NotFound(usize),
+    // a frame, the index of its preamble and the count of samples used by it
+    Found(PhysicalPackage, usize, usize),
+}
+
 impl PhysicalLayer {
     pub fn new(padding_zero_byte_len: usize, max_package_byte_len: usize) -> Self {
-        let host = cpal::default_host();
-        for (index, input_) in host.input_devices().unwrap().enumerate() {
-            println!("input_device {}: {}", index, input_.name().unwrap());
-        }
-        println!("please choose your input audio device: ");
-        let mut buf = String::new();
-        std::io::stdin().read_line(&mut buf).unwrap();
-        let input = buf.trim().parse().unwrap();
-        let input_buffer = Arc::new(DefaultBuffer::new());
-        let (input_device, input_descriptor) = InputDevice::new_with_specific_device(input_buffer.clone(), input);
-
-        for (index, output_) in host.output_devices().unwrap().enumerate() {
-            println!("output_device {}: {}", index, output_.name().unwrap());
-        }
-        println!("please choose your output audio device: ");
-        buf.clear();
-        std::io::stdin().read_line(&mut buf).unwrap();
-        let output = buf.trim().parse().unwrap();
-        let output_buffer = Arc::new(DefaultBuffer::new());
-        let (output_device, output_descriptor) = OutputDevice::new_with_specific_device(output_buffer.clone(), output);
-        input_device.listen();
-        output_device.play();
+        Self::with_backend(CpalBackend::choose_from_stdin(), padding_zero_byte_len, max_package_byte_len)
+    }
+
+    pub fn with_backend(backend: impl AudioBackend + 'static, padding_zero_byte_len: usize, max_package_byte_len: usize) -> Self {
+        let (input_descriptor, input_buffer) = backend.input();
+        let (output_descriptor, output_buffer) = backend.output();
+        if input_descriptor.sample_rate != output_descriptor.sample_rate {
+            warn!("input sample rate {} is different from output sample rate {}", input_descriptor.sample_rate, output_descriptor.sample_rate);
+        }
+        let link_status = backend.link_status();
         PhysicalLayer {
+            backend: Box::new(backend),
             input_descriptor,
-            input_buffer,
+            input_buffer: Arc::new(input_buffer),
             output_descriptor,
             output_buffer,
+            link_status,
             padding_zero_byte_len,
             max_package_byte_len,
-            zero_reader: ZeroReader::new(),
+            line: LineConfig::default(),
+            lane_mode: LaneMode::Mono,
+            zero_reader: ZeroReader::new(LineConfig::default()),
+            preamble: Preamble::modulated(&BasebandModulation::new()),
+            modulation: Box::new(BasebandModulation::new()),
+            line_code: Box::new(FourBFiveB),
+            mismatch_preambles: Vec::new(),
+            link_mismatch: None,
+            link_quality: None,
+            calibration: Calibration::default(),
+            training_timeout: None,
+            trained: false,
+            received_during_training: VecDeque::new(),
+            backoff: None,
+            stats: Arc::new(PhysicalStats::default()),
+            diagnostic_tap: None,
+        }.with_line_config(LineConfig::default())
+    }
+
+    /// replace the line config and the modulation by the baseband modulation of the line config,
+    /// call it before with_modulation. It panics if the line config is invalid.
+    pub fn with_line_config(mut self, line: LineConfig) -> Self {
+        line.validate().unwrap();
+        let modulation = BasebandModulation::with_config(line);
+        self.line = line;
+        self.zero_reader = ZeroReader::new(line);
+        self.preamble = Preamble::modulated(&modulation);
+        self.modulation = Box::new(modulation);
+        self.mismatch_preambles = Self::build_mismatch_preambles(line.sample_per_bit, self.input_descriptor.sample_rate);
+        self
+    }
+
+    /// replace the baseband modulation, both ends of the link must use the same modulation.
+    /// Mismatched line parameters are only detected for the baseband modulation.
+    pub fn with_modulation(mut self, modulation: Box<dyn Modulation>) -> Self {
+        self.preamble = Preamble::modulated(modulation.as_ref());
+        self.modulation = modulation;
+        self.mismatch_preambles.clear();
+        self
+    }
+
+    /// replace the 4B5B and NRZI line code, both ends of the link must use the same line code
+    pub fn with_line_code(mut self, line_code: Box<dyn LineCode>) -> Self {
+        self.line_code = line_code;
+        self
+    }
+
+    /// use the channels of the backend by the lane mode, the backend must have lane_mode.channels() channels
+    pub fn with_lane_mode(mut self, lane_mode: LaneMode) -> Self {
+        assert_eq!(self.input_descriptor.channels, lane_mode.channels(), "the input of the backend does not fit {:?}", lane_mode);
+        assert_eq!(self.output_descriptor.channels, lane_mode.channels(), "the output of the backend does not fit {:?}", lane_mode);
+        self.lane_mode = lane_mode;
+        self
+    }
+
+    /// sense the carrier before sending and wait for a random backoff if the medium is busy,
+    /// for the links shared by more than two nodes or used in half duplex
+    pub fn with_csma(mut self, config: CsmaConfig) -> Self {
+        self.backoff = Some(Backoff::new(config, rand::random()));
+        self
+    }
+
+    /// save the frames failing the decoding and how their bits are read by the tap, see DiagnosticTap
+    pub fn with_diagnostic_tap(mut self, mut tap: DiagnosticTap) -> Self {
+        tap.sample_rate = self.input_descriptor.sample_rate;
+        self.diagnostic_tap = Some(tap);
+        self
+    }
+
+    /// save the last received frame by the diagnostic tap, for the layers above to save the frames failing their checks.
+    /// The frames with invalid line symbols are already saved.
+    pub fn capture_last_frame(&mut self) {
+        if let Some(tap) = self.diagnostic_tap.as_mut() {
+            tap.capture_held();
         }
     }
 
-    pub fn max_package_byte(&self) -> usize {
+    /// the statistics of the received frames, keep the Arc to read them after the layer is moved into the upper layers
+    pub fn stats(&self) -> Arc<PhysicalStats> {
+        self.stats.clone()
+    }
+
+    /// the status of the sound card, the receiver is notified when the devices are lost or come back
+    pub fn link_status(&self) -> watch::Receiver<LinkStatus> {
+        self.link_status.clone()
+    }
+
+    /// subscribe the samples received from now on besides the layer, such as for a recorder,
+    /// None if the backend does not share its input
+    pub fn subscribe_input(&self, slow: SlowSubscriber) -> Option<BroadcastSubscriber<f32>> {
+        self.input_buffer.subscribe(slow)
+    }
+
+    pub fn lane_mode(&self) -> LaneMode {
+        self.lane_mode
+    }
+
+    pub fn line_config(&self) -> &LineConfig {
+        &self.line
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.input_descriptor.sample_rate
+    }
+
+    /// the line parameters of the last preamble that does not match the parameters of this layer.
+    /// It is cleared when a frame is received.
+    pub fn link_mismatch(&self) -> Option<LinkMismatch> {
+        self.link_mismatch
+    }
+
+    /// the result of the last training
+    pub fn link_quality(&self) -> Option<LinkQuality> {
+        self.link_quality
+    }
+
+    /// train the link when it comes up, which is before the first frame is sent or received and after the status of the devices changes.
+    /// The peer should train at the same time, such as by this option too. The frames of the peer before its training pattern are lost.
+    pub fn with_training(mut self, timeout: Duration) -> Self {
+        self.training_timeout = Some(timeout);
+        self
+    }
+
+    // train the link if it is up and not trained since it comes up, a cancelled training is started again
+    async fn train_on_link_up(&mut self) {
+        let timeout = match self.training_timeout {
+            None => return,
+            Some(timeout) => timeout,
+        };
+        if self.link_status.has_changed().unwrap_or(false) {
+            self.link_status.borrow_and_update();
+            self.trained = false;
+        }
+        if self.trained || !self.link_status.borrow().is_up() {
+            return;
+        }
+        match self.train(timeout).await {
+            Some(link_quality) => debug!("the link is trained: {:?}", link_quality),
+            None => warn!("the training pattern of the peer is not received in {:?}", timeout),
+        }
+        self.trained = true;
+    }
+
+    /// train the link with the peer, which should call train at about the same time.
+    /// Each end sends the training pattern, measures the training pattern of the peer, calibrates its receiver
+    /// by the dc offset, the noise floor and the amplitude of the measurement, and reports the measurement back to the peer.
+    /// The other frames received before the report are kept for receive.
+    /// It returns None if the training pattern of the peer is not received before the timeout.
+    pub async fn train(&mut self, timeout: Duration) -> Option<LinkQuality> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let pattern = Preamble::from_samples(self.modulation.modulate(&training_pattern()));
+        let mut samples = pattern.samples().to_vec();
+        samples.extend(std::iter::repeat(0.0).take(self.padding_zero_byte_len * 8));
+        let samples: Vec<f32> = match self.lane_mode {
+            LaneMode::Mono => samples,
+            LaneMode::Striped => samples.into_iter().flat_map(|sample| [sample, sample]).collect(),
+            LaneMode::Differential => samples.into_iter().flat_map(|sample| [sample, -sample]).collect(),
+        };
+        self.output_buffer.push_by_ref(&samples).await;
+
+        let lane_mode = self.lane_mode;
+        let channels = lane_mode.channels() as usize;
+        let acceptable_signal_range = self.line.acceptable_signal_range;
+        let input_buffer = self.input_buffer.clone();
+        let window = (pattern.len() + NOISE_FLOOR_SAMPLE_COUNT) * 2;
+        // the training pattern is received on the left lane, the noise floor is measured by the samples before it
+        let receive = tokio::time::timeout_at(deadline, async {
+            loop {
+                let measurement = input_buffer.pop_by_ref(window * channels, |data| {
+                    let lane: Vec<f32> = match lane_mode {
+                        LaneMode::Mono => data.to_vec(),
+                        LaneMode::Striped => data.iter().step_by(2).cloned().collect(),
+                        LaneMode::Differential => data.chunks_exact(2).map(|frame| (frame[0] - frame[1]) / 2.0).collect(),
+                    };
+                    let search_len = lane.len() - pattern.len();
+                    // the pattern is found without the dc offset, which is measured with the amplitude
+                    let mean = lane.iter().sum::<f32>() / lane.len() as f32;
+                    let centered: Vec<f32> = lane.iter().map(|sample| sample - mean).collect();
+                    match pattern.find(&centered, search_len, 0.0) {
+                        None => (None, search_len * channels),
+                        Some(found) => {
+                            let received = &lane[found.index..found.index + pattern.len()];
+                            let idle = &lane[found.index.saturating_sub(NOISE_FLOOR_SAMPLE_COUNT)..found.index];
+                            let measurement = ChannelMeasurement::measure(pattern.samples(), received, idle, acceptable_signal_range);
+                            (Some(measurement), (found.index + pattern.len()) * channels)
+                        }
+                    }
+                }).await;
+                if let Some(measurement) = measurement {
+                    return measurement;
+                }
+            }
+        }).await.ok()?;
+        // the signal threshold of the zero reader is acceptable_signal_range * amplitude
+        let amplitude = receive.decision_threshold / acceptable_signal_range;
+        self.zero_reader = ZeroReader::with_amplitude(self.line, amplitude, -amplitude);
+        self.calibration = Calibration {
+            dc_offset: receive.dc_offset,
+            noise_threshold: receive.noise_threshold(),
+        };
+        if let Err(err) = self.send_frame(&PhysicalPackage::from(receive.to_report())).await {
+            warn!("drop the training report: {:?}", err);
+        }
+
+        let send = tokio::time::timeout_at(deadline, async {
+            loop {
+                let package = self.receive_frame().await;
+                match ChannelMeasurement::from_report(&package.bits) {
+                    Some(measurement) => return measurement,
+                    None => {
+                        trace!("keep a frame of {} bits received during the training", package.bits.len());
+                        self.received_during_training.push_back(package);
+                    }
+                }
+            }
+        }).await.ok();
+        let link_quality = LinkQuality { receive, send };
+        self.link_quality = Some(link_quality);
+        Some(link_quality)
+    }
+
+    // a preamble sent with sample_per_bit at sample_rate is received with sample_per_bit * own_sample_rate / sample_rate samples per chip
+    fn build_mismatch_preambles(own_sample_per_bit: usize, own_sample_rate: u32) -> Vec<(LinkMismatch, Preamble)> {
+        let mut sample_rates = vec![own_sample_rate];
+        sample_rates.extend(CANDIDATE_SAMPLE_RATE.iter().filter(|&&sample_rate| sample_rate != own_sample_rate));
+        let mut sample_per_chips = vec![own_sample_per_bit as f32];
+        let mut preambles = Vec::new();
+        for &sample_rate in &sample_rates {
+            for &sample_per_bit in &CANDIDATE_SAMPLE_PER_BIT {
+                let sample_per_chip = sample_per_bit as f32 * own_sample_rate as f32 / sample_rate as f32;
+                // the preambles which are almost the same can not be told apart
+                if sample_per_chip < 1.0 || sample_per_chips.iter().any(|x| (x - sample_per_chip).abs() < 0.25) {
+                    continue;
+                }
+                sample_per_chips.push(sample_per_chip);
+                preambles.push((LinkMismatch { sample_per_bit, sample_rate }, Preamble::barker_13_stretched(sample_per_chip)));
+            }
+        }
+        preambles
+    }
+
+    /// find a preamble of other line parameters starting in data[..search_len], it is only called when no frame is found
+    fn detect_mismatch(mismatch_preambles: &[(LinkMismatch, Preamble)], threshold: f32, data: &[f32], search_len: usize) -> Option<LinkMismatch> {
+        if data[..std::cmp::min(search_len, data.len())].iter().all(|x| x.abs() < threshold) {
+            return None;
+        }
+        mismatch_preambles.iter()
+            .find(|(_, preamble)| preamble.find(data, search_len, threshold).is_some())
+            .map(|(mismatch, _)| *mismatch)
+    }
+
+    /// wait until the backend takes the samples of the sent frames and plays the last buffer of the device,
+    /// or the flush timeout passes, then stop the backend. The frames not taken by the backend before the timeout are dropped.
+    pub async fn shutdown(self, flush_timeout: Duration) {
+        let deadline = tokio::time::Instant::now() + flush_timeout;
+        if self.output_buffer.drain_until(deadline).await.is_err() {
+            warn!("drop {} samples which are not sent before the shutdown", self.output_buffer.len());
+            return;
+        }
+        // the last samples popped by the device are still in its buffer until they are played
+        let channels = self.output_descriptor.channels as u64;
+        let period = Duration::from_micros(self.output_buffer.largest_must_pop() as u64 * 1_000_000 / channels / self.output_descriptor.sample_rate as u64);
+        tokio::time::sleep_until(std::cmp::min(tokio::time::Instant::now() + period, deadline)).await;
+    }
+
+    /// send the package, the medium is sensed first if csma is enabled.
+    /// It returns SendError::Collision if a collision is detected, the caller should call it again with the same package,
+    /// which waits for a longer backoff after each collision. It returns SendError::LinkDown without sending if a device is lost.
+    pub async fn try_send(&mut self, package: &PhysicalPackage) -> Result<(), SendError> {
+        self.train_on_link_up().await;
+        self.send_frame(package).await
+    }
+
+    async fn send_frame(&mut self, package: &PhysicalPackage) -> Result<(), SendError> {
+        if !self.link_status.borrow().is_up() {
+            return Err(SendError::LinkDown);
+        }
+        let samples = match self.lane_mode {
+            LaneMode::Mono => self.frame_samples(package),
+            LaneMode::Differential => self.frame_samples(package).into_iter().flat_map(|sample| [sample, -sample]).collect(),
+            LaneMode::Striped => {
+                let (left, right) = package.split();
+                let (left, right) = (self.frame_samples(&left), self.frame_samples(&right));
+                (0..std::cmp::max(left.len(), right.len()))
+                    .flat_map(|index| [left.get(index).cloned().unwrap_or(0.0), right.get(index).cloned().unwrap_or(0.0)])
+                    .collect()
+            }
+        };
+        let collision_detection = match self.backoff.as_ref() {
+            None => {
+                self.output_buffer.push_by_ref(&samples).await;
+                return Ok(());
+            }
+            Some(backoff) => backoff.config().collision_detection,
+        };
+        let mut busy = self.backoff.as_ref().unwrap().has_collided();
+        while busy || self.carrier_sense().await {
+            let backoff = self.backoff.as_mut().unwrap();
+            match backoff.next_delay() {
+                Some(delay) => {
+                    trace!("the medium is busy, wait for {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                }
+                None => {
+                    backoff.reset();
+                    return Err(SendError::Busy);
+                }
+            }
+            busy = false;
+        }
+        match collision_detection {
+            None => self.output_buffer.push_by_ref(&samples).await,
+            Some(detection) => {
+                let result = self.send_with_collision_detection(&samples, detection).await;
+                if result == Err(SendError::Collision) {
+                    self.backoff.as_mut().unwrap().collide();
+                }
+                result?;
+            }
+        }
+        self.backoff.as_mut().unwrap().reset();
+        Ok(())
+    }
+
+    /// send the package until it is sent or given up, it is sent again after each collision with a longer backoff.
+    /// The frame is dropped if the medium stays busy for all the attempts of the backoff or the link is down,
+    /// the error is returned and the frame is counted in the stats. on_collision is called after each collision.
+    pub async fn send_or_drop(&mut self, package: &PhysicalPackage, mut on_collision: impl FnMut() + Send) -> Result<(), SendError> {
+        loop {
+            match self.try_send(package).await {
+                Err(SendError::Collision) => on_collision(),
+                Err(err) => {
+                    self.stats.frames_dropped.fetch_add(1, Relaxed);
+                    return Err(err);
+                }
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+
+    // send the samples a few windows ahead of the backend and compare the received samples with them,
+    // returns SendError::Collision if the frame collides and the jam pattern is sent instead of the rest of the frame,
+    // or SendError::LinkDown if the backend does not play the samples of the previous frames before the deadline
+    async fn send_with_collision_detection(&mut self, samples: &[f32], detection: CollisionDetection) -> Result<(), SendError> {
+        let channels = self.lane_mode.channels() as usize;
+        let window = detection.window_sample_count * channels;
+        let max_latency = detection.max_latency * channels;
+        let sample_count_per_second = self.output_descriptor.sample_rate as usize * channels;
+        let window_duration = Duration::from_secs_f64(window as f64 / sample_count_per_second as f64);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1)
+            + Duration::from_secs_f64((self.output_buffer.len() + samples.len() + max_latency) as f64 / sample_count_per_second as f64);
+        // the samples of the previous frames are sent first, so the latency is measured from an empty output buffer
+        if self.output_buffer.drain_until(deadline).await.is_err() {
+            warn!("the samples of the previous frames are not played");
+            return Err(SendError::LinkDown);
+        }
+        let base = self.input_buffer.len();
+        let frame_power = samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32;
+        let first_window = std::cmp::min(window, samples.len());
+        let mut echo: Option<(usize, f32)> = None;
+        let mut sent = 0;
+        let mut checked = 0;
+        let collided = loop {
+            if checked >= samples.len() {
+                break false;
+            }
+            if sent < samples.len() && self.output_buffer.len() < 2 * window {
+                let end = std::cmp::min(sent + window, samples.len());
+                self.output_buffer.push_by_ref(&samples[sent..end]).await;
+                sent = end;
+                continue;
+            }
+            let end = std::cmp::min(checked + window, samples.len());
+            let required = match echo {
+                None => max_latency + first_window,
+                Some((offset, _)) => offset + end,
+            };
+            let sent_window = &samples[checked..end];
+            // wake up in a window to send more samples before the backend plays the queued windows
+            let wake = if sent < samples.len() {
+                std::cmp::min(deadline, tokio::time::Instant::now() + window_duration)
+            } else {
+                deadline
+            };
+            // the samples are left to the receiver
+            let result = self.input_buffer.peek_until(base + required, wake, |first, second| {
+                let received = ring_range(first, second, base..base + required);
+                match echo {
+                    None => detection.find_echo(&samples[..first_window], &received)
+                        .map(|(offset, gain)| (offset, gain, detection.collides(sent_window, &received[offset..offset + end], gain, frame_power))),
+                    Some((offset, gain)) => Some((offset, gain, detection.collides(sent_window, &received[offset + checked..offset + end], gain, frame_power))),
+                }
+            }).await;
+            let result = match result {
+                Ok(result) => result,
+                Err(_) if tokio::time::Instant::now() < deadline => continue,
+                Err(_) => {
+                    warn!("the sent samples are not received back");
+                    break true;
+                }
+            };
+            match result {
+                Some((offset, gain, false)) => {
+                    echo = Some((offset, gain));
+                    checked = end;
+                }
+                _ => break true,
+            }
+        };
+        if collided {
+            // the backend is the only consumer of the output buffer, so the at most two windows of the frame
+            // queued in it are played before the jam pattern
+            let jam: Vec<f32> = match self.lane_mode {
+                LaneMode::Mono => detection.jam(),
+                LaneMode::Differential => detection.jam().into_iter().flat_map(|sample| [sample, -sample]).collect(),
+                LaneMode::Striped => detection.jam().into_iter().flat_map(|sample| [sample, sample]).collect(),
+            };
+            self.output_buffer.push_by_ref(&jam).await;
+            debug!("a collision is detected after {} samples, send the jam pattern", checked);
+            return Err(SendError::Collision);
+        }
+        Ok(())
+    }
+
+    /// whether the latest received samples of any lane have energy
+    async fn carrier_sense(&mut self) -> bool {
+        let lane_mode = self.lane_mode;
+        let channels = lane_mode.channels() as usize;
+        let count = self.backoff.as_ref().map_or(0, |backoff| backoff.config().sense_sample_count) * channels;
+        let threshold = self.calibration.signal_threshold(&self.zero_reader);
+        let dc_offset = self.calibration.dc_offset;
+        // the samples are left to the receiver
+        self.input_buffer.peek(count, |first, second| {
+            let len = first.len() + second.len();
+            let latest = ring_range(first, second, len - count..len);
+            let lanes = Self::split_lanes(lane_mode, &latest, dc_offset);
+            let lanes: Vec<&[f32]> = if lanes.is_empty() { vec![&latest] } else { lanes.iter().map(|lane| lane.as_slice()).collect() };
+            lanes.iter().any(|lane| lane.iter().any(|sample| sample.abs() >= threshold))
+        }).await
+    }
+
+    pub fn max_package_byte_len(&self) -> usize {
         self.max_package_byte_len
     }
+
+    fn max_frame_sample_count(&self) -> usize {
+        self.preamble.len() + self.modulation.sample_count(self.line_code.encoded_bit_count(BYTE_IN_PHYSICAL_LENGTH + self.max_package_byte_len)) + SAMPLE_MARGIN_IN_FRAME
+    }
+
+    /// find the first frame in data whose preamble starts in data[..search_len]
+    fn search_frame(format: &FrameFormat, demodulator: &mut dyn Demodulator, zero_reader: &mut ZeroReader, monitor: &mut FrameMonitor, data: &[f32], search_len: usize) -> FrameSearch {
+        let preamble = format.preamble;
+        let mut start = 0;
+        loop {
+            let found = match preamble.find(&data[start..], search_len.saturating_sub(start), format.calibration.signal_threshold(zero_reader)) {
+                None => return FrameSearch::NotFound(std::cmp::max(start, search_len)),
+                Some(found) => found,
+            };
+            let frame_start = start + found.index;
+            let frame = &data[frame_start + preamble.len()..];
+            demodulator.begin(&found);
+            if let Some((bits, sample_used)) = Self::read_frame(format, demodulator, frame) {
+                *zero_reader = ZeroReader::with_amplitude(zero_reader.line_config(), found.one_amplitude, found.neg_one_amplitude);
+                let package = PhysicalPackage::from_bits(&bits, format.line_code);
+                monitor.stats.frames_detected.fetch_add(1, Relaxed);
+                monitor.stats.bit_slips.fetch_add(demodulator.bit_slip_count(), Relaxed);
+                monitor.stats.decode_failures.fetch_add(package.symbol_errors().len(), Relaxed);
+                if let Some(clock_offset_ppm) = demodulator.clock_offset_ppm() {
+                    monitor.stats.clock_offset_ppm.store(clock_offset_ppm.round() as i32, Relaxed);
+                }
+                if let Some(tap) = monitor.diagnostic_tap.as_mut() {
+                    let samples = &data[frame_start..frame_start + preamble.len() + sample_used];
+                    if package.symbol_errors().is_empty() {
+                        tap.hold(samples, preamble.len(), demodulator.trace());
+                    } else {
+                        tap.capture(samples, preamble.len(), demodulator.trace());
+                    }
+                }
+                return FrameSearch::Found(package, frame_start, frame_start + preamble.len() + sample_used);
+            }
+            trace!("false preamble at {}", frame_start);
+            start = frame_start + 1;
+        }
+    }
+
+    /// find the first frame in the lanes, there is one lane except for the striped mode.
+    /// In the striped mode the second half of the frame is searched near the start of the first half.
+    fn search_lanes(format: &FrameFormat, demodulator: &mut dyn Demodulator, zero_reader: &mut ZeroReader, monitor: &mut FrameMonitor, lanes: &[&[f32]], search_len: usize) -> FrameSearch {
+        let (left, start, end) = match Self::search_frame(format, demodulator, zero_reader, monitor, lanes[0], search_len) {
+            FrameSearch::Found(left, start, end) if lanes.len() > 1 => (left, start, end),
+            search => return search,
+        };
+        let right_start = start.saturating_sub(STRIPE_SKEW_SAMPLE_COUNT);
+        let right_search_len = start + STRIPE_SKEW_SAMPLE_COUNT + 1 - right_start;
+        match Self::search_frame(format, demodulator, zero_reader, monitor, &lanes[1][right_start..], right_search_len) {
+            FrameSearch::Found(right, _, right_end) => {
+                FrameSearch::Found(PhysicalPackage::merge(left, right), start, std::cmp::max(end, right_start + right_end))
+            }
+            FrameSearch::NotFound(_) => {
+                trace!("the second half of the striped frame at {} is lost", start);
+                FrameSearch::NotFound(start + 1)
+            }
+        }
+    }
+
+    // the lanes of the interleaved samples without the dc offset, which cancels out in the differential mode.
+    // It is empty in the mono mode without a dc offset, in which the samples are the lane
+    fn split_lanes(lane_mode: LaneMode, data: &[f32], dc_offset: f32) -> Vec<Vec<f32>> {
+        match lane_mode {
+            LaneMode::Mono if dc_offset == 0.0 => Vec::new(),
+            LaneMode::Mono => vec![data.iter().map(|sample| sample - dc_offset).collect()],
+            LaneMode::Differential => vec![data.chunks_exact(2).map(|frame| (frame[0] - frame[1]) / 2.0).collect()],
+            LaneMode::Striped => (0..2).map(|index| data.iter().skip(index).step_by(2).map(|sample| sample - dc_offset).collect()).collect(),
+        }
+    }
+
+    /// receive all the frames in the samples instead of the input buffer, such as the samples of a recording.
+    /// The samples are interleaved like the input of the backend, the frames are searched by the same receiver as receive.
+    pub fn decode(&mut self, data: &[f32]) -> Vec<PhysicalPackage> {
+        self.modulation.set_tracing(self.diagnostic_tap.is_some());
+        let mut monitor = FrameMonitor {
+            stats: self.stats.as_ref(),
+            diagnostic_tap: self.diagnostic_tap.as_mut(),
+        };
+        let lanes = Self::split_lanes(self.lane_mode, data, self.calibration.dc_offset);
+        let lanes: Vec<&[f32]> = if lanes.is_empty() { vec![data] } else { lanes.iter().map(|lane| lane.as_slice()).collect() };
+        let format = FrameFormat {
+            preamble: &self.preamble,
+            line_code: self.line_code.as_ref(),
+            max_package_byte_len: self.max_package_byte_len,
+            calibration: self.calibration,
+        };
+        let mut packages = Vec::new();
+        let mut start = 0;
+        loop {
+            let rest: Vec<&[f32]> = lanes.iter().map(|lane| &lane[start..]).collect();
+            match Self::search_lanes(&format, self.modulation.as_mut(), &mut self.zero_reader, &mut monitor, &rest, rest[0].len()) {
+                FrameSearch::NotFound(_) => return packages,
+                FrameSearch::Found(package, _, sample_used) => {
+                    packages.push(package);
+                    start += sample_used;
+                }
+            }
+        }
+    }
+
+    fn frame_samples(&self, package: &PhysicalPackage) -> Vec<f32> {
+        let mut samples: Vec<_> = self.preamble.samples().to_vec();
+        samples.extend(self.modulation.modulate(&package.to_samples(self.line_code.as_ref())));
+        samples.extend(std::iter::repeat(0.0).take(self.padding_zero_byte_len * 8));
+        samples
+    }
+
+    fn read_frame(format: &FrameFormat, demodulator: &mut dyn Demodulator, frame: &[f32]) -> Option<(BitStore, usize)> {
+        let (mut bits, header_sample_used) = demodulator.demodulate(frame, format.line_code.encoded_bit_count(BYTE_IN_PHYSICAL_LENGTH))?;
+        let (header, symbol_errors) = format.line_code.decode(&bits);
+        if !symbol_errors.is_empty() {
+            return None;
+        }
+        let length = header.into_vec().iter().rev().fold(0, |length, &byte| (length << 8) + byte as usize);
+        if length > format.max_package_byte_len {
+            return None;
+        }
+        let (data_bits, data_sample_used) = demodulator.demodulate(&frame[header_sample_used..], format.line_code.encoded_bit_count(length))?;
+        bits.extend_from_bitslice(data_bits.as_bitslice());
+        Some((bits, header_sample_used + data_sample_used))
+    }
 }
 
 #[async_trait]
 impl HandlePackageMut<PhysicalPackage> for PhysicalLayer {
     async fn send(&mut self, package: PhysicalPackage) {
-        let mut samples: Vec<_> = package.to_samples().into_iter().flat_map(|bit| {
-            if bit {
-                std::iter::repeat(1.0).take(2)
-            } else {
-                std::iter::repeat(-1.0).take(2)
-            }
-        }).collect();
-        samples.extend(std::iter::repeat(0.0).take(self.padding_zero_byte_len * 8));
-        self.output_buffer.push_by_ref(&samples).await;
+        if let Err(err) = self.send_or_drop(&package, || debug!("the frame collides, send it again")).await {
+            warn!("drop a frame: {:?}", err);
+        }
     }
 
     async fn receive(&mut self) -> PhysicalPackage {
+        self.train_on_link_up().await;
+        if let Some(package) = self.received_during_training.pop_front() {
+            return package;
+        }
+        self.receive_frame().await
+    }
+}
+
+impl PhysicalLayer {
+    async fn receive_frame(&mut self) -> PhysicalPackage {
         loop {
-            let something_more = 7;
-            let margin = (self.padding_zero_byte_len + something_more) * 8;
-            let max_sample_in_package = self.max_package_byte_len * 8 / 4 * 5 * 2 + margin;
-            let return_package = self.input_buffer.pop_by_ref(max_sample_in_package + margin, |data| {
-                let index = self.zero_reader.read_all(data);
-                return if index > margin {
-                    (None, index)
-                } else {
-                    let data = &data[index..];
-                    let mut sample_reader = SampleReader::from(self.zero_reader.clone());
-                    let (bit_store, sample_used) = sample_reader.read_all(data);
-                    self.zero_reader = sample_reader.into();
-                    (Some(bit_store), sample_used + index)
-                };
+            let max_frame_sample_count = self.max_frame_sample_count();
+            let format = FrameFormat {
+                preamble: &self.preamble,
+                line_code: self.line_code.as_ref(),
+                max_package_byte_len: self.max_package_byte_len,
+                calibration: self.calibration,
+            };
+            let demodulator = self.modulation.as_mut();
+            demodulator.set_tracing(self.diagnostic_tap.is_some());
+            let zero_reader = &mut self.zero_reader;
+            let mut monitor = FrameMonitor {
+                stats: self.stats.as_ref(),
+                diagnostic_tap: self.diagnostic_tap.as_mut(),
+            };
+            let mismatch_preambles = &self.mismatch_preambles;
+            let link_mismatch = &mut self.link_mismatch;
+            let lane_mode = self.lane_mode;
+            let channels = lane_mode.channels() as usize;
+            // the samples are searched once more than a frame is received,
+            // every preamble starting before the last max_frame_sample_count samples is followed by a whole frame
+            self.input_buffer.peek((max_frame_sample_count + 1) * channels, |_, _| ()).await;
+            let return_package = self.input_buffer.pop_at_most_by_ref(max_frame_sample_count * 2 * channels, |data| {
+                // the indexes are counted in samples of a lane
+                let lanes = Self::split_lanes(lane_mode, data, format.calibration.dc_offset);
+                let lanes: Vec<&[f32]> = if lanes.is_empty() { vec![data] } else { lanes.iter().map(|lane| lane.as_slice()).collect() };
+                let search_len = data.len() / channels - max_frame_sample_count;
+                match Self::search_lanes(&format, demodulator, zero_reader, &mut monitor, &lanes, search_len) {
+                    FrameSearch::NotFound(index) => {
+                        if let Some(mismatch) = Self::detect_mismatch(mismatch_preambles, format.calibration.signal_threshold(zero_reader), lanes[0], search_len) {
+                            warn!("a preamble of {} samples per bit at {} Hz is received, the line parameters of the sender may be different", mismatch.sample_per_bit, mismatch.sample_rate);
+                            *link_mismatch = Some(mismatch);
+                        }
+                        (None, index * channels)
+                    }
+                    FrameSearch::Found(package, _, sample_used) => {
+                        *link_mismatch = None;
+                        (Some(package), sample_used * channels)
+                    }
+                }
             }).await;
             if let Some(return_package) = return_package {
-                return PhysicalPackage::from_bits(&return_package);
+                return return_package;
             }
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
\ No newline at end of file
+mod tests {
+    use cs140_buffer::broadcast_ring_buffer::BroadcastRingBuffer;
+
+    use crate::backend::{LoopbackBackend, WavBackend};
+    use crate::channel::ChannelConfig;
+    use crate::diagnostic::Capture;
+    use crate::encoding::LineCodeKind;
+    use crate::modulation::{ModulationKind, Modulator};
+    use crate::sample_reader::TimingRecovery;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loopback() {
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut sender = PhysicalLayer::with_backend(first, 1, 64);
+        let mut receiver = PhysicalLayer::with_backend(second, 1, 64);
+        let data: Vec<u8> = (0..64).collect();
+        sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+        let package: BitStore = receiver.receive().await.into();
+        assert_eq!(package.into_vec(), data);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loopback_with_modulation() {
+        let channel = ChannelConfig {
+            seed: 4,
+            snr_db: Some(20.0),
+            gain: 0.5,
+            ..Default::default()
+        };
+        for kind in [ModulationKind::Fsk, ModulationKind::Qpsk, ModulationKind::Ofdm] {
+            let (first, second) = LoopbackBackend::pair_with_channel(48000, channel);
+            let mut sender = PhysicalLayer::with_backend(first, 1, 64).with_modulation(kind.build(48000, LineConfig::default()));
+            let mut receiver = PhysicalLayer::with_backend(second, 1, 64).with_modulation(kind.build(48000, LineConfig::default()));
+            let data: Vec<u8> = (0..64).map(|x| x * 3).collect();
+            sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+            let package: BitStore = receiver.receive().await.into();
+            assert_eq!(package.into_vec(), data, "{:?}", kind);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loopback_with_line_code() {
+        let channel = ChannelConfig {
+            seed: 5,
+            snr_db: Some(25.0),
+            gain: 0.5,
+            ..Default::default()
+        };
+        for kind in [LineCodeKind::Manchester, LineCodeKind::EightBTenB] {
+            let (first, second) = LoopbackBackend::pair_with_channel(48000, channel);
+            let mut sender = PhysicalLayer::with_backend(first, 1, 64).with_line_code(kind.build());
+            let mut receiver = PhysicalLayer::with_backend(second, 1, 64).with_line_code(kind.build());
+            let data: Vec<u8> = (0..64).map(|x| x * 3 + 1).collect();
+            sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+            let package = receiver.receive().await;
+            assert!(package.symbol_errors().is_empty(), "{:?}", kind);
+            let package: BitStore = package.into();
+            assert_eq!(package.into_vec(), data, "{:?}", kind);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loopback_with_line_config() {
+        for (sample_rate, sample_per_bit) in [(44100, 3), (48000, 4), (96000, 8)] {
+            let channel = ChannelConfig {
+                seed: sample_per_bit as u64,
+                snr_db: Some(25.0),
+                gain: 0.5,
+                ..Default::default()
+            };
+            let (first, second) = LoopbackBackend::pair_with_channel(sample_rate, channel);
+            let line = LineConfig::sample_per_bit(sample_per_bit);
+            let mut sender = PhysicalLayer::with_backend(first, 1, 64).with_line_config(line);
+            let mut receiver = PhysicalLayer::with_backend(second, 1, 64).with_line_config(line);
+            let data: Vec<u8> = (0..64).map(|x| x * 3 + 1).collect();
+            sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+            let package: BitStore = receiver.receive().await.into();
+            assert_eq!(package.into_vec(), data, "{} samples per bit at {} Hz", sample_per_bit, sample_rate);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loopback_with_clock_drift() {
+        let channel = ChannelConfig {
+            seed: 6,
+            snr_db: Some(25.0),
+            gain: 0.5,
+            drift_ppm: 1200.0,
+            ..Default::default()
+        };
+        let (first, second) = LoopbackBackend::pair_with_channel(48000, channel);
+        let line = LineConfig { timing_recovery: TimingRecovery::gardner(), ..Default::default() };
+        let mut sender = PhysicalLayer::with_backend(first, 1, 256).with_line_config(line);
+        let mut receiver = PhysicalLayer::with_backend(second, 1, 256).with_line_config(line);
+        let data: Vec<u8> = (0..=255).collect();
+        sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+        let package: BitStore = receiver.receive().await.into();
+        assert_eq!(package.into_vec(), data);
+        let clock_offset_ppm = receiver.stats().snapshot().clock_offset_ppm;
+        assert!((clock_offset_ppm - 1200).abs() < 50, "{}", clock_offset_ppm);
+    }
+
+    #[test]
+    fn test_detect_mismatch() {
+        let mismatch_preambles = PhysicalLayer::build_mismatch_preambles(2, 48000);
+        let zero_reader = ZeroReader::new(LineConfig::default());
+        let frame = |sample_per_bit: usize, sample_rate: u32| {
+            let modulation = BasebandModulation::with_config(LineConfig::sample_per_bit(sample_per_bit));
+            let package = PhysicalPackage::from(BitStore::from_vec(vec![1, 2, 3, 4]));
+            let mut samples = Preamble::modulated(&modulation).samples().to_vec();
+            samples.extend(modulation.modulate(&package.to_samples(&FourBFiveB)));
+            // resample to 48000 Hz
+            let ratio = sample_rate as f32 / 48000.0;
+            let mut data = vec![0.0; 50];
+            data.extend((0..(samples.len() as f32 / ratio) as usize).map(|index| samples[(index as f32 * ratio) as usize] * 0.5));
+            data.extend(vec![0.0; 50]);
+            data
+        };
+        let data = frame(4, 48000);
+        assert_eq!(PhysicalLayer::detect_mismatch(&mismatch_preambles, zero_reader.signal_threshold(), &data, data.len()), Some(LinkMismatch { sample_per_bit: 4, sample_rate: 48000 }));
+        let data = frame(3, 96000);
+        assert_eq!(PhysicalLayer::detect_mismatch(&mismatch_preambles, zero_reader.signal_threshold(), &data, data.len()), Some(LinkMismatch { sample_per_bit: 3, sample_rate: 96000 }));
+        let data = frame(2, 48000);
+        assert_eq!(PhysicalLayer::detect_mismatch(&mismatch_preambles, zero_reader.signal_threshold(), &data, data.len()), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stereo_lanes() {
+        let channel = ChannelConfig {
+            seed: 6,
+            snr_db: Some(20.0),
+            gain: 0.4,
+            ..Default::default()
+        };
+        for lane_mode in [LaneMode::Striped, LaneMode::Differential] {
+            let (first, second) = LoopbackBackend::stereo_pair_with_channel(48000, channel);
+            let mut sender = PhysicalLayer::with_backend(first, 1, 64).with_lane_mode(lane_mode);
+            let mut receiver = PhysicalLayer::with_backend(second, 1, 64).with_lane_mode(lane_mode);
+            let frames: Vec<Vec<u8>> = vec![(0..64).collect(), (0..63).rev().collect(), vec![7]];
+            for frame in &frames {
+                sender.send(PhysicalPackage::from(BitStore::from_vec(frame.clone()))).await;
+            }
+            for frame in &frames {
+                let package: BitStore = receiver.receive().await.into();
+                assert_eq!(&package.into_vec(), frame, "{:?}", lane_mode);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_train() {
+        // the default threshold of the receiver is above the amplitude of the signal
+        let channel = ChannelConfig {
+            seed: 7,
+            snr_db: Some(20.0),
+            gain: 0.03,
+            ..Default::default()
+        };
+        let (first, second) = LoopbackBackend::pair_with_channel(48000, channel);
+        let mut first = PhysicalLayer::with_backend(first, 1, 64);
+        let mut second = PhysicalLayer::with_backend(second, 1, 64);
+        let timeout = Duration::from_secs(2);
+        let (first_quality, second_quality) = tokio::join!(first.train(timeout), second.train(timeout));
+        let (first_quality, second_quality) = (first_quality.unwrap(), second_quality.unwrap());
+        assert_eq!(first.link_quality(), Some(first_quality));
+        for quality in [first_quality, second_quality] {
+            assert!((quality.receive.amplitude - 0.03).abs() < 0.003, "{:?}", quality);
+            assert!((quality.receive.snr_db() - 20.0).abs() < 3.0, "{:?}", quality);
+        }
+        assert_eq!(first_quality.send, Some(second_quality.receive));
+        assert_eq!(second_quality.send, Some(first_quality.receive));
+
+        let data: Vec<u8> = (0..64).collect();
+        first.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+        let package: BitStore = second.receive().await.into();
+        assert_eq!(package.into_vec(), data);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_train_on_link_up() {
+        // the dc offset is as large as the amplitude of the signal
+        let channel = ChannelConfig {
+            seed: 3,
+            snr_db: Some(20.0),
+            gain: 0.03,
+            dc_offset: 0.03,
+            ..Default::default()
+        };
+        let (first, second) = LoopbackBackend::pair_with_channel(48000, channel);
+        let timeout = Duration::from_secs(2);
+        let mut first = PhysicalLayer::with_backend(first, 1, 64).with_training(timeout);
+        let mut second = PhysicalLayer::with_backend(second, 1, 64).with_training(timeout);
+        let data: Vec<u8> = (0..64).collect();
+        // the first frame is sent and received after both ends are trained
+        let (_, package) = tokio::join!(
+            first.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))),
+            second.receive()
+        );
+        let package: BitStore = package.into();
+        assert_eq!(package.into_vec(), data);
+        let quality = second.link_quality().unwrap();
+        assert!((quality.receive.dc_offset - 0.03).abs() < 0.003, "{:?}", quality);
+        assert!(first.link_quality().is_some());
+        for _ in 0..3 {
+            first.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+            let package: BitStore = second.receive().await.into();
+            assert_eq!(package.into_vec(), data);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_frames_during_training() {
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut first = PhysicalLayer::with_backend(first, 1, 64);
+        let mut second = PhysicalLayer::with_backend(second, 1, 64);
+        // the peer sends its training pattern, then a frame before its report
+        let pattern = first.modulation.modulate(&training_pattern());
+        first.output_buffer.push_by_ref(&pattern).await;
+        let data: Vec<u8> = (0..16).collect();
+        first.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+        let report = ChannelMeasurement::measure(&pattern, &pattern, &[], 0.5);
+        first.send(PhysicalPackage::from(report.to_report())).await;
+        let quality = second.train(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(quality.send, Some(report));
+        let package: BitStore = second.receive().await.into();
+        assert_eq!(package.into_vec(), data);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_carrier_sense() {
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut sender = PhysicalLayer::with_backend(first, 1, 64);
+        let mut receiver = PhysicalLayer::with_backend(second, 1, 64).with_csma(CsmaConfig { max_attempts: 1, ..Default::default() });
+        assert!(!receiver.carrier_sense().await);
+        let data: Vec<u8> = (0..64).collect();
+        for _ in 0..2 {
+            sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+        }
+        let busy = tokio::time::timeout(Duration::from_secs(1), async {
+            while !receiver.carrier_sense().await {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await;
+        assert!(busy.is_ok());
+        assert_eq!(receiver.try_send(&PhysicalPackage::from(BitStore::from_vec(vec![1, 2, 3]))).await, Err(SendError::Busy));
+        // the sensed samples are still received
+        let package: BitStore = receiver.receive().await.into();
+        assert_eq!(package.into_vec(), data);
+    }
+
+    // a loopback backend whose link status is set by the test
+    struct FlakyBackend {
+        backend: LoopbackBackend,
+        link_status: watch::Receiver<LinkStatus>,
+    }
+
+    impl AudioBackend for FlakyBackend {
+        fn input(&self) -> (SoundDescriptor, InputBuffer) {
+            self.backend.input()
+        }
+
+        fn output(&self) -> (SoundDescriptor, Arc<DefaultBuffer>) {
+            self.backend.output()
+        }
+
+        fn link_status(&self) -> watch::Receiver<LinkStatus> {
+            self.link_status.clone()
+        }
+    }
+
+    // a loopback backend whose received samples are shared through a broadcast buffer like CpalBackend::with_shared_input
+    struct SharedBackend {
+        backend: LoopbackBackend,
+        input_buffer: Arc<BroadcastRingBuffer<f32>>,
+    }
+
+    impl SharedBackend {
+        fn new(backend: LoopbackBackend) -> Self {
+            let (_, InputBuffer::Single(source)) = backend.input() else { unreachable!() };
+            // ten seconds of samples are enough for a test
+            let input_buffer = Arc::new(BroadcastRingBuffer::new(480000));
+            let shared = Arc::downgrade(&input_buffer);
+            // the samples are moved like the callback of the sound card until the backend is dropped
+            std::thread::spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+                while let Some(shared) = shared.upgrade() {
+                    let deadline = tokio::time::Instant::now() + Duration::from_millis(100);
+                    rt.block_on(source.pop_at_most_until(4800, deadline, |first, second| {
+                        let len = first.len() + second.len();
+                        shared.push(len, |store_first, store_second| {
+                            first.iter().chain(second).zip(store_first.iter_mut().chain(store_second.iter_mut())).for_each(|(sample, store)| *store = *sample);
+                            len
+                        });
+                        ((), len)
+                    }));
+                }
+            });
+            Self { backend, input_buffer }
+        }
+    }
+
+    impl AudioBackend for SharedBackend {
+        fn input(&self) -> (SoundDescriptor, InputBuffer) {
+            (self.backend.input().0, InputBuffer::Shared(self.input_buffer.subscribe_lossy()))
+        }
+
+        fn output(&self) -> (SoundDescriptor, Arc<DefaultBuffer>) {
+            self.backend.output()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shared_input() {
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut sender = PhysicalLayer::with_backend(first, 1, 64);
+        let mut receiver = PhysicalLayer::with_backend(SharedBackend::new(second), 1, 64);
+        let recorder = receiver.subscribe_input(SlowSubscriber::Lossy).unwrap();
+        let data: Vec<u8> = (0..64).collect();
+        sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+        let package: BitStore = receiver.receive().await.into();
+        assert_eq!(package.into_vec(), data);
+        // the recorder gets the same samples as the layer, including the frame above the noise of the wire
+        let loudest = recorder.pop_at_most(recorder.len(), |first, second| {
+            (first.iter().chain(second).fold(0.0f32, |loudest, sample| loudest.max(sample.abs())), first.len() + second.len())
+        }).await.unwrap();
+        assert!(loudest > 0.1);
+        assert_eq!(recorder.lost(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_link_status() {
+        use cs140_common::device::DeviceStatus;
+
+        let (first, second) = LoopbackBackend::pair(48000);
+        let (status_sender, status_receiver) = watch::channel(LinkStatus::default());
+        let mut sender = PhysicalLayer::with_backend(FlakyBackend { backend: first, link_status: status_receiver }, 1, 64);
+        let mut receiver = PhysicalLayer::with_backend(second, 1, 64);
+        assert!(receiver.link_status().borrow().is_up());
+        let mut link_status = sender.link_status();
+        let package = PhysicalPackage::from(BitStore::from_vec((0..64).collect()));
+
+        status_sender.send_modify(|link| link.output = DeviceStatus::Down("device unplugged".to_string()));
+        link_status.changed().await.unwrap();
+        assert!(!link_status.borrow().is_up());
+        assert_eq!(sender.try_send(&package).await, Err(SendError::LinkDown));
+        assert_eq!(sender.send_or_drop(&package, || ()).await, Err(SendError::LinkDown));
+        assert_eq!(sender.stats().snapshot().frames_dropped, 1);
+
+        // the frame is sent after the device comes back
+        status_sender.send_modify(|link| link.output = DeviceStatus::Up);
+        link_status.changed().await.unwrap();
+        assert!(link_status.borrow().is_up());
+        assert_eq!(sender.try_send(&package).await, Ok(()));
+        let received: BitStore = receiver.receive().await.into();
+        assert_eq!(received.into_vec(), (0..64).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_collision_detection() {
+        let mut nodes = LoopbackBackend::bus(48000, 2, ChannelConfig::default()).into_iter();
+        let csma = CsmaConfig {
+            collision_detection: Some(CollisionDetection::default()),
+            ..Default::default()
+        };
+        let mut first = PhysicalLayer::with_backend(nodes.next().unwrap(), 1, 64).with_csma(csma);
+        let mut second = PhysicalLayer::with_backend(nodes.next().unwrap(), 1, 64).with_csma(csma);
+        let first_package = PhysicalPackage::from(BitStore::from_vec((0..64).collect()));
+        let second_package = PhysicalPackage::from(BitStore::from_vec((0..64).rev().collect()));
+        // both nodes find the medium idle and send at the same time
+        let (first_result, second_result) = tokio::join!(first.try_send(&first_package), second.try_send(&second_package));
+        assert_eq!((first_result, second_result), (Err(SendError::Collision), Err(SendError::Collision)));
+        // the node sending alone receives its own frame back without a collision
+        assert_eq!(first.try_send(&first_package).await, Ok(()));
+        let package: BitStore = tokio::time::timeout(Duration::from_secs(5), second.receive()).await.unwrap().into();
+        assert_eq!(package.into_vec(), (0..64).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_back_to_back_frames() {
+        let channel = ChannelConfig {
+            seed: 3,
+            snr_db: Some(25.0),
+            gain: 0.4,
+            ..Default::default()
+        };
+        let (first, second) = LoopbackBackend::pair_with_channel(48000, channel);
+        let mut sender = PhysicalLayer::with_backend(first, 0, 64);
+        let mut receiver = PhysicalLayer::with_backend(second, 0, 64);
+        let frames: Vec<Vec<u8>> = (0..4u8).map(|index| (0..64 - index * 8).map(|x| x ^ index).collect()).collect();
+        for frame in &frames {
+            sender.send(PhysicalPackage::from(BitStore::from_vec(frame.clone()))).await;
+        }
+        for frame in &frames {
+            let package: BitStore = receiver.receive().await.into();
+            assert_eq!(&package.into_vec(), frame);
+        }
+    }
+
+    #[test]
+    fn test_reject_false_start() {
+        const SAMPLE_PER_BIT: usize = 2;
+        let preamble = Preamble::barker_13(SAMPLE_PER_BIT);
+        let package = PhysicalPackage::from(BitStore::from_vec(vec![1, 2, 3, 4]));
+        let mut data = vec![0.0; 50];
+        // a preamble followed by a length larger than the max package
+        data.extend(preamble.samples().iter().map(|x| x * 0.5));
+        let too_long = PhysicalPackage::from(BitStore::from_vec(vec![0; 100]));
+        data.extend(too_long.to_samples(&FourBFiveB).into_iter().take(FourBFiveB.encoded_bit_count(BYTE_IN_PHYSICAL_LENGTH)).flat_map(|bit| std::iter::repeat(if bit { 0.5 } else { -0.5 }).take(SAMPLE_PER_BIT)));
+        data.extend(vec![0.0; 50]);
+        let frame_start = data.len();
+        data.extend(preamble.samples().iter().map(|x| x * 0.5));
+        data.extend(package.to_samples(&FourBFiveB).into_iter().flat_map(|bit| std::iter::repeat(if bit { 0.5 } else { -0.5 }).take(SAMPLE_PER_BIT)));
+        let frame_end = data.len();
+        data.extend(vec![0.0; 50]);
+        let mut zero_reader = ZeroReader::new(LineConfig::default());
+        let stats = PhysicalStats::default();
+        let mut monitor = FrameMonitor { stats: &stats, diagnostic_tap: None };
+        let format = FrameFormat { preamble: &preamble, line_code: &FourBFiveB, max_package_byte_len: 64, calibration: Calibration::default() };
+        match PhysicalLayer::search_frame(&format, &mut BasebandModulation::new(), &mut zero_reader, &mut monitor, &data, data.len()) {
+            FrameSearch::NotFound(_) => panic!("the frame is not found"),
+            FrameSearch::Found(package, start, sample_used) => {
+                assert_eq!(start, frame_start);
+                assert_eq!(sample_used, frame_end);
+                assert_eq!(BitStore::from(package).into_vec(), vec![1, 2, 3, 4]);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_diagnostic_tap() {
+        let directory = std::env::temp_dir().join("cs140_physical_test_diagnostic_tap");
+        let _ = std::fs::remove_dir_all(&directory);
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut sender = PhysicalLayer::with_backend(first, 1, 64);
+        let mut receiver = PhysicalLayer::with_backend(second, 1, 64).with_diagnostic_tap(DiagnosticTap::new(&directory).unwrap());
+        let data: Vec<u8> = (0..16).collect();
+        sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+        let package: BitStore = receiver.receive().await.into();
+        assert_eq!(package.into_vec(), data);
+        // the frame is decoded without errors, so it is saved only if the layers above ask
+        receiver.capture_last_frame();
+        let preamble_len = receiver.preamble.len();
+        drop(receiver);
+
+        let capture = Capture::load(directory.join("frame_000000.wav")).unwrap();
+        assert_eq!(capture.sample_rate, 48000);
+        assert_eq!(capture.trace.len(), FourBFiveB.encoded_bit_count(BYTE_IN_PHYSICAL_LENGTH + data.len()));
+        assert_eq!(capture.trace[0].sample_index, preamble_len);
+        assert_eq!(capture.sample_per_bit(), Some(2));
+        assert!(capture.margins().iter().all(|margin| *margin > 0.5));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wav_record_and_replay() {
+        let path = std::env::temp_dir().join("cs140_physical_test_wav_record_and_replay.wav");
+        let data: Vec<u8> = (0..64).rev().collect();
+        {
+            let mut sender = PhysicalLayer::with_backend(WavBackend::new(None, Some(&path), 48000).unwrap(), 1, 64);
+            sender.send(PhysicalPackage::from(BitStore::from_vec(data.clone()))).await;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        for _ in 0..2 {
+            let mut receiver = PhysicalLayer::with_backend(WavBackend::new(Some(&path), None, 48000).unwrap(), 1, 64);
+            let package: BitStore = receiver.receive().await.into();
+            assert_eq!(package.into_vec(), data);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,147 @@
+use crate::encoding::BitStore;
+use crate::modulation::Modulator;
+
+// the Barker code of length 13, its autocorrelation sidelobes are at most 1/13 of the peak
+static BARKER_13: [bool; 13] = [true, true, true, true, true, false, false, true, true, false, true, false, true];
+// the normalized correlation between the samples and the preamble that is treated as a preamble
+static CORRELATION_THRESHOLD: f32 = 0.8;
+
+/// Preamble is the known sample pattern in front of every physical frame, the receiver finds the start of a frame by correlation.
+#[derive(Debug, Clone)]
+pub struct Preamble {
+    samples: Vec<f32>,
+    norm: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PreambleMatch {
+    /// the index of the first sample of the preamble
+    pub index: usize,
+    /// the average amplitude of the positive chips in the preamble
+    pub one_amplitude: f32,
+    /// the average amplitude of the negative chips in the preamble
+    pub neg_one_amplitude: f32,
+}
+
+impl Preamble {
+    pub fn barker_13(sample_per_chip: usize) -> Self {
+        let samples: Vec<f32> = BARKER_13
+            .iter()
+            .flat_map(|&chip| std::iter::repeat(if chip { 1.0 } else { -1.0 }).take(sample_per_chip))
+            .collect();
+        Self::from_samples(samples)
+    }
+
+    /// the Barker-13 code with a fractional count of samples per chip,
+    /// which is how a preamble sent with another sample_per_bit or sample rate looks like
+    pub fn barker_13_stretched(sample_per_chip: f32) -> Self {
+        let sample_count = (BARKER_13.len() as f32 * sample_per_chip).round() as usize;
+        let samples: Vec<f32> = (0..sample_count)
+            .map(|index| {
+                let chip = std::cmp::min((index as f32 / sample_per_chip) as usize, BARKER_13.len() - 1);
+                if BARKER_13[chip] { 1.0 } else { -1.0 }
+            })
+            .collect();
+        Self::from_samples(samples)
+    }
+
+    /// the Barker-13 code modulated by the modulator, one chip per bit
+    pub fn modulated(modulator: &dyn Modulator) -> Self {
+        let bits: BitStore = BARKER_13.iter().copied().collect();
+        Self::from_samples(modulator.modulate(&bits))
+    }
+
+    pub fn from_samples(samples: Vec<f32>) -> Self {
+        let norm = samples.iter().map(|x| x * x).sum::<f32>().sqrt();
+        Self { samples, norm }
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// the normalized correlation between the preamble and data[..self.len()], in range [-1, 1]
+    pub fn correlation(&self, data: &[f32]) -> f32 {
+        let data = &data[..self.len()];
+        let energy = data.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if energy == 0.0 {
+            return 0.0;
+        }
+        let product: f32 = data.iter().zip(self.samples.iter()).map(|(x, y)| x * y).sum();
+        product / energy / self.norm
+    }
+
+    /// find the first preamble starting in data[..search_len].
+    /// A preamble whose average absolute amplitude is less than min_amplitude is treated as noise.
+    pub fn find(&self, data: &[f32], search_len: usize, min_amplitude: f32) -> Option<PreambleMatch> {
+        let search_len = std::cmp::min(search_len, (data.len() + 1).saturating_sub(self.len()));
+        let mut index = 0;
+        while index < search_len {
+            let correlation = self.correlation(&data[index..]);
+            if correlation < CORRELATION_THRESHOLD {
+                index += 1;
+                continue;
+            }
+            // the correlation is still rising in the next samples, move to the peak
+            let mut peak = (index, correlation);
+            let mut next = index + 1;
+            while next + self.len() <= data.len() {
+                let correlation = self.correlation(&data[next..]);
+                if correlation <= peak.1 {
+                    break;
+                }
+                peak = (next, correlation);
+                next += 1;
+            }
+            let window = &data[peak.0..peak.0 + self.len()];
+            let average_amplitude = window.iter().map(|x| x.abs()).sum::<f32>() / self.len() as f32;
+            if average_amplitude < min_amplitude {
+                index = peak.0 + 1;
+                continue;
+            }
+            let (one_sum, one_count, neg_one_sum, neg_one_count) = window.iter().zip(self.samples.iter()).fold((0.0, 0, 0.0, 0), |(one_sum, one_count, neg_one_sum, neg_one_count), (&x, &y)| {
+                if y > 0.0 {
+                    (one_sum + x, one_count + 1, neg_one_sum, neg_one_count)
+                } else {
+                    (one_sum, one_count, neg_one_sum + x, neg_one_count + 1)
+                }
+            });
+            return Some(PreambleMatch {
+                index: peak.0,
+                one_amplitude: one_sum / one_count as f32,
+                neg_one_amplitude: neg_one_sum / neg_one_count as f32,
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_preamble() {
+        let preamble = Preamble::barker_13(2);
+        let mut data = vec![0.0; 100];
+        // a noise spike is not a preamble
+        data[10] = 0.9;
+        data.extend(preamble.samples().iter().map(|x| x * 0.3));
+        data.extend(vec![0.3, -0.3, 0.3, 0.3, -0.3, -0.3]);
+        let found = preamble.find(&data, data.len(), 0.05).unwrap();
+        assert_eq!(found.index, 100);
+        assert!((found.one_amplitude - 0.3).abs() < 1e-6);
+        assert!((found.neg_one_amplitude + 0.3).abs() < 1e-6);
+        assert!(preamble.find(&data, 101, 0.05).is_some());
+        assert!(preamble.find(&data, 100, 0.05).is_none());
+        assert!(preamble.find(&data, data.len(), 0.5).is_none());
+    }
+}
@@ -1,49 +1,87 @@
-use std::sync::atomic::AtomicUsize;
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
 use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
 use async_trait::async_trait;
-use crc::{Crc, CRC_16_IBM_SDLC};
-use log::debug;
+use crc::{Crc, CRC_16_IBM_SDLC, CRC_32_ISO_HDLC, CRC_64_XZ};
+use log::{debug, warn};
+use tokio::sync::watch;
 
+use cs140_common::device::LinkStatus;
+
+use crate::ack::{Arq, ArqHeader, ArqReceiver, ArqSender, SEQUENCE_MODULO};
 use crate::encoding::{BitStore, HandlePackageMut, NetworkPackage};
+use crate::fec::Fec;
 use crate::physical::{PhysicalLayer, PhysicalPackage};
+use crate::stats::{PhysicalStats, RedundancyStats};
+
+static CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_XZ);
 
+/// Checksum is the crc at the end of a RedundancyPackage, its id is sent in the header so the receiver knows which one to check.
+/// CRC16 is IBM-SDLC, CRC32 is ISO-HDLC and CRC64 is XZ.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
 pub enum Checksum {
-    CRC16(&'static Crc<u16>),
-    CRC32(&'static Crc<u32>),
-    CRC64(&'static Crc<u64>),
+    #[default]
+    CRC16,
+    CRC32,
+    CRC64,
 }
 
 impl Checksum {
     pub fn len(&self) -> usize {
         match self {
-            Checksum::CRC16(_) => 2,
-            Checksum::CRC32(_) => 4,
-            Checksum::CRC64(_) => 8,
+            Checksum::CRC16 => 2,
+            Checksum::CRC32 => 4,
+            Checksum::CRC64 => 8,
         }
     }
 
     pub fn checksum(&self, data: &[u8]) -> usize {
         match self {
-            Checksum::CRC16(c) => c.checksum(data) as usize,
-            Checksum::CRC32(c) => c.checksum(data) as usize,
-            Checksum::CRC64(c) => c.checksum(data) as usize,
+            Checksum::CRC16 => CRC16.checksum(data) as usize,
+            Checksum::CRC32 => CRC32.checksum(data) as usize,
+            Checksum::CRC64 => CRC64.checksum(data) as usize,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Checksum::CRC16 => 1,
+            Checksum::CRC32 => 2,
+            Checksum::CRC64 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Checksum::CRC16),
+            2 => Some(Checksum::CRC32),
+            3 => Some(Checksum::CRC64),
+            _ => None,
         }
     }
 }
 
 pub const BYTE_IN_LENGTH: usize = 2;
 pub const BYTE_IN_ENDING: usize = 1;
-pub const CHECKSUM: Checksum = Checksum::CRC16(&Crc::<u16>::new(&CRC_16_IBM_SDLC));
 pub const BYTE_IN_ADDRESS: usize = 2;
-static LOSS_PACKAGE_COUNT: AtomicUsize = AtomicUsize::new(0);
-static RECEIVED_PACKAGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+pub const BYTE_IN_CHECKSUM_TYPE: usize = 1;
+pub const BYTE_IN_ARQ: usize = 2;
+pub const BYTE_IN_HEADER: usize = BYTE_IN_LENGTH + BYTE_IN_ENDING + BYTE_IN_ADDRESS + BYTE_IN_CHECKSUM_TYPE + BYTE_IN_ARQ;
+/// the destination address of a package which is received by all the nodes on the bus
+pub const BROADCAST_ADDRESS: u8 = 0xff;
 
 // RedundancyPackage
 // length: BYTE_IN_LENGTH
 // has_more_fragments: BYTE_IN_ENDING
 // address: BYTE_IN_ADDRESS
+// checksum type: BYTE_IN_CHECKSUM_TYPE, the id of the checksum
+// arq: BYTE_IN_ARQ, the ArqHeader
 // data: len(data)
-// checksum: CHECKSUM::len()
+// checksum: checksum.len()
 
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub struct RedundancyPackage {
@@ -52,36 +90,69 @@ pub struct RedundancyPackage {
 
 impl RedundancyPackage {
     pub fn new(data: impl Iterator<Item=u8>, data_len: usize, has_more_fragments: bool, src: u8, dest: u8) -> Self {
-        let package_length = data_len + BYTE_IN_LENGTH + BYTE_IN_ENDING + BYTE_IN_ADDRESS + CHECKSUM.len();
+        Self::with_checksum(data, data_len, has_more_fragments, src, dest, Checksum::default())
+    }
+
+    pub fn with_checksum(data: impl Iterator<Item=u8>, data_len: usize, has_more_fragments: bool, src: u8, dest: u8, checksum: Checksum) -> Self {
+        let package_length = data_len + BYTE_IN_HEADER + checksum.len();
         let mut package = Self {
             data: Vec::with_capacity(package_length),
         };
         package.set_package_length(package_length);
         package.set_has_more_fragments(has_more_fragments);
         package.set_address(src, dest);
+        package.data.push(checksum.id());
+        package.data.extend(ArqHeader::None.to_bytes());
         package.data.extend(data);
-        package.set_checksum();
+        package.set_checksum(checksum);
+        package
+    }
+
+    /// the same package protected by another checksum
+    pub fn to_checksum(&self, checksum: Checksum) -> Self {
+        if self.checksum_type() == Some(checksum) {
+            return self.clone();
+        }
+        let (src, dest) = self.address();
+        let mut package = Self::with_checksum(self.data().iter().cloned(), self.len(), self.has_more_fragments(), src, dest, checksum);
+        if let Some(header) = self.arq_header() {
+            package.set_arq_header(header);
+        }
         package
     }
 
     pub fn from_physical(package: PhysicalPackage) -> Option<Self> {
+        if !package.symbol_errors().is_empty() {
+            return None;
+        }
         let bits:BitStore = package.into();
         let package = Self {
             data: bits.into_vec(),
         };
-        RECEIVED_PACKAGE_COUNT.fetch_add(1, Relaxed);
-        debug!("total reaceived packages: {}", RECEIVED_PACKAGE_COUNT.load(Relaxed));
         if package.validate_checksum() {
             debug!("{:?}",package.data);
             Some(package)
         } else {
-            let count = LOSS_PACKAGE_COUNT.fetch_add(1, Relaxed) + 1;
-            let total = RECEIVED_PACKAGE_COUNT.load(Relaxed);
-            debug!("loss rate: {}, total loss: {}", (count as f32) / (total as f32), count);
             None
         }
     }
 
+    /// remove the fec of a frame, returns the package and the count of corrected bytes, or None if the fec can not correct it.
+    /// The bytes of the invalid line symbols are erasures of the fec, without the fec such a frame is erased, which is None too.
+    /// The checksum of the package is not validated.
+    pub fn from_fec(fec: &Fec, package: PhysicalPackage) -> Option<(Self, usize)> {
+        if matches!(fec, Fec::None) && !package.symbol_errors().is_empty() {
+            return None;
+        }
+        let mut erasures: Vec<usize> = package.symbol_errors().iter()
+            .flat_map(|error| error.bit_index / 8..=(error.bit_index + error.bit_count - 1) / 8)
+            .collect();
+        erasures.dedup();
+        let bits: BitStore = package.into();
+        let (data, corrected) = fec.decode_with_erasures(bits.as_raw_slice(), &erasures)?;
+        Some((Self { data }, corrected))
+    }
+
     pub fn len(&self) -> usize {
         assert!(BYTE_IN_LENGTH >= 1);
         assert!(BYTE_IN_LENGTH <= (std::mem::size_of::<usize>()));
@@ -90,7 +161,7 @@ impl RedundancyPackage {
         for data in len_data.iter().rev() {
             len = (len << 8) + (*data as usize);
         }
-        len - BYTE_IN_LENGTH - BYTE_IN_ENDING - BYTE_IN_ADDRESS - CHECKSUM.len()
+        len - BYTE_IN_HEADER - self.checksum_len()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -106,8 +177,29 @@ impl RedundancyPackage {
         }
     }
 
+    /// the checksum written in the header, None if the id is unknown
+    pub fn checksum_type(&self) -> Option<Checksum> {
+        Checksum::from_id(*self.data.get(BYTE_IN_LENGTH + BYTE_IN_ENDING + BYTE_IN_ADDRESS)?)
+    }
+
+    /// the ArqHeader in the header, None if the bytes are invalid
+    pub fn arq_header(&self) -> Option<ArqHeader> {
+        ArqHeader::from_bytes(self.data.get(BYTE_IN_HEADER - BYTE_IN_ARQ..BYTE_IN_HEADER)?)
+    }
+
+    pub(crate) fn set_arq_header(&mut self, header: ArqHeader) {
+        let checksum = self.checksum_type().expect("unknown checksum type");
+        self.data[BYTE_IN_HEADER - BYTE_IN_ARQ..BYTE_IN_HEADER].copy_from_slice(&header.to_bytes());
+        self.data.truncate(self.data.len() - checksum.len());
+        self.set_checksum(checksum);
+    }
+
+    fn checksum_len(&self) -> usize {
+        self.checksum_type().expect("unknown checksum type").len()
+    }
+
     pub fn checksum(&self) -> usize {
-        let len = CHECKSUM.len();
+        let len = self.checksum_len();
         let checksum_data = &self.data[self.data.len() - len..];
         let mut checksum = 0;
         for data in checksum_data.iter().rev() {
@@ -117,18 +209,22 @@ impl RedundancyPackage {
     }
 
     pub fn validate_checksum(&self) -> bool {
-        if self.data.len() < BYTE_IN_LENGTH+BYTE_IN_ENDING + BYTE_IN_ADDRESS + CHECKSUM.len() {
+        let checksum = match self.checksum_type() {
+            Some(checksum) => checksum,
+            None => return false,
+        };
+        if self.data.len() < BYTE_IN_HEADER + checksum.len() {
             return false;
         }
-        CHECKSUM.checksum(&self.data[..self.data.len() - CHECKSUM.len()]) as usize
+        checksum.checksum(&self.data[..self.data.len() - checksum.len()])
             == self.checksum()
     }
 
-    fn set_checksum(&mut self) {
-        let mut checksum = CHECKSUM.checksum(&self.data);
-        for _ in 0..CHECKSUM.len() {
-            self.data.push((checksum & 0xff) as u8);
-            checksum >>= 8;
+    fn set_checksum(&mut self, checksum: Checksum) {
+        let mut value = checksum.checksum(&self.data);
+        for _ in 0..checksum.len() {
+            self.data.push((value & 0xff) as u8);
+            value >>= 8;
         }
     }
 
@@ -158,55 +254,316 @@ impl RedundancyPackage {
         (src, dest)
     }
     pub fn data(&self)-> &[u8] {
-        let start = BYTE_IN_LENGTH + BYTE_IN_ENDING + BYTE_IN_ADDRESS;
-        let end = self.data.len() - CHECKSUM.len();
+        let start = BYTE_IN_HEADER;
+        let end = self.data.len() - self.checksum_len();
         &self.data[start..end]
     }
 }
 
 impl NetworkPackage for RedundancyPackage {}
 
+/// AddressFilter decides which received packages are for this node
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AddressFilter {
+    /// the mac address of this node
+    pub address: u8,
+    /// accept the packages for the other nodes too, for sniffing the bus
+    pub promiscuous: bool,
+}
+
+impl AddressFilter {
+    pub fn accepts(&self, package: &RedundancyPackage) -> bool {
+        let (_, dest) = package.address();
+        self.promiscuous || dest == self.address || dest == BROADCAST_ADDRESS
+    }
+}
+
 pub struct RedundancyLayer {
     physical: PhysicalLayer,
     pub(crate) byte_in_frame: usize,
+    fec: Fec,
+    checksum: Checksum,
+    filter: AddressFilter,
+    peer: u8,
+    // the mac addresses of the ip addresses on the bus
+    neighbors: HashMap<Ipv4Addr, u8>,
+    arq: Arq,
+    // the sequence numbers of each destination are counted separately
+    arq_senders: HashMap<u8, ArqSender>,
+    arq_receiver: ArqReceiver,
+    // the received packages which are not taken by the layer above yet
+    pending: VecDeque<RedundancyPackage>,
+    stats: Arc<RedundancyStats>,
 }
 
 impl RedundancyLayer {
     pub fn new(physical: PhysicalLayer) -> Self {
-        let byte_in_frame = physical.max_package_byte()
-            - BYTE_IN_ADDRESS
-            - BYTE_IN_ENDING
-            - BYTE_IN_LENGTH
-            - CHECKSUM.len();
-        Self {
+        let mut layer = Self {
             physical,
-            byte_in_frame,
+            byte_in_frame: 0,
+            fec: Fec::None,
+            checksum: Checksum::default(),
+            filter: AddressFilter {
+                address: 0,
+                promiscuous: false,
+            },
+            peer: 0,
+            neighbors: HashMap::new(),
+            arq: Arq::None,
+            arq_senders: HashMap::new(),
+            arq_receiver: ArqReceiver::default(),
+            pending: VecDeque::new(),
+            stats: Arc::new(RedundancyStats::default()),
+        };
+        layer.byte_in_frame = layer.max_byte_in_frame();
+        layer
+    }
+
+    /// correct the errors in the received frames by the forward error correction, both ends must use the same fec.
+    /// The fec takes some bytes of the physical frame, so it should be set before the layers above are constructed.
+    pub fn with_fec(mut self, fec: Fec) -> Self {
+        self.fec = fec;
+        self.byte_in_frame = self.max_byte_in_frame();
+        self
+    }
+
+    /// protect the sent packages by the checksum, the received packages are checked by the checksum in their header.
+    /// A longer checksum takes some bytes of the frame but lets fewer corrupted frames through, use CRC32 for long frames.
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self.byte_in_frame = self.max_byte_in_frame();
+        self
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// the mac address of this node, the packages for the other nodes are dropped. The default address is 0.
+    pub fn with_address(mut self, address: u8) -> Self {
+        assert_ne!(address, BROADCAST_ADDRESS, "the broadcast address can not be the address of a node");
+        self.filter.address = address;
+        self
+    }
+
+    /// the destination of the packages sent by the layers above which are not for a neighbor, such as the gateway of the bus.
+    /// BROADCAST_ADDRESS sends them to all the nodes. The default peer is 0.
+    pub fn with_peer(mut self, peer: u8) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    /// send the ip packages for the ip address to the node of the mac address
+    pub fn with_neighbor(mut self, ip: Ipv4Addr, address: u8) -> Self {
+        self.neighbors.insert(ip, address);
+        self
+    }
+
+    /// receive the packages for all the nodes
+    pub fn with_promiscuous(mut self, promiscuous: bool) -> Self {
+        self.filter.promiscuous = promiscuous;
+        self
+    }
+
+    /// acknowledge the received packages and send the lost packages again, both ends must use the same arq.
+    /// The broadcast packages are not acknowledged. The packages which are not acknowledged yet are dropped,
+    /// the arq restarts in the next epoch.
+    pub fn with_arq(mut self, arq: Arq) -> Self {
+        assert!(arq.window_size() < SEQUENCE_MODULO as usize, "the window size must be less than {}", SEQUENCE_MODULO);
+        self.arq = arq;
+        for sender in self.arq_senders.values_mut() {
+            *sender = sender.restart(arq);
+        }
+        self
+    }
+
+    /// the statistics of the packages, keep the Arc to read them after the layer is moved into the upper layers
+    pub fn stats(&self) -> Arc<RedundancyStats> {
+        self.stats.clone()
+    }
+
+    /// the statistics of the physical layer below
+    pub fn physical_stats(&self) -> Arc<PhysicalStats> {
+        self.physical.stats()
+    }
+
+    /// wait until the sent packages are acknowledged or the flush timeout passes, then shut down the physical layer.
+    /// The packages received meanwhile are dropped.
+    pub async fn shutdown(mut self, flush_timeout: Duration) {
+        let deadline = tokio::time::Instant::now() + flush_timeout;
+        let _ = tokio::time::timeout_at(deadline, async {
+            while !self.is_acknowledged() {
+                self.poll().await;
+            }
+        }).await;
+        if !self.is_acknowledged() {
+            warn!("drop the packages which are not acknowledged before the shutdown");
         }
+        self.physical.shutdown(deadline.saturating_duration_since(tokio::time::Instant::now())).await;
+    }
+
+    /// the status of the sound card of the physical layer below
+    pub fn link_status(&self) -> watch::Receiver<LinkStatus> {
+        self.physical.link_status()
+    }
+
+    pub fn address(&self) -> u8 {
+        self.filter.address
+    }
+
+    pub fn peer(&self) -> u8 {
+        self.peer
+    }
+
+    /// the mac address of the node of the ip address, the peer if it is not a neighbor or the destination is unknown
+    pub fn resolve(&self, destination: Option<Ipv4Addr>) -> u8 {
+        destination.and_then(|ip| self.neighbors.get(&ip).cloned()).unwrap_or(self.peer)
+    }
+
+    fn arq_sender(&mut self, dest: u8) -> &mut ArqSender {
+        let arq = self.arq;
+        self.arq_senders.entry(dest).or_insert_with(|| ArqSender::new(arq))
+    }
+
+    fn is_acknowledged(&self) -> bool {
+        self.arq_senders.values().all(|sender| sender.is_empty())
+    }
+
+    fn max_byte_in_frame(&self) -> usize {
+        self.fec.max_data_len(self.physical.max_package_byte_len())
+            - BYTE_IN_HEADER
+            - self.checksum.len()
     }
 
     fn make_redundancy(&self, package: RedundancyPackage) -> BitStore {
-        BitStore::from_vec(package.data)
+        let package = package.to_checksum(self.checksum);
+        BitStore::from_vec(self.fec.encode(&package.data))
     }
 
-    fn erase_redundancy(&self, data: PhysicalPackage) -> Option<RedundancyPackage> {
-        RedundancyPackage::from_physical(data)
+    async fn transmit(&mut self, package: RedundancyPackage) {
+        let package: PhysicalPackage = self.make_redundancy(package).into();
+        let stats = &self.stats;
+        let result = self.physical.send_or_drop(&package, || {
+            stats.collisions.fetch_add(1, Relaxed);
+            debug!("the package collides, send it again");
+        }).await;
+        if let Err(err) = result {
+            warn!("drop a package: {:?}", err);
+        }
+    }
+
+    // receive a frame or send the unacknowledged packages again, the packages for the layer above are queued in pending.
+    // It is cancel safe, a package is queued before its acknowledgement is sent. A cancelled acknowledgement
+    // or retransmission is lost like a corrupted frame, it is sent again after the package or the deadline.
+    async fn poll(&mut self) {
+        let result = match self.arq_senders.values().filter_map(|sender| sender.deadline()).min() {
+            None => self.physical.receive().await,
+            Some(deadline) => match tokio::time::timeout_at(deadline, self.physical.receive()).await {
+                Ok(result) => result,
+                Err(_) => {
+                    let now = tokio::time::Instant::now();
+                    let expired: Vec<RedundancyPackage> = self.arq_senders.values_mut().flat_map(|sender| sender.expired(now)).collect();
+                    for package in expired {
+                        self.stats.retransmissions.fetch_add(1, Relaxed);
+                        self.transmit(package).await;
+                    }
+                    return;
+                }
+            }
+        };
+        if let Some(ack) = self.accept(result) {
+            self.transmit(ack).await;
+        }
+    }
+
+    // queue the received package in pending if it is new, returns the acknowledgement to send
+    fn accept(&mut self, data: PhysicalPackage) -> Option<RedundancyPackage> {
+        let package = self.erase_redundancy(data)?;
+        if !self.filter.accepts(&package) {
+            debug!("drop the package for {:?}", package.address());
+            return None;
+        }
+        let (src, dest) = package.address();
+        if dest != self.filter.address {
+            // the broadcast packages and the packages sniffed for the other nodes are not acknowledged
+            self.pending.push_back(package);
+            return None;
+        }
+        match package.arq_header()? {
+            ArqHeader::None => {
+                self.pending.push_back(package);
+                None
+            }
+            ArqHeader::Ack { epoch, sequence } => {
+                if let Some(sender) = self.arq_senders.get_mut(&src) {
+                    sender.acknowledge(epoch, sequence);
+                }
+                None
+            }
+            header => {
+                let (is_new, header) = self.arq_receiver.receive(src, header);
+                if is_new {
+                    self.pending.push_back(package);
+                }
+                let mut ack = RedundancyPackage::with_checksum(std::iter::empty(), 0, false, self.filter.address, src, self.checksum);
+                ack.set_arq_header(header?);
+                Some(ack)
+            }
+        }
+    }
+
+    fn erase_redundancy(&mut self, data: PhysicalPackage) -> Option<RedundancyPackage> {
+        self.stats.packages_received.fetch_add(1, Relaxed);
+        let symbol_error_count = data.symbol_errors().len();
+        let (package, corrected) = match RedundancyPackage::from_fec(&self.fec, data) {
+            Some(result) => result,
+            None if matches!(self.fec, Fec::None) => {
+                self.stats.erasures.fetch_add(1, Relaxed);
+                debug!("{} invalid line symbols, the frame is erased, loss rate: {}", symbol_error_count, self.stats.snapshot().loss_rate());
+                return None;
+            }
+            None => {
+                self.stats.fec_failures.fetch_add(1, Relaxed);
+                self.physical.capture_last_frame();
+                debug!("too many errors to correct, loss rate: {}", self.stats.snapshot().loss_rate());
+                return None;
+            }
+        };
+        if corrected > 0 {
+            self.stats.corrected_bytes.fetch_add(corrected, Relaxed);
+            debug!("{} bytes are corrected", corrected);
+        }
+        if !package.validate_checksum() {
+            self.stats.crc_failures.fetch_add(1, Relaxed);
+            self.physical.capture_last_frame();
+            debug!("wrong checksum, loss rate: {}", self.stats.snapshot().loss_rate());
+            return None;
+        }
+        Some(package)
     }
 }
 
 #[async_trait]
 impl HandlePackageMut<RedundancyPackage> for RedundancyLayer {
     async fn send(&mut self, package: RedundancyPackage) {
-        let package = self.make_redundancy(package).into();
-        self.physical.send(package).await;
+        let (_, dest) = package.address();
+        if self.arq == Arq::None || dest == BROADCAST_ADDRESS {
+            self.transmit(package).await;
+            return;
+        }
+        while self.arq_sender(dest).is_full() {
+            self.poll().await;
+        }
+        let package = self.arq_sender(dest).push(package);
+        self.transmit(package).await;
     }
 
     async fn receive(&mut self) -> RedundancyPackage {
         loop {
-            let result = self.physical.receive().await;
-            let result = self.erase_redundancy(result);
-            if let Some(result) = result {
-                return result;
+            if let Some(package) = self.pending.pop_front() {
+                return package;
             }
+            self.poll().await;
         }
     }
 }
@@ -215,6 +572,11 @@ impl HandlePackageMut<RedundancyPackage> for RedundancyLayer {
 mod tests {
     use cs140_common::padding::padding;
 
+    use crate::backend::LoopbackBackend;
+    use crate::channel::ChannelConfig;
+    use crate::csma::CsmaConfig;
+    use crate::encoding::Manchester;
+
     use super::*;
 
     #[test]
@@ -240,5 +602,225 @@ mod tests {
             corrupted_package.set(index, reversed);
             assert_eq!(RedundancyPackage::from_physical(PhysicalPackage::from(corrupted_package)), None);
         }
+
+        // the single bit errors are recovered by the fec
+        let fec = Fec::reed_solomon(8);
+        let encoded_package = fec.encode(&package.data);
+        for index in 0..encoded_package.len() * 8 {
+            let mut corrupted_package = BitStore::from_vec(encoded_package.clone());
+            let reversed = !corrupted_package[index];
+            corrupted_package.set(index, reversed);
+            let (data, corrected) = fec.decode(corrupted_package.as_raw_slice()).unwrap();
+            assert_eq!(corrected, 1);
+            assert_eq!(RedundancyPackage::from_physical(PhysicalPackage::from(BitStore::from_vec(data))), Some(package.clone()));
+        }
+    }
+
+    #[test]
+    fn test_checksum_in_header() {
+        let data: Vec<_> = padding().take(256).collect();
+        for checksum in [Checksum::CRC16, Checksum::CRC32, Checksum::CRC64] {
+            let package = RedundancyPackage::with_checksum(data.iter().cloned(), 256, true, 3, 4, checksum);
+            assert_eq!(package.data.len(), BYTE_IN_HEADER + 256 + checksum.len());
+            assert_eq!(package.checksum_type(), Some(checksum));
+            assert_eq!(package.len(), 256);
+            assert_eq!(package.data(), &data);
+            let received = RedundancyPackage::from_physical(PhysicalPackage::from(BitStore::from_vec(package.data.clone())));
+            assert_eq!(received, Some(package.clone()));
+            // the receiver does not need to know the checksum of the sender
+            let converted = package.to_checksum(Checksum::default());
+            assert_eq!(converted.checksum_type(), Some(Checksum::default()));
+            assert_eq!((converted.data(), converted.address(), converted.has_more_fragments()), (&data[..], (3, 4), true));
+        }
+        let mut unknown = RedundancyPackage::new(data.iter().cloned(), 256, false, 1, 2).data;
+        unknown[BYTE_IN_LENGTH + BYTE_IN_ENDING + BYTE_IN_ADDRESS] = 0;
+        assert_eq!(RedundancyPackage::from_physical(PhysicalPackage::from(BitStore::from_vec(unknown))), None);
+    }
+
+    #[test]
+    fn test_erasure() {
+        let package = RedundancyPackage::new(padding().take(32), 32, false, 1, 2);
+        let received = |data: Vec<u8>| {
+            let mut line = PhysicalPackage::from(BitStore::from_vec(data)).to_samples(&Manchester);
+            // an invalid Manchester symbol in the first byte after the length field
+            let bit = line[32];
+            line.set(33, bit);
+            PhysicalPackage::from_bits(&line, &Manchester)
+        };
+        assert_eq!(received(package.data.clone()).symbol_errors().len(), 1);
+        assert_eq!(RedundancyPackage::from_physical(received(package.data.clone())), None);
+        assert_eq!(RedundancyPackage::from_fec(&Fec::None, received(package.data.clone())), None);
+        // the fec corrects the zeros of the invalid symbol
+        let fec = Fec::reed_solomon(8);
+        let (corrected, _) = RedundancyPackage::from_fec(&fec, received(fec.encode(&package.data))).unwrap();
+        assert_eq!(corrected, package);
+        // two parity bytes correct one wrong byte, or two erased bytes
+        let fec = Fec::reed_solomon(2);
+        let encoded = BitStore::from_vec(fec.encode(&package.data));
+        let mut line = PhysicalPackage::from(encoded.clone()).to_samples(&Manchester);
+        for byte_index in [3, 6] {
+            // a bit 1 which is decoded as 0 from the invalid symbol, after the length field of 16 bits
+            let bit_index = (byte_index * 8..).find(|&index| encoded[index]).unwrap();
+            let bit = line[32 + bit_index * 2];
+            line.set(32 + bit_index * 2 + 1, bit);
+        }
+        let received = PhysicalPackage::from_bits(&line, &Manchester);
+        assert_eq!(received.symbol_errors().len(), 2);
+        let (corrected, _) = RedundancyPackage::from_fec(&fec, received).unwrap();
+        assert_eq!(corrected, package);
+    }
+
+    #[test]
+    fn test_address_filter() {
+        let data: Vec<_> = padding().take(10).collect();
+        let filter = AddressFilter { address: 2, promiscuous: false };
+        let to = |dest| RedundancyPackage::new(data.iter().cloned(), 10, false, 1, dest);
+        assert!(filter.accepts(&to(2)));
+        assert!(filter.accepts(&to(BROADCAST_ADDRESS)));
+        assert!(!filter.accepts(&to(3)));
+        let sniffer = AddressFilter { promiscuous: true, ..filter };
+        assert!(sniffer.accepts(&to(3)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fec_over_burst() {
+        let fec = || Fec::reed_solomon(4).with_depth(4);
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut sender = PhysicalLayer::with_backend(first, 1, 128);
+        let mut receiver = RedundancyLayer::new(PhysicalLayer::with_backend(second, 1, 128)).with_address(2).with_fec(fec());
+        let package = RedundancyPackage::new(padding().take(64), 64, false, 1, 2);
+        // the frame of a RedundancyLayer with the same fec, 8 bytes of which are broken by a burst on the line
+        let mut frame = fec().encode(&package.to_checksum(Checksum::default()).data);
+        for byte in frame[20..28].iter_mut() {
+            *byte = !*byte;
+        }
+        sender.send(PhysicalPackage::from(BitStore::from_vec(frame))).await;
+        let received = tokio::time::timeout(Duration::from_secs(5), receiver.receive()).await.unwrap();
+        assert_eq!(received, package);
+        assert_eq!(receiver.stats().snapshot().corrected_bytes, 8);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_arq_over_lossy_link() {
+        let channel = ChannelConfig {
+            seed: 7,
+            drop_probability: 2e-4,
+            ..Default::default()
+        };
+        for arq in [Arq::StopAndWait { timeout: Duration::from_millis(100) }, Arq::SlidingWindow { window_size: 4, timeout: Duration::from_millis(200) }] {
+            let (first, second) = LoopbackBackend::pair_with_channel(48000, channel);
+            let mut sender = RedundancyLayer::new(PhysicalLayer::with_backend(first, 1, 64)).with_address(1).with_peer(2).with_arq(arq);
+            let mut receiver = RedundancyLayer::new(PhysicalLayer::with_backend(second, 1, 64)).with_address(2).with_peer(1).with_arq(arq);
+            let stats = sender.stats();
+            let send = tokio::spawn(async move {
+                for index in 0..20u8 {
+                    sender.send(RedundancyPackage::new(std::iter::repeat(index).take(32), 32, false, 1, 2)).await;
+                }
+                // keep sending the lost packages again
+                loop {
+                    sender.receive().await;
+                }
+            });
+            let received = tokio::time::timeout(Duration::from_secs(30), async {
+                let mut received = Vec::new();
+                for _ in 0..20 {
+                    received.push(receiver.receive().await.data()[0]);
+                }
+                received
+            }).await;
+            send.abort();
+            assert_eq!(received.unwrap(), (0..20).collect::<Vec<u8>>(), "{:?}", arq);
+            assert!(stats.snapshot().retransmissions > 0);
+        }
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancelled_receive() {
+        let arq = Arq::SlidingWindow { window_size: 4, timeout: Duration::from_millis(200) };
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut sender = RedundancyLayer::new(PhysicalLayer::with_backend(first, 1, 64)).with_address(1).with_peer(2).with_arq(arq);
+        let mut receiver = RedundancyLayer::new(PhysicalLayer::with_backend(second, 1, 64).with_csma(CsmaConfig::default())).with_address(2).with_arq(arq);
+        let send = tokio::spawn(async move {
+            for index in 0..10u8 {
+                sender.send(RedundancyPackage::new(std::iter::repeat(index).take(32), 32, false, 1, 2)).await;
+            }
+            loop {
+                sender.receive().await;
+            }
+        });
+        // the receive is cancelled like a branch of a select, also while the acknowledgement is sent
+        let received = tokio::time::timeout(Duration::from_secs(30), async {
+            let mut received = Vec::new();
+            while received.len() < 10 {
+                if let Ok(package) = tokio::time::timeout(Duration::from_millis(1), receiver.receive()).await {
+                    received.push(package.data()[0]);
+                }
+            }
+            received
+        }).await;
+        send.abort();
+        assert_eq!(received.unwrap(), (0..10).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_peer_restart() {
+        let arq = Arq::StopAndWait { timeout: Duration::from_millis(200) };
+        let (first, second) = LoopbackBackend::pair(48000);
+        let mut sender = RedundancyLayer::new(PhysicalLayer::with_backend(first, 1, 64)).with_address(1).with_peer(2).with_arq(arq);
+        let mut receiver = RedundancyLayer::new(PhysicalLayer::with_backend(second, 1, 64)).with_address(2).with_peer(1).with_arq(arq);
+        let package = |index| RedundancyPackage::new(std::iter::repeat(index).take(32), 32, false, 1, 2);
+        for (first, last) in [(0, 5), (5, 8)] {
+            let received = tokio::time::timeout(Duration::from_secs(10), async {
+                let mut received = Vec::new();
+                for index in first..last {
+                    let (_, package) = tokio::join!(sender.send(package(index)), receiver.receive());
+                    received.push(package.data()[0]);
+                }
+                received
+            }).await;
+            assert_eq!(received.unwrap(), (first..last).collect::<Vec<u8>>());
+            // the arq of the sender restarts, its sequence numbers start again
+            sender = sender.with_arq(arq);
+        }
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sniffer_does_not_acknowledge() {
+        let arq = Arq::StopAndWait { timeout: Duration::from_millis(200) };
+        let mut nodes = LoopbackBackend::bus(48000, 3, ChannelConfig::default()).into_iter();
+        let mut layer = |address| RedundancyLayer::new(PhysicalLayer::with_backend(nodes.next().unwrap(), 1, 64).with_csma(CsmaConfig::default()))
+            .with_address(address)
+            .with_arq(arq);
+        let mut sender = layer(1).with_peer(2);
+        let mut receiver = layer(2);
+        let mut sniffer = layer(3).with_promiscuous(true);
+        // all the nodes keep receiving, so the bus is not blocked by a full input buffer
+        let sniff = tokio::spawn(async move {
+            let mut sniffed = Vec::new();
+            while sniffed.len() < 8 {
+                sniffed.push(sniffer.receive().await);
+            }
+            sniffed
+        });
+        let send = tokio::spawn(async move {
+            for index in 0..4u8 {
+                sender.send(RedundancyPackage::new(std::iter::repeat(index).take(32), 32, false, 1, 2)).await;
+            }
+            loop {
+                sender.receive().await;
+            }
+        });
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            let mut received = Vec::new();
+            for _ in 0..4 {
+                received.push(receiver.receive().await.data()[0]);
+            }
+            received
+        }).await;
+        assert_eq!(received.unwrap(), (0..4).collect::<Vec<u8>>());
+        // the sniffer receives the packages and the acknowledgements of the other nodes, and its own frames if it sends any
+        let sniffed = tokio::time::timeout(Duration::from_secs(10), sniff).await.unwrap().unwrap();
+        send.abort();
+        assert!(sniffed.iter().all(|package| package.address().0 != 3), "{:?}", sniffed);
+        assert!(sniffed.iter().any(|package| package.address() == (1, 2)));
+        assert!(sniffed.iter().any(|package| package.address() == (2, 1)));
     }
 }
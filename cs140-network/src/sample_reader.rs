@@ -1,29 +1,203 @@
+use std::str::FromStr;
+
 use log::trace;
 
 use crate::encoding::BitStore;
 
-static BIT_SLIP_HISTORY_COUNT: usize = 4;
-static SAMPLE_PER_BIT: usize = 2;
-static EXPONENTIALLY_WEIGHTED_MOVING_AVERAGE_NEW_DATA_RATIO: f32 = 0.5;
-static ZERO_RANGE: f32 = ACCEPTABLE_NO_OFFSET_SIGNAL_RANGE;
-// check the sample is in 50% range of zero
-static ACCEPTABLE_NO_OFFSET_SIGNAL_RANGE: f32 = 0.5;
+// the bound of the frequency estimate of the timing recovery loop, in ratio to sample_per_bit
+const MAX_CLOCK_OFFSET: f32 = 0.01;
+
+/// TimingRecovery is how the SampleReader follows the difference between the sample clocks of the sender and the receiver
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimingRecovery {
+    /// skip or repeat a sample when the weakest sample of a bit is on its edge, see bit_slip_history_count
+    BitSlip,
+    /// a phase-locked loop of the Gardner timing error detector and a proportional-integral loop filter.
+    /// The bits are read at a fractional sample position by the linear interpolation,
+    /// the error of a bit is (this bit - last bit) * the sample between them, in ratio to the amplitude.
+    Gardner {
+        /// the ratio of the timing error by which the position of the next bit moves, in samples
+        proportional_gain: f32,
+        /// the ratio of the timing error which is added to the estimate of the samples per bit
+        integral_gain: f32,
+    },
+}
+
+impl TimingRecovery {
+    pub fn gardner() -> Self {
+        TimingRecovery::Gardner {
+            proportional_gain: 0.05,
+            integral_gain: 0.002,
+        }
+    }
+}
+
+impl FromStr for TimingRecovery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "bitslip" | "bit_slip" => Ok(TimingRecovery::BitSlip),
+            "gardner" => Ok(TimingRecovery::gardner()),
+            _ => Err(anyhow::anyhow!("unknown timing recovery {}", s)),
+        }
+    }
+}
+
+/// LineConfig is the parameters of the baseband line signal.
+/// The sender and the receiver must use the same sample_per_bit and sample rate,
+/// the other parameters only change how the receiver reads the samples.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LineConfig {
+    /// the count of samples of a bit, at least 2
+    pub sample_per_bit: usize,
+    /// a sample is treated as a bit if it is out of this ratio of the amplitude around zero,
+    /// and a bit is flawless if all its samples are out of this ratio
+    pub acceptable_signal_range: f32,
+    /// the count of bits after a bit slip in which no other bit slip is detected
+    pub bit_slip_history_count: usize,
+    /// the ratio of a new bit in the exponentially weighted moving average of the amplitude, in range (0, 1]
+    pub ewma_new_data_ratio: f32,
+    pub timing_recovery: TimingRecovery,
+}
+
+impl Default for LineConfig {
+    fn default() -> Self {
+        Self {
+            sample_per_bit: 2,
+            acceptable_signal_range: 0.5,
+            bit_slip_history_count: 4,
+            ewma_new_data_ratio: 0.5,
+            timing_recovery: TimingRecovery::BitSlip,
+        }
+    }
+}
+
+impl LineConfig {
+    pub fn sample_per_bit(sample_per_bit: usize) -> Self {
+        Self {
+            sample_per_bit,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.sample_per_bit >= 2, "sample_per_bit must be at least 2, got {}", self.sample_per_bit);
+        anyhow::ensure!(self.acceptable_signal_range > 0.0 && self.acceptable_signal_range < 1.0, "acceptable_signal_range must be in (0, 1), got {}", self.acceptable_signal_range);
+        anyhow::ensure!(self.ewma_new_data_ratio > 0.0 && self.ewma_new_data_ratio <= 1.0, "ewma_new_data_ratio must be in (0, 1], got {}", self.ewma_new_data_ratio);
+        if let TimingRecovery::Gardner { proportional_gain, integral_gain } = self.timing_recovery {
+            anyhow::ensure!(proportional_gain > 0.0 && proportional_gain < 1.0, "proportional_gain must be in (0, 1), got {}", proportional_gain);
+            anyhow::ensure!(integral_gain > 0.0 && integral_gain < proportional_gain, "integral_gain must be in (0, proportional_gain), got {}", integral_gain);
+        }
+        Ok(())
+    }
+}
+
+/// BitTrace is how the SampleReader reads a bit, it is recorded for the diagnostics
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BitTrace {
+    /// the index of the first sample of the bit
+    pub sample_index: usize,
+    pub bit: bool,
+    /// the average of the samples of the bit
+    pub average: f32,
+    /// the amplitude estimates with which the bit is read
+    pub threshold: f32,
+    pub one_amplitude: f32,
+    pub neg_one_amplitude: f32,
+    /// the samples used by the bit minus sample_per_bit, it is not 0 if a bit slip is corrected
+    pub slip: i8,
+}
+
+impl BitTrace {
+    /// how far the average is from the threshold relative to the amplitude of the bit,
+    /// 1 if the average is on the amplitude estimate and 0 if it is on the threshold
+    pub fn margin(&self) -> f32 {
+        if self.bit {
+            (self.average - self.threshold) / (self.one_amplitude - self.threshold)
+        } else {
+            (self.threshold - self.average) / (self.threshold - self.neg_one_amplitude)
+        }
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct SampleReader {
+    config: LineConfig,
     one_amplitude: f32,
     zero_amplitude: f32,
     neg_one_amplitude: f32,
     bit_slip_history: usize,
+    bit_slip_count: usize,
+    timing: TimingState,
+}
+
+// the state of the Gardner timing recovery loop, which is kept between the calls of read_exact
+#[derive(Debug, Default, Copy, Clone)]
+struct TimingState {
+    // the position of the next bit after the first sample of the data of the next read, in [0, 1)
+    phase: f32,
+    // the estimate of the samples per bit minus sample_per_bit
+    period_offset: f32,
+    // the sample before the data of the next read and the level of the last bit in ratio to the amplitude
+    last_sample: Option<f32>,
+    last_level: Option<f32>,
+    // the samples used since the reader is created
+    sample_used: usize,
+    // the sums of the least squares fit of the positions of the bits to their indexes, whose slope is the samples per bit
+    bit_count: usize,
+    sum_index: f64,
+    sum_index_square: f64,
+    sum_position: f64,
+    sum_index_position: f64,
+}
+
+impl TimingState {
+    fn add_bit(&mut self, position: f32) {
+        let index = self.bit_count as f64;
+        let position = self.sample_used as f64 + position as f64;
+        self.bit_count += 1;
+        self.sum_index += index;
+        self.sum_index_square += index * index;
+        self.sum_position += position;
+        self.sum_index_position += index * position;
+    }
+
+    fn period(&self) -> Option<f64> {
+        let count = self.bit_count as f64;
+        let denominator = count * self.sum_index_square - self.sum_index * self.sum_index;
+        if self.bit_count < 2 || denominator <= 0.0 {
+            return None;
+        }
+        Some((count * self.sum_index_position - self.sum_index * self.sum_position) / denominator)
+    }
 }
 
 impl SampleReader {
-    pub fn new(zero_amplitude: f32, one_amplitude: f32, neg_one_amplitude: f32) -> Self {
+    pub fn new(config: LineConfig, zero_amplitude: f32, one_amplitude: f32, neg_one_amplitude: f32) -> Self {
         Self {
+            config,
             zero_amplitude,
             one_amplitude,
             neg_one_amplitude,
             bit_slip_history: 0,
+            bit_slip_count: 0,
+            timing: TimingState::default(),
+        }
+    }
+
+    /// the count of bit slips corrected since the reader is created
+    pub fn bit_slip_count(&self) -> usize {
+        self.bit_slip_count
+    }
+
+    /// the estimated offset of the sample clock of the receiver to the one of the sender in ppm,
+    /// positive if the receiver takes more samples per bit than the sender.
+    /// It is fitted to the positions of all the bits read, None if the timing recovery is not Gardner or less than 2 bits are read.
+    pub fn clock_offset_ppm(&self) -> Option<f32> {
+        match self.config.timing_recovery {
+            TimingRecovery::Gardner { .. } => self.timing.period().map(|period| ((period / self.config.sample_per_bit as f64 - 1.0) * 1e6) as f32),
+            TimingRecovery::BitSlip => None,
         }
     }
 
@@ -40,15 +214,72 @@ impl SampleReader {
         }
     }
 
+    /// read exactly count bits, returns None if the signal ends before that
+    pub fn read_exact(&mut self, data: &[f32], count: usize) -> Option<(BitStore, usize)> {
+        self.read_exact_with(data, count, |_| {})
+    }
+
+    /// read exactly count bits like read_exact and push how each bit is read to trace,
+    /// the sample indexes in the trace are counted from first_sample_index at the start of data
+    pub fn read_exact_traced(&mut self, data: &[f32], count: usize, first_sample_index: usize, trace: &mut Vec<BitTrace>) -> Option<(BitStore, usize)> {
+        self.read_exact_with(data, count, |mut bit_trace| {
+            bit_trace.sample_index += first_sample_index;
+            trace.push(bit_trace);
+        })
+    }
+
+    fn read_exact_with(&mut self, data: &[f32], count: usize, mut on_bit: impl FnMut(BitTrace)) -> Option<(BitStore, usize)> {
+        let sample_per_bit = self.config.sample_per_bit;
+        let mut result = BitStore::with_capacity(count);
+        let mut data_ref = data;
+        while result.len() < count {
+            // a bit slip may skip one more sample than a bit, the timing recovery may read a fraction of a sample more
+            let max_sample_count = match self.config.timing_recovery {
+                TimingRecovery::BitSlip => (sample_per_bit + 1) as f32,
+                TimingRecovery::Gardner { proportional_gain, .. } => self.timing.phase + sample_per_bit as f32 + self.timing.period_offset + proportional_gain + 1.0,
+            };
+            if data_ref.len() as f32 <= max_sample_count {
+                return None;
+            }
+            let (threshold, one_amplitude, neg_one_amplitude) = (self.zero_amplitude, self.one_amplitude, self.neg_one_amplitude);
+            let sample_index = data.len() - data_ref.len();
+            let (bit, average) = match self.config.timing_recovery {
+                TimingRecovery::BitSlip => {
+                    let average = data_ref[..sample_per_bit].iter().sum::<f32>() / sample_per_bit as f32;
+                    (self.read(&mut data_ref)?, average)
+                }
+                TimingRecovery::Gardner { proportional_gain, integral_gain } => {
+                    let bit = self.read_timed(&mut data_ref, proportional_gain, integral_gain)?;
+                    // the average of the samples around the middle of the bit
+                    (bit, threshold + self.timing.last_level.unwrap() * (one_amplitude - neg_one_amplitude) / 2.0)
+                }
+            };
+            on_bit(BitTrace {
+                sample_index,
+                bit,
+                average,
+                threshold,
+                one_amplitude,
+                neg_one_amplitude,
+                slip: (data.len() - data_ref.len() - sample_index) as i8 - sample_per_bit as i8,
+            });
+            result.push(bit);
+        }
+        Some((result, data.len() - data_ref.len()))
+    }
+
     fn read(&mut self, data: &mut &[f32]) -> Option<bool> {
+        let sample_per_bit = self.config.sample_per_bit;
+        let zero_range = self.config.acceptable_signal_range;
+        let new_data_ratio = self.config.ewma_new_data_ratio;
         // if this assertion fails, please check the count of your max package size with the count of samples that pop from buffer
-        assert!(data.len() > SAMPLE_PER_BIT);
+        assert!(data.len() > sample_per_bit);
 
-        let current_bit_sample = &data[..SAMPLE_PER_BIT];
+        let current_bit_sample = &data[..sample_per_bit];
 
         if current_bit_sample.iter().all(|&sample| {
             let sample = sample + self.zero_amplitude;
-            (sample >= 0.0 && sample < ZERO_RANGE * (self.one_amplitude - self.zero_amplitude)) || (sample < 0.0 && sample > -ZERO_RANGE * (self.zero_amplitude-self.neg_one_amplitude))
+            (sample >= 0.0 && sample < zero_range * (self.one_amplitude - self.zero_amplitude)) || (sample < 0.0 && sample > -zero_range * (self.zero_amplitude-self.neg_one_amplitude))
         }) {
             return None;
         }
@@ -57,69 +288,130 @@ impl SampleReader {
 
         let result = current_bit_average_value > self.zero_amplitude;
 
-        let (current_bit_max_amplitude_index, current_bit_max_amplitude) = current_bit_sample.iter().map(|x| x.abs()).enumerate().fold((SAMPLE_PER_BIT, 0f32), |old_max, (index, abs_value)| {
+        let (current_bit_max_amplitude_index, current_bit_max_amplitude) = current_bit_sample.iter().map(|x| x.abs()).enumerate().fold((sample_per_bit, 0f32), |old_max, (index, abs_value)| {
             return if abs_value > old_max.1 {
                 (index, abs_value)
             } else {
                 old_max
             };
         });
-        assert_ne!(current_bit_max_amplitude_index, SAMPLE_PER_BIT);
+        assert_ne!(current_bit_max_amplitude_index, sample_per_bit);
 
-        let (current_bit_min_amplitude_index, current_bit_min_amplitude) = current_bit_sample.iter().map(|x| x.abs()).enumerate().fold((SAMPLE_PER_BIT, 1.0), |old_min, (index, abs_value)| {
+        let (current_bit_min_amplitude_index, current_bit_min_amplitude) = current_bit_sample.iter().map(|x| x.abs()).enumerate().fold((sample_per_bit, f32::MAX), |old_min, (index, abs_value)| {
             return if abs_value < old_min.1 {
                 (index, abs_value)
             } else {
                 old_min
             };
         });
-        assert_ne!(current_bit_min_amplitude_index, SAMPLE_PER_BIT);
+        assert_ne!(current_bit_min_amplitude_index, sample_per_bit);
 
         // update 1 and -1
         if result {
-            self.one_amplitude = self.one_amplitude * (1.0 - EXPONENTIALLY_WEIGHTED_MOVING_AVERAGE_NEW_DATA_RATIO) + current_bit_max_amplitude * EXPONENTIALLY_WEIGHTED_MOVING_AVERAGE_NEW_DATA_RATIO;
+            self.one_amplitude = self.one_amplitude * (1.0 - new_data_ratio) + current_bit_max_amplitude * new_data_ratio;
         } else {
-            self.neg_one_amplitude = self.neg_one_amplitude * (1.0 - EXPONENTIALLY_WEIGHTED_MOVING_AVERAGE_NEW_DATA_RATIO) - current_bit_max_amplitude * EXPONENTIALLY_WEIGHTED_MOVING_AVERAGE_NEW_DATA_RATIO;
+            self.neg_one_amplitude = self.neg_one_amplitude * (1.0 - new_data_ratio) - current_bit_max_amplitude * new_data_ratio;
         }
 
-        if current_bit_min_amplitude_index != 0 && current_bit_min_amplitude_index != SAMPLE_PER_BIT - 1 {
-            // if assertion failed, please check the signal in Au.
-            assert!(self.check_sample_is_acceptable(current_bit_sample, result));
-            // the bit is flawless
-            *data = &data[SAMPLE_PER_BIT..];
+        if current_bit_min_amplitude_index != 0 && current_bit_min_amplitude_index != sample_per_bit - 1 {
+            // the edges of the bit are not the weakest samples, so the bit is not slipped
+            *data = &data[sample_per_bit..];
             return Some(result);
         }
 
         if self.check_sample_is_acceptable(current_bit_sample, result) {
             // the bit is flawless
-            *data = &data[SAMPLE_PER_BIT..];
+            *data = &data[sample_per_bit..];
             return Some(result);
         }
 
         if self.bit_slip_history != 0 {
             self.bit_slip_history -= 1;
-            *data = &data[SAMPLE_PER_BIT..];
+            *data = &data[sample_per_bit..];
         } else {
             if (current_bit_sample[current_bit_min_amplitude_index] + self.zero_amplitude) * (current_bit_sample[current_bit_max_amplitude_index] + self.zero_amplitude) < 0.0 {
-                self.bit_slip_history = BIT_SLIP_HISTORY_COUNT;
+                self.bit_slip_history = self.config.bit_slip_history_count;
+                self.bit_slip_count += 1;
                 if current_bit_min_amplitude_index == 0 {
-                    *data = &data[SAMPLE_PER_BIT + 1..];
+                    *data = &data[sample_per_bit + 1..];
                 } else {
-                    *data = &data[SAMPLE_PER_BIT - 1..];
+                    *data = &data[sample_per_bit - 1..];
                 }
             } else {
-                *data = &data[SAMPLE_PER_BIT..];
+                *data = &data[sample_per_bit..];
             }
         }
         Some(result)
     }
 
+    // the sample at a fractional position of data, the position in [-1, 0) is between the last sample and the first one
+    fn interpolate(&self, data: &[f32], position: f32) -> Option<f32> {
+        let index = position.floor();
+        let fraction = position - index;
+        let sample_at = |index: f32| if index < 0.0 { self.timing.last_sample } else { Some(data[index as usize]) };
+        Some(sample_at(index)? * (1.0 - fraction) + sample_at(index + 1.0)? * fraction)
+    }
+
+    fn read_timed(&mut self, data: &mut &[f32], proportional_gain: f32, integral_gain: f32) -> Option<bool> {
+        let sample_per_bit = self.config.sample_per_bit;
+        let zero_range = self.config.acceptable_signal_range;
+        let new_data_ratio = self.config.ewma_new_data_ratio;
+        let period = sample_per_bit as f32 + self.timing.period_offset;
+        let position = self.timing.phase;
+        assert!((data.len() as f32) > position + period + 1.0);
+
+        // the samples at least half a sample away from the edges of the bit, centered on its middle
+        let center = position + (period - 1.0) / 2.0;
+        let side_count = (sample_per_bit - 2) / 2;
+        let current_bit_sample: Vec<f32> = (0..2 * side_count + 1).map(|index| self.interpolate(data, center + index as f32 - side_count as f32).unwrap()).collect();
+
+        if current_bit_sample.iter().all(|&sample| {
+            let sample = sample + self.zero_amplitude;
+            (sample >= 0.0 && sample < zero_range * (self.one_amplitude - self.zero_amplitude)) || (sample < 0.0 && sample > -zero_range * (self.zero_amplitude - self.neg_one_amplitude))
+        }) {
+            return None;
+        }
+
+        let current_bit_average_value = current_bit_sample.iter().sum::<f32>() / current_bit_sample.len() as f32;
+        let result = current_bit_average_value > self.zero_amplitude;
+        let current_bit_max_amplitude = current_bit_sample.iter().map(|x| x.abs()).fold(0f32, f32::max);
+        let scale = (self.one_amplitude - self.neg_one_amplitude) / 2.0;
+        let level = (current_bit_average_value - self.zero_amplitude) / scale;
+
+        // the sample between the bits is on the threshold if the position is right, it leans to the later bit if the position is late.
+        // The error is clamped so that a spike of noise does not move the position much
+        let error = match (self.timing.last_level, self.interpolate(data, position - 0.5)) {
+            (Some(last_level), Some(middle)) => ((level - last_level) * (middle - self.zero_amplitude) / scale).clamp(-1.0, 1.0),
+            _ => 0.0,
+        };
+        let max_offset = MAX_CLOCK_OFFSET * sample_per_bit as f32;
+        self.timing.period_offset = (self.timing.period_offset - integral_gain * error).clamp(-max_offset, max_offset);
+        let next_position = position + sample_per_bit as f32 + self.timing.period_offset - proportional_gain * error;
+        let sample_used = next_position.floor().max(0.0) as usize;
+        if sample_used > 0 {
+            self.timing.last_sample = Some(data[sample_used - 1]);
+        }
+        self.timing.add_bit(position);
+        self.timing.phase = next_position - sample_used as f32;
+        self.timing.last_level = Some(level);
+        self.timing.sample_used += sample_used;
+        *data = &data[sample_used..];
+
+        // update 1 and -1
+        if result {
+            self.one_amplitude = self.one_amplitude * (1.0 - new_data_ratio) + current_bit_max_amplitude * new_data_ratio;
+        } else {
+            self.neg_one_amplitude = self.neg_one_amplitude * (1.0 - new_data_ratio) - current_bit_max_amplitude * new_data_ratio;
+        }
+        Some(result)
+    }
+
     fn check_sample_is_acceptable(&self, current_bit_sample: &[f32], result: bool) -> bool {
         current_bit_sample.iter().all(|sample| {
             if result {
-                *sample > (1.0 - ACCEPTABLE_NO_OFFSET_SIGNAL_RANGE) * self.zero_amplitude + ACCEPTABLE_NO_OFFSET_SIGNAL_RANGE * self.one_amplitude
+                *sample > (1.0 - self.config.acceptable_signal_range) * self.zero_amplitude + self.config.acceptable_signal_range * self.one_amplitude
             } else {
-                *sample < (1.0 - ACCEPTABLE_NO_OFFSET_SIGNAL_RANGE) * self.zero_amplitude + ACCEPTABLE_NO_OFFSET_SIGNAL_RANGE * self.neg_one_amplitude
+                *sample < (1.0 - self.config.acceptable_signal_range) * self.zero_amplitude + self.config.acceptable_signal_range * self.neg_one_amplitude
             }
         })
     }
@@ -127,25 +419,47 @@ impl SampleReader {
 
 #[derive(Debug, Copy, Clone)]
 pub struct ZeroReader {
+    config: LineConfig,
     one_amplitude: f32,
     zero_amplitude: f32,
     neg_one_amplitude: f32,
 }
 
 impl ZeroReader {
-    pub fn new() -> Self {
+    pub fn new(config: LineConfig) -> Self {
         ZeroReader {
+            config,
             one_amplitude: 0.1,
             zero_amplitude: 0.0,
             neg_one_amplitude: -0.1,
         }
     }
 
+    pub fn with_amplitude(config: LineConfig, one_amplitude: f32, neg_one_amplitude: f32) -> Self {
+        ZeroReader {
+            config,
+            one_amplitude,
+            zero_amplitude: 0.0,
+            neg_one_amplitude,
+        }
+    }
+
+    pub fn line_config(&self) -> LineConfig {
+        self.config
+    }
+
+    /// the minimum absolute value of a sample that is treated as signal
+    pub fn signal_threshold(&self) -> f32 {
+        let zero_range = self.config.acceptable_signal_range;
+        f32::min(zero_range * (self.one_amplitude - self.zero_amplitude), zero_range * (self.zero_amplitude - self.neg_one_amplitude))
+    }
+
     pub fn read_all(&mut self, data: &[f32]) -> usize {
         let mut index = 0;
         while index < data.len() {
             let sample = data[index] + self.zero_amplitude;
-            if (sample >= 0.0 && sample < ZERO_RANGE * (self.one_amplitude - self.zero_amplitude)) || (sample < 0.0 && sample > -ZERO_RANGE * (self.zero_amplitude-self.neg_one_amplitude)) {
+            let zero_range = self.config.acceptable_signal_range;
+            if (sample >= 0.0 && sample < zero_range * (self.one_amplitude - self.zero_amplitude)) || (sample < 0.0 && sample > -zero_range * (self.zero_amplitude-self.neg_one_amplitude)) {
                 index += 1;
             } else {
                 trace!("Sample value: {}, signal found",data[index]);
@@ -159,6 +473,7 @@ impl ZeroReader {
 impl From<SampleReader> for ZeroReader {
     fn from(reader: SampleReader) -> Self {
         Self {
+            config: reader.config,
             one_amplitude: reader.one_amplitude,
             zero_amplitude: reader.zero_amplitude,
             neg_one_amplitude: reader.neg_one_amplitude,
@@ -169,10 +484,48 @@ impl From<SampleReader> for ZeroReader {
 impl From<ZeroReader> for SampleReader {
     fn from(reader: ZeroReader) -> Self {
         Self {
+            config: reader.config,
             one_amplitude: reader.one_amplitude,
             zero_amplitude: reader.zero_amplitude,
             neg_one_amplitude: reader.neg_one_amplitude,
             bit_slip_history: 0,
+            bit_slip_count: 0,
+            timing: TimingState::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    use crate::channel::{ChannelConfig, ChannelSimulator};
+    use crate::encoding::{FourBFiveB, LineCode};
+
+    use super::*;
+
+    #[test]
+    fn test_gardner_timing_recovery() {
+        let mut rng = Pcg64::seed_from_u64(0);
+        let data: Vec<u8> = (0..512).map(|_| rng.gen()).collect();
+        let line = FourBFiveB.encode(&BitStore::from_vec(data));
+        let samples: Vec<f32> = line.iter().flat_map(|bit| [if *bit { 0.5 } else { -0.5 }; 2]).chain([0.0; 64]).collect();
+        for drift_ppm in [-1000.0, 0.0, 600.0, 1500.0] {
+            let channel = ChannelConfig { seed: 1, snr_db: Some(25.0), drift_ppm, ..Default::default() };
+            let received = ChannelSimulator::new(channel).transmit(&samples);
+            let config = LineConfig { timing_recovery: TimingRecovery::gardner(), ..Default::default() };
+            let mut reader = SampleReader::new(config, 0.0, 0.5, -0.5);
+            // the header and the body of a frame are read by two calls
+            let (mut bits, sample_used) = reader.read_exact(&received, 20).unwrap();
+            let (body, _) = reader.read_exact(&received[sample_used..], line.len() - 20).unwrap();
+            bits.extend_from_bitslice(body.as_bitslice());
+            assert_eq!(bits, line, "{}", drift_ppm);
+            let clock_offset_ppm = reader.clock_offset_ppm().unwrap();
+            assert!((clock_offset_ppm - drift_ppm as f32).abs() < 20.0, "{} estimated as {}", drift_ppm, clock_offset_ppm);
         }
+        assert_eq!(SampleReader::new(LineConfig::default(), 0.0, 0.5, -0.5).clock_offset_ppm(), None);
+        assert!(LineConfig { timing_recovery: TimingRecovery::Gardner { proportional_gain: 0.1, integral_gain: 0.2 }, ..Default::default() }.validate().is_err());
+        assert_eq!("gardner".parse::<TimingRecovery>().unwrap(), TimingRecovery::gardner());
     }
 }
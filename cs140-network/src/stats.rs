@@ -0,0 +1,202 @@
+use std::sync::atomic::{AtomicI32, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+
+// The statistics of a layer are shared by the layer and its tasks through an Arc, so the counters are atomic.
+// Each layer instance has its own statistics, several stacks in one process do not share any counter.
+
+/// PhysicalStats counts the frames received and dropped by a PhysicalLayer
+#[derive(Debug, Default)]
+pub struct PhysicalStats {
+    pub(crate) frames_detected: AtomicUsize,
+    pub(crate) bit_slips: AtomicUsize,
+    pub(crate) decode_failures: AtomicUsize,
+    pub(crate) clock_offset_ppm: AtomicI32,
+    pub(crate) frames_dropped: AtomicUsize,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PhysicalStatsSnapshot {
+    /// the frames whose preamble and length are received, the halves of a striped frame are counted separately
+    pub frames_detected: usize,
+    /// the bit slips corrected by the sample reader in the detected frames
+    pub bit_slips: usize,
+    /// the invalid line symbols in the detected frames
+    pub decode_failures: usize,
+    /// the offset of the sample clock of the receiver to the sender estimated in the last detected frame, in ppm.
+    /// It is 0 if the timing recovery of the sample reader does not estimate it, see TimingRecovery
+    pub clock_offset_ppm: i32,
+    /// the frames not sent because the medium stays busy or the link is down
+    pub frames_dropped: usize,
+}
+
+impl PhysicalStats {
+    pub fn snapshot(&self) -> PhysicalStatsSnapshot {
+        PhysicalStatsSnapshot {
+            frames_detected: self.frames_detected.load(Relaxed),
+            bit_slips: self.bit_slips.load(Relaxed),
+            decode_failures: self.decode_failures.load(Relaxed),
+            clock_offset_ppm: self.clock_offset_ppm.load(Relaxed),
+            frames_dropped: self.frames_dropped.load(Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.frames_detected.store(0, Relaxed);
+        self.bit_slips.store(0, Relaxed);
+        self.decode_failures.store(0, Relaxed);
+        self.clock_offset_ppm.store(0, Relaxed);
+        self.frames_dropped.store(0, Relaxed);
+    }
+}
+
+/// RedundancyStats counts the packages received and sent by a RedundancyLayer
+#[derive(Debug, Default)]
+pub struct RedundancyStats {
+    pub(crate) packages_received: AtomicUsize,
+    pub(crate) crc_failures: AtomicUsize,
+    pub(crate) erasures: AtomicUsize,
+    pub(crate) fec_failures: AtomicUsize,
+    pub(crate) corrected_bytes: AtomicUsize,
+    pub(crate) retransmissions: AtomicUsize,
+    pub(crate) collisions: AtomicUsize,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct RedundancyStatsSnapshot {
+    /// the frames received from the physical layer
+    pub packages_received: usize,
+    /// the packages with a wrong checksum
+    pub crc_failures: usize,
+    /// the frames with invalid line symbols which are dropped without the fec
+    pub erasures: usize,
+    /// the frames with more errors than the fec corrects
+    pub fec_failures: usize,
+    /// the bytes corrected by the fec
+    pub corrected_bytes: usize,
+    /// the packages sent again by the arq
+    pub retransmissions: usize,
+    /// the collisions detected while sending
+    pub collisions: usize,
+}
+
+impl RedundancyStatsSnapshot {
+    /// the ratio of the received frames that are lost by the errors
+    pub fn loss_rate(&self) -> f32 {
+        if self.packages_received == 0 {
+            return 0.0;
+        }
+        (self.crc_failures + self.erasures + self.fec_failures) as f32 / self.packages_received as f32
+    }
+}
+
+impl RedundancyStats {
+    pub fn snapshot(&self) -> RedundancyStatsSnapshot {
+        RedundancyStatsSnapshot {
+            packages_received: self.packages_received.load(Relaxed),
+            crc_failures: self.crc_failures.load(Relaxed),
+            erasures: self.erasures.load(Relaxed),
+            fec_failures: self.fec_failures.load(Relaxed),
+            corrected_bytes: self.corrected_bytes.load(Relaxed),
+            retransmissions: self.retransmissions.load(Relaxed),
+            collisions: self.collisions.load(Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.packages_received.store(0, Relaxed);
+        self.crc_failures.store(0, Relaxed);
+        self.erasures.store(0, Relaxed);
+        self.fec_failures.store(0, Relaxed);
+        self.corrected_bytes.store(0, Relaxed);
+        self.retransmissions.store(0, Relaxed);
+        self.collisions.store(0, Relaxed);
+    }
+}
+
+/// IPStats counts the fragments of an IPLayer
+#[derive(Debug, Default)]
+pub struct IPStats {
+    pub(crate) fragments_sent: AtomicUsize,
+    pub(crate) fragments_received: AtomicUsize,
+    pub(crate) reassemblies: AtomicUsize,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct IPStatsSnapshot {
+    pub fragments_sent: usize,
+    pub fragments_received: usize,
+    /// the packages merged from the received fragments
+    pub reassemblies: usize,
+}
+
+impl IPStats {
+    pub fn snapshot(&self) -> IPStatsSnapshot {
+        IPStatsSnapshot {
+            fragments_sent: self.fragments_sent.load(Relaxed),
+            fragments_received: self.fragments_received.load(Relaxed),
+            reassemblies: self.reassemblies.load(Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.fragments_sent.store(0, Relaxed);
+        self.fragments_received.store(0, Relaxed);
+        self.reassemblies.store(0, Relaxed);
+    }
+}
+
+/// TCPStats counts the retransmissions of a TCPLayer
+#[derive(Debug, Default)]
+pub struct TCPStats {
+    pub(crate) retransmissions: AtomicUsize,
+    pub(crate) sack_timeouts: AtomicUsize,
+    pub(crate) rtt_millis: AtomicUsize,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct TCPStatsSnapshot {
+    /// the segments sent again because the peer reports them missing
+    pub retransmissions: usize,
+    /// the times no sack is received in time while sending
+    pub sack_timeouts: usize,
+    /// the current estimate of the round trip time, it is not cleared by reset
+    pub rtt_millis: usize,
+}
+
+impl TCPStats {
+    pub fn snapshot(&self) -> TCPStatsSnapshot {
+        TCPStatsSnapshot {
+            retransmissions: self.retransmissions.load(Relaxed),
+            sack_timeouts: self.sack_timeouts.load(Relaxed),
+            rtt_millis: self.rtt_millis.load(Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.retransmissions.store(0, Relaxed);
+        self.sack_timeouts.store(0, Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_and_reset() {
+        let first = RedundancyStats::default();
+        let second = RedundancyStats::default();
+        first.packages_received.fetch_add(4, Relaxed);
+        first.crc_failures.fetch_add(1, Relaxed);
+        assert_eq!(first.snapshot().loss_rate(), 0.25);
+        assert_eq!(second.snapshot(), RedundancyStatsSnapshot::default());
+        first.reset();
+        assert_eq!(first.snapshot(), RedundancyStatsSnapshot::default());
+
+        let tcp = TCPStats::default();
+        tcp.rtt_millis.store(400, Relaxed);
+        tcp.sack_timeouts.fetch_add(2, Relaxed);
+        tcp.reset();
+        assert_eq!(tcp.snapshot(), TCPStatsSnapshot { rtt_millis: 400, ..Default::default() });
+    }
+}
@@ -1,16 +1,20 @@
 use std::collections::{BTreeSet, LinkedList};
 use std::ops::Range;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::AtomicU16;
+use std::sync::atomic::{AtomicU16, AtomicUsize};
 use std::sync::atomic::Ordering::Relaxed;
-use std::time::Instant;
+use std::time::Duration;
 use bincode::{config::Configuration, Decode, Encode};
 use log::{debug, info, warn};
 use tokio::select;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
 use crate::encoding::{HandlePackage};
-use crate::ip::{IPLayer, IPPackage};
+use crate::ip::{join_task, IPLayer, IPPackage};
+use crate::stats::{IPStats, PhysicalStats, RedundancyStats, TCPStats};
 use crate::tcp::TCPPackage::{Data, Header, RttResponse, RttRequest, PeerVacant, Sack};
 use crate::tcp::TCPState::{Receiving, Sending};
 
@@ -60,6 +64,13 @@ pub struct RTTPackage {
 pub struct TCPLayer {
     send_package_sender: Sender<BinaryData>,
     recv_package_receiver: tokio::sync::Mutex<Receiver<BinaryData>>,
+    stats: Arc<TCPStats>,
+    ip_stats: Arc<IPStats>,
+    redundancy_stats: Arc<RedundancyStats>,
+    physical_stats: Arc<PhysicalStats>,
+    // the deadline to finish the transmissions before the task shuts down the ip layer
+    shutdown_sender: oneshot::Sender<Instant>,
+    task: JoinHandle<()>,
 }
 
 #[derive(Debug)]
@@ -82,7 +93,8 @@ pub struct TCPSendingStatus {
 }
 
 impl TCPSendingStatus {
-    fn set_next_send_package(&mut self, sequence_length: usize) {
+    // returns true if a lost package is sent again
+    fn set_next_send_package(&mut self, sequence_length: usize) -> bool {
         debug!("We are sender, we should sending something");
         let (new_segment_id, new_package) = match self.last_send_segment_id {
             None => {
@@ -99,7 +111,7 @@ impl TCPSendingStatus {
                     if next_segment_id >= self.sequence_count {
                         debug!("we have sent all the data, return");
                         self.next_package_to_send = None;
-                        return;
+                        return false;
                     }
                     (next_segment_id, Data(DataPackage {
                         sequence_id: next_segment_id,
@@ -124,8 +136,10 @@ impl TCPSendingStatus {
                 }
             }
         };
+        let retransmitted = self.sequence_missing.contains(&new_segment_id);
         self.next_package_to_send = Some(new_package);
         self.last_send_segment_id = Some(new_segment_id);
+        retransmitted
     }
 
     fn completed(&self) -> bool {
@@ -205,12 +219,14 @@ impl TCPReceivingStatus {
 #[derive(Debug)]
 pub struct TCPRTTStatus {
     rtt: AtomicU16,
+    stats: Arc<TCPStats>,
 }
 
 impl TCPRTTStatus {
     fn update_rtt(&self, new_rtt: u16) {
         // this is safe because there is only one thread to call this function
         self.rtt.store((self.rtt.load(Relaxed) + new_rtt) / 2, Relaxed);
+        self.stats.rtt_millis.store(self.rtt.load(Relaxed) as usize, Relaxed);
     }
 
     async fn get_rtt_timeout(&self, ratio: f32) {
@@ -231,19 +247,29 @@ impl TCPRTTStatus {
 impl TCPLayer {
     pub fn new(ip: IPLayer) -> TCPLayer {
         let sequence_length: u16 = (ip.byte_in_frame - 12) as u16;
+        let stats = Arc::new(TCPStats {
+            rtt_millis: AtomicUsize::new(400),
+            ..Default::default()
+        });
+        let (ip_stats, redundancy_stats, physical_stats) = (ip.stats(), ip.redundancy_stats(), ip.physical_stats());
+        let task_stats = stats.clone();
         let ip = Arc::new(ip);
         let (send_package_sender, mut send_package_receiver) = tokio::sync::mpsc::channel::<BinaryData>(1024);
         let (recv_package_sender, recv_package_receiver) = tokio::sync::mpsc::channel::<BinaryData>(1024);
 
         let state: Arc<Mutex<TCPState>> = Arc::new(Mutex::new(TCPState::Ready));
+        let (shutdown_sender, mut shutdown_receiver) = oneshot::channel::<Instant>();
 
         let future = async move {
             let rtt_status = TCPRTTStatus {
                 rtt: AtomicU16::new(400),
+                stats: task_stats.clone(),
             };
             let mut rtt_timeout = Box::pin(rtt_status.get_rtt_timeout(0.0));
             let mut sack_timeout = Box::pin(rtt_status.get_rtt_timeout(1.5));
             let mut sack_timeout_count = 0;
+            // the queued data is still sent after the shutdown until the deadline
+            let mut shutdown_deadline = None;
             loop {
                 let state = state.clone();
                 let state_for_package_to_send_future = state.clone();
@@ -270,6 +296,15 @@ impl TCPLayer {
                     }
                 };
                 select! {
+                    deadline = &mut shutdown_receiver, if shutdown_deadline.is_none() => {
+                        // the layer is dropped without a shutdown if the sender is dropped
+                        shutdown_deadline = Some(deadline.unwrap_or_else(|_| Instant::now()));
+                        send_package_receiver.close();
+                    }
+                    _ = tokio::time::sleep_until(shutdown_deadline.unwrap_or_else(Instant::now)), if shutdown_deadline.is_some() => {
+                        warn!("the transmissions are not finished before the shutdown");
+                        break;
+                    }
                     _ = rtt_timeout.as_mut() => {
                         info!("rtt timeout, sending rtt...");
                         ip.send(RttRequest(TCPRTTStatus::generate_rtt_package()).into()).await;
@@ -310,6 +345,7 @@ impl TCPLayer {
                     _ = sack_timeout.as_mut() => {
                         if is_sending{
                             sack_timeout_count += 1;
+                            task_stats.sack_timeouts.fetch_add(1, Relaxed);
                             warn!("sack timeout, now we have {} sack timeout",sack_timeout_count);
                         }
                         sack_timeout = Box::pin(rtt_status.get_rtt_timeout(1.5));
@@ -319,7 +355,7 @@ impl TCPLayer {
                             let mut state = state.lock().unwrap();
                             let package_len  = package.len();
                             let mut sending_status = TCPSendingStatus{
-                                transmit_start: Instant::now(),
+                                transmit_start: std::time::Instant::now(),
                                 data_sending: package,
                                 sequence_missing: BTreeSet::new(),
                                 last_send_segment_id: None,
@@ -331,14 +367,16 @@ impl TCPLayer {
                             info!("now we have something to send, {:?}",sending_status);
                             *state = Sending(sending_status);
                         }else{
-                            return;
+                            break;
                         }
                     },
                     _ = package_to_send_future, if is_sending => {
                         match &mut *state.lock().unwrap(){
                             Sending(sending)=>{
                                 debug!("we are sending the package, {:?}",sending.next_package_to_send);
-                                sending.set_next_send_package(sequence_length.into());
+                                if sending.set_next_send_package(sequence_length.into()) {
+                                    task_stats.retransmissions.fetch_add(1, Relaxed);
+                                }
                             }
                             _ =>{
                                 unreachable!();
@@ -400,7 +438,7 @@ impl TCPLayer {
 
                                         let send_result = recv_package_sender.send(old_state.data_received).await;
                                         if send_result.is_err(){
-                                            return;
+                                            break;
                                         }
                                         info!("all packages received, send ack!");
                                         ip.send(Sack(SackPackage{
@@ -434,13 +472,52 @@ impl TCPLayer {
                     }
                 }
             }
+            // the futures of the loop are dropped, the task holds the only reference to the ip layer
+            if let Ok(ip) = Arc::try_unwrap(ip) {
+                let deadline = shutdown_deadline.unwrap_or_else(Instant::now);
+                ip.shutdown(deadline.saturating_duration_since(Instant::now())).await;
+            }
         };
-        tokio::spawn(future);
+        let task = tokio::spawn(future);
         Self {
             send_package_sender,
             recv_package_receiver:tokio::sync::Mutex::new(recv_package_receiver),
+            stats,
+            ip_stats,
+            redundancy_stats,
+            physical_stats,
+            shutdown_sender,
+            task,
         }
     }
+
+    /// stop accepting data, finish the transmissions of the data accepted before until the flush timeout,
+    /// then shut down the ip layer. The data received and not taken by receive is dropped.
+    pub async fn shutdown(self, flush_timeout: Duration) {
+        let deadline = Instant::now() + flush_timeout;
+        let _ = self.shutdown_sender.send(deadline);
+        drop(self.recv_package_receiver);
+        join_task(self.task, deadline).await;
+    }
+
+    pub fn stats(&self) -> Arc<TCPStats> {
+        self.stats.clone()
+    }
+
+    /// the statistics of the ip layer below
+    pub fn ip_stats(&self) -> Arc<IPStats> {
+        self.ip_stats.clone()
+    }
+
+    /// the statistics of the redundancy layer below
+    pub fn redundancy_stats(&self) -> Arc<RedundancyStats> {
+        self.redundancy_stats.clone()
+    }
+
+    /// the statistics of the physical layer below
+    pub fn physical_stats(&self) -> Arc<PhysicalStats> {
+        self.physical_stats.clone()
+    }
 }
 
 impl TCPLayer {
@@ -468,8 +545,29 @@ impl TCPLayer {
 
 #[cfg(test)]
 mod tests {
+    use crate::backend::LoopbackBackend;
+    use crate::physical::PhysicalLayer;
+    use crate::redundancy::RedundancyLayer;
+
     use super::*;
 
+    fn tcp_layer(backend: LoopbackBackend) -> TCPLayer {
+        TCPLayer::new(IPLayer::new(RedundancyLayer::new(PhysicalLayer::with_backend(backend, 1, 64))))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown() {
+        let (first, second) = LoopbackBackend::pair(48000);
+        let sender = tcp_layer(first);
+        let receiver = tcp_layer(second);
+        let data: Vec<u8> = (0..200).collect();
+        sender.send_raw(data.clone()).await;
+        // the receiver keeps acknowledging while the sender flushes the data
+        let (_, received) = tokio::join!(sender.shutdown(Duration::from_secs(20)), tokio::time::timeout(Duration::from_secs(20), receiver.receive_raw()));
+        assert_eq!(received.unwrap(), Some(data));
+        receiver.shutdown(Duration::from_secs(1)).await;
+    }
+
     #[test]
     fn test_set_ack() {
         let mut s = TCPReceivingStatus {
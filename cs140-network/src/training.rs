@@ -0,0 +1,134 @@
+use crate::encoding::BitStore;
+
+// the payload of a training report starts with the magic bytes
+static REPORT_MAGIC: [u8; 4] = *b"TRN1";
+// the count of samples before the training pattern which are used to measure the noise floor
+pub(crate) static NOISE_FLOOR_SAMPLE_COUNT: usize = 480;
+// the signal is this many times above the noise floor
+const NOISE_FLOOR_MARGIN: f32 = 4.0;
+
+/// the training pattern, the maximum length sequence of length 63 generated by x^6 + x^5 + 1.
+/// Its autocorrelation sidelobes are -1/63 of the peak, so the position and the amplitude are measured precisely.
+pub(crate) fn training_pattern() -> BitStore {
+    let mut state: u8 = 0b111111;
+    (0..63).map(|_| {
+        let bit = state & 1 == 1;
+        let feedback = (state ^ (state >> 1)) & 1;
+        state = (state >> 1) | (feedback << 5);
+        bit
+    }).collect()
+}
+
+/// ChannelMeasurement is how the signal of one direction of a link arrives at the receiver
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChannelMeasurement {
+    /// the received amplitude of a full scale sample
+    pub amplitude: f32,
+    /// the constant offset of the received samples
+    pub dc_offset: f32,
+    /// the root mean square of the difference between the received samples and the scaled training pattern
+    pub noise_rms: f32,
+    /// the root mean square of the samples before the training pattern, which is the noise of the idle line
+    pub noise_floor_rms: f32,
+    /// the absolute value of a sample above which the receiver treats it as signal
+    pub decision_threshold: f32,
+}
+
+impl ChannelMeasurement {
+    /// measure the amplitude and the noise by the least squares fit received = amplitude * template + dc_offset
+    pub(crate) fn measure(template: &[f32], received: &[f32], idle: &[f32], acceptable_signal_range: f32) -> Self {
+        let len = template.len() as f32;
+        let template_mean = template.iter().sum::<f32>() / len;
+        let received_mean = received.iter().sum::<f32>() / len;
+        let (covariance, variance) = template.iter().zip(received.iter()).fold((0.0, 0.0), |(covariance, variance), (t, x)| {
+            (covariance + (t - template_mean) * (x - received_mean), variance + (t - template_mean) * (t - template_mean))
+        });
+        let amplitude = covariance / variance;
+        let dc_offset = received_mean - amplitude * template_mean;
+        let noise_rms = (template.iter().zip(received.iter())
+            .map(|(t, x)| (x - amplitude * t - dc_offset).powi(2))
+            .sum::<f32>() / len).sqrt();
+        let noise_floor_rms = if idle.is_empty() {
+            noise_rms
+        } else {
+            (idle.iter().map(|x| (x - dc_offset).powi(2)).sum::<f32>() / idle.len() as f32).sqrt()
+        };
+        // the threshold is the usual ratio of the amplitude, but it should be far above the noise floor
+        let decision_threshold = f32::min(f32::max(acceptable_signal_range * amplitude, NOISE_FLOOR_MARGIN * noise_floor_rms), 0.9 * amplitude);
+        Self {
+            amplitude,
+            dc_offset,
+            noise_rms,
+            noise_floor_rms,
+            decision_threshold,
+        }
+    }
+
+    /// the absolute value of a sample below which it is the noise of the idle line
+    pub fn noise_threshold(&self) -> f32 {
+        NOISE_FLOOR_MARGIN * self.noise_floor_rms
+    }
+
+    /// signal to noise ratio in dB
+    pub fn snr_db(&self) -> f32 {
+        20.0 * (self.amplitude / self.noise_rms).log10()
+    }
+
+    pub(crate) fn to_report(self) -> BitStore {
+        let mut bytes = REPORT_MAGIC.to_vec();
+        for value in [self.amplitude, self.dc_offset, self.noise_rms, self.noise_floor_rms, self.decision_threshold] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        BitStore::from_vec(bytes)
+    }
+
+    pub(crate) fn from_report(bits: &BitStore) -> Option<Self> {
+        let bytes = bits.as_raw_slice();
+        if bytes.len() < REPORT_MAGIC.len() + 5 * 4 || bytes[..REPORT_MAGIC.len()] != REPORT_MAGIC {
+            return None;
+        }
+        let value = |index: usize| {
+            let start = REPORT_MAGIC.len() + index * 4;
+            f32::from_le_bytes([bytes[start], bytes[start + 1], bytes[start + 2], bytes[start + 3]])
+        };
+        Some(Self {
+            amplitude: value(0),
+            dc_offset: value(1),
+            noise_rms: value(2),
+            noise_floor_rms: value(3),
+            decision_threshold: value(4),
+        })
+    }
+}
+
+/// LinkQuality is the result of the training of a link
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LinkQuality {
+    /// how the signal of the peer arrives here
+    pub receive: ChannelMeasurement,
+    /// how the signal of this end arrives at the peer, None if the report of the peer is lost
+    pub send: Option<ChannelMeasurement>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measure() {
+        let template: Vec<f32> = training_pattern().iter().map(|bit| if *bit { 1.0 } else { -1.0 }).collect();
+        assert_eq!(template.len(), 63);
+        // a maximum length sequence has one more 1 than 0
+        assert_eq!(template.iter().sum::<f32>(), 1.0);
+        let received: Vec<f32> = template.iter().map(|t| 0.3 * t + 0.02).collect();
+        let idle = vec![0.025, 0.015];
+        let measurement = ChannelMeasurement::measure(&template, &received, &idle, 0.5);
+        assert!((measurement.amplitude - 0.3).abs() < 1e-3);
+        assert!((measurement.dc_offset - 0.02).abs() < 1e-3);
+        assert!(measurement.noise_rms < 1e-3);
+        assert!((measurement.noise_floor_rms - 0.005).abs() < 1e-3);
+        assert!((measurement.decision_threshold - 0.15).abs() < 1e-3);
+        assert_eq!(ChannelMeasurement::from_report(&measurement.to_report()), Some(measurement));
+        assert_eq!(ChannelMeasurement::from_report(&BitStore::from_vec(vec![1, 2, 3])), None);
+    }
+}
@@ -1,6 +1,6 @@
 use tokio::net::UdpSocket;
 use cs140_network::ip::IPLayer;
-use cs140_network::physical::PhysicalLayer;
+use cs140_network::config::PhysicalLayerConfig;
 use cs140_network::redundancy::RedundancyLayer;
 use cs140_util::icmp::IcmpSocket;
 use cs140_util::nat;
@@ -12,9 +12,20 @@ use cs140_util::tcp::tcp::TCPSocket;
 async fn main() {
     let mut builder = env_logger::Builder::from_default_env();
     builder.format_timestamp_millis().init();
-    let layer = PhysicalLayer::new(1, 128);
+    let layer = PhysicalLayerConfig::new().padding_zero_byte_len(1).max_package_byte_len(128).with_env().unwrap().build().unwrap();
     let layer = RedundancyLayer::new(layer);
     let layer = IPLayer::new(layer);
+    let mut link_status = layer.link_status();
+    tokio::spawn(async move {
+        while link_status.changed().await.is_ok() {
+            let status = link_status.borrow().clone();
+            if status.is_up() {
+                log::info!("the link is up");
+            } else {
+                log::warn!("the link is down: {:?}", status);
+            }
+        }
+    });
     // let socket = UdpSocket::bind("10.19.73.32:18888").await.unwrap();
     // run_nat(layer, socket, CS120ProtocolType::Udp).await;
     // let socket = IcmpSocket::new();
@@ -26,6 +37,5 @@ async fn main() {
     // println!("GGGG");
 
     let socket = UdpSocket::bind("10.19.73.32:18888").await.unwrap();
-    run_nat(layer, socket, CS120ProtocolType::Tcp).await;
-    std::thread::park();
+    run_nat(layer, socket, CS120ProtocolType::Tcp, async { tokio::signal::ctrl_c().await.unwrap() }).await;
 }
\ No newline at end of file
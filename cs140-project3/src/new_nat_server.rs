@@ -21,6 +21,5 @@ async fn main() {
     read(&mut buf);
     let remote_addr: Ipv4Addr = buf.parse().unwrap();
     // run_nat_server(Ipv4Addr::new(10, 19, 73, 32), Ipv4Addr::new(10, 19, 75, 4)).await;
-    run_nat_server(local_addr, remote_addr).await;
-    std::thread::park();
+    run_nat_server(local_addr, remote_addr, async { tokio::signal::ctrl_c().await.unwrap() }).await;
 }
\ No newline at end of file
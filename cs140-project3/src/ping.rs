@@ -8,11 +8,13 @@ use cs140_util::icmp::{AudioPinger, AudioPingUtil};
 
 const PING_COUNT: usize = 1;
 
-fn read(buf: &mut String) {
+// read a line, false at the end of the input
+fn read(buf: &mut String) -> bool {
     buf.clear();
-    std::io::stdin().read_line(buf);
+    let len = std::io::stdin().read_line(buf).unwrap_or(0);
     let tmp = buf.trim().clone();
     *buf = String::from(tmp);
+    len > 0
 }
 
 #[tokio::main]
@@ -25,12 +27,13 @@ async fn main() {
 
     loop {
         println!("please type the ping address,");
-        read(&mut buf);
+        if !read(&mut buf) {
+            break;
+        }
         for i in 0..PING_COUNT {
             let addr = buf.parse::<Ipv4Addr>().unwrap();
             pinger.ping_once(addr, i as u16).await;
         }
     }
-
-    std::thread::park();
+    pinger.shutdown(std::time::Duration::from_secs(5)).await;
 }
@@ -2,7 +2,7 @@ use std::net::{IpAddr, SocketAddr, SocketAddrV4};
 use std::str::FromStr;
 use log::trace;
 use cs140_network::ip::{IPLayer, IPPackage};
-use cs140_network::physical::PhysicalLayer;
+use cs140_network::config::PhysicalLayerConfig;
 use cs140_network::redundancy::RedundancyLayer;
 use cs140_util::icmp::AudioPinger;
 
@@ -12,13 +12,15 @@ const PING_COUNT: usize = 20;
 async fn main() {
     let mut builder = env_logger::Builder::from_default_env();
     builder.format_timestamp_millis().init();
-    let layer = PhysicalLayer::new(1, 128);
+    let layer = PhysicalLayerConfig::new().padding_zero_byte_len(1).max_package_byte_len(128).with_env().unwrap().build().unwrap();
     let layer = RedundancyLayer::new(layer);
     let mut layer = IPLayer::new(layer);
 
     let mut ping_replyer = AudioPinger::new(layer, 0x0002);
 
-    ping_replyer.wait_icmp_request_and_reply().await;
-
-    std::thread::park();
+    tokio::select! {
+        _ = ping_replyer.wait_icmp_request_and_reply() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    ping_replyer.shutdown(std::time::Duration::from_secs(5)).await;
 }
@@ -4,7 +4,7 @@ use log::trace;
 use tokio::net::UdpSocket;
 use cs140_network::encoding::HandlePackage;
 use cs140_network::ip::{IPLayer, IPPackage};
-use cs140_network::physical::PhysicalLayer;
+use cs140_network::config::PhysicalLayerConfig;
 use cs140_network::redundancy::RedundancyLayer;
 use cs140_util::file_io;
 use cs140_util::rpc::{CS120RPC, Transport, UdpPackage};
@@ -19,13 +19,14 @@ async fn main() {
     builder.format_timestamp_millis().init();
     let data = file_io::read_bytes_from_file(PATH);
     trace!("{:?}", data);
-    let layer = PhysicalLayer::new(1, 64);
+    let layer = PhysicalLayerConfig::new().padding_zero_byte_len(1).max_package_byte_len(64).with_env().unwrap().build().unwrap();
     let layer = RedundancyLayer::new(layer);
     let mut layer = IPLayer::new(layer);
     let src = SocketAddr::from(SocketAddrV4::from_str("192.168.1.2:1234").unwrap());
     let dst = SocketAddr::from(SocketAddrV4::from_str("10.19.75.77:28888").unwrap());
     let package = CS120RPC::UdpPackage(UdpPackage{src, dst, data});
     layer.trans(package).await;
+    // the fragments are still being sent, they are flushed before the sound card is stopped
+    layer.shutdown(std::time::Duration::from_secs(60)).await;
     trace!("send completed!");
-    std::thread::park();
 }
@@ -25,8 +25,9 @@ async fn main() {
         std::str::from_utf8(data.as_ref()).unwrap_or("(invalid utf8)")
     );
     tcp_socket.close(src_port).await;
+    // the closing segments are flushed before the sound card is stopped
+    tcp_socket.shutdown(std::time::Duration::from_secs(5)).await;
     println!("connection terminated!");
-    std::thread::park();
 
     // let addr = std::net::Ipv4Addr::new(101, 32, 194, 18);
     // let addr1 = std::net::Ipv4Addr::new(10, 19, 75, 4);
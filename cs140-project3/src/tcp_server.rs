@@ -18,5 +18,6 @@ async fn main() {
         // std::thread::sleep(std::time::Duration::from_millis(1000));
         tcp_socket.send(pic.to_vec(), src_port).await;
     }
-    std::thread::park();
+    // the queued segments are flushed before the sound card is stopped
+    tcp_socket.shutdown(std::time::Duration::from_secs(5)).await;
 }
\ No newline at end of file
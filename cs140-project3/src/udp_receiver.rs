@@ -19,6 +19,4 @@ async fn main() {
         let string : &str = std::str::from_utf8(result).expect("couldn't convert");
         println!("{}", string);
     }
-
-    std::thread::park();
 }
\ No newline at end of file
@@ -23,5 +23,4 @@ async fn main() {
     let socket = UdpSocket::bind("10.19.75.77:22791").await.unwrap();
     socket.send_to(data.as_slice(), dst).await;
     trace!("send completed!");
-    std::thread::park();
 }
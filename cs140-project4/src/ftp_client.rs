@@ -298,6 +298,8 @@ async fn user_tcp_ftp() {
     stream.close(PORT1).await;
     stream.close(PORT2).await;
     stream.close(PORT3).await;
+    // the closing segments are flushed before the sound card is stopped
+    stream.shutdown(std::time::Duration::from_secs(5)).await;
     println!("ftp connection terminated!");
 }
 
@@ -307,5 +309,4 @@ async fn main() {
     builder.format_timestamp_millis().init();
     // std_tcp_ftp();
     user_tcp_ftp().await;
-    std::thread::park();
 }
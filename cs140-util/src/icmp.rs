@@ -1,11 +1,13 @@
 use std::mem::MaybeUninit;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddrV4};
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
 use pnet::packet::icmp::echo_request::{MutableEchoRequestPacket, EchoRequestPacket, EchoRequest};
 use pnet::packet::icmp::echo_reply::{EchoReply, EchoReplyPacket, MutableEchoReplyPacket};
@@ -22,7 +24,7 @@ use smoltcp::wire::ieee802154::Address;
 use tokio::io;
 use cs140_network::encoding::HandlePackage;
 use cs140_network::ip::{IPLayer, IPPackage};
-use cs140_network::physical::PhysicalLayer;
+use cs140_network::config::PhysicalLayerConfig;
 use cs140_network::redundancy::RedundancyLayer;
 use crate::rpc::{CS120RPC, CS120Socket, IcmpPackage, Transport};
 
@@ -127,20 +129,31 @@ impl IcmpSocket {
 pub struct AudioPingUtil {
     send_ping_send: Sender<(Ipv4Addr, u16)>,
     ping_result_recv: Receiver<(Ipv4Address, u16)>,
+    shutdown_send: oneshot::Sender<Duration>,
+    task: JoinHandle<()>,
 }
 
 impl AudioPingUtil {
     pub fn new() -> Self {
-        let layer = PhysicalLayer::new(1, 128);
+        Self::with_config(PhysicalLayerConfig::new().padding_zero_byte_len(1).max_package_byte_len(128).with_env().unwrap()).unwrap()
+    }
+
+    pub fn with_config(config: PhysicalLayerConfig) -> anyhow::Result<Self> {
+        let layer = config.build()?;
         let layer = RedundancyLayer::new(layer);
         let mut layer = IPLayer::new(layer);
         let (send_ping_send, mut send_ping_recv) = channel::<(Ipv4Addr, u16)>(1024);
         let (ping_result_send, ping_result_recv) = channel::<(Ipv4Address, u16)>(1024);
+        let (shutdown_send, mut shutdown_recv) = oneshot::channel::<Duration>();
         let mut identifier: u16 = 0x02;
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             loop {
                 let mut TIME = tokio::time::Instant::now();
                 tokio::select! {
+                    flush_timeout = &mut shutdown_recv => {
+                        layer.shutdown(flush_timeout.unwrap()).await;
+                        return;
+                    }
                     target = send_ping_recv.recv() => {
                         let (target, sequence_number) = target.unwrap();
                         let packet_size = EchoRequestPacket::minimum_packet_size();
@@ -210,10 +223,18 @@ impl AudioPingUtil {
                 };
             }
         });
-        AudioPingUtil{
+        Ok(AudioPingUtil{
             send_ping_send,
-            ping_result_recv
-        }
+            ping_result_recv,
+            shutdown_send,
+            task,
+        })
+    }
+
+    /// flush the replies and stop the sound card
+    pub async fn shutdown(self, flush_timeout: Duration) {
+        let _ = self.shutdown_send.send(flush_timeout);
+        self.task.await.unwrap();
     }
     pub async fn ping_once(&mut self, target: Ipv4Addr, sequence_number: u16) {
         let mut TIME = Instant::now();
@@ -328,6 +349,11 @@ impl AudioPinger {
         }
     }
 
+    /// flush the replies and stop the sound card
+    pub async fn shutdown(self, flush_timeout: Duration) {
+        self.layer.shutdown(flush_timeout).await;
+    }
+
     fn make_reply_packet(&self, buf: &mut [u8]) {
         let mut echo_reply_packet = MutableEchoReplyPacket::new(buf).unwrap();
         echo_reply_packet.set_icmp_type(IcmpTypes::EchoReply);
@@ -1,5 +1,7 @@
+use std::future::Future;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::str::FromStr;
+use std::time::Duration;
 use tokio::{
     net::{UdpSocket},
     sync::mpsc::{channel},
@@ -16,15 +18,22 @@ use crate::icmp::IcmpSocket;
 use crate::tcp::tcp::TCPSocket;
 
 static TCPPORT: u16 = 33113;
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub async fn run_nat(mut layer: IPLayer, mut listen_socket: impl CS120Socket + std::marker::Send + 'static, protocol_type: CS120ProtocolType) {
+/// forward the packages between the layer and the socket until shutdown resolves, then flush the layer and stop the sound card
+pub async fn run_nat(layer: IPLayer, mut listen_socket: impl CS120Socket + std::marker::Send + 'static, protocol_type: CS120ProtocolType, shutdown: impl Future<Output=()> + Send + 'static) {
     let (audio_to_socket_sender, mut audio_to_socket_receiver) = channel::<CS120RPC>(1024);
     let (socket_to_audio_sender, mut socket_to_audio_receiver) = channel::<CS120RPC>(1024);
     let mut icmp_socket = IcmpSocket::new();
     let mut tcp_socket = TCPSocket::new();
-    tokio::spawn(async move {
+    let audio = tokio::spawn(async move {
+        tokio::pin!(shutdown);
         loop {
             tokio::select! {
+                _ = &mut shutdown => {
+                    layer.shutdown(SHUTDOWN_FLUSH_TIMEOUT).await;
+                    return;
+                }
                 package = socket_to_audio_receiver.recv() => {
                     match package {
                         None => {
@@ -170,4 +179,5 @@ pub async fn run_nat(mut layer: IPLayer, mut listen_socket: impl CS120Socket + s
             }
         }
     });
-}
\ No newline at end of file
+    audio.await.unwrap();
+}
@@ -1,10 +1,12 @@
 use std::{
+    future::Future,
     net::{
         SocketAddr,
         Ipv4Addr,
         IpAddr,
     },
     sync::Arc,
+    time::Duration,
 };
 use tokio::{
     net::{
@@ -17,8 +19,8 @@ use tokio::{
     },
 };
 use cs140_network::{
+    config::PhysicalLayerConfig,
     ip::IPLayer,
-    physical::PhysicalLayer,
     redundancy::RedundancyLayer,
 };
 use smoltcp::{
@@ -33,18 +35,25 @@ use log::{
 use crate::rpc::Transport;
 
 pub static PORT: u16 = 18888;
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub async fn run_nat_server(local_addr: Ipv4Addr, unix_server_addr: Ipv4Addr) {
+/// forward the packages between the layer and the unix server until shutdown resolves, then flush the layer and stop the sound card
+pub async fn run_nat_server(local_addr: Ipv4Addr, unix_server_addr: Ipv4Addr, shutdown: impl Future<Output=()> + Send + 'static) {
     let udp_socket = UdpSocket::bind(SocketAddr::new(IpAddr::from(local_addr), PORT)).await.unwrap();
     let unix_server_addr = SocketAddr::new(IpAddr::from(unix_server_addr), PORT);
-    let layer = PhysicalLayer::new(1, 128);
+    let layer = PhysicalLayerConfig::new().padding_zero_byte_len(1).max_package_byte_len(128).with_env().unwrap().build().unwrap();
     let layer = RedundancyLayer::new(layer);
     let layer = IPLayer::new(layer);
     let (audio_to_socket_sender, mut audio_to_socket_receiver) = channel::<Ipv4Packet<Vec<u8>>>(1024);
     let (socket_to_audio_sender, mut socket_to_audio_receiver) = channel::<Ipv4Packet<Vec<u8>>>(1024);
-    tokio::spawn(async move {
+    let audio = tokio::spawn(async move {
+        tokio::pin!(shutdown);
         loop {
             tokio::select! {
+                    _ = &mut shutdown => {
+                        layer.shutdown(SHUTDOWN_FLUSH_TIMEOUT).await;
+                        return;
+                    }
                     package = socket_to_audio_receiver.recv() => {
                         match package {
                             None => {
@@ -91,4 +100,5 @@ pub async fn run_nat_server(local_addr: Ipv4Addr, unix_server_addr: Ipv4Addr) {
             }
         }
     });
-}
\ No newline at end of file
+    audio.await.unwrap();
+}
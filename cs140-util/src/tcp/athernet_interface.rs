@@ -6,7 +6,7 @@ use std::task::Poll;
 use tokio::runtime::Handle;
 use tokio::time::error::Elapsed;
 use cs140_network::ip::{IPLayer, IPPackage};
-use cs140_network::physical::PhysicalLayer;
+use cs140_network::config::PhysicalLayerConfig;
 use cs140_network::redundancy::RedundancyLayer;
 use crate::rpc::{CS120RPC, TcpPackage, Transport};
 
@@ -18,15 +18,26 @@ pub struct AthernetInterface {
 
 impl AthernetInterface {
     pub fn new(mtu: usize, medium: Medium) -> Self {
-        let layer = PhysicalLayer::new(1, mtu);
+        Self::with_config(PhysicalLayerConfig::new().padding_zero_byte_len(1).max_package_byte_len(mtu).with_env().unwrap(), medium).unwrap()
+    }
+
+    /// the mtu is the max package size of the config
+    pub fn with_config(config: PhysicalLayerConfig, medium: Medium) -> anyhow::Result<Self> {
+        let layer = config.build()?;
+        let mtu = layer.max_package_byte_len();
         let layer = RedundancyLayer::new(layer);
         let layer = IPLayer::new(layer);
         let layer = Arc::new(layer);
-        AthernetInterface {
+        Ok(AthernetInterface {
             layer,
             mtu,
             medium,
-        }
+        })
+    }
+
+    /// the layer below the interface, it can be shut down after the interface is dropped
+    pub fn layer(&self) -> Arc<IPLayer> {
+        self.layer.clone()
     }
 }
 
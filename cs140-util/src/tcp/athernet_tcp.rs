@@ -3,6 +3,7 @@ use std::{
     collections::VecDeque,
 };
 use std::sync::Mutex;
+use std::time::Duration;
 use crate::tcp::tcp_stack::TCPClient;
 use smoltcp::{
     time::Instant,
@@ -14,6 +15,7 @@ use smoltcp::iface::SocketHandle;
 use tokio::{
     sync::mpsc::{channel, Sender, Receiver},
     runtime::Handle,
+    task::JoinHandle,
 };
 
 pub enum TcpSocketCommand {
@@ -28,6 +30,8 @@ pub struct AthernetTcpSocket {
     connect_command: Sender<((u16, (Ipv4Addr, u16)), usize)>,
     index: usize,
     tcp_socket_count: usize,
+    shutdown_send: Sender<Duration>,
+    task: JoinHandle<()>,
 }
 
 impl AthernetTcpSocket {
@@ -40,6 +44,8 @@ impl AthernetTcpSocket {
         let mut quit_signal_send: Vec<(Option<u16>, Sender<()>)> = Vec::new();
         let mut tcp_active: Vec<bool> = Vec::new();
         let (connect_send, mut connect_recv) = channel::<((u16, (Ipv4Addr, u16)), usize)>(1024);
+        let (shutdown_send, mut shutdown_recv) = channel::<Duration>(1);
+        let runtime = Handle::current();
         let index_now = 0;
         for _ in 0..tcp_socket_count {
             let (command_send_, mut command_recv_) = channel::<TcpSocketCommand>(1024);
@@ -53,7 +59,7 @@ impl AthernetTcpSocket {
             quit_signal_send.push((None, quit_signal_send_));
             tcp_active.push(false);
         }
-        tokio::task::spawn_blocking(move || {
+        let task = tokio::task::spawn_blocking(move || {
             let mut tcp_handle: Vec<(Option<u16>, SocketHandle, VecDeque<TcpSocketCommand>)> = Vec::new();
             let mtu: usize = 256;
             let tcp_client = Mutex::new(TCPClient::new(mtu));
@@ -68,6 +74,10 @@ impl AthernetTcpSocket {
             // }
             // let mut tcp_active = false;
             loop {
+                if let Ok(flush_timeout) = shutdown_recv.try_recv() {
+                    runtime.block_on(tcp_client.into_inner().unwrap().shutdown(flush_timeout));
+                    return;
+                }
                 let mut guard = tcp_client.lock().unwrap();
                 let timestamp = Instant::now();
                 let result = connect_recv.try_recv();
@@ -162,9 +172,18 @@ impl AthernetTcpSocket {
             quit_signal_recv,
             connect_command: connect_send,
             index: index_now,
-            tcp_socket_count
+            tcp_socket_count,
+            shutdown_send,
+            task,
         }
     }
+
+    /// stop polling the sockets, then flush the packages sent by them and stop the sound card,
+    /// the sockets should be closed before
+    pub async fn shutdown(self, flush_timeout: Duration) {
+        let _ = self.shutdown_send.send(flush_timeout).await;
+        self.task.await.unwrap();
+    }
     pub async fn send(&self, data: Vec<u8>, src_port: u16) {
         for (port, command_send_) in &self.command_send {
             if port.is_none() { continue; }
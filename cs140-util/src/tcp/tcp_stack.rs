@@ -1,6 +1,8 @@
 use std::collections::BTreeMap;
 use std::io::{self, Write};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use log::warn;
 use smoltcp::iface::{InterfaceBuilder, NeighborCache, SocketHandle, Routes, Interface};
 use smoltcp::phy::{Device, Medium, FaultInjector, Tracer, PcapWriter, PcapMode};
 use smoltcp::socket::{TcpSocket, TcpSocketBuffer};
@@ -8,16 +10,19 @@ use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
 use smoltcp::time::{Duration, Instant};
 use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, TcpRepr, IpRepr};
 use crate::rpc::CS120RPC::TcpPackage;
+use cs140_network::ip::IPLayer;
 use crate::tcp::athernet_interface::AthernetInterface;
 
 pub struct TCPClient<'a> {
     // pub tcp_handle: SocketHandle,
     pub iface: Interface<'a, FaultInjector<Tracer<PcapWriter<AthernetInterface, Box<dyn Write>>>>>,
+    layer: Arc<IPLayer>,
 }
 
 impl TCPClient<'_> {
     pub fn new(mtu: usize) -> Self {
         let device = AthernetInterface::new(mtu, Medium::Ip);
+        let layer = device.layer();
 
         let device = middleware(device, /*loopback=*/ true);
 
@@ -37,7 +42,17 @@ impl TCPClient<'_> {
         // let tcp_handle = iface.add_socket(tcp_socket);
         TCPClient {
             // tcp_handle,
-            iface
+            iface,
+            layer,
+        }
+    }
+
+    /// drop the interface, then flush the packages sent by it and stop the sound card
+    pub async fn shutdown(self, flush_timeout: std::time::Duration) {
+        drop(self.iface);
+        match Arc::try_unwrap(self.layer) {
+            Ok(layer) => layer.shutdown(flush_timeout).await,
+            Err(_) => warn!("the layer is still used after the interface is dropped, it is not shut down"),
         }
     }
 